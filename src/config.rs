@@ -1,5 +1,10 @@
+use crate::document::{Document, DocumentIndex};
+use crate::error::SiteError;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    path::{Path, PathBuf},
+};
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct File {
@@ -36,6 +41,15 @@ pub struct DownloadInfo {
     gold_only: bool,
     #[serde(default)]
     login_prompt: bool,
+    // Free-form, but "stable" is what the main download page shows.
+    #[serde(default = "default_channel")]
+    channel: String,
+}
+
+const STABLE_CHANNEL: &str = "stable";
+
+fn default_channel() -> String {
+    STABLE_CHANNEL.to_string()
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, Default)]
@@ -59,6 +73,8 @@ struct BinaryVersion {
 pub struct VersionDownloads {
     version: String,
     downloads: Vec<DownloadInfo>,
+    #[serde(default)]
+    highlights: Vec<String>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -111,12 +127,96 @@ pub struct Screenshot {
 pub struct GlobalMeta {
     pub app_version: String,
     pub platforms: Vec<PlatformInfo>,
+    pub platforms_stable: Vec<PlatformInfo>,
+    pub platforms_by_channel: HashMap<String, Vec<PlatformInfo>>,
     pub version_downloads: Vec<VersionDownloads>,
     pub top_nav: Vec<DocLink>,
     pub prod: bool,
     pub authors: HashMap<String, Author>,
     pub screenshots: Vec<Screenshot>,
     pub latest_news: Vec<DocLink>,
+    // Whether to load the Mermaid script on pages with diagrams.
+    pub mermaid: bool,
+    // Set when serving locally, makes pages reload when the site is rebuilt.
+    pub livereload: bool,
+    // For the page header. None leaves just the site name.
+    pub site_logo: Option<String>,
+    // Absolute URL of the default cover, for social previews of pages without their own.
+    pub og_image: Option<String>,
+    // Whether there's an opensearch.xml to link to.
+    pub opensearch: bool,
+    // Keeps search engines from indexing anything but production builds, see gen_robots.
+    pub noindex: bool,
+    // Icon tags for the page header, when they're generated, see favicon.
+    #[serde(skip_deserializing)]
+    pub favicons: Vec<crate::favicon::FaviconLink>,
+}
+
+// Platforms with only the downloads of the given channel. Platforms left empty are dropped.
+fn filter_channel(platforms: &[PlatformInfo], channel: &str) -> Vec<PlatformInfo> {
+    let mut filtered = platforms
+        .iter()
+        .map(|platform| PlatformInfo {
+            downloads: platform
+                .downloads
+                .iter()
+                .filter(|download| download.channel == channel)
+                .cloned()
+                .collect::<Vec<_>>(),
+            ..platform.clone()
+        })
+        .filter(|platform| !platform.downloads.is_empty())
+        .collect::<Vec<_>>();
+    for (index, platform) in filtered.iter_mut().enumerate() {
+        platform.newline = (index % 3) == 2;
+    }
+    filtered
+}
+
+fn platforms_by_channel(platforms: &[PlatformInfo]) -> HashMap<String, Vec<PlatformInfo>> {
+    let mut channel_counts = HashMap::<&str, usize>::new();
+    for download in platforms.iter().flat_map(|platform| &platform.downloads) {
+        *channel_counts.entry(&download.channel).or_default() += 1;
+    }
+
+    // A channel only used once is usually a typo.
+    let mut singles = channel_counts
+        .iter()
+        .filter(|(_, count)| **count == 1)
+        .map(|(channel, _)| *channel)
+        .collect::<Vec<_>>();
+    if !singles.is_empty() {
+        singles.sort();
+        println!(
+            "Warning: download channels used only once (typo?): {}",
+            singles.join(", ")
+        );
+    }
+
+    channel_counts
+        .keys()
+        .map(|channel| (channel.to_string(), filter_channel(platforms, channel)))
+        .collect()
+}
+
+// Expands ${VAR} references from the environment, so deploy-specific values like domains
+// don't have to be committed. Referencing an unset variable is an error.
+pub fn expand_env_vars(value: &str) -> anyhow::Result<String> {
+    let env_regex = regex::Regex::new(r"\$\{([A-Za-z_][A-Za-z0-9_]*)\}").unwrap();
+    let mut missing = vec![];
+    let expanded = env_regex.replace_all(value, |captures: &regex::Captures<'_>| {
+        std::env::var(&captures[1]).unwrap_or_else(|_| {
+            missing.push(captures[1].to_string());
+            String::new()
+        })
+    });
+    anyhow::ensure!(
+        missing.is_empty(),
+        "Unset environment variables referenced in \"{}\": {}",
+        value,
+        missing.join(", ")
+    );
+    Ok(expanded.to_string())
 }
 
 fn download_path(url_base: &str, version: &str, filename: &str) -> String {
@@ -137,25 +237,30 @@ fn gold_download_path(url_base: &str, version: &str, filename: &str) -> String {
     )
 }
 
+fn read_data_json<T: serde::de::DeserializeOwned>(path: &str) -> Result<T, SiteError> {
+    let json = std::fs::read_to_string(path).map_err(|source| SiteError::Io {
+        path: path.into(),
+        source,
+    })?;
+    serde_json::from_str(&json).map_err(|err| SiteError::Config {
+        path: path.into(),
+        message: err.to_string(),
+    })
+}
+
 impl GlobalMeta {
     pub fn new(production: bool, url_base: &str, top_nav: Vec<DocLink>) -> anyhow::Result<Self> {
         // Parse the download path dump.
 
-        let downloads_json = std::fs::read_to_string("data/downloads.json")?;
-        let downloads_gold_json = std::fs::read_to_string("data/downloads_gold.json")?;
-        let platforms_json = std::fs::read_to_string("data/platform.json")?;
-        let authors_json = std::fs::read_to_string("data/authors.json")?;
-        let screenshots_json = std::fs::read_to_string("data/screenshots.json")?;
-
-        let downloads: File = serde_json::from_str(&downloads_json).unwrap();
-        let downloads_gold: File = serde_json::from_str(&downloads_gold_json).unwrap();
-        let authors: HashMap<String, Author> = serde_json::from_str(&authors_json).unwrap();
-        let mut screenshots: Vec<Screenshot> = serde_json::from_str(&screenshots_json).unwrap();
+        let downloads: File = read_data_json("data/downloads.json")?;
+        let downloads_gold: File = read_data_json("data/downloads_gold.json")?;
+        let authors: HashMap<String, Author> = read_data_json("data/authors.json")?;
+        let mut screenshots: Vec<Screenshot> = read_data_json("data/screenshots.json")?;
         for (index, shot) in screenshots.iter_mut().enumerate() {
             shot.index = (index as i32) + 1;
         }
 
-        let mut platforms: Vec<PlatformInfo> = serde_json::from_str(&platforms_json).unwrap();
+        let mut platforms: Vec<PlatformInfo> = read_data_json("data/platform.json")?;
 
         let version_binaries = parse_files(downloads, downloads_gold);
 
@@ -182,6 +287,8 @@ impl GlobalMeta {
         }
 
         let version_downloads = boil(url_base, &version_binaries, &platforms);
+        let platforms_stable = filter_channel(&platforms, STABLE_CHANNEL);
+        let platforms_by_channel = platforms_by_channel(&platforms);
 
         //println!("{:#?}", version_binaries);
         //println!("{:#?}", file_versions);
@@ -203,12 +310,46 @@ impl GlobalMeta {
             authors,
             prod: production,
             platforms,
+            platforms_stable,
+            platforms_by_channel,
             version_downloads,
             top_nav,
             screenshots,
             latest_news: vec![],
+            mermaid: true,
+            livereload: false,
+            site_logo: None,
+            og_image: None,
+            opensearch: false,
+            noindex: !production,
+            favicons: vec![],
         })
     }
+
+    // Attaches the "what's new" highlights from each version's release announcement, if any.
+    pub fn add_version_highlights(&mut self, documents: &DocumentIndex<'_>) {
+        for version in &mut self.version_downloads {
+            if let Some(doc) = find_release_news(documents, &version.version) {
+                version.highlights = doc.meta.highlights.clone();
+            }
+        }
+    }
+}
+
+// News posts announcing a release use the slug `release-<version>`. Point releases often
+// don't get their own post, so fall back to the announcement of the major.minor version.
+pub fn find_release_news<'a>(documents: &DocumentIndex<'a>, version: &str) -> Option<&'a Document> {
+    let mut version = version;
+    loop {
+        if let Some(doc) = documents.by_slug("news", &format!("release-{version}")) {
+            return Some(doc);
+        }
+        let (prefix, _) = version.rsplit_once('.')?;
+        if !prefix.contains('.') {
+            return None;
+        }
+        version = prefix;
+    }
 }
 
 fn to_binaries_per_version(files: File) -> Vec<BinaryVersion> {
@@ -269,6 +410,7 @@ fn boil(
         let mut version_download = VersionDownloads {
             version: version_str.clone(),
             downloads: vec![],
+            highlights: vec![],
         };
 
         for platform in platforms {
@@ -307,12 +449,255 @@ fn boil(
     downloads
 }
 
+// How pages are written, and linked to. It's one or the other for the whole site.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum UrlStyle {
+    // about/index.html, linked to as /about.
+    Folders,
+    // about.html, linked to as /about.html.
+    #[value(alias = "html_files")]
+    HtmlFiles,
+}
+
 pub struct Config {
     pub url_base: String,
+    // Non-empty when the site is hosted in a subdirectory, like "/ppsspp". No trailing slash.
+    pub path_prefix: String,
+    pub url_style: UrlStyle,
     pub in_dir: PathBuf,
     pub out_dir: PathBuf,
     pub markdown_options: markdown::Options,
     pub global_meta: GlobalMeta,
     pub build_date: String,
     pub github_url: &'static str,
+    // Deepest heading level listed in page TOCs, 3 means h2 and h3.
+    pub toc_max_depth: u32,
+    // Most recent posts included in each feed. The sitemap lists them all regardless.
+    pub feed_item_limit: usize,
+    // Shown by feed readers. A site path or a full URL.
+    pub feed_image: Option<String>,
+    // Cover for posts and pages that don't set one. A site path or a full URL.
+    pub default_cover: Option<String>,
+    // Normalize whitespace in generated pages, see post_process::tidy_html.
+    pub tidy_html: bool,
+    // Blog-like sections whose posts don't need a date, like news.
+    pub optional_date_sections: Vec<String>,
+    // For opensearch.xml, with {searchTerms} for the query. A site path or a full URL.
+    pub search_url: Option<String>,
+    // More .siteignore patterns for static/.
+    pub static_exclude: Vec<String>,
+    // What copying static/ does with symlinks.
+    pub symlinks: crate::util::SymlinkMode,
+    // Also write every doc on one page, see gen_doctree.
+    pub all_docs_page: bool,
+    // Older versions of the docs, each in docs/<version>/, newest first.
+    pub doc_versions: Vec<String>,
+    // Posts per page in blog listings.
+    pub blog_page_size: usize,
+    // Abort on the first broken document instead of skipping it with a warning, and on broken
+    // links.
+    pub strict: bool,
+    // Fields missing from template data fail the build, see --strict-templates.
+    pub strict_templates: bool,
+    // Minify generated pages, see post_process::minify_html. Takes precedence over tidy_html.
+    pub minify_html: bool,
+    // Write static files under hashed names, see fingerprint.
+    pub fingerprint: bool,
+    // And under the original names too.
+    pub keep_unhashed: bool,
+    // Site path of each static file -> its fingerprinted path, like /static/css/all.css ->
+    // /static/css/all.<hash>.css. Filled in while copying, before any page is rendered.
+    pub assets: std::cell::RefCell<BTreeMap<String, String>>,
+    // Look for links to static files that aren't in the output. On with fingerprinting, and
+    // for --check.
+    pub check_assets: bool,
+    // Static paths linked from pages that aren't in the output, see
+    // fingerprint::check_asset_references.
+    pub missing_assets: std::cell::RefCell<BTreeSet<String>>,
+    // Widths to resize images in static/ to, see images. None when empty.
+    pub thumbnail_widths: Vec<u32>,
+    // Also convert them to WebP.
+    pub webp: bool,
+    // Site path of each image that was processed -> what it got.
+    pub images: std::cell::RefCell<HashMap<String, crate::images::ImageVariants>>,
+    pub image_stats: std::cell::Cell<crate::images::ImageStats>,
+    pub warnings: std::cell::RefCell<Vec<String>>,
+    // Documents skip_on_error left out.
+    pub skipped: std::cell::Cell<usize>,
+    // What minify_html saved.
+    pub minified_pages: std::cell::Cell<crate::stats::MinifyStats>,
+    // Lowercased output path -> (output path, source), to catch pages that would overwrite
+    // each other, also on case-insensitive filesystems.
+    pub outputs: std::cell::RefCell<HashMap<String, (PathBuf, PathBuf)>>,
+    // Output file of each page -> its links and ids, see linkcheck.
+    pub pages: std::cell::RefCell<HashMap<PathBuf, crate::linkcheck::PageLinks>>,
+    // UI strings for the t helper.
+    pub translations: std::sync::Arc<crate::i18n::Translations>,
+}
+
+impl Config {
+    pub fn is_doc_version_folder(&self, path: &Path) -> bool {
+        self.doc_versions
+            .iter()
+            .any(|version| path == self.in_dir.join("docs").join(version))
+    }
+
+    // Like site_url, but full URLs are passed through as they are.
+    pub fn absolute_url(&self, url: &str) -> String {
+        if url.contains("://") {
+            url.to_owned()
+        } else {
+            self.site_url(url)
+        }
+    }
+
+    // Absolute URL for a site-relative path, for feeds and the sitemap.
+    pub fn site_url(&self, path: &str) -> String {
+        format!("{}{}{}", self.url_base, self.path_prefix, path)
+    }
+
+    // The URL of a page like /docs/intro in the URL style. With HtmlFiles, that's
+    // /docs/intro.html. The root, and paths of files like /blog/rss.xml, stay as they are.
+    // Version folders like /docs/1.16 aren't files, so extensions need a letter.
+    pub fn page_url(&self, url: &str) -> String {
+        page_url(self.url_style, url)
+    }
+    // Per-document failures only abort strict builds, or with strict_templates, fields missing
+    // from template data. Otherwise they're recorded as warnings
+    // and the caller skips the document, so one broken file doesn't block previewing the rest.
+    // Output collisions always abort, since which page wins would depend on the file order.
+    pub fn skip_on_error<T>(
+        &self,
+        what: &Path,
+        result: anyhow::Result<T>,
+    ) -> anyhow::Result<Option<T>> {
+        match result {
+            Ok(value) => Ok(Some(value)),
+            Err(err) if self.strict_templates && crate::error::is_missing_field(&err) => {
+                Err(crate::error::with_document(err, what))
+            }
+            Err(err) if crate::error::is_missing_include(&err) => {
+                Err(crate::error::with_document(err, what))
+            }
+            Err(err) if crate::error::is_collision(&err) => Err(err),
+            Err(err) if self.strict => Err(err),
+            Err(err) => {
+                self.warn(format!("Skipped {}: {:#}", what.display(), err));
+                self.skipped.set(self.skipped.get() + 1);
+                Ok(None)
+            }
+        }
+    }
+
+    // Records that source generates out_path. Fails if something else already did, naming
+    // both, since one would overwrite the other. Also fails if a file in static/ is copied
+    // there, or in place of a folder on the way.
+    pub fn claim_output(&self, out_path: &Path, source: &Path) -> anyhow::Result<()> {
+        let key = out_path.to_string_lossy().to_lowercase();
+        let mut outputs = self.outputs.borrow_mut();
+        if let Some((other_out, other_source)) = outputs.get(&key) {
+            if other_source != source {
+                let message = if other_out == out_path {
+                    format!(
+                        "writes {}, like {}",
+                        out_path.display(),
+                        other_source.display()
+                    )
+                } else {
+                    format!(
+                        "writes {}, differing only in case from {} by {}",
+                        out_path.display(),
+                        other_out.display(),
+                        other_source.display()
+                    )
+                };
+                return Err(SiteError::Collision {
+                    path: source.to_path_buf(),
+                    message,
+                }
+                .into());
+            }
+            return Ok(());
+        }
+        let static_file = out_path
+            .ancestors()
+            .filter_map(|path| path.strip_prefix(&self.out_dir).ok())
+            .filter(|relative| relative.starts_with("static"))
+            .map(|relative| self.in_dir.join(relative))
+            .find(|path| path.is_file());
+        if let Some(static_file) = static_file {
+            return Err(SiteError::Collision {
+                path: source.to_path_buf(),
+                message: format!(
+                    "writes {}, where {} is copied to",
+                    out_path.display(),
+                    static_file.display()
+                ),
+            }
+            .into());
+        }
+        outputs.insert(key, (out_path.to_path_buf(), source.to_path_buf()));
+        Ok(())
+    }
+
+    // Printed right away, and again in the summary at the end of the build.
+    pub fn warn(&self, warning: String) {
+        println!("Warning: {}", warning);
+        self.warnings.borrow_mut().push(warning);
+    }
+
+    // Templates and markdown link to root-relative paths like "/docs/intro". Links to pages
+    // get the URL style, and when hosting in a subdirectory, they all need the prefix, except
+    // the ones from asset_url, which have it already. Protocol-relative links are left alone.
+    pub fn prefix_root_links(&self, html: String) -> String {
+        let html = if self.url_style == UrlStyle::Folders {
+            html
+        } else {
+            let href_regex = regex::Regex::new(r#"\bhref="(/[^/"][^"]*|/)""#).unwrap();
+            href_regex
+                .replace_all(&html, |captures: &regex::Captures<'_>| {
+                    format!("href=\"{}\"", self.page_url(&captures[1]))
+                })
+                .into_owned()
+        };
+        if self.path_prefix.is_empty() {
+            return html;
+        }
+        let link_regex = regex::Regex::new(r#"\b(href|src|action)="(/(?:[^/"][^"]*)?)""#).unwrap();
+        link_regex
+            .replace_all(&html, |captures: &regex::Captures<'_>| {
+                if is_prefixed_asset(&self.path_prefix, &captures[2]) {
+                    captures[0].to_owned()
+                } else {
+                    format!("{}=\"{}{}\"", &captures[1], self.path_prefix, &captures[2])
+                }
+            })
+            .to_string()
+    }
+}
+
+// Whether a path already has the prefix, like the ones from the asset_url helper. Only static
+// files are recognized, the site could have its own folder named like the prefix.
+pub fn is_prefixed_asset(path_prefix: &str, path: &str) -> bool {
+    !path_prefix.is_empty()
+        && path
+            .strip_prefix(path_prefix)
+            .is_some_and(|path| path.starts_with("/static/"))
+}
+
+// Config::page_url, for the helpers, which don't keep the config.
+pub fn page_url(url_style: UrlStyle, url: &str) -> String {
+    if url_style == UrlStyle::Folders || !url.starts_with('/') {
+        return url.to_owned();
+    }
+    let (path, rest) = url.split_at(url.find(['?', '#']).unwrap_or(url.len()));
+    let path = path.trim_end_matches('/');
+    let name = path.rsplit('/').next().unwrap_or_default();
+    let is_file = name
+        .rsplit_once('.')
+        .is_some_and(|(_, extension)| extension.contains(|c: char| c.is_ascii_alphabetic()));
+    if path.is_empty() || is_file || path.starts_with("/static/") {
+        return url.to_owned();
+    }
+    format!("{path}.html{rest}")
 }
@@ -0,0 +1,265 @@
+use crate::fingerprint;
+use crate::stats;
+use rayon::prelude::*;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+// What --dedupe-static does with static files that have the same contents.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum DedupeMode {
+    // Hard-link the copies in the output to one file, so the deploy carries it once.
+    Link,
+    // List them, for cleaning up static/.
+    Report,
+}
+
+// Files in the output with the same contents, by site path.
+pub struct Duplicates {
+    pub size: u64,
+    pub paths: Vec<String>,
+}
+
+impl Duplicates {
+    pub fn wasted(&self) -> u64 {
+        self.size * (self.paths.len() as u64 - 1)
+    }
+}
+
+#[derive(Default)]
+pub struct DedupeStats {
+    pub groups: Vec<Duplicates>,
+    // Copies that are links to the first file of their group, maybe since the last build.
+    pub linked: usize,
+    // By linking.
+    pub saved: u64,
+    // Copies that couldn't be linked, and stay copies.
+    pub link_failed: usize,
+}
+
+impl DedupeStats {
+    pub fn wasted(&self) -> u64 {
+        self.groups.iter().map(Duplicates::wasted).sum()
+    }
+}
+
+// Finds the files under dir in out_dir with the same contents, and with DedupeMode::Link,
+// replaces all but the first of each group with hard links to it. Only same-sized files are
+// read, and groups are compared byte for byte before linking. Symlinks are left alone.
+pub fn dedupe(out_dir: &Path, dir: &Path, mode: DedupeMode) -> anyhow::Result<DedupeStats> {
+    let mut stats = DedupeStats::default();
+    if !dir.is_dir() {
+        // Built in memory.
+        return Ok(stats);
+    }
+    let mut by_size = BTreeMap::<u64, Vec<PathBuf>>::new();
+    collect_files(dir, &mut by_size)?;
+    let candidates = by_size
+        .into_iter()
+        .filter(|(size, paths)| *size > 0 && paths.len() > 1)
+        .collect::<Vec<_>>();
+    let mut groups = candidates
+        .into_par_iter()
+        .map(|(size, paths)| {
+            let mut by_hash = BTreeMap::<String, Vec<PathBuf>>::new();
+            for path in paths {
+                let data = std::fs::read(&path)?;
+                by_hash
+                    .entry(fingerprint::content_hash(&data))
+                    .or_default()
+                    .push(path);
+            }
+            anyhow::Ok(
+                by_hash
+                    .into_values()
+                    .filter(|paths| paths.len() > 1)
+                    .map(|mut paths| {
+                        paths.sort();
+                        (size, paths)
+                    })
+                    .collect::<Vec<_>>(),
+            )
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+    // Biggest waste first.
+    groups.sort_by_key(|(size, paths)| std::cmp::Reverse(*size * paths.len() as u64));
+
+    for (size, paths) in groups {
+        if mode == DedupeMode::Link {
+            let first = std::fs::read(&paths[0])?;
+            for path in &paths[1..] {
+                let already_linked = same_file(&paths[0], path);
+                // The hash only says they're probably the same.
+                if !already_linked && std::fs::read(path)? != first {
+                    continue;
+                }
+                if already_linked || link_over(&paths[0], path) {
+                    stats.linked += 1;
+                    stats.saved += size;
+                } else {
+                    stats.link_failed += 1;
+                }
+            }
+        }
+        stats.groups.push(Duplicates {
+            size,
+            paths: paths.iter().map(|path| site_path(out_dir, path)).collect(),
+        });
+    }
+    Ok(stats)
+}
+
+// Prints the groups, for DedupeMode::Report, or what linking saved.
+pub fn print(stats: &DedupeStats, mode: DedupeMode) {
+    if stats.groups.is_empty() {
+        println!("No duplicate static files");
+        return;
+    }
+    let copies = stats
+        .groups
+        .iter()
+        .map(|group| group.paths.len() - 1)
+        .sum::<usize>();
+    match mode {
+        DedupeMode::Report => {
+            println!(
+                "Duplicate static files, {copies} copies wasting {}:",
+                stats::format_size(stats.wasted())
+            );
+            for group in &stats.groups {
+                println!(
+                    "  {} wasted, {} copies of {}:",
+                    stats::format_size(group.wasted()),
+                    group.paths.len(),
+                    stats::format_size(group.size)
+                );
+                for path in &group.paths {
+                    println!("    {path}");
+                }
+            }
+        }
+        DedupeMode::Link => {
+            println!(
+                "Linked {} duplicate static files, saving {}",
+                stats.linked,
+                stats::format_size(stats.saved)
+            );
+            if stats.link_failed > 0 {
+                println!(
+                    "Warning: {} duplicates couldn't be hard-linked, and stay copies",
+                    stats.link_failed
+                );
+            }
+        }
+    }
+}
+
+fn collect_files(dir: &Path, by_size: &mut BTreeMap<u64, Vec<PathBuf>>) -> anyhow::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let ty = entry.file_type()?;
+        if ty.is_dir() {
+            collect_files(&entry.path(), by_size)?;
+        } else if ty.is_file() {
+            by_size
+                .entry(entry.metadata()?.len())
+                .or_default()
+                .push(entry.path());
+        }
+    }
+    Ok(())
+}
+
+// Replaces copy with a hard link to original. Goes through a temporary name, so copy is never
+// missing. False when the filesystem can't link, and copy is left as it was.
+fn link_over(original: &Path, copy: &Path) -> bool {
+    let mut temp = copy.as_os_str().to_owned();
+    temp.push(".dedupe");
+    let temp = PathBuf::from(temp);
+    let _ = std::fs::remove_file(&temp);
+    if std::fs::hard_link(original, &temp).is_err() {
+        return false;
+    }
+    if std::fs::rename(&temp, copy).is_err() {
+        let _ = std::fs::remove_file(&temp);
+        return false;
+    }
+    true
+}
+
+// Whether the two paths are links to the same file already, like when both were linked from
+// the last build.
+#[cfg(unix)]
+fn same_file(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (std::fs::metadata(a), std::fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn same_file(_a: &Path, _b: &Path) -> bool {
+    false
+}
+
+fn site_path(out_dir: &Path, path: &Path) -> String {
+    let relative = path.strip_prefix(out_dir).unwrap_or(path);
+    format!("/{}", relative.to_string_lossy().replace('\\', "/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dedupe_links_copies() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let files = [
+            ("static/img/shot.png", "screenshot"),
+            ("static/news/2020/shot.png", "screenshot"),
+            ("static/news/2021/shot-copy.png", "screenshot"),
+            ("static/img/other.png", "screensh0t"),
+            ("static/empty.txt", ""),
+            ("static/empty2.txt", ""),
+        ];
+        for (path, contents) in files {
+            let path = dir.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        }
+
+        let static_dir = dir.join("static");
+        let report = dedupe(dir, &static_dir, DedupeMode::Report).unwrap();
+        assert_eq!(report.groups.len(), 1);
+        assert_eq!(
+            report.groups[0].paths,
+            [
+                "/static/img/shot.png",
+                "/static/news/2020/shot.png",
+                "/static/news/2021/shot-copy.png"
+            ]
+        );
+        assert_eq!(report.wasted(), 20);
+        assert_eq!(report.linked, 0);
+
+        let linked = dedupe(dir, &static_dir, DedupeMode::Link).unwrap();
+        assert_eq!(linked.linked + linked.link_failed, 2);
+        for (path, contents) in files {
+            assert_eq!(std::fs::read_to_string(dir.join(path)).unwrap(), contents);
+        }
+        #[cfg(unix)]
+        if linked.linked == 2 {
+            assert!(same_file(
+                &dir.join("static/img/shot.png"),
+                &dir.join("static/news/2021/shot-copy.png")
+            ));
+            // Links from earlier runs count too.
+            let again = dedupe(dir, &static_dir, DedupeMode::Link).unwrap();
+            assert_eq!((again.linked, again.saved), (2, 20));
+        }
+    }
+}
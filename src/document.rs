@@ -1,13 +1,16 @@
 use crate::{
     config::{Config, DocLink, GlobalMeta},
+    error::SiteError,
     post_process, util,
 };
 use std::{
+    collections::HashMap,
     io::{BufRead, BufReader, Read},
     path::{Path, PathBuf},
 };
 
-use serde::Serialize;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
 
 // This is passed into rendering of blog posts, for example,
 // as well as being used in the rust code. And being deserialized
@@ -22,6 +25,9 @@ pub struct DocumentMeta {
     pub summary: Option<String>,
     #[serde(default)]
     pub date: String,
+    // Set when a post has been revised after publishing. Sorting still uses date.
+    #[serde(default)]
+    pub updated: Option<String>,
     #[serde(default)]
     pub slug: String,
     #[serde(default)]
@@ -35,23 +41,80 @@ pub struct DocumentMeta {
     #[serde(default)]
     pub contains_code: bool,
     #[serde(default)]
+    pub contains_mermaid: bool,
+    #[serde(default)]
     pub breadcrumbs: Vec<DocLink>,
     #[serde(default)]
     pub section: String,
+    #[serde(default)]
+    pub highlights: Vec<String>,
+    // Overrides Config::toc_max_depth for this document.
+    #[serde(default)]
+    pub toc_max_depth: Option<u32>,
+    // Passed to templates as page_toc instead.
+    #[serde(skip)]
+    pub toc: Vec<TocEntry>,
+    // Old URLs of a doc that has moved, like /docs/old-section/foo. They get redirect stubs.
+    #[serde(default)]
+    pub moved_from: Vec<String>,
+    // Site path of the image shown on cards and at the top of the post, like /static/img/blog/x.png.
+    #[serde(default)]
+    pub cover: Option<String>,
+    // Absolute URL of the cover, or of the default one, for social previews.
+    #[serde(default)]
+    pub og_image: Option<String>,
+    // Posts with a pinned priority are listed first, highest first. 0 isn't pinned.
+    #[serde(default)]
+    pub pinned: u32,
+    // Explicit position among the posts of a section, lowest first, ahead of the posts sorted
+    // by date. For sections like news where items don't need a date.
+    #[serde(default)]
+    pub order: Option<i32>,
+    // The same doc in each doc version, for the version switcher. The current one is selected.
+    #[serde(default)]
+    pub versions: Vec<DocLink>,
+    // False leaves the page out of the sitemap. Not found and redirect pages always are.
+    #[serde(default)]
+    pub sitemap: Option<bool>,
+    // A page shown for missing URLs, like pages/404.
+    #[serde(default)]
+    pub not_found: bool,
+    // A hand-written stand-in for a page that has moved, see util::redirect_html.
+    #[serde(default)]
+    pub redirect: bool,
+    // Another template to render the page with, over the section's, like doc_wide. See
+    // PageContext::render_layout.
+    #[serde(default)]
+    pub layout: Option<String>,
+    // Sitemap hints, over the section's defaults. See gen_sitemap.
+    #[serde(default)]
+    pub changefreq: Option<String>,
+    #[serde(default)]
+    pub priority: Option<f32>,
+}
+
+// A heading below the title, for the "On this page" nav. The slug is the heading's id.
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct TocEntry {
+    pub slug: String,
+    pub text: String,
+    pub depth: u32,
 }
 
 #[derive(Debug, Clone)]
 pub struct Document {
     pub path: PathBuf, // written file, the link-to path is in meta
+    pub source: PathBuf,
     pub markdown: Option<String>,
     pub html: String,
     pub meta: DocumentMeta,
 }
 
-#[derive(Debug, Serialize, Clone, Default)]
-pub struct SidebarContext {
+#[derive(Debug, Serialize, Clone)]
+pub struct SidebarContext<'a> {
     pub title: String,
     pub links: Vec<DocLink>,
+    pub globals: &'a GlobalMeta,
 }
 
 #[derive(Debug, Serialize, Clone, Default)]
@@ -61,6 +124,96 @@ pub struct Tag {
     pub selected: bool,
 }
 
+// Docs category structure, for templates that want to list it.
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct NavCategory {
+    pub title: String,
+    pub url: String,
+    pub summary: Option<String>,
+    pub docs: Vec<DocLink>,
+    pub categories: Vec<NavCategory>,
+}
+
+// Where a listing split over several pages is, for the pagination partial. Blog and tag
+// listings use it, and so should anything else that pages.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Pagination {
+    // From 1.
+    pub current_page: usize,
+    pub total_pages: usize,
+    pub prev_url: Option<String>,
+    pub next_url: Option<String>,
+    // Of every page, the first one first. The page_window helper picks the ones to show.
+    pub page_urls: Vec<String>,
+}
+
+// An entry of page_window, a page or an ellipsis marker for the ones left out.
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct PageLink {
+    pub number: usize,
+    pub url: String,
+    pub current: bool,
+    pub ellipsis: bool,
+}
+
+impl Pagination {
+    pub fn new(current_page: usize, page_urls: Vec<String>) -> Self {
+        let url = |page: usize| page_urls.get(page.wrapping_sub(1)).cloned();
+        Self {
+            current_page,
+            total_pages: page_urls.len(),
+            prev_url: url(current_page - 1),
+            next_url: url(current_page + 1),
+            page_urls,
+        }
+    }
+
+    // The first and last pages, and the ones up to radius away from the current one, with an
+    // ellipsis where pages are left out. A gap of a single page shows the page instead.
+    pub fn window(&self, radius: usize) -> Vec<PageLink> {
+        let last = self.total_pages;
+        let low = self.current_page.saturating_sub(radius).max(1);
+        let high = (self.current_page + radius).min(last);
+        let mut numbers = vec![1];
+        numbers.extend(low..=high);
+        numbers.push(last);
+        numbers.retain(|page| (1..=last).contains(page));
+        numbers.dedup();
+
+        let link = |number: usize| PageLink {
+            number,
+            url: self.page_urls[number - 1].clone(),
+            current: number == self.current_page,
+            ellipsis: false,
+        };
+        let mut links = vec![];
+        for (i, &number) in numbers.iter().enumerate() {
+            if let Some(&previous) = i.checked_sub(1).map(|i| &numbers[i]) {
+                if number - previous == 2 {
+                    links.push(link(previous + 1));
+                } else if number - previous > 2 {
+                    links.push(PageLink {
+                        number: 0,
+                        url: String::new(),
+                        current: false,
+                        ellipsis: true,
+                    });
+                }
+            }
+            links.push(link(number));
+        }
+        links
+    }
+}
+
+// Everything generated before the pages, so .hbs pages like the front page can list it.
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct SiteData {
+    pub recent_posts: Vec<DocLink>,
+    pub recent_news: Vec<DocLink>,
+    pub docs: NavCategory,
+}
+
 // Used when rendering templates.
 // Should probably split into multiple more focused ones .. but then again, not really necessary,
 // can just omit what we don't need.
@@ -75,7 +228,16 @@ pub struct PageContext<'a> {
     pub globals: Option<&'a GlobalMeta>,
     pub tags: &'a [Tag],
     pub contains_code: bool,
+    pub contains_mermaid: bool,
     pub top_nav: Vec<DocLink>,
+    // Only set for .hbs pages.
+    pub site: Option<&'a SiteData>,
+    // None when the page has no headings besides the title.
+    pub page_toc: Option<Vec<TocEntry>>,
+    // The page's own URL, for the is_active helper. Taken from meta when not set.
+    pub url: String,
+    // For listings that don't fit on one page.
+    pub pagination: Option<Pagination>,
 }
 
 impl<'a> PageContext<'a> {
@@ -90,7 +252,12 @@ impl<'a> PageContext<'a> {
             globals: Some(globals),
             tags: &[],
             contains_code: false,
+            contains_mermaid: false,
             top_nav: globals.top_nav.clone(),
+            site: None,
+            page_toc: None,
+            url: String::new(),
+            pagination: None,
         }
     }
     pub fn from_document(document: &Document, globals: &'a GlobalMeta) -> Self {
@@ -104,7 +271,16 @@ impl<'a> PageContext<'a> {
             globals: Some(globals),
             tags: &[],
             contains_code: document.meta.contains_code,
+            contains_mermaid: document.meta.contains_mermaid && globals.mermaid,
             top_nav: globals.top_nav.clone(),
+            site: None,
+            page_toc: if document.meta.toc.is_empty() {
+                None
+            } else {
+                Some(document.meta.toc.clone())
+            },
+            url: String::new(),
+            pagination: None,
         }
     }
     pub fn render(
@@ -112,32 +288,110 @@ impl<'a> PageContext<'a> {
         template_name: &str,
         handlebars: &mut handlebars::Handlebars<'_>,
     ) -> anyhow::Result<String> {
-        self.update_selected();
-        Ok(handlebars.render(template_name, &self)?)
+        // Templates count on globals, like the footer with globals.prod.
+        debug_assert!(
+            self.globals.is_some(),
+            "rendering {template_name} without globals"
+        );
+        self.fill_in_url();
+        handlebars.render(template_name, &self).map_err(|source| {
+            let registered = match source.reason() {
+                handlebars::RenderErrorReason::TemplateNotFound(_)
+                | handlebars::RenderErrorReason::PartialNotFound(_) => {
+                    let mut names = handlebars
+                        .get_templates()
+                        .keys()
+                        .cloned()
+                        .collect::<Vec<_>>();
+                    names.sort();
+                    names
+                }
+                _ => vec![],
+            };
+            SiteError::Template {
+                name: template_name.to_owned(),
+                source: Box::new(source),
+                registered,
+                document: None,
+            }
+            .into()
+        })
+    }
+    // Renders a whole page, with the layout from its front matter or else default_layout.
+    // Layouts are templates like doc, that fill in the blocks of the base layout, so a new one
+    // is just a template file. Not for the posts inside listings, which keep their template.
+    pub fn render_layout(
+        self,
+        default_layout: &str,
+        handlebars: &mut handlebars::Handlebars<'_>,
+    ) -> anyhow::Result<String> {
+        let layout = self
+            .meta
+            .as_ref()
+            .and_then(|meta| meta.layout.clone())
+            .unwrap_or_else(|| default_layout.to_owned());
+        self.render(&layout, handlebars)
     }
+    // For pages that are templates themselves. Errors name the page's file.
     pub fn render_template(
         mut self,
         template_string: &str,
+        path: &Path,
         handlebars: &mut handlebars::Handlebars<'_>,
     ) -> anyhow::Result<String> {
-        self.update_selected();
-        Ok(handlebars.render_template(template_string, &self)?)
+        debug_assert!(
+            self.globals.is_some(),
+            "rendering {} without globals",
+            path.display()
+        );
+        self.fill_in_url();
+        handlebars
+            .render_template(template_string, &self)
+            .map_err(|source| {
+                SiteError::Template {
+                    name: path.display().to_string(),
+                    source: Box::new(source),
+                    registered: vec![],
+                    document: None,
+                }
+                .into()
+            })
     }
 
-    fn update_selected(&mut self) {
-        if let Some(meta) = &self.meta {
-            let self_url = &meta.url;
-            for link in &mut self.top_nav {
-                if self_url.starts_with(&link.url) {
-                    link.selected = true;
-                }
-            }
+    fn fill_in_url(&mut self) {
+        if let (true, Some(meta)) = (self.url.is_empty(), &self.meta) {
+            self.url = meta.url.clone();
         }
     }
 }
 
 fn postprocess_html(md: String) -> String {
-    md.replace("<table>", "<table class=\"nice-table\">")
+    let md = md.replace("<table>", "<table class=\"nice-table\">");
+    // Mermaid renders from the text content of <pre class="mermaid">, so keep the escaped source as is.
+    let mermaid_regex =
+        regex::Regex::new(r#"(?s)<pre><code class="language-mermaid">(.*?)</code></pre>"#).unwrap();
+    let md = mermaid_regex.replace_all(&md, r#"<pre class="mermaid">$1</pre>"#);
+    // Line highlights come glued onto the language, see post_process::attach_line_highlights.
+    // The spec stays around as an attribute, for main.js to redo them after highlight.js.
+    let line_highlight_regex =
+        regex::Regex::new(r#"(?s)<code class="language-([^"{]+)\{([0-9,-]+)\}">(.*?)</code>"#)
+            .unwrap();
+    let md = line_highlight_regex.replace_all(&md, |captures: &regex::Captures<'_>| {
+        format!(
+            r#"<code class="language-{}" data-highlight-lines="{}">{}</code>"#,
+            &captures[1],
+            &captures[2],
+            post_process::highlight_lines(&captures[3], &captures[2])
+        )
+    });
+    // Wrap code blocks (not inline code) so main.js can attach a copy button to each.
+    let code_block_regex = regex::Regex::new(r#"(?s)<pre><code(.*?)</code></pre>"#).unwrap();
+    code_block_regex
+        .replace_all(
+            &md,
+            r#"<div class="code-block"><button type="button" class="copy-code-button">Copy</button><pre><code$1</code></pre></div>"#,
+        )
+        .to_string()
 }
 
 fn split_bracketed_list(value: &str) -> Vec<String> {
@@ -152,13 +406,52 @@ fn split_bracketed_list(value: &str) -> Vec<String> {
     vec![]
 }
 
-fn cleanup_path(path: &Path) -> Option<String> {
-    path.to_string_lossy()
-        .strip_prefix('.')
-        .map(|s| s.to_string().replace('\\', "/"))
+// The front matter fields each section can't do without. Blog and news get date and slug
+// from the file name when not specified, so this runs after that.
+fn required_fields(section: &str) -> &'static [&'static str] {
+    match section {
+        "blog" | "news" => &["title", "date", "slug"],
+        "docs" => &["title"],
+        _ => &[],
+    }
+}
+
+// Covers are images under /static, so "img/blog/x.png" is short for "/static/img/blog/x.png".
+fn cover_path(value: &str) -> String {
+    if value.starts_with('/') || value.contains("://") {
+        value.to_owned()
+    } else {
+        format!("/static/{value}")
+    }
+}
+
+// The site URL of a source path, relative to the input folder.
+fn cleanup_path(in_dir: &Path, path: &Path) -> Option<String> {
+    let relative = path.strip_prefix(in_dir).ok()?;
+    Some(format!(
+        "/{}",
+        relative.to_string_lossy().replace('\\', "/")
+    ))
 }
 
 impl Document {
+    // A bare page at url, for tests that only need a title and a link.
+    #[cfg(test)]
+    pub fn for_test(url: &str, title: &str) -> Self {
+        let path = url.trim_start_matches('/');
+        Self {
+            path: path.into(),
+            source: format!("{path}.md").into(),
+            markdown: None,
+            html: String::new(),
+            meta: DocumentMeta {
+                title: title.to_owned(),
+                url: url.to_owned(),
+                ..Default::default()
+            },
+        }
+    }
+
     pub fn to_doclink(&self, selected_url: &str) -> DocLink {
         DocLink::new(
             &self.meta.url.clone(),
@@ -169,6 +462,45 @@ impl Document {
         )
     }
 
+    pub fn validate(
+        &self,
+        section: &str,
+        source_path: &Path,
+        config: &Config,
+    ) -> anyhow::Result<()> {
+        let is_date = |date: &str| chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d").is_ok();
+        // Sections with optional dates still reject malformed ones.
+        let date_optional =
+            config.optional_date_sections.iter().any(|s| s == section) && self.meta.date.is_empty();
+        let mut missing = required_fields(section)
+            .iter()
+            .filter(|field| match **field {
+                "title" => self.meta.title.is_empty(),
+                "slug" => self.meta.slug.is_empty(),
+                "date" => !date_optional && !is_date(&self.meta.date),
+                _ => false,
+            })
+            .copied()
+            .collect::<Vec<_>>();
+        if let Some(updated) = &self.meta.updated {
+            if !is_date(updated) {
+                missing.push("updated");
+            }
+        }
+        if !missing.is_empty() {
+            return Err(SiteError::Validation {
+                path: source_path.to_path_buf(),
+                message: format!(
+                    "missing or invalid front matter for {}: {}",
+                    section,
+                    missing.join(", ")
+                ),
+            }
+            .into());
+        }
+        Ok(())
+    }
+
     pub fn read_dash_meta(reader: &mut impl BufRead) -> anyhow::Result<(DocumentMeta, bool)> {
         let mut meta = DocumentMeta::default();
         let mut buffer = String::new();
@@ -187,7 +519,7 @@ impl Document {
 
         let mut found_end = false;
         buffer.clear();
-        while reader.read_line(&mut buffer).is_ok() {
+        while reader.read_line(&mut buffer)? > 0 {
             if buffer.starts_with("---") {
                 found_end = true;
                 break;
@@ -197,9 +529,28 @@ impl Document {
                 match key {
                     "title" => meta.title = value,
                     "slug" => meta.slug = value,
+                    "date" => meta.date = value,
+                    "order" => meta.order = str::parse(&value).ok(),
+                    "updated" => meta.updated = Some(value),
                     "authors" => meta.author = value,
                     "tags" => meta.tags = split_bracketed_list(&value),
+                    "highlights" => meta.highlights = split_bracketed_list(&value),
                     "position" => meta.position = str::parse(&value).unwrap_or_default(),
+                    "toc_max_depth" => meta.toc_max_depth = str::parse(&value).ok(),
+                    "moved_from" => meta.moved_from = split_bracketed_list(&value),
+                    "cover" => meta.cover = Some(cover_path(&value)),
+                    "sitemap" => meta.sitemap = str::parse(&value).ok(),
+                    "changefreq" => meta.changefreq = Some(value),
+                    "layout" => meta.layout = Some(value),
+                    "priority" => meta.priority = str::parse(&value).ok(),
+                    // Either a priority, or true for 1.
+                    "pinned" => {
+                        meta.pinned = if value == "true" {
+                            1
+                        } else {
+                            str::parse(&value).unwrap_or_default()
+                        }
+                    }
                     _ => {}
                 }
             }
@@ -207,21 +558,30 @@ impl Document {
             buffer.clear();
         }
 
-        // TODO: runtime error
-        assert!(found_end);
+        anyhow::ensure!(found_end, "front matter is missing its closing ---");
         Ok((meta, false))
     }
 
     // Handles page, blog posts, etc, including triple-dash docusaurus-style metadata.
+    #[tracing::instrument(skip_all, fields(md_path = ?md_path))]
     pub fn from_md(md_path: &Path, config: &Config) -> anyhow::Result<Self> {
-        let md_file = std::fs::File::open(md_path)?;
+        let md_file = std::fs::File::open(md_path).map_err(|source| SiteError::Io {
+            path: md_path.to_path_buf(),
+            source,
+        })?;
         let mut reader = BufReader::new(md_file);
-        let (mut meta, ate_title) = Self::read_dash_meta(&mut reader)?;
+        let (mut meta, ate_title) = Self::read_dash_meta(&mut reader)
+            .with_context(|| format!("reading front matter of {}", md_path.display()))?;
 
         let mut path = md_path.to_path_buf();
         path.set_extension("");
 
-        meta.url = cleanup_path(&path).unwrap();
+        meta.url = cleanup_path(&config.in_dir, &path).unwrap();
+        meta.og_image = meta
+            .cover
+            .as_ref()
+            .or(config.default_cover.as_ref())
+            .map(|cover| config.absolute_url(cover));
 
         let mut buffer = vec![];
         reader.read_to_end(&mut buffer)?;
@@ -238,15 +598,24 @@ impl Document {
         if md.contains("```c") || md.contains("```rust") {
             meta.contains_code = true;
         }
+        if md.contains("```mermaid") {
+            meta.contains_mermaid = true;
+        }
 
-        let md = post_process::preprocess_markdown(&md, &meta.title, config)?;
+        let md = post_process::preprocess_markdown(&md, &meta.url, config)?;
 
         let html = markdown::to_html_with_options(&md, &config.markdown_options)
             .map_err(anyhow::Error::msg)?;
         let html = postprocess_html(html);
+        let (html, mut toc) = post_process::add_heading_ids(&html);
+        // Deeper headings keep their ids, they're just left out of the TOC.
+        let toc_max_depth = meta.toc_max_depth.unwrap_or(config.toc_max_depth);
+        toc.retain(|entry| entry.depth <= toc_max_depth);
+        meta.toc = toc;
 
         Ok(Self {
             path,
+            source: md_path.to_path_buf(),
             markdown: Some(md),
             html,
             meta,
@@ -256,21 +625,23 @@ impl Document {
     // The document itself is the template so we apply it immediately. Used for pages.
     pub fn from_hbs(
         globals: &GlobalMeta,
+        site: &SiteData,
         name: &str,
         hbs_path: &Path,
         handlebars: &mut handlebars::Handlebars<'_>,
     ) -> anyhow::Result<Self> {
         let hbs = std::fs::read_to_string(hbs_path)?;
         let mut context = PageContext::new(None, None, globals);
-        context.globals = Some(globals);
+        context.site = Some(site);
         let meta = DocumentMeta {
             url: format!("/{name}"),
             ..Default::default()
         };
         context.meta = Some(meta.clone());
-        let html = context.render_template(&hbs, handlebars)?;
+        let html = context.render_template(&hbs, hbs_path, handlebars)?;
         Ok(Self {
             path: hbs_path.to_path_buf(),
+            source: hbs_path.to_path_buf(),
             markdown: None,
             meta,
             html,
@@ -282,6 +653,7 @@ impl Document {
         let html = std::fs::read_to_string(html_path)?;
         Ok(Self {
             path: html_path.to_path_buf(),
+            source: html_path.to_path_buf(),
             markdown: None,
             html,
             meta: DocumentMeta {
@@ -298,11 +670,20 @@ impl Document {
         globals: &GlobalMeta,
     ) -> anyhow::Result<Self> {
         let mut context = PageContext::new(Some(category.meta.title.clone()), None, globals);
+        context.url = category.meta.url.clone();
 
+        // cat_contents shows summaries as HTML. The ones of docs are text, the ones of
+        // categories the list of what's in them.
         context.children = category
             .documents
             .iter()
-            .map(|doc| doc.to_doclink(&category.meta.url))
+            .map(|doc| {
+                let mut link = doc.to_doclink(&category.meta.url);
+                link.summary = link
+                    .summary
+                    .map(|summary| handlebars::html_escape(&summary));
+                link
+            })
             .collect::<Vec<_>>();
 
         context
@@ -313,9 +694,10 @@ impl Document {
 
         // Add children document titles to the context here.
 
-        let html = handlebars.render("cat_contents", &context)?;
+        let html = context.render("cat_contents", handlebars)?;
         Ok(Self {
             path: category.path.clone(),
+            source: category.path.clone(),
             html,
             markdown: None,
             meta: category.meta.clone(),
@@ -362,12 +744,20 @@ impl Category {
             let name = util::filename_to_string(&entry.file_name());
 
             if entry.metadata()?.is_dir() {
+                // Older doc versions are generated on their own.
+                if config.is_doc_version_folder(&path) {
+                    continue;
+                }
                 sub_categories.push(Self::from_folder_tree(&path, config)?);
             } else if let Some(os_str) = path.extension() {
                 // Check file extension to figure out what to do.
                 match os_str.to_str().unwrap() {
                     "md" => {
-                        let doc = Document::from_md(&path, config)?;
+                        let result = Document::from_md(&path, config)
+                            .and_then(|doc| doc.validate("docs", &path, config).map(|_| doc));
+                        let Some(doc) = config.skip_on_error(&path, result)? else {
+                            continue;
+                        };
                         if name == "_category_.md" {
                             meta = doc.meta.clone();
                             html = doc.html;
@@ -408,13 +798,16 @@ impl Category {
         // Compute the summaries after sorting.
         for cat in &sub_categories {
             if summary_line_count < MAX_SUMMARY_LINES {
-                summary += &format!("<li><strong>{}</strong></li>", &cat.meta.title);
+                summary += &format!(
+                    "<li><strong>{}</strong></li>",
+                    handlebars::html_escape(&cat.meta.title)
+                );
             }
             summary_line_count += 1;
         }
         for doc in &documents {
             if summary_line_count < MAX_SUMMARY_LINES {
-                summary += &format!("<li>{}</li>", &doc.meta.title);
+                summary += &format!("<li>{}</li>", handlebars::html_escape(&doc.meta.title));
             }
             summary_line_count += 1;
         }
@@ -425,7 +818,7 @@ impl Category {
         summary += "</ul>";
 
         let path = folder.to_path_buf();
-        meta.url = cleanup_path(&path).unwrap();
+        meta.url = cleanup_path(&config.in_dir, &path).unwrap();
         meta.summary = Some(summary);
 
         Ok(Self {
@@ -479,6 +872,20 @@ impl Category {
         }
     }
 
+    pub fn to_nav(&self) -> NavCategory {
+        NavCategory {
+            title: self.meta.title.clone(),
+            url: self.meta.url.clone(),
+            summary: self.meta.summary.clone(),
+            docs: self
+                .documents
+                .iter()
+                .map(|doc| doc.to_doclink(""))
+                .collect(),
+            categories: self.sub_categories.iter().map(|cat| cat.to_nav()).collect(),
+        }
+    }
+
     pub fn compute_breadcrumbs(&mut self, crumbs: &mut Vec<DocLink>) {
         self.meta.breadcrumbs = crumbs.clone();
         add_positions(&mut self.meta.breadcrumbs);
@@ -495,3 +902,147 @@ impl Category {
         }
     }
 }
+
+// Finds documents across sections by their URL, their slug within a section, or the file
+// they were made from. Built once all the documents are there.
+#[derive(Default)]
+pub struct DocumentIndex<'a> {
+    by_url: HashMap<&'a str, &'a Document>,
+    by_slug: HashMap<(&'a str, &'a str), &'a Document>,
+    by_source_path: HashMap<&'a Path, &'a Document>,
+}
+
+impl<'a> DocumentIndex<'a> {
+    // Earlier documents win when two share a URL or slug.
+    pub fn new(sections: &[&'a [Document]]) -> Self {
+        let mut index = Self::default();
+        for doc in sections.iter().copied().flatten() {
+            index
+                .by_url
+                .entry(doc.meta.url.trim_end_matches('/'))
+                .or_insert(doc);
+            if !doc.meta.slug.is_empty() {
+                index
+                    .by_slug
+                    .entry((&doc.meta.section, &doc.meta.slug))
+                    .or_insert(doc);
+            }
+            index.by_source_path.entry(&doc.source).or_insert(doc);
+        }
+        index
+    }
+
+    // With or without a trailing slash.
+    pub fn by_url(&self, url: &str) -> Option<&'a Document> {
+        self.by_url.get(url.trim_end_matches('/')).copied()
+    }
+
+    // Slugs are only unique within a section, like blog or news.
+    pub fn by_slug(&self, section: &str, slug: &str) -> Option<&'a Document> {
+        self.by_slug.get(&(section, slug)).copied()
+    }
+
+    pub fn by_source_path(&self, path: &Path) -> Option<&'a Document> {
+        self.by_source_path.get(path).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pagination(current_page: usize, total_pages: usize) -> Pagination {
+        let urls = (1..=total_pages).map(|page| format!("/blog/page/{page}"));
+        Pagination::new(current_page, urls.collect())
+    }
+
+    // The window as text, like "1 … 4 [5] 6 … 9".
+    fn window(current_page: usize, total_pages: usize, radius: usize) -> String {
+        let links = pagination(current_page, total_pages).window(radius);
+        for link in links.iter().filter(|link| !link.ellipsis) {
+            assert_eq!(link.url, format!("/blog/page/{}", link.number));
+        }
+        links
+            .iter()
+            .map(|link| match link {
+                PageLink { ellipsis: true, .. } => "…".to_owned(),
+                PageLink { current: true, .. } => format!("[{}]", link.number),
+                _ => link.number.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    #[test]
+    fn page_window_edges() {
+        assert_eq!(window(1, 1, 2), "[1]");
+        assert_eq!(window(1, 3, 2), "[1] 2 3");
+        assert_eq!(window(2, 4, 5), "1 [2] 3 4");
+        assert_eq!(window(1, 9, 2), "[1] 2 3 … 9");
+        assert_eq!(window(9, 9, 2), "1 … 7 8 [9]");
+        assert_eq!(window(5, 9, 2), "1 2 3 4 [5] 6 7 8 9");
+        assert_eq!(window(5, 9, 1), "1 … 4 [5] 6 … 9");
+        assert_eq!(window(6, 20, 0), "1 … [6] … 20");
+        // One page left out shows the page rather than an ellipsis.
+        assert_eq!(window(4, 9, 1), "1 2 3 [4] 5 … 9");
+    }
+
+    #[test]
+    fn pagination_links_neighbours() {
+        let first = pagination(1, 3);
+        assert_eq!(first.prev_url, None);
+        assert_eq!(first.next_url.as_deref(), Some("/blog/page/2"));
+        let last = pagination(3, 3);
+        assert_eq!(last.prev_url.as_deref(), Some("/blog/page/2"));
+        assert_eq!(last.next_url, None);
+    }
+
+    #[test]
+    fn document_index_lookups() {
+        let post = |section: &str, slug: &str, title: &str| {
+            let mut doc = Document::for_test(&format!("/{section}/{slug}"), title);
+            doc.meta.section = section.to_owned();
+            doc.meta.slug = slug.to_owned();
+            doc
+        };
+        let blog = [post("blog", "save-states", "Blog post")];
+        let news = [
+            post("news", "save-states", "News post"),
+            post("news", "release", "Release"),
+        ];
+        // A later section with the same URL loses.
+        let mut dupe = post("news", "release", "Duplicate");
+        dupe.source = "older/release.md".into();
+        let older = [dupe];
+        let mut docs = [Document::for_test("/docs/intro/", "Intro")];
+        docs[0].source = "docs/intro.md".into();
+        let index = DocumentIndex::new(&[&docs, &blog, &news, &older]);
+
+        fn title(doc: Option<&Document>) -> Option<&str> {
+            doc.map(|doc| doc.meta.title.as_str())
+        }
+        assert_eq!(title(index.by_url("/docs/intro")), Some("Intro"));
+        assert_eq!(title(index.by_url("/docs/intro/")), Some("Intro"));
+        assert_eq!(title(index.by_url("/news/release")), Some("Release"));
+        assert_eq!(title(index.by_url("/docs/missing")), None);
+
+        assert_eq!(
+            title(index.by_slug("blog", "save-states")),
+            Some("Blog post")
+        );
+        assert_eq!(
+            title(index.by_slug("news", "save-states")),
+            Some("News post")
+        );
+        assert_eq!(title(index.by_slug("docs", "save-states")), None);
+        assert_eq!(title(index.by_slug("news", "release")), Some("Release"));
+        // Docs have no slug of their own.
+        assert_eq!(title(index.by_slug("", "")), None);
+
+        let by_source = |path: &str| title(index.by_source_path(Path::new(path)));
+        assert_eq!(by_source("docs/intro.md"), Some("Intro"));
+        assert_eq!(by_source("older/release.md"), Some("Duplicate"));
+        assert_eq!(by_source("news/release.md"), Some("Release"));
+        assert_eq!(by_source("docs/missing.md"), None);
+    }
+}
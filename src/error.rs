@@ -0,0 +1,234 @@
+use handlebars::RenderErrorReason;
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+// What went wrong in a build, for callers that want to react to the kind of failure.
+// Internally we use anyhow, and convert at the boundary in From<anyhow::Error>.
+#[derive(Debug)]
+pub enum SiteError {
+    // Bad or unparseable data files, like data/downloads.json.
+    Config {
+        path: PathBuf,
+        message: String,
+    },
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    Template {
+        name: String,
+        source: Box<handlebars::RenderError>,
+        // When the template or a partial wasn't found, the names there are, for spotting typos.
+        registered: Vec<String>,
+        // The page being rendered, when known.
+        document: Option<PathBuf>,
+    },
+    // Content that doesn't meet the requirements of its section, like missing front matter.
+    Validation {
+        path: PathBuf,
+        message: String,
+    },
+    // Two sources writing the same output file, or one written where a static file goes.
+    // Always fails the build, skipping either source would silently drop a page.
+    Collision {
+        path: PathBuf,
+        message: String,
+    },
+    // Anything not classified above, with its full context chain.
+    Other(anyhow::Error),
+}
+
+impl SiteError {
+    // The file to go fix, when the error knows it.
+    pub fn file(&self) -> Option<String> {
+        match self {
+            Self::Config { path, .. }
+            | Self::Io { path, .. }
+            | Self::Validation { path, .. }
+            | Self::Collision { path, .. } => Some(path.display().to_string()),
+            // The error can come from a partial rather than the template being rendered. Pages
+            // that are templates go by their path.
+            Self::Template { name, source, .. } => Some(match &source.template_name {
+                Some(template) => format!("template/{template}.hbs"),
+                None if name.ends_with(".hbs") => name.clone(),
+                None => format!("template/{name}.hbs"),
+            }),
+            // Like templates that don't parse.
+            Self::Other(err) => err
+                .chain()
+                .find_map(|cause| cause.downcast_ref::<handlebars::TemplateError>())
+                .and_then(|err| err.name())
+                .map(|name| format!("template/{name}.hbs")),
+        }
+    }
+
+    // The message followed by its causes, like anyhow's {:#}.
+    pub fn message(&self) -> String {
+        let mut message = self.to_string();
+        let mut cause = std::error::Error::source(self);
+        while let Some(err) = cause {
+            message.push_str(&format!(": {err}"));
+            cause = err.source();
+        }
+        message
+    }
+}
+
+impl fmt::Display for SiteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Config { path, message }
+            | Self::Validation { path, message }
+            | Self::Collision { path, message } => write!(f, "{}: {}", path.display(), message),
+            // The cause follows from source().
+            Self::Io { path, .. } => write!(f, "{}", path.display()),
+            Self::Template {
+                name,
+                source,
+                registered,
+                document,
+            } => {
+                write!(f, "template {}", name)?;
+                if let Some(document) = document {
+                    write!(f, ", rendering {}", document.display())?;
+                }
+                match source.reason() {
+                    RenderErrorReason::MissingVariable(path) => write!(
+                        f,
+                        ": {} line {}: {} is missing. Optional fields need {{{{#if}}}} or {{{{default}}}}",
+                        source.template_name.as_ref().unwrap_or(name),
+                        source.line_no.unwrap_or_default(),
+                        path.as_deref().unwrap_or("a value")
+                    )?,
+                    // The reason follows from source().
+                    _ => {
+                        if let Some(line) = source.line_no {
+                            let template = source.template_name.as_ref().unwrap_or(name);
+                            write!(f, ": {} line {}", template, line)?;
+                        }
+                    }
+                }
+                if !registered.is_empty() {
+                    write!(f, " (registered templates: {})", registered.join(", "))?;
+                }
+                Ok(())
+            }
+            Self::Other(err) => write!(f, "{:#}", err),
+        }
+    }
+}
+
+impl std::error::Error for SiteError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io { source, .. } => Some(source),
+            Self::Template { source, .. } => match source.reason() {
+                // Already explained by the message.
+                RenderErrorReason::MissingVariable(_) => None,
+                // Like a file {{include_raw}} couldn't read. Handlebars' wrapper would only
+                // repeat it.
+                RenderErrorReason::NestedError(cause) => Some(&**cause),
+                reason => Some(reason),
+            },
+            _ => None,
+        }
+    }
+}
+
+// Whether a render failed on a field the data doesn't have, see --strict-templates.
+pub fn is_missing_field(err: &anyhow::Error) -> bool {
+    render_errors(err)
+        .any(|source| matches!(source.reason(), RenderErrorReason::MissingVariable(_)))
+}
+
+// Whether a render failed on a file {{include_raw}} couldn't read.
+pub fn is_missing_include(err: &anyhow::Error) -> bool {
+    render_errors(err).any(|source| match source.reason() {
+        RenderErrorReason::NestedError(cause) => {
+            matches!(cause.downcast_ref(), Some(SiteError::Io { .. }))
+        }
+        _ => false,
+    })
+}
+
+// Whether two sources claimed the same output, see Config::claim_output.
+pub fn is_collision(err: &anyhow::Error) -> bool {
+    matches!(err.downcast_ref(), Some(SiteError::Collision { .. }))
+}
+
+fn render_errors(err: &anyhow::Error) -> impl Iterator<Item = &handlebars::RenderError> {
+    err.chain()
+        .filter_map(|cause| match cause.downcast_ref::<SiteError>() {
+            Some(SiteError::Template { source, .. }) => Some(&**source),
+            _ => cause.downcast_ref(),
+        })
+}
+
+// Template errors only know the template, this adds the document it was rendering.
+pub fn with_document(err: anyhow::Error, document: &Path) -> anyhow::Error {
+    match err.downcast::<SiteError>() {
+        Ok(SiteError::Template {
+            name,
+            source,
+            registered,
+            ..
+        }) => SiteError::Template {
+            name,
+            source,
+            registered,
+            document: Some(document.to_path_buf()),
+        }
+        .into(),
+        Ok(err) => err.into(),
+        Err(err) => err.context(format!("rendering {}", document.display())),
+    }
+}
+
+impl From<anyhow::Error> for SiteError {
+    fn from(err: anyhow::Error) -> Self {
+        match err.downcast::<SiteError>() {
+            Ok(err) => err,
+            Err(err) => Self::Other(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn causes_are_chained() {
+        let io = SiteError::Io {
+            path: "data/team.json".into(),
+            source: std::io::Error::new(std::io::ErrorKind::NotFound, "no such file"),
+        };
+        assert_eq!(io.message(), "data/team.json: no such file");
+        let err = anyhow::Error::from(io).context("loading data");
+        assert_eq!(
+            format!("{err:#}"),
+            "loading data: data/team.json: no such file"
+        );
+        assert!(err
+            .chain()
+            .any(|cause| cause.downcast_ref::<std::io::Error>().is_some()));
+
+        let mut handlebars = handlebars::Handlebars::new();
+        handlebars
+            .register_template_string("page", "<p>\n{{> sidebar}}</p>")
+            .unwrap();
+        let template = SiteError::Template {
+            name: "page".to_owned(),
+            source: Box::new(handlebars.render("page", &()).unwrap_err()),
+            registered: vec!["page".to_owned()],
+            document: Some("docs/intro.md".into()),
+        };
+        assert_eq!(
+            format!("{:#}", anyhow::Error::from(template)),
+            "template page, rendering docs/intro.md: page line 2 (registered templates: page): \
+             Partial not found sidebar"
+        );
+    }
+}
@@ -0,0 +1,298 @@
+use crate::config::Config;
+use crate::fingerprint;
+use crate::writer::SiteWriter;
+use anyhow::Context;
+use serde::Serialize;
+use std::{borrow::Cow, cell::OnceCell, path::Path};
+
+// When this exists, every icon is made from it, instead of copying static/img/favicon.ico.
+pub const SOURCE: &str = "static/img/icon-source.png";
+
+// Generated icons by source hash, so rebuilds don't resize again.
+const CACHE_DIR: &str = ".cache/favicons";
+
+// Sizes in favicon.ico.
+const ICO_SIZES: &[u32] = &[16, 32, 48];
+
+// For the web manifest, shown while an installed site starts.
+const BACKGROUND_COLOR: &str = "#1c1e21";
+
+// A PNG icon written to the output root.
+struct PngIcon {
+    name: &'static str,
+    size: u32,
+    rel: &'static str,
+    // Listed in the web manifest rather than linked from pages.
+    manifest: bool,
+}
+
+const PNG_ICONS: &[PngIcon] = &[
+    PngIcon {
+        name: "favicon-16x16.png",
+        size: 16,
+        rel: "icon",
+        manifest: false,
+    },
+    PngIcon {
+        name: "favicon-32x32.png",
+        size: 32,
+        rel: "icon",
+        manifest: false,
+    },
+    PngIcon {
+        name: "apple-touch-icon.png",
+        size: 180,
+        rel: "apple-touch-icon",
+        manifest: false,
+    },
+    PngIcon {
+        name: "android-chrome-192x192.png",
+        size: 192,
+        rel: "icon",
+        manifest: true,
+    },
+    PngIcon {
+        name: "android-chrome-512x512.png",
+        size: 512,
+        rel: "icon",
+        manifest: true,
+    },
+];
+
+// A <link> in the page header, see the favicons partial.
+#[derive(Serialize, Debug, Clone)]
+pub struct FaviconLink {
+    pub rel: String,
+    pub href: String,
+    pub mime: Option<String>,
+    pub sizes: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ManifestIcon {
+    src: String,
+    sizes: String,
+    #[serde(rename = "type")]
+    mime: String,
+}
+
+// https://developer.mozilla.org/en-US/docs/Web/Manifest
+#[derive(Serialize)]
+struct WebManifest {
+    name: String,
+    short_name: String,
+    icons: Vec<ManifestIcon>,
+    theme_color: String,
+    background_color: String,
+    display: String,
+}
+
+// The tags pages need for the generated icons, empty when there's no source image and
+// browsers find favicon.ico by themselves.
+pub fn favicon_links(in_dir: &Path) -> Vec<FaviconLink> {
+    if !in_dir.join(SOURCE).is_file() {
+        return vec![];
+    }
+    let link = |rel: &str, href: &str, mime: Option<&str>, sizes: Option<String>| FaviconLink {
+        rel: rel.to_owned(),
+        href: href.to_owned(),
+        mime: mime.map(str::to_owned),
+        sizes,
+    };
+    let mut links = vec![link("icon", "/favicon.ico", None, Some("any".to_owned()))];
+    for icon in PNG_ICONS.iter().filter(|icon| !icon.manifest) {
+        links.push(link(
+            icon.rel,
+            &format!("/{}", icon.name),
+            (icon.rel == "icon").then_some("image/png"),
+            Some(format!("{0}x{0}", icon.size)),
+        ));
+    }
+    links.push(link("manifest", "/site.webmanifest", None, None));
+    links
+}
+
+// Writes favicon.ico, the PNG icons and site.webmanifest to the output root, made from
+// SOURCE. False when there's no source image, and nothing was written.
+pub fn generate_favicons(config: &Config, writer: &mut dyn SiteWriter) -> anyhow::Result<bool> {
+    let source = config.in_dir.join(SOURCE);
+    let data = match std::fs::read(&source) {
+        Ok(data) => data,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err).context("favicon source"),
+    };
+    let hash = fingerprint::content_hash(&data);
+    let cache_dir = config.in_dir.join(CACHE_DIR);
+    std::fs::create_dir_all(&cache_dir).context("favicon cache")?;
+
+    // Decoded once, and only when something isn't cached.
+    let decoded = OnceCell::new();
+    let image = || -> anyhow::Result<&image::DynamicImage> {
+        if let Some(image) = decoded.get() {
+            return Ok(image);
+        }
+        let image = image::load_from_memory_with_format(&data, image::ImageFormat::Png)
+            .with_context(|| format!("decode {}", source.display()))?;
+        anyhow::ensure!(
+            image.width() == image.height(),
+            "{} is {}x{}, icons need a square image",
+            source.display(),
+            image.width(),
+            image.height()
+        );
+        Ok(decoded.get_or_init(|| image))
+    };
+    let cached = |name: &str,
+                  make: &mut dyn FnMut() -> anyhow::Result<Vec<u8>>|
+     -> anyhow::Result<Vec<u8>> {
+        let path = cache_dir.join(format!("{hash}-{name}"));
+        if let Ok(data) = std::fs::read(&path) {
+            return Ok(data);
+        }
+        let data = make()?;
+        std::fs::write(&path, &data).context("favicon cache")?;
+        Ok(data)
+    };
+
+    for icon in PNG_ICONS {
+        let png = cached(icon.name, &mut || encode_png(&resize(image()?, icon.size)))?;
+        writer.write(&config.out_dir.join(icon.name), &png)?;
+    }
+    let ico = cached("favicon.ico", &mut || {
+        let image = image()?;
+        let mut dir = ico::IconDir::new(ico::ResourceType::Icon);
+        for size in ICO_SIZES {
+            let rgba = resize(image, *size).to_rgba8();
+            let icon = ico::IconImage::from_rgba_data(*size, *size, rgba.into_raw());
+            dir.add_entry(ico::IconDirEntry::encode(&icon)?);
+        }
+        let mut out = vec![];
+        dir.write(&mut out)?;
+        Ok(out)
+    })?;
+    writer.write(&config.out_dir.join("favicon.ico"), &ico)?;
+
+    let manifest = WebManifest {
+        name: "PPSSPP".to_owned(),
+        short_name: "PPSSPP".to_owned(),
+        icons: PNG_ICONS
+            .iter()
+            .filter(|icon| icon.manifest)
+            .map(|icon| ManifestIcon {
+                src: format!("{}/{}", config.path_prefix, icon.name),
+                sizes: format!("{0}x{0}", icon.size),
+                mime: "image/png".to_owned(),
+            })
+            .collect(),
+        theme_color: BACKGROUND_COLOR.to_owned(),
+        background_color: BACKGROUND_COLOR.to_owned(),
+        display: "standalone".to_owned(),
+    };
+    writer.write(
+        &config.out_dir.join("site.webmanifest"),
+        serde_json::to_string_pretty(&manifest)?.as_bytes(),
+    )?;
+    Ok(true)
+}
+
+fn resize(image: &image::DynamicImage, size: u32) -> Cow<'_, image::DynamicImage> {
+    if image.width() == size {
+        Cow::Borrowed(image)
+    } else {
+        Cow::Owned(image.resize_exact(size, size, image::imageops::FilterType::Lanczos3))
+    }
+}
+
+fn encode_png(image: &image::DynamicImage) -> anyhow::Result<Vec<u8>> {
+    let mut out = std::io::Cursor::new(vec![]);
+    image.write_to(&mut out, image::ImageFormat::Png)?;
+    Ok(out.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::writer::MemoryWriter;
+
+    fn site_with_source(width: u32, height: u32) -> (tempfile::TempDir, Config) {
+        let temp = tempfile::tempdir().unwrap();
+        let source = temp.path().join(SOURCE);
+        std::fs::create_dir_all(source.parent().unwrap()).unwrap();
+        image::RgbaImage::from_pixel(width, height, image::Rgba([255, 128, 0, 255]))
+            .save(&source)
+            .unwrap();
+        let (config, _) = crate::test_site(temp.path(), &["--path-prefix", "/sub"]);
+        (temp, config)
+    }
+
+    #[test]
+    fn icons_are_made_from_the_source() {
+        let (temp, config) = site_with_source(64, 64);
+        let mut writer = MemoryWriter::default();
+        assert!(generate_favicons(&config, &mut writer).unwrap());
+        for icon in PNG_ICONS {
+            let png = &writer.files[&config.out_dir.join(icon.name)];
+            let png = image::load_from_memory(png).unwrap();
+            assert_eq!(
+                (png.width(), png.height()),
+                (icon.size, icon.size),
+                "{}",
+                icon.name
+            );
+        }
+        let ico = &writer.files[&config.out_dir.join("favicon.ico")];
+        let ico = ico::IconDir::read(std::io::Cursor::new(ico)).unwrap();
+        let sizes = ico
+            .entries()
+            .iter()
+            .map(|entry| entry.width())
+            .collect::<Vec<_>>();
+        assert_eq!(sizes, ICO_SIZES);
+        let manifest = &writer.files[&config.out_dir.join("site.webmanifest")];
+        let manifest: serde_json::Value = serde_json::from_slice(manifest).unwrap();
+        assert_eq!(
+            manifest["icons"][0]["src"],
+            "/sub/android-chrome-192x192.png"
+        );
+
+        let links = favicon_links(temp.path());
+        let hrefs = links
+            .iter()
+            .map(|link| link.href.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            hrefs,
+            [
+                "/favicon.ico",
+                "/favicon-16x16.png",
+                "/favicon-32x32.png",
+                "/apple-touch-icon.png",
+                "/site.webmanifest"
+            ]
+        );
+
+        // For the next build, every icon is cached under the source's hash.
+        let cached = std::fs::read_dir(temp.path().join(CACHE_DIR))
+            .unwrap()
+            .count();
+        assert_eq!(cached, PNG_ICONS.len() + 1);
+    }
+
+    #[test]
+    fn icons_need_a_square_source() {
+        let (_temp, config) = site_with_source(64, 32);
+        let err = generate_favicons(&config, &mut MemoryWriter::default()).unwrap_err();
+        assert!(
+            err.to_string().contains("icons need a square image"),
+            "{err}"
+        );
+
+        let temp = tempfile::tempdir().unwrap();
+        let config = Config {
+            in_dir: temp.path().to_path_buf(),
+            ..config
+        };
+        assert!(!generate_favicons(&config, &mut MemoryWriter::default()).unwrap());
+        assert!(favicon_links(temp.path()).is_empty());
+    }
+}
@@ -1,7 +1,6 @@
-use std::io::Write;
-
 use crate::config::Config;
 use crate::document::Document;
+use crate::writer::SiteWriter;
 use chrono::{NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc};
 
 use anyhow::Context;
@@ -13,6 +12,7 @@ struct Item {
     title: String,
     link: String,
     pubDate: String,
+    updated: String,
     category: String,
     description: String,
     tags: Vec<String>,
@@ -28,6 +28,10 @@ struct Channel {
     lastBuildDate: String,
     docs: String,
     language: String,
+    // The site's favicon, for Atom's icon.
+    icon: String,
+    // RSS image and Atom logo, left out when not configured.
+    image: Option<String>,
     items: Vec<Item>,
 }
 
@@ -50,13 +54,14 @@ pub fn format_time(yyyy_mm_dd: &str, format: FeedFormat) -> String {
 }
 
 #[allow(clippy::upper_case_acronyms)]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug)]
 pub enum FeedFormat {
     RSS,
     Atom,
 }
 
 // Posts should be passed-in in reverse time order.
+#[tracing::instrument(skip_all, fields(folder = %folder, ?format))]
 pub fn write_feed(
     config: &Config,
     title: &str,
@@ -65,23 +70,38 @@ pub fn write_feed(
     all_posts: &[Document],
     format: FeedFormat,
     handlebars: &mut handlebars::Handlebars<'_>,
+    writer: &mut dyn SiteWriter,
 ) -> anyhow::Result<()> {
-    let base_path = format!("https://www.ppssspp.org/{folder}");
+    // Feed items need a date. Newest first, whatever order the listing has, like with order
+    // in the front matter.
+    let mut posts = all_posts
+        .iter()
+        .filter(|x| !x.meta.date.is_empty())
+        .collect::<Vec<_>>();
+    posts.sort_by(|a, b| b.meta.date.cmp(&a.meta.date));
+    let base_path = config.site_url(&format!("/{folder}"));
+    let home = config.site_url(&config.page_url(&format!("/{folder}")));
     let rss = Rss {
         version: "2.0".to_string(),
         channel: Channel {
             title: title.to_string(),
             link: format!("{base_path}/rss.xml"),
-            link_folder: base_path.clone(),
+            link_folder: home,
             description: description.to_string(),
             lastBuildDate: config.build_date.clone(),
             docs: "https://validator.w3.org/feed/docs/rss2.html".to_owned(),
             language: "en".to_owned(),
-            items: all_posts
+            icon: config.site_url("/favicon.ico"),
+            image: config
+                .feed_image
+                .as_deref()
+                .map(|url| config.absolute_url(url)),
+            items: posts
                 .iter()
+                .take(config.feed_item_limit)
                 .map(|x| Item {
                     title: x.meta.title.clone(),
-                    link: format!("{base_path}{}", x.meta.url),
+                    link: config.site_url(&config.page_url(&x.meta.url)),
                     description: x
                         .meta
                         .summary
@@ -90,6 +110,7 @@ pub fn write_feed(
                     category: x.meta.tags.first().map(String::clone).unwrap_or_default(),
                     tags: x.meta.tags.clone(),
                     pubDate: format_time(&x.meta.date, format),
+                    updated: format_time(x.meta.updated.as_ref().unwrap_or(&x.meta.date), format),
                 })
                 .collect::<Vec<_>>(),
         },
@@ -103,7 +124,112 @@ pub fn write_feed(
     let contents = handlebars.render(template, &rss)?;
     let file_path = config.out_dir.join(folder).join(filename);
     println!("writing {} to {}...", template, file_path.display());
-    let mut file = std::fs::File::create(file_path).context("generate_feed")?;
-    file.write_all(contents.as_bytes())?;
-    Ok(())
+    writer
+        .write(&file_path, contents.as_bytes())
+        .context("generate_feed")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::writer::MemoryWriter;
+    use std::path::Path;
+
+    #[test]
+    fn feeds_escape_fields() {
+        let (config, mut handlebars) = crate::test_site(Path::new("."), &[]);
+        let title = r#"Save & load "states" <fast> ]]> 'n' easy"#;
+        let summary = "Fast < slow & <b>bold</b>";
+        let mut post = Document::for_test("/blog/save-states", title);
+        post.meta.summary = Some(summary.to_owned());
+        post.meta.date = "2024-01-26".to_owned();
+        post.meta.tags = vec!["Q&A".to_owned()];
+        let mut writer = MemoryWriter::default();
+        for format in [FeedFormat::Atom, FeedFormat::RSS] {
+            write_feed(
+                &config,
+                "PPSSPP <Blog>",
+                "News & more",
+                "blog",
+                std::slice::from_ref(&post),
+                format,
+                &mut handlebars,
+                &mut writer,
+            )
+            .unwrap();
+        }
+        let read = |name: &str| {
+            String::from_utf8(writer.files[&Path::new("build/blog").join(name)].clone()).unwrap()
+        };
+        let text = |xml: &roxmltree::Document<'_>, path: &[&str]| {
+            let mut node = xml.root_element();
+            for name in path {
+                node = node.children().find(|n| n.has_tag_name(*name)).unwrap();
+            }
+            node.text().unwrap_or_default().trim().to_owned()
+        };
+        // Titles are text, summaries HTML, with the text of the summary escaped in it.
+        let summary_html = handlebars::html_escape(summary);
+
+        let atom = read("atom.xml");
+        let atom = roxmltree::Document::parse(&atom).unwrap();
+        assert_eq!(text(&atom, &["title"]), "PPSSPP <Blog>");
+        assert_eq!(text(&atom, &["entry", "title"]), title);
+        assert_eq!(text(&atom, &["entry", "summary"]), summary_html);
+        let category = atom
+            .descendants()
+            .find(|n| n.has_tag_name("category"))
+            .unwrap();
+        assert_eq!(category.attribute("term"), Some("Q&A"));
+
+        let rss = read("rss.xml");
+        let rss = roxmltree::Document::parse(&rss).unwrap();
+        assert_eq!(text(&rss, &["channel", "description"]), "News & more");
+        assert_eq!(text(&rss, &["channel", "item", "title"]), title);
+        assert_eq!(
+            text(&rss, &["channel", "item", "description"]),
+            summary_html
+        );
+        assert_eq!(text(&rss, &["channel", "item", "category"]), "Q&A");
+    }
+
+    #[test]
+    fn feeds_are_newest_first() {
+        let (mut config, mut handlebars) = crate::test_site(Path::new("."), &[]);
+        config.feed_item_limit = 2;
+        // In listing order, an ordered announcement first.
+        let posts = [
+            ("/news/welcome", "2013-01-01", Some(1)),
+            ("/news/undated", "", Some(2)),
+            ("/news/release", "2024-01-26", None),
+            ("/news/update", "2023-06-01", None),
+        ]
+        .map(|(url, date, order)| {
+            let mut post = Document::for_test(url, url);
+            post.meta.date = date.to_owned();
+            post.meta.order = order;
+            post
+        });
+        let mut writer = MemoryWriter::default();
+        write_feed(
+            &config,
+            "News",
+            "News",
+            "news",
+            &posts,
+            FeedFormat::RSS,
+            &mut handlebars,
+            &mut writer,
+        )
+        .unwrap();
+        let rss = String::from_utf8(writer.files[Path::new("build/news/rss.xml")].clone()).unwrap();
+        let rss = roxmltree::Document::parse(&rss).unwrap();
+        let titles = rss
+            .descendants()
+            .filter(|n| n.has_tag_name("item"))
+            .filter_map(|item| item.children().find(|n| n.has_tag_name("title")))
+            .map(|title| title.text().unwrap_or_default().trim().to_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(titles, ["/news/release", "/news/update"]);
+    }
 }
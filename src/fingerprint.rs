@@ -0,0 +1,50 @@
+use crate::config::Config;
+use std::path::{Path, PathBuf};
+
+// Hex digits of the hash that go in file names. The server treats 8 or more as a fingerprint.
+const HASH_LENGTH: usize = 10;
+
+// FNV-1a. Doesn't need to be cryptographic, just stable between builds and toolchains.
+pub fn content_hash(data: &[u8]) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in data {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("{hash:016x}")[..HASH_LENGTH].to_owned()
+}
+
+// style.css -> style.<hash>.css
+pub fn hashed_path(path: &Path, hash: &str) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(extension) => format!("{stem}.{hash}.{}", extension.to_string_lossy()),
+        None => format!("{stem}.{hash}"),
+    };
+    path.with_file_name(name)
+}
+
+// Notes the /static/ files a page links to that won't be in the output. With fingerprinting,
+// those are the ones not in the manifest, and the original names unless they're kept.
+// Otherwise, the static files are on disk by the time pages are rendered.
+pub fn check_asset_references(config: &Config, html: &str) {
+    let asset_regex = regex::Regex::new(&format!(
+        r#"\b(?:src|href)="{}(/static/[^"?#]+)"#,
+        regex::escape(&config.path_prefix)
+    ))
+    .unwrap();
+    let assets = config.assets.borrow();
+    let mut missing = config.missing_assets.borrow_mut();
+    for captures in asset_regex.captures_iter(html) {
+        let path = &captures[1];
+        let found = if config.fingerprint {
+            assets.values().any(|hashed| hashed == path)
+                || (config.keep_unhashed && assets.contains_key(path))
+        } else {
+            config.out_dir.join(path.trim_start_matches('/')).is_file()
+        };
+        if !found {
+            missing.insert(path.to_owned());
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use crate::config::Config;
+use crate::writer::SiteWriter;
+use serde::Serialize;
+
+// Follows the shields.io endpoint schema: https://shields.io/badges/endpoint-badge
+#[derive(Serialize)]
+struct Badge {
+    #[serde(rename = "schemaVersion")]
+    schema_version: u32,
+    label: String,
+    message: String,
+    color: String,
+}
+
+fn write_api_file(
+    config: &Config,
+    name: &str,
+    contents: &str,
+    writer: &mut dyn SiteWriter,
+) -> anyhow::Result<()> {
+    let file_path = config.out_dir.join("api").join(name);
+    writer.write(&file_path, contents.as_bytes())
+}
+
+// Small static files for external consumers, like the GitHub README badge and scripts.
+#[tracing::instrument(skip_all)]
+pub fn generate_api(config: &Config, writer: &mut dyn SiteWriter) -> anyhow::Result<()> {
+    let version = &config.global_meta.app_version;
+
+    let badge = Badge {
+        schema_version: 1,
+        label: "version".to_string(),
+        message: version.clone(),
+        color: "blue".to_string(),
+    };
+    write_api_file(
+        config,
+        "badge/version.json",
+        &serde_json::to_string(&badge)?,
+        writer,
+    )?;
+    write_api_file(config, "latest.txt", version, writer)?;
+
+    println!("Wrote api files for version {}", version);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::writer::MemoryWriter;
+    use std::path::Path;
+
+    #[test]
+    fn api_files_have_the_version() {
+        let (mut config, _) = crate::test_site(Path::new("."), &[]);
+        config.global_meta.app_version = "1.17.1".to_owned();
+        let mut writer = MemoryWriter::default();
+        generate_api(&config, &mut writer).unwrap();
+
+        let badge = &writer.files[Path::new("build/api/badge/version.json")];
+        let badge: serde_json::Value = serde_json::from_slice(badge).unwrap();
+        assert_eq!(
+            badge,
+            serde_json::json!({
+                "schemaVersion": 1,
+                "label": "version",
+                "message": "1.17.1",
+                "color": "blue",
+            })
+        );
+        assert_eq!(writer.files[Path::new("build/api/latest.txt")], b"1.17.1");
+    }
+}
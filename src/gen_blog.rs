@@ -1,17 +1,63 @@
+use crate::writer::SiteWriter;
 use crate::{config::*, document::*, feed, util};
 use std::{
     cmp::Ordering,
-    path::{Path, PathBuf},
+    path::{Component, Path, PathBuf},
 };
 
+// Sections can override any of the blog templates with their own, like news_post for
+// blog_post. Missing ones fall back to the blog template.
+pub fn section_template(
+    handlebars: &handlebars::Handlebars<'_>,
+    template_prefix: Option<&str>,
+    name: &str,
+) -> String {
+    if let Some(prefix) = template_prefix {
+        let template = format!("{prefix}_{name}");
+        if handlebars.has_template(&template) {
+            return template;
+        }
+    }
+    format!("blog_{name}")
+}
+
+// File names are expected to look like YYYY-MM-DD-slug.md, unless the section has optional
+// dates. A date in the front matter wins. Missing or bad dates are caught by validation.
+pub fn meta_from_file_name(meta: &mut DocumentMeta, name: &str) {
+    let is_number = |part: &str| part.parse::<u32>().is_ok();
+    match name.splitn(4, '-').collect::<Vec<_>>()[..] {
+        [year, month, day, remainder] if [year, month, day].into_iter().all(is_number) => {
+            if meta.date.is_empty() {
+                meta.date = format!("{}-{}-{}", year, month, day);
+            }
+            if meta.slug.is_empty() {
+                let remainder = remainder.trim_end_matches(".md");
+                println!(
+                    "Warning: Blog entry missing slug, auto-detecting {}: {}",
+                    name, remainder
+                );
+                meta.slug = remainder.to_string();
+            }
+        }
+        _ => {
+            if meta.slug.is_empty() {
+                meta.slug = name.trim_end_matches(".md").to_string();
+            }
+        }
+    }
+}
+
 // Posts should be passed-in in reverse time order.
 fn generate_blog_sidebar(
     title: &str,
+    template_prefix: Option<&str>,
     url: &str,
     all_posts: &[&Document],
+    globals: &GlobalMeta,
     handlebars: &mut handlebars::Handlebars<'_>,
 ) -> anyhow::Result<String> {
     let context = SidebarContext {
+        globals,
         title: title.to_string(),
         links: all_posts
             .iter()
@@ -19,24 +65,29 @@ fn generate_blog_sidebar(
             .collect::<Vec<_>>(),
     };
 
-    let output = handlebars.render("blog_sidebar", &context)?;
+    let template = section_template(handlebars, template_prefix, "sidebar");
+    let output = handlebars.render(&template, &context)?;
     Ok(output)
 }
 
+#[tracing::instrument(skip_all, fields(folder = %folder))]
 pub fn generate_blog(
     config: &Config,
     folder: &str,
     title: &str,
+    template_prefix: Option<&str>,
     handlebars: &mut handlebars::Handlebars<'_>,
+    writer: &mut dyn SiteWriter,
 ) -> anyhow::Result<Vec<Document>> {
+    let post_template = section_template(handlebars, template_prefix, "post");
+    let page_template = section_template(handlebars, template_prefix, "page");
+
     // For the blog
 
     let root_folder = config.in_dir.join(folder);
     anyhow::ensure!(root_folder.exists());
     let out_root_folder = config.out_dir.join(folder);
 
-    util::create_folder_if_missing(&out_root_folder)?;
-
     let mut documents = vec![];
 
     let listing = root_folder.read_dir()?;
@@ -58,21 +109,21 @@ pub fn generate_blog(
         }
         let name = util::filename_to_string(&entry.file_name());
 
-        let parts: [&str; 4] = name.splitn(4, '-').collect::<Vec<_>>().try_into().unwrap();
-
-        let mut doc = Document::from_md(&root_folder.join(entry.file_name()), config)?;
+        let source_path = root_folder.join(entry.file_name());
+        let Some(mut doc) =
+            config.skip_on_error(&source_path, Document::from_md(&source_path, config))?
+        else {
+            continue;
+        };
 
-        let [year, month, day, remainder] = parts;
         doc.meta.section = folder.to_string();
-        doc.meta.date = format!("{}-{}-{}", year, month, day);
-        if doc.meta.slug.is_empty() {
-            println!(
-                "Warning: Blog entry missing slug, auto-detecting {}: {}",
-                name, remainder
-            );
-            doc.meta.slug = remainder.to_string();
+        meta_from_file_name(&mut doc.meta, &name);
+        if config
+            .skip_on_error(&source_path, doc.validate(folder, &source_path, config))?
+            .is_none()
+        {
+            continue;
         }
-        assert!(!doc.meta.slug.is_empty());
         doc.meta.url = format!("/{folder}/{}", &doc.meta.slug);
         doc.path = out_root_folder.join(&doc.meta.slug);
 
@@ -91,12 +142,18 @@ pub fn generate_blog(
         documents.push(doc);
     }
 
-    documents.sort_by(|a, b| {
-        a.meta
+    // Posts with an explicit order go first, then the rest from newest to oldest. Undated
+    // ones end up last.
+    documents.sort_by(|a, b| match (a.meta.order, b.meta.order) {
+        (Some(a), Some(b)) => a.cmp(&b),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => a
+            .meta
             .date
             .partial_cmp(&b.meta.date)
             .unwrap_or(Ordering::Equal)
-            .reverse()
+            .reverse(),
     });
 
     // Reformat the tag data to a vector.
@@ -119,24 +176,48 @@ pub fn generate_blog(
         filtered_documents.push(doc);
     }
 
+    let mut skipped = vec![];
     for doc in &documents {
         let context = PageContext::from_document(doc, &config.global_meta);
 
         // First, render the blog post itself, without the surrounding chrome. This is so that we can add on
         // more blog posts underneath later for a more continuous experience.
-        let post_html = handlebars.render("blog_post", &context)?;
-        let sidebar = generate_blog_sidebar(title, &doc.meta.url, &filtered_documents, handlebars)?;
+        let rendered = context.render(&post_template, handlebars);
+        let Some(post_html) = config.skip_on_error(&doc.source, rendered)? else {
+            skipped.push(doc.meta.url.clone());
+            continue;
+        };
+        let sidebar = generate_blog_sidebar(
+            title,
+            template_prefix,
+            &doc.meta.url,
+            &filtered_documents,
+            &config.global_meta,
+            handlebars,
+        )?;
 
         let mut context = PageContext::from_document(doc, &config.global_meta);
         // Now, use that as contents and render into a doc template.
         context.contents = Some(post_html);
         context.sidebar = Some(sidebar);
         //println!("{:#?}", context.meta);
-        let html = context.render("blog_page", handlebars)?;
+        let html = config.prefix_root_links(context.render_layout(&page_template, handlebars)?);
 
         let target_path = &doc.path;
-        util::write_file_as_folder_with_index(target_path, html, false)?;
+        let written = util::write_file_as_folder_with_index(
+            config,
+            writer,
+            target_path,
+            html,
+            false,
+            &doc.source,
+        );
+        if config.skip_on_error(&doc.source, written)?.is_none() {
+            skipped.push(doc.meta.url.clone());
+        }
     }
+    // Keep posts that failed to render out of the feeds and listings.
+    documents.retain(|doc| !skipped.contains(&doc.meta.url));
 
     // Generate RSS feed
     feed::write_feed(
@@ -147,6 +228,7 @@ pub fn generate_blog(
         &documents,
         feed::FeedFormat::Atom,
         handlebars,
+        writer,
     )?;
     feed::write_feed(
         config,
@@ -156,35 +238,61 @@ pub fn generate_blog(
         &documents,
         feed::FeedFormat::RSS,
         handlebars,
+        writer,
     )?;
 
-    // Generate a full blog listing as the root blog post.
-    // TODO: paginate.
+    // Generate a blog listing as the root blog post, blog_page_size posts per page.
     let target_path = out_root_folder.clone();
     generate_blog_page(
         config,
         &documents,
         folder,
         title,
+        template_prefix,
         &target_path,
         &tags,
         &tags,
         handlebars,
+        writer,
     )?;
 
     // Now for each tag, generate another, but filter by tag.
     for tag in &tags {
-        let target_path = out_root_folder.join("tags").join(&tag.name);
+        let slug = util::slugify(&tag.name);
+        let target_path = out_root_folder.join("tags").join(&slug);
         generate_blog_page(
             config,
             &documents,
             folder,
             title,
+            template_prefix,
             &target_path,
             &[tag.clone()],
             &tags,
             handlebars,
+            writer,
         )?;
+
+        // Tag pages used to be at tags/<name>, those links get a redirect. Not when only the
+        // case differs, the stub would land on the tag page on case-insensitive disks.
+        let old_name = Path::new(&tag.name);
+        let is_one_folder = matches!(
+            old_name.components().collect::<Vec<_>>()[..],
+            [Component::Normal(_)]
+        );
+        if is_one_folder && tag.name.to_lowercase() != slug {
+            let url = config.page_url(&format!("/{folder}/tags/{slug}"));
+            let target_url = format!("{}{}", config.path_prefix, url);
+            let html = util::redirect_html(&target_url, &config.site_url(&url));
+            util::write_file_as_folder_with_index(
+                config,
+                writer,
+                &out_root_folder.join("tags").join(old_name),
+                html,
+                false,
+                &root_folder,
+            )?;
+        }
     }
 
     println!("Wrote blog {}", folder);
@@ -197,10 +305,12 @@ fn generate_blog_page(
     documents: &[Document],
     folder: &str,
     title: &str,
+    template_prefix: Option<&str>,
     target_path: &Path,
     tag_filter: &[Tag],
     all_tags: &[Tag],
     handlebars: &mut handlebars::Handlebars<'_>,
+    writer: &mut dyn SiteWriter,
 ) -> anyhow::Result<()> {
     // Filter the documents by tag.
     let mut filtered_documents = vec![];
@@ -216,33 +326,170 @@ fn generate_blog_page(
         }
     }
 
+    let post_template = section_template(handlebars, template_prefix, "post");
+    let page_template = section_template(handlebars, template_prefix, "page");
     let sidebar = generate_blog_sidebar(
         title,
+        template_prefix,
         &format!("/{}", folder),
         &filtered_documents,
+        &config.global_meta,
         handlebars,
     )?;
 
-    // First, render the blog post itself, without the surrounding chrome. This is so that we can add on
-    // more blog posts underneath later for a more continuous experience.
-    let post_html = filtered_documents
-        .iter()
-        .map(|doc| {
-            let context = PageContext::from_document(doc, &config.global_meta);
-            // Now, use that as contents and render into a doc template.
-            context.render("blog_post", handlebars).unwrap()
-        })
-        .collect::<Vec<_>>()
-        .join("\n");
-
-    let mut context =
-        PageContext::new(Some(title.to_owned()), Some(post_html), &config.global_meta);
-    context.sidebar = Some(sidebar);
-    context.tags = all_tags;
-    context.meta = Some(documents[0].meta.clone());
-
-    let html = context.render("blog_page", handlebars)?;
-
-    util::write_file_as_folder_with_index(target_path, html, false)?;
+    // Pinned posts go on top of the listing. The sort is stable, so the rest stay by date.
+    filtered_documents.sort_by_key(|doc| std::cmp::Reverse(doc.meta.pinned));
+
+    // Page 1 goes at the target path, the rest under page/N.
+    let url = format!(
+        "/{}",
+        target_path
+            .strip_prefix(&config.out_dir)?
+            .to_string_lossy()
+            .replace('\\', "/")
+    );
+    let page_url = |page: usize| {
+        if page == 1 {
+            url.clone()
+        } else {
+            format!("{url}/page/{page}")
+        }
+    };
+    let page_size = config.blog_page_size.max(1);
+    let page_count = filtered_documents.len().div_ceil(page_size).max(1);
+
+    for page in 1..=page_count {
+        let page_documents = filtered_documents
+            .iter()
+            .skip((page - 1) * page_size)
+            .take(page_size);
+
+        // First, render the blog post itself, without the surrounding chrome. This is so that we can add on
+        // more blog posts underneath later for a more continuous experience.
+        let post_html = page_documents
+            .map(|doc| {
+                let context = PageContext::from_document(doc, &config.global_meta);
+                // Now, use that as contents and render into a doc template.
+                context
+                    .render(&post_template, handlebars)
+                    .map_err(|err| crate::error::with_document(err, &doc.source))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?
+            .join("\n");
+
+        let mut context =
+            PageContext::new(Some(title.to_owned()), Some(post_html), &config.global_meta);
+        context.sidebar = Some(sidebar.clone());
+        context.tags = all_tags;
+        let mut meta = documents[0].meta.clone();
+        // The listing gets the site's default social preview, not the newest post's.
+        meta.cover = None;
+        meta.og_image = None;
+        meta.layout = None;
+        // The prev/next links page through the listing.
+        meta.prev =
+            (page > 1).then(|| DocLink::new(&page_url(page - 1), "Newer posts", "", None, ""));
+        meta.next = (page < page_count)
+            .then(|| DocLink::new(&page_url(page + 1), "Older posts", "", None, ""));
+        context.meta = Some(meta);
+        context.url = page_url(page);
+        if page_count > 1 {
+            context.pagination = Some(Pagination::new(
+                page,
+                (1..=page_count).map(page_url).collect(),
+            ));
+        }
+
+        let html = config.prefix_root_links(context.render(&page_template, handlebars)?);
+
+        let page_path = if page == 1 {
+            target_path.to_path_buf()
+        } else {
+            target_path.join("page").join(page.to_string())
+        };
+        let source = config.in_dir.join(folder);
+        util::write_file_as_folder_with_index(config, writer, &page_path, html, false, &source)?;
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sidebar_escapes_titles() {
+        let (config, mut handlebars) = crate::test_site(Path::new("."), &[]);
+        let post = Document::for_test("/blog/save-states", r#"Save & load "states" <fast>"#);
+        let html = generate_blog_sidebar(
+            "Blog <3",
+            None,
+            "/blog",
+            &[&post],
+            &config.global_meta,
+            &mut handlebars,
+        )
+        .unwrap();
+        assert!(html.contains("<h2>Blog &lt;3</h2>"), "{html}");
+        assert!(html.contains(">Save &amp; load &quot;states&quot; &lt;fast&gt;</a>"));
+    }
+
+    #[test]
+    fn old_tag_urls_redirect() {
+        let temp = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(temp.path().join("blog")).unwrap();
+        std::fs::write(
+            temp.path().join("blog/2024-01-26-save-states.md"),
+            "---\nslug: save-states\ntitle: Save states\nauthors: hrydgard\ntags: [Save States, Android, news]\n---\n\nFaster.\n",
+        )
+        .unwrap();
+        let (config, mut handlebars) = crate::test_site(temp.path(), &[]);
+        let mut writer = crate::writer::MemoryWriter::default();
+        generate_blog(&config, "blog", "Blog", None, &mut handlebars, &mut writer).unwrap();
+
+        let page = |path: &str| {
+            let contents = writer.files.get(Path::new(path));
+            contents.map(|contents| String::from_utf8(contents.clone()).unwrap())
+        };
+        assert!(page("build/blog/tags/save-states/index.html").is_some());
+        let stub = page("build/blog/tags/Save States/index.html").unwrap();
+        assert!(stub.contains(r#"url=/blog/tags/save-states""#), "{stub}");
+        // Differing only in case, it would overwrite the tag page.
+        assert!(page("build/blog/tags/android/index.html").is_some());
+        assert!(page("build/blog/tags/Android/index.html").is_none());
+    }
+
+    #[test]
+    fn post_errors_name_the_post() {
+        let (config, mut handlebars) = crate::test_site(Path::new("."), &[]);
+        handlebars
+            .register_template_string("broken_post", "{{> no_such_partial}}")
+            .unwrap();
+        let mut post = Document::for_test("/blog/save-states", "Save states");
+        post.source = "blog/2024-01-26-save-states.md".into();
+        post.meta.tags = vec!["Saves".to_owned()];
+        let tags = [Tag {
+            name: "Saves".to_owned(),
+            ..Default::default()
+        }];
+        let err = generate_blog_page(
+            &config,
+            &[post],
+            "blog",
+            "Blog",
+            Some("broken"),
+            &config.out_dir.join("blog"),
+            &tags,
+            &tags,
+            &mut handlebars,
+            &mut crate::writer::MemoryWriter::default(),
+        )
+        .unwrap_err();
+        let err = crate::error::SiteError::from(err);
+        assert!(
+            err.to_string()
+                .contains("rendering blog/2024-01-26-save-states.md"),
+            "{err}"
+        );
+    }
+}
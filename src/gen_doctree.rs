@@ -1,12 +1,17 @@
-use crate::document::{self, Category, Document, PageContext};
-use crate::index;
+use crate::document::{
+    self, Category, Document, DocumentIndex, DocumentMeta, NavCategory, PageContext,
+};
+use crate::writer::SiteWriter;
 use crate::{config::*, util};
+use crate::{index, post_process};
 use anyhow::Context;
-use std::io::Write;
+use serde::Deserialize;
+use std::collections::HashMap;
 
 // TODO: Involve templates here for easier modification?
 // Can handlebars templates recurse?
-// Should be surrounded in an <ul class="nav-tree">
+// Should be surrounded in an <ul class="nav-tree">. Titles are text, so they're escaped like
+// {{title}} would be.
 fn generate_docnav_html(root: &Category, level: usize, breadcrumbs: &[DocLink]) -> String {
     let mut str = String::new();
 
@@ -21,7 +26,9 @@ fn generate_docnav_html(root: &Category, level: usize, breadcrumbs: &[DocLink])
         let extra_classes = if expanded { " selected" } else { "" };
         str += &format!(
             "<li><a href=\"{}\" class=\"nav-tree-category{}\">{}</a>",
-            cat.meta.url, extra_classes, cat.meta.title
+            handlebars::html_escape(&cat.meta.url),
+            extra_classes,
+            handlebars::html_escape(&cat.meta.title)
         );
         if expanded {
             str += &generate_docnav_html(cat, level + 1, breadcrumbs);
@@ -37,7 +44,9 @@ fn generate_docnav_html(root: &Category, level: usize, breadcrumbs: &[DocLink])
         let extra_classes = if expanded { " selected" } else { "" };
         str += &format!(
             "<li><a href=\"{}\" class=\"nav-tree-item {}\">{}</a></li>\n",
-            doc.meta.url, extra_classes, doc.meta.title,
+            handlebars::html_escape(&doc.meta.url),
+            extra_classes,
+            handlebars::html_escape(&doc.meta.title),
         );
     }
     str += "</ul>\n";
@@ -45,19 +54,332 @@ fn generate_docnav_html(root: &Category, level: usize, breadcrumbs: &[DocLink])
     str
 }
 
-pub fn generate_doctree(
+// An entry in sidebars.json. Docs and categories are named by their path under the doc folder,
+// like "getting-started/installation". Within a category, docs are listed ahead of
+// sub-categories, same as with the filesystem order.
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum SidebarItem {
+    Doc(String),
+    Category {
+        category: String,
+        #[serde(default)]
+        items: Vec<SidebarItem>,
+    },
+}
+
+// Splits the tree up into id -> category (emptied) and id -> document maps.
+fn flatten_tree(
+    mut cat: Category,
+    url_prefix: &str,
+    categories: &mut HashMap<String, Category>,
+    docs: &mut HashMap<String, Document>,
+) {
+    for doc in cat.documents.drain(..) {
+        docs.insert(doc.meta.url.trim_start_matches(url_prefix).to_owned(), doc);
+    }
+    for sub_cat in std::mem::take(&mut cat.sub_categories) {
+        flatten_tree(sub_cat, url_prefix, categories, docs);
+    }
+    categories.insert(cat.meta.url.trim_start_matches(url_prefix).to_owned(), cat);
+}
+
+fn fill_category(
+    cat: &mut Category,
+    items: Vec<SidebarItem>,
+    categories: &mut HashMap<String, Category>,
+    docs: &mut HashMap<String, Document>,
+) -> anyhow::Result<()> {
+    for item in items {
+        match item {
+            SidebarItem::Doc(id) => {
+                let doc = docs
+                    .remove(&id)
+                    .with_context(|| format!("sidebars.json: no doc named {id}"))?;
+                cat.documents.push(doc);
+            }
+            SidebarItem::Category { category, items } => {
+                let mut sub_cat = categories
+                    .remove(&category)
+                    .with_context(|| format!("sidebars.json: no category named {category}"))?;
+                fill_category(&mut sub_cat, items, categories, docs)?;
+                cat.sub_categories.push(sub_cat);
+            }
+        }
+    }
+    Ok(())
+}
+
+// Rearranges the filesystem tree to follow sidebars.json. Docs it doesn't mention are returned
+// separately, so they still get built, just without a place in the navigation.
+fn apply_sidebars(
     config: &Config,
+    root_cat: Category,
     folder: &str,
+    items: Vec<SidebarItem>,
+) -> anyhow::Result<(Category, Vec<Document>)> {
+    let url_prefix = format!("/{folder}/");
+    let root_url = root_cat.meta.url.clone();
+    let mut categories = HashMap::new();
+    let mut docs = HashMap::new();
+    flatten_tree(root_cat, &url_prefix, &mut categories, &mut docs);
+
+    let mut root_cat = categories
+        .remove(root_url.trim_start_matches(&url_prefix))
+        .context("sidebars.json: lost the root category")?;
+    fill_category(&mut root_cat, items, &mut categories, &mut docs)?;
+
+    let mut omitted = categories.into_keys().collect::<Vec<_>>();
+    omitted.sort();
+    for id in omitted {
+        config.warn(format!(
+            "Category {id} is not listed in sidebars.json, omitting it"
+        ));
+    }
+    let mut unlisted = docs.into_values().collect::<Vec<_>>();
+    unlisted.sort_by(|a, b| a.meta.url.cmp(&b.meta.url));
+    for doc in &unlisted {
+        config.warn(format!(
+            "Doc {} is not listed in sidebars.json",
+            doc.meta.url
+        ));
+    }
+    Ok((root_cat, unlisted))
+}
+
+// moved_from URLs can be given as full paths, or relative to the doc folder, like
+// old-section/foo.
+fn resolve_moved_from(folder: &str, old_url: &str) -> String {
+    let old_url = old_url.trim_end_matches('/');
+    if old_url.starts_with('/') {
+        old_url.to_owned()
+    } else {
+        format!("/{folder}/{old_url}")
+    }
+}
+
+// Writes redirect stubs at the moved_from URLs of docs, unless something else is there.
+pub fn write_moved_stubs(
+    config: &Config,
+    docs: &[Document],
+    documents: &DocumentIndex<'_>,
+    writer: &mut dyn SiteWriter,
+) -> anyhow::Result<()> {
+    let mut moved = HashMap::<&str, &Document>::new();
+    for doc in docs {
+        for old_url in &doc.meta.moved_from {
+            if let Some(existing) = documents.by_url(old_url) {
+                config.warn(format!(
+                    "{} moved_from {old_url}, but {} is still there, not redirecting",
+                    doc.source.display(),
+                    existing.source.display()
+                ));
+            } else if let Some(other) = moved.get(old_url.as_str()) {
+                config.warn(format!(
+                    "{} and {} both moved_from {old_url}, redirecting to {}",
+                    other.source.display(),
+                    doc.source.display(),
+                    other.meta.url
+                ));
+            } else {
+                moved.insert(old_url, doc);
+            }
+        }
+    }
+
+    for (old_url, doc) in moved {
+        let url = config.page_url(&doc.meta.url);
+        let target_url = format!("{}{}", config.path_prefix, url);
+        let html = util::redirect_html(&target_url, &config.site_url(&url));
+        let target_path = config.out_dir.join(old_url.trim_start_matches('/'));
+        util::write_file_as_folder_with_index(
+            config,
+            writer,
+            &target_path,
+            html,
+            false,
+            &doc.source,
+        )?;
+    }
+    Ok(())
+}
+
+// Where a doc starts on the all docs page, like docs-getting-started-installation.
+fn all_docs_anchor(url: &str) -> String {
+    crate::util::slugify(url)
+}
+
+fn generate_all_docs_toc(cat: &Category) -> String {
+    let mut str = String::new();
+    str += &format!(
+        "<li><a href=\"#{}\">{}</a>\n<ul>\n",
+        all_docs_anchor(&cat.meta.url),
+        handlebars::html_escape(&cat.meta.title)
+    );
+    for doc in &cat.documents {
+        str += &format!(
+            "<li><a href=\"#{}\">{}</a></li>\n",
+            all_docs_anchor(&doc.meta.url),
+            handlebars::html_escape(&doc.meta.title)
+        );
+    }
+    for sub_cat in &cat.sub_categories {
+        str += &generate_all_docs_toc(sub_cat);
+    }
+    str += "</ul>\n</li>\n";
+    str
+}
+
+// Every doc in reading order on one page, at /docs/all/, for printing and offline reading.
+// Ids get prefixed per doc so they don't clash, and links between the docs go to where
+// they are on the page.
+fn write_all_docs_page(
+    config: &Config,
+    folder: &str,
+    root_cat: &Category,
+    docs: &[Document],
+    handlebars: &mut handlebars::Handlebars<'_>,
+    writer: &mut dyn SiteWriter,
+) -> anyhow::Result<()> {
+    let anchors = docs
+        .iter()
+        .map(|doc| {
+            (
+                doc.meta.url.trim_end_matches('/'),
+                all_docs_anchor(&doc.meta.url),
+            )
+        })
+        .collect::<HashMap<_, _>>();
+    let link_regex = regex::Regex::new(r##"href="(/[^"#]*)(#[^"]*)?""##).unwrap();
+
+    let mut contents = format!(
+        "<h1>All docs</h1>\n<nav class=\"all-docs-toc\">\n<ul>\n{}</ul>\n</nav>\n",
+        generate_all_docs_toc(root_cat)
+    );
+    for doc in docs {
+        let anchor = all_docs_anchor(&doc.meta.url);
+        let html = post_process::prefix_ids(&doc.html, &anchor);
+        let html = link_regex.replace_all(&html, |captures: &regex::Captures<'_>| {
+            let Some(target) = anchors.get(captures[1].trim_end_matches('/')) else {
+                return captures[0].to_owned();
+            };
+            match captures.get(2) {
+                Some(fragment) => format!("href=\"#{target}-{}\"", &fragment.as_str()[1..]),
+                None => format!("href=\"#{target}\""),
+            }
+        });
+        contents +=
+            &format!("<section class=\"all-docs-doc\" id=\"{anchor}\">\n{html}\n</section>\n");
+    }
+
+    let url = format!("/{folder}/all");
+    let meta = DocumentMeta {
+        title: "All docs".to_owned(),
+        url: url.clone(),
+        breadcrumbs: root_cat.meta.breadcrumbs.clone(),
+        contains_code: docs.iter().any(|doc| doc.meta.contains_code),
+        contains_mermaid: docs.iter().any(|doc| doc.meta.contains_mermaid),
+        ..Default::default()
+    };
+    let page = Document {
+        path: format!("{folder}/all").into(),
+        source: config.in_dir.join(folder),
+        markdown: None,
+        html: contents,
+        meta,
+    };
+    let target_path = config.out_dir.join(&page.path);
+    let html = PageContext::from_document(&page, &config.global_meta).render("doc", handlebars)?;
+    let html = config.prefix_root_links(html);
+    util::write_file_as_folder_with_index(config, writer, &target_path, html, false, &page.source)?;
+    println!("Wrote {} docs to {}", docs.len(), url);
+    Ok(())
+}
+
+// The latest docs are in base, older versions in subfolders of it named after the version.
+fn version_folder(base: &str, version: Option<&str>) -> String {
+    match version {
+        Some(version) => format!("{base}/{version}"),
+        None => base.to_owned(),
+    }
+}
+
+// Links to the same doc in every version, for the version switcher, with the current version
+// selected. Docs are matched by their path below the version folder. Versions that don't have
+// the doc link to their root instead.
+fn version_links(
+    config: &Config,
+    base: &str,
+    current: Option<&str>,
+    source: &std::path::Path,
+) -> Vec<DocLink> {
+    let root_folder = config.in_dir.join(version_folder(base, current));
+    let relative = source.strip_prefix(&root_folder).unwrap_or(source.as_ref());
+    let versions =
+        std::iter::once(None).chain(config.doc_versions.iter().map(|v| Some(v.as_str())));
+    versions
+        .map(|version| {
+            let folder = version_folder(base, version);
+            let root_url = format!("/{folder}");
+            let mut url = root_url.clone();
+            if config.in_dir.join(&folder).join(relative).exists() {
+                let path = relative.with_extension("");
+                for component in path.components() {
+                    url += "/";
+                    url += &component.as_os_str().to_string_lossy();
+                }
+            }
+            DocLink {
+                title: version.unwrap_or(LATEST_VERSION).to_owned(),
+                selected: version == current,
+                ..DocLink::new(&url, "", "", None, "")
+            }
+        })
+        .collect()
+}
+
+// What the switcher calls the docs in the base folder.
+const LATEST_VERSION: &str = "latest";
+
+// Generates the docs in folder, or one of its versions. Version folders are left out of the
+// latest docs.
+#[tracing::instrument(skip_all, fields(folder = %base, version = ?version))]
+pub fn generate_doctree(
+    config: &Config,
+    base: &str,
+    version: Option<&str>,
     handlebars: &mut handlebars::Handlebars<'_>,
-) -> anyhow::Result<Vec<Document>> {
+    writer: &mut dyn SiteWriter,
+) -> anyhow::Result<(Vec<Document>, NavCategory)> {
+    let folder = &version_folder(base, version);
     // First, build the tree and convert all the markdown to html and metadata.
     let root_folder = config.in_dir.join(folder);
-    anyhow::ensure!(root_folder.exists());
+    anyhow::ensure!(
+        root_folder.exists(),
+        "{} doesn't exist",
+        root_folder.display()
+    );
     let out_root_folder = config.out_dir.clone();
     let mut root_cat = document::Category::from_folder_tree(&root_folder, config)?;
 
+    // An explicit reading order, if there is one. Otherwise we go by the filesystem.
+    let sidebars_path = match version {
+        Some(version) => config.in_dir.join(format!("sidebars-{version}.json")),
+        None => config.in_dir.join("sidebars.json"),
+    };
+    let mut unlisted = vec![];
+    if sidebars_path.exists() {
+        let items: Vec<SidebarItem> =
+            serde_json::from_str(&std::fs::read_to_string(&sidebars_path)?)
+                .with_context(|| format!("parsing {}", sidebars_path.display()))?;
+        (root_cat, unlisted) = apply_sidebars(config, root_cat, folder, items)?;
+    }
+
     let mut crumbs = vec![DocLink {
-        title: "Docs".to_owned(),
+        title: match version {
+            Some(version) => format!("Docs {version}"),
+            None => "Docs".to_owned(),
+        },
         url: format!("/{}", folder),
         date: "N/A".to_owned(),
         summary: None,
@@ -71,18 +393,57 @@ pub fn generate_doctree(
     // Note that we also generate the categories as documents in `all_documents`.
     let mut docs = root_cat.all_documents(handlebars, &config.global_meta)?;
     Category::add_prev_next_links(&mut docs);
+    let unlisted_count = unlisted.len();
+    // They can still be linked to, but aren't advertised.
+    for doc in &mut unlisted {
+        doc.meta.sitemap.get_or_insert(false);
+    }
+    docs.extend(unlisted);
+    for doc in &mut docs {
+        doc.meta.moved_from = doc
+            .meta
+            .moved_from
+            .iter()
+            .map(|old_url| resolve_moved_from(folder, old_url))
+            .collect();
+    }
+    if !config.doc_versions.is_empty() {
+        for doc in &mut docs {
+            doc.meta.versions = version_links(config, base, version, &doc.source);
+        }
+    }
 
     for doc in &docs {
-        let target_path = out_root_folder.join(&doc.path);
-
-        util::create_folder_if_missing(&target_path)?;
+        // The output mirrors the source folders.
+        let relative = doc.path.strip_prefix(&config.in_dir).unwrap_or(&doc.path);
+        let target_path = out_root_folder.join(relative);
 
         // We apply the template right here.
         let mut context = PageContext::from_document(doc, &config.global_meta);
         context.sidebar = Some(generate_docnav_html(&root_cat, 0, &doc.meta.breadcrumbs));
-        let html = context.render("doc", handlebars)?;
+        let Some(html) =
+            config.skip_on_error(&doc.path, context.render_layout("doc", handlebars))?
+        else {
+            continue;
+        };
+        let html = config.prefix_root_links(html);
+
+        // The paths have no extension already, and version folders like 1.16 have dots.
+        let written = util::write_file_as_folder_with_index(
+            config,
+            writer,
+            &target_path,
+            html,
+            false,
+            &doc.source,
+        );
+        config.skip_on_error(&doc.source, written)?;
+    }
 
-        util::write_file_as_folder_with_index(&target_path, html, true)?;
+    if config.all_docs_page {
+        // Unlisted docs aren't part of the reading order.
+        let listed = &docs[..docs.len() - unlisted_count];
+        write_all_docs_page(config, folder, &root_cat, listed, handlebars, writer)?;
     }
 
     let mut index = index::Index::new();
@@ -95,9 +456,14 @@ pub fn generate_doctree(
     }
 
     let json_index = index.to_index_json();
-    let json_index_path = out_root_folder.join("index.json");
-    let mut file = std::fs::File::create(&json_index_path).context("create_json_index")?;
-    file.write_all(json_index.as_bytes())?;
+    // The latest docs' index is the one the site search uses.
+    let json_index_path = match version {
+        Some(_) => out_root_folder.join(folder).join("index.json"),
+        None => out_root_folder.join("index.json"),
+    };
+    writer
+        .write(&json_index_path, json_index.as_bytes())
+        .context("create_json_index")?;
 
     println!(
         "Wrote doctree {}, index as {}",
@@ -105,5 +471,58 @@ pub fn generate_doctree(
         json_index_path.display()
     );
 
-    Ok(docs)
+    Ok((docs, root_cat.to_nav()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    const HOSTILE: &str = r#"Save & load "states" <fast> 'n' easy"#;
+    const ESCAPED: &str = "Save &amp; load &quot;states&quot; &lt;fast&gt; &#x27;n&#x27; easy";
+
+    fn meta(url: &str, title: &str) -> DocumentMeta {
+        DocumentMeta {
+            title: title.to_owned(),
+            url: url.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn nav_escapes_titles() {
+        let category = |url: &str, title: &str, sub_categories| Category {
+            meta: meta(url, title),
+            documents: vec![],
+            sub_categories,
+            path: PathBuf::from(url.trim_start_matches('/')),
+            html: String::new(),
+        };
+        let mut root = category(
+            "/docs",
+            "Docs",
+            vec![category("/docs/q&a", "Q&A <faq>", vec![])],
+        );
+        root.documents
+            .push(Document::for_test("/docs/save-states", HOSTILE));
+
+        let nav = generate_docnav_html(&root, 0, &[]);
+        assert!(
+            nav.contains(&format!(r#"class="nav-tree-item ">{ESCAPED}</a>"#)),
+            "{nav}"
+        );
+        assert!(nav.contains(
+            r#"<a href="/docs/q&amp;a" class="nav-tree-category">Q&amp;A &lt;faq&gt;</a>"#
+        ));
+        let toc = generate_all_docs_toc(&root);
+        assert!(
+            toc.contains(&format!(r##"<a href="#docs-save-states">{ESCAPED}</a>"##)),
+            "{toc}"
+        );
+        assert!(toc.contains(">Q&amp;A &lt;faq&gt;</a>"));
+        for html in [nav, toc] {
+            assert!(!html.contains("<fast>") && !html.contains("<faq>"));
+        }
+    }
 }
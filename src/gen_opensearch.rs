@@ -0,0 +1,41 @@
+use crate::config::Config;
+use crate::writer::SiteWriter;
+use anyhow::Context;
+use serde::Serialize;
+
+// https://github.com/dewitt/opensearch/blob/master/opensearch-1-1-draft-6.md
+#[derive(Serialize)]
+struct OpenSearchDescription {
+    short_name: String,
+    description: String,
+    icon: String,
+    // The search URL, with {searchTerms} where the query goes.
+    template: String,
+    search_form: String,
+}
+
+// Lets browsers offer the site's search from the address bar. Only written when there's a
+// search URL to point them at.
+#[tracing::instrument(skip_all)]
+pub fn generate_opensearch(
+    config: &Config,
+    handlebars: &mut handlebars::Handlebars<'_>,
+    writer: &mut dyn SiteWriter,
+) -> anyhow::Result<()> {
+    let Some(search_url) = &config.search_url else {
+        return Ok(());
+    };
+    let description = OpenSearchDescription {
+        short_name: "PPSSPP".to_owned(),
+        description: "Search the PPSSPP website".to_owned(),
+        icon: config.site_url("/favicon.ico"),
+        template: config.absolute_url(search_url),
+        search_form: config.site_url("/"),
+    };
+    let contents = handlebars.render("opensearch_xml", &description)?;
+    writer
+        .write(&config.out_dir.join("opensearch.xml"), contents.as_bytes())
+        .context("generate_opensearch")?;
+    println!("Wrote opensearch.xml");
+    Ok(())
+}
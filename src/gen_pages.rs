@@ -1,13 +1,15 @@
-use crate::document::{Document, PageContext};
+use crate::document::{Document, PageContext, SiteData};
+use crate::writer::SiteWriter;
 use crate::{config::*, util};
-use anyhow::Context;
-use std::io::Write;
 use std::path::PathBuf;
 
+#[tracing::instrument(skip_all, fields(folder = %folder))]
 pub fn generate_pages(
     config: &Config,
     folder: &str,
+    site: &SiteData,
     handlebars: &mut handlebars::Handlebars<'_>,
+    writer: &mut dyn SiteWriter,
 ) -> anyhow::Result<Vec<Document>> {
     let root_folder = config.in_dir.join(folder);
     anyhow::ensure!(root_folder.exists());
@@ -15,6 +17,7 @@ pub fn generate_pages(
     let out_root_folder = &config.out_dir;
 
     let listing = root_folder.read_dir()?;
+    let mut documents = vec![];
     for entry in listing {
         let entry = entry?;
         let path = root_folder.join(entry.file_name());
@@ -24,7 +27,7 @@ pub fn generate_pages(
             continue;
         };
         let name = util::strip_extension(entry.file_name());
-        let (document, apply_doc_template) = match os_str.to_str().unwrap() {
+        let (mut document, apply_doc_template) = match os_str.to_str().unwrap() {
             "md" => {
                 file_name.set_extension("html");
                 (Document::from_md(&path, config)?, true)
@@ -33,7 +36,7 @@ pub fn generate_pages(
             "hbs" => {
                 file_name.set_extension("html");
                 (
-                    Document::from_hbs(&config.global_meta, &name, &path, handlebars)?,
+                    Document::from_hbs(&config.global_meta, site, &name, &path, handlebars)?,
                     false,
                 )
             }
@@ -51,28 +54,116 @@ pub fn generate_pages(
 
         let html = if apply_doc_template {
             let mut context = PageContext::from_document(&document, &config.global_meta);
-            context.globals = Some(&config.global_meta);
             if let Some(ref mut meta) = &mut context.meta {
                 meta.url = format!(
                     "/{}",
                     fname.as_str().strip_suffix(".hbs").unwrap_or_default()
                 );
             }
-            context.render("page", handlebars)?
+            context
+                .render_layout("page", handlebars)
+                .map_err(|err| crate::error::with_document(err, &path))?
         } else {
-            document.html
+            document.html.clone()
         };
+        let html = config.prefix_root_links(html);
 
-        if fname == "index.hbs" {
-            println!("index.html special case");
-            // Just write it plain.
-            let mut file = std::fs::File::create(&target_path).context("create_file_as_dir")?;
-            file.write_all(html.as_bytes())?;
+        // Static hosts like GitHub Pages and Netlify serve a top-level 404.html for missing
+        // paths, so that one keeps its name, like the front page.
+        let written = if fname == "index.hbs" || name == "404" {
+            util::write_page(config, writer, &target_path, html, &path)
         } else {
             // Otherwise, get rid of the extension by putting it in a subdirectory.
-            util::write_file_as_folder_with_index(&target_path, html, true)?;
+            util::write_file_as_folder_with_index(config, writer, &target_path, html, true, &path)
+        };
+        if config.skip_on_error(&path, written)?.is_none() {
+            continue;
         }
+        document.meta.url = if fname == "index.hbs" {
+            "/".to_owned()
+        } else {
+            format!("/{name}")
+        };
+        // Templates have no front matter, so the not found pages go by name.
+        document.meta.not_found |= name == "404" || name == "notfound";
+        documents.push(document);
     }
     println!("Wrote pages from {}", folder);
-    Ok(vec![])
+    Ok(documents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::writer::MemoryWriter;
+    use std::path::Path;
+
+    // Generates the pages in a fixture folder, with the crate's templates.
+    fn generate(
+        site: &Path,
+        args: &[&str],
+    ) -> (Config, MemoryWriter, anyhow::Result<Vec<Document>>) {
+        let (config, mut handlebars) = crate::test_site(site, args);
+        let mut writer = MemoryWriter::default();
+        let result = generate_pages(
+            &config,
+            "pages",
+            &SiteData::default(),
+            &mut handlebars,
+            &mut writer,
+        );
+        (config, writer, result)
+    }
+
+    #[test]
+    fn root_pages_are_post_processed() {
+        let temp = tempfile::tempdir().unwrap();
+        let pages = temp.path().join("pages");
+        std::fs::create_dir_all(&pages).unwrap();
+        std::fs::write(pages.join("index.hbs"), "<p>\n    Front   page\n</p>\n").unwrap();
+        std::fs::write(pages.join("404.md"), "# Lost\n\nNothing    here.\n").unwrap();
+        std::fs::write(pages.join("about.md"), "# About\n").unwrap();
+
+        let (config, writer, documents) = generate(temp.path(), &["--minify"]);
+        assert_eq!(documents.unwrap().len(), 3);
+        let page = |path: &str| String::from_utf8(writer.files[Path::new(path)].clone()).unwrap();
+        assert_eq!(page("build/index.html"), "<p>Front page</p>");
+        assert!(page("build/404.html").contains("<p>Nothing here.</p>"));
+        assert!(page("build/about/index.html").contains("<h1>About</h1>"));
+        // The same checks as every page, like the link check.
+        let checked = config.pages.borrow();
+        for path in [
+            "build/index.html",
+            "build/404.html",
+            "build/about/index.html",
+        ] {
+            assert!(checked.contains_key(Path::new(path)), "{path}");
+        }
+        assert_eq!(config.minified_pages.get().files, 3);
+    }
+
+    #[test]
+    fn colliding_outputs_fail_the_build() {
+        let temp = tempfile::tempdir().unwrap();
+        let pages = temp.path().join("pages");
+        std::fs::create_dir_all(&pages).unwrap();
+        std::fs::write(pages.join("about.md"), "# About\n").unwrap();
+        std::fs::write(pages.join("about.html"), "<p>About</p>").unwrap();
+        let (_, _, result) = generate(temp.path(), &[]);
+        let err = result.unwrap_err();
+        assert!(crate::error::is_collision(&err), "{err:#}");
+        assert!(
+            format!("{err:#}").contains("build/about/index.html"),
+            "{err:#}"
+        );
+
+        // A page where a static file is copied to.
+        std::fs::remove_file(pages.join("about.html")).unwrap();
+        std::fs::write(pages.join("static.md"), "# Static\n").unwrap();
+        std::fs::create_dir_all(temp.path().join("static")).unwrap();
+        std::fs::write(temp.path().join("static/index.html"), "").unwrap();
+        let (_, _, result) = generate(temp.path(), &[]);
+        let err = result.unwrap_err();
+        assert!(format!("{err:#}").contains("is copied to"), "{err:#}");
+    }
 }
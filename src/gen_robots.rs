@@ -0,0 +1,73 @@
+use crate::config::Config;
+use crate::writer::SiteWriter;
+use anyhow::Context;
+use std::path::Path;
+
+// In the source folder. Used as it is instead of the generated one when it's there.
+const OVERRIDE_FILE: &str = "robots.txt";
+
+// Production builds let crawlers in and point them at the sitemap. Anything else, like
+// staging deployments, keeps them out, and pages get a noindex meta tag too, see
+// common_header.
+#[tracing::instrument(skip_all)]
+pub fn generate_robots(config: &Config, writer: &mut dyn SiteWriter) -> anyhow::Result<()> {
+    let contents = robots_txt(
+        &config.in_dir,
+        !config.global_meta.noindex,
+        &config.site_url("/sitemap.xml"),
+    )?;
+    writer
+        .write(&config.out_dir.join("robots.txt"), contents.as_bytes())
+        .context("generate_robots")?;
+    println!("Wrote robots.txt");
+    Ok(())
+}
+
+fn robots_txt(in_dir: &Path, prod: bool, sitemap_url: &str) -> anyhow::Result<String> {
+    let override_path = in_dir.join(OVERRIDE_FILE);
+    if override_path.exists() {
+        return std::fs::read_to_string(&override_path)
+            .with_context(|| format!("reading {}", override_path.display()));
+    }
+    Ok(if prod {
+        format!("User-agent: *\nAllow: /\n\nSitemap: {sitemap_url}\n")
+    } else {
+        "User-agent: *\nDisallow: /\n".to_owned()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SITEMAP: &str = "https://www.ppsspp.org/sitemap.xml";
+
+    #[test]
+    fn robots_txt_production() {
+        let robots = robots_txt(Path::new("nonexistent"), true, SITEMAP).unwrap();
+        assert!(robots.contains("Allow: /\n"));
+        assert!(!robots.contains("Disallow"));
+        assert!(robots.contains("Sitemap: https://www.ppsspp.org/sitemap.xml\n"));
+    }
+
+    #[test]
+    fn robots_txt_staging() {
+        let robots = robots_txt(Path::new("nonexistent"), false, SITEMAP).unwrap();
+        assert_eq!(robots, "User-agent: *\nDisallow: /\n");
+
+        // The override wins in both modes.
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        std::fs::write(
+            dir.join("robots.txt"),
+            "User-agent: Bingbot\nDisallow: /docs\n",
+        )
+        .unwrap();
+        for prod in [false, true] {
+            assert_eq!(
+                robots_txt(dir, prod, SITEMAP).unwrap(),
+                "User-agent: Bingbot\nDisallow: /docs\n"
+            );
+        }
+    }
+}
@@ -1,45 +1,257 @@
 use crate::config::Config;
 use crate::document::Document;
+use crate::writer::SiteWriter;
 use serde::Serialize;
+use std::collections::HashSet;
 
 #[derive(Clone, Serialize)]
 struct SitemapEntry {
-    loc: String,              // https://www.ppsspp.org/
-    lastmod: String,          // 2005-01-01
-    changefreq: &'static str, // "monthly"
-    priority: String,         // 0.8
+    loc: String,             // https://www.ppsspp.org/
+    lastmod: Option<String>, // 2005-01-01
+    changefreq: String,      // "monthly"
+    priority: String,        // 0.8
 }
 
+// What a section's pages get unless their front matter says otherwise.
+#[derive(Clone, Copy)]
+pub struct SitemapHints {
+    pub changefreq: &'static str,
+    pub priority: f32,
+}
+
+const HOMEPAGE_HINTS: SitemapHints = SitemapHints {
+    changefreq: "daily",
+    priority: 1.0,
+};
+
+const CHANGEFREQS: &[&str] = &[
+    "always", "hourly", "daily", "weekly", "monthly", "yearly", "never",
+];
+
+// The most a sitemap can list. Bigger sites get several, and an index of them.
+const MAX_URLS: usize = 50_000;
+
 #[derive(Serialize)]
+struct Sitemap<'a> {
+    entries: &'a [SitemapEntry],
+}
+
+#[derive(Serialize)]
+struct SitemapIndex {
+    sitemaps: Vec<String>,
+}
+
 pub struct SitemapGenerator {
     entries: Vec<SitemapEntry>,
+    seen: HashSet<String>,
 }
 
 impl SitemapGenerator {
     pub fn new() -> Self {
-        Self { entries: vec![] }
+        Self {
+            entries: vec![],
+            seen: HashSet::new(),
+        }
     }
-    pub fn add(&mut self, documents: &[Document], priority: f32) {
-        for doc in documents {
+    // A URL added earlier wins over the same one from a later section.
+    pub fn add(&mut self, config: &Config, documents: &[Document], hints: SitemapHints) {
+        for doc in documents.iter().filter(|doc| is_listed(doc)) {
+            let loc = config.site_url(&config.page_url(&doc.meta.url));
+            if !self.seen.insert(loc.clone()) {
+                continue;
+            }
+            let hints = if doc.meta.url == "/" {
+                HOMEPAGE_HINTS
+            } else {
+                hints
+            };
+            let changefreq = match &doc.meta.changefreq {
+                Some(changefreq) if CHANGEFREQS.contains(&changefreq.as_str()) => changefreq,
+                Some(changefreq) => {
+                    config.warn(format!(
+                        "{}: changefreq should be one of {}, not {changefreq}",
+                        doc.source.display(),
+                        CHANGEFREQS.join(", ")
+                    ));
+                    hints.changefreq
+                }
+                None => hints.changefreq,
+            };
+            let priority = match doc.meta.priority {
+                Some(priority) if (0.0..=1.0).contains(&priority) => priority,
+                Some(priority) => {
+                    config.warn(format!(
+                        "{}: priority should be between 0 and 1, not {priority}",
+                        doc.source.display()
+                    ));
+                    hints.priority
+                }
+                None => hints.priority,
+            };
+            let lastmod = doc.meta.updated.as_ref().unwrap_or(&doc.meta.date);
             self.entries.push(SitemapEntry {
-                loc: format!("https://www.ppsspp.org{}", doc.meta.url),
-                lastmod: doc.meta.date.clone(),
-                changefreq: "daily",
+                loc,
+                lastmod: is_date(lastmod).then(|| lastmod.clone()),
+                changefreq: changefreq.to_owned(),
                 priority: format!("{:.2}", priority),
             });
         }
     }
+
+    // Writes sitemap.xml. robots.txt points at it, see gen_robots.
+    #[tracing::instrument(skip_all)]
     pub fn generate(
         &self,
-        _config: &Config,
-        _handlebars: &mut handlebars::Handlebars<'_>,
+        config: &Config,
+        handlebars: &mut handlebars::Handlebars<'_>,
+        writer: &mut dyn SiteWriter,
     ) -> anyhow::Result<()> {
-        //use anyhow::Context;
-        // use std::io::Write;
-        // let xml = handlebars.render("sitemap_xml", &self)?;
-        // let target_path = config.out_dir.join("sitemap.xml");
-        // let mut file = std::fs::File::create(target_path).context("sitemap.xml")?;
-        // file.write_all(xml.as_bytes())?;
+        let files = self.render(handlebars, MAX_URLS, |path| config.site_url(path))?;
+        for (name, xml) in &files {
+            writer.write(&config.out_dir.join(name), xml.as_bytes())?;
+        }
+        println!(
+            "Wrote sitemap with {} URLs in {} files",
+            self.entries.len(),
+            files.len()
+        );
         Ok(())
     }
+
+    // File names and contents. sitemap.xml is the index when there's more than one.
+    fn render(
+        &self,
+        handlebars: &handlebars::Handlebars<'_>,
+        max_urls: usize,
+        site_url: impl Fn(&str) -> String,
+    ) -> anyhow::Result<Vec<(String, String)>> {
+        if self.entries.len() <= max_urls {
+            let sitemap = Sitemap {
+                entries: &self.entries,
+            };
+            let xml = handlebars.render("sitemap_xml", &sitemap)?;
+            return Ok(vec![("sitemap.xml".to_owned(), xml)]);
+        }
+        let mut files = vec![];
+        let mut index = SitemapIndex { sitemaps: vec![] };
+        for (i, entries) in self.entries.chunks(max_urls).enumerate() {
+            let name = format!("sitemap-{}.xml", i + 1);
+            let xml = handlebars.render("sitemap_xml", &Sitemap { entries })?;
+            index.sitemaps.push(site_url(&format!("/{name}")));
+            files.push((name, xml));
+        }
+        let xml = handlebars.render("sitemap_index_xml", &index)?;
+        files.push(("sitemap.xml".to_owned(), xml));
+        Ok(files)
+    }
+}
+
+fn is_date(date: &str) -> bool {
+    chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d").is_ok()
+}
+
+fn is_listed(doc: &Document) -> bool {
+    doc.meta.sitemap != Some(false) && !doc.meta.not_found && !doc.meta.redirect
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str, lastmod: Option<&str>) -> SitemapEntry {
+        SitemapEntry {
+            loc: format!("https://www.ppsspp.org{path}"),
+            lastmod: lastmod.map(str::to_owned),
+            changefreq: "weekly".to_owned(),
+            priority: "0.60".to_owned(),
+        }
+    }
+
+    fn parse_urls(xml: &str) -> Vec<(String, Option<String>)> {
+        let doc = roxmltree::Document::parse(xml).unwrap();
+        assert_eq!(doc.root_element().tag_name().name(), "urlset");
+        let text = |node: roxmltree::Node<'_, '_>, name: &str| {
+            node.children()
+                .find(|child| child.has_tag_name(name))
+                .and_then(|child| child.text())
+                .map(str::to_owned)
+        };
+        doc.root_element()
+            .children()
+            .filter(|node| node.has_tag_name("url"))
+            .map(|url| (text(url, "loc").unwrap(), text(url, "lastmod")))
+            .collect()
+    }
+
+    #[test]
+    fn sitemap_parses_back() {
+        let mut handlebars = handlebars::Handlebars::new();
+        for name in ["sitemap_xml", "sitemap_index_xml"] {
+            handlebars
+                .register_template_file(name, format!("template/{name}.hbs"))
+                .unwrap();
+        }
+        let sitemap = SitemapGenerator {
+            entries: vec![
+                entry("/", None),
+                entry("/blog/a&b", Some("2024-01-02")),
+                entry("/docs/faq", None),
+            ],
+            seen: HashSet::new(),
+        };
+        let site_url = |path: &str| format!("https://www.ppsspp.org{path}");
+
+        let files = sitemap.render(&handlebars, MAX_URLS, site_url).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].0, "sitemap.xml");
+        assert_eq!(
+            parse_urls(&files[0].1),
+            vec![
+                ("https://www.ppsspp.org/".to_owned(), None),
+                (
+                    "https://www.ppsspp.org/blog/a&b".to_owned(),
+                    Some("2024-01-02".to_owned())
+                ),
+                ("https://www.ppsspp.org/docs/faq".to_owned(), None),
+            ]
+        );
+
+        // Split, with an index.
+        let files = sitemap.render(&handlebars, 2, site_url).unwrap();
+        let names = files
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["sitemap-1.xml", "sitemap-2.xml", "sitemap.xml"]);
+        assert_eq!(parse_urls(&files[0].1).len(), 2);
+        assert_eq!(parse_urls(&files[1].1).len(), 1);
+        let index = roxmltree::Document::parse(&files[2].1).unwrap();
+        let locs = index
+            .descendants()
+            .filter(|node| node.has_tag_name("loc"))
+            .filter_map(|node| node.text())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            locs,
+            [
+                "https://www.ppsspp.org/sitemap-1.xml",
+                "https://www.ppsspp.org/sitemap-2.xml"
+            ]
+        );
+    }
+
+    #[test]
+    fn unlisted_pages() {
+        let doc = |url: &str| Document::for_test(url, "");
+        assert!(is_listed(&doc("/docs/print")));
+        let mut page = doc("/404");
+        page.meta.not_found = true;
+        assert!(!is_listed(&page));
+        let mut page = doc("/old");
+        page.meta.redirect = true;
+        assert!(!is_listed(&page));
+        let mut page = doc("/draft");
+        page.meta.sitemap = Some(false);
+        assert!(!is_listed(&page));
+    }
 }
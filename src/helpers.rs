@@ -0,0 +1,928 @@
+use crate::config::{Config, UrlStyle};
+use crate::document::Pagination;
+use crate::error::SiteError;
+use anyhow::Context as _;
+use chrono::{DateTime, NaiveDate};
+use handlebars::{
+    Context, Handlebars, Helper, HelperDef, HelperResult, Output, RenderContext, RenderError,
+    RenderErrorReason, Renderable, ScopedJson,
+};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+
+// Helpers for the templates that don't depend on the build, see register_templates.
+pub fn register(handlebars: &mut Handlebars<'_>) {
+    handlebars.register_helper("format_date", Box::new(format_date));
+    handlebars.register_helper("truncate", Box::new(truncate));
+    handlebars.register_helper("strip_html", Box::new(strip_html));
+    handlebars.register_helper("default", Box::new(DefaultHelper));
+    handlebars.register_helper("is_active", Box::new(IsActive));
+    handlebars.register_helper("slugify", Box::new(slugify));
+    handlebars.register_helper("page_window", Box::new(PageWindow));
+}
+
+// {{#each (page_window pagination 2)}} gives the page links for the pagination partial, see
+// Pagination::window. The number is how many pages on each side of the current one.
+struct PageWindow;
+
+impl HelperDef for PageWindow {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        helper: &Helper<'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'rc>, RenderError> {
+        let pagination = helper
+            .param(0)
+            .ok_or(RenderErrorReason::ParamNotFoundForIndex("page_window", 0))?;
+        let pagination: Pagination = serde_json::from_value(pagination.value().clone())
+            .map_err(|err| RenderErrorReason::Other(format!("page_window: {err}")))?;
+        let radius = helper
+            .param(1)
+            .and_then(|param| param.value().as_u64())
+            .unwrap_or(2);
+        let window = pagination.window(radius as usize);
+        let window = serde_json::to_value(window).map_err(RenderErrorReason::SerdeError)?;
+        Ok(ScopedJson::Derived(window))
+    }
+}
+
+// {{#if (is_active "/blog")}} is true on /blog and the pages under it, going by the url of
+// the page being rendered, also inside {{#each}}.
+struct IsActive;
+
+impl HelperDef for IsActive {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        helper: &Helper<'rc>,
+        _: &'reg Handlebars<'reg>,
+        context: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'rc>, RenderError> {
+        let section = helper
+            .param(0)
+            .and_then(|param| param.value().as_str())
+            .ok_or(RenderErrorReason::ParamNotFoundForIndex("is_active", 0))?;
+        let url = context
+            .data()
+            .get("url")
+            .and_then(|url| url.as_str())
+            .unwrap_or_default();
+        Ok(ScopedJson::Derived(serde_json::Value::Bool(is_under(
+            url, section,
+        ))))
+    }
+}
+
+// Whether url is section, or a page in it: /blog/post is in /blog, /blogging isn't. The root
+// only matches itself.
+pub fn is_under(url: &str, section: &str) -> bool {
+    let section = section.trim_end_matches('/');
+    let url = url.split(['?', '#']).next().unwrap_or_default();
+    if section.is_empty() {
+        return url == "/" || url.is_empty();
+    }
+    url.strip_prefix(section)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+// {{default meta.subtitle "PPSSPP"}} is the value, or the fallback when it's missing, null or
+// empty. The fallback can be left out for an empty string. For optional fields with
+// --strict-templates, which only complains about fields used directly.
+struct DefaultHelper;
+
+impl HelperDef for DefaultHelper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        helper: &Helper<'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'rc>, RenderError> {
+        let value = helper.param(0).map(|param| param.value());
+        let empty = match value {
+            None | Some(serde_json::Value::Null) => true,
+            Some(serde_json::Value::String(text)) => text.is_empty(),
+            Some(_) => false,
+        };
+        let value = if empty {
+            helper.param(1).map_or_else(
+                || serde_json::json!(""),
+                |fallback| fallback.value().clone(),
+            )
+        } else {
+            value.cloned().unwrap_or_default()
+        };
+        Ok(ScopedJson::Derived(value))
+    }
+}
+
+// Helpers that need the site config. Registered again once the static files are copied, so
+// asset_url knows the fingerprinted names.
+pub fn register_site_helpers(handlebars: &mut Handlebars<'_>, config: &Config) {
+    handlebars.register_helper("markdown", markdown_helper(&config.markdown_options));
+    handlebars.register_helper(
+        "t",
+        Box::new(crate::i18n::TranslateHelper(config.translations.clone())),
+    );
+    let urls = SiteUrls {
+        url_base: config.url_base.clone(),
+        path_prefix: config.path_prefix.clone(),
+        url_style: config.url_style,
+        assets: config.assets.borrow().clone(),
+    };
+    // {{asset "/static/..."}} came first with fingerprinting, and is the same as asset_url.
+    for name in ["asset", "asset_url", "abs_url"] {
+        handlebars.register_helper(
+            name,
+            Box::new(UrlHelper {
+                urls: urls.clone(),
+                name,
+            }),
+        );
+    }
+    let prod = config.global_meta.prod;
+    handlebars.register_helper("if_prod", Box::new(BuildIf(prod)));
+    handlebars.register_helper("if_dev", Box::new(BuildIf(!prod)));
+    handlebars.register_helper(
+        "load_data",
+        Box::new(LoadData {
+            in_dir: config.in_dir.clone(),
+            cache: Default::default(),
+        }),
+    );
+    handlebars.register_helper(
+        "include_raw",
+        Box::new(IncludeRaw {
+            in_dir: config.in_dir.clone(),
+            cache: Default::default(),
+        }),
+    );
+}
+
+// A file for the helpers that read them, from a path relative to the site folder. Paths
+// that would leave it, also through symlinks, are refused.
+fn site_file(in_dir: &Path, path: &str) -> anyhow::Result<PathBuf> {
+    let relative = Path::new(path);
+    let outside = || anyhow::anyhow!("only paths inside the site folder can be read");
+    if !relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+    {
+        return Err(outside());
+    }
+    let path = in_dir.join(relative);
+    let path = path
+        .canonicalize()
+        .map_err(|source| SiteError::Io { path, source })?;
+    if !path.starts_with(in_dir.canonicalize()?) {
+        return Err(outside());
+    }
+    Ok(path)
+}
+
+// {{#if_prod}}...{{else}}...{{/if_prod}}, and if_dev, for analytics and the livereload script,
+// going by --prod rather than the data being rendered.
+struct BuildIf(bool);
+
+impl HelperDef for BuildIf {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        helper: &Helper<'rc>,
+        handlebars: &'reg Handlebars<'reg>,
+        context: &'rc Context,
+        render_context: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        let template = if self.0 {
+            helper.template()
+        } else {
+            helper.inverse()
+        };
+        match template {
+            Some(template) => template.render(handlebars, context, render_context, out),
+            None => Ok(()),
+        }
+    }
+}
+
+// {{#with (load_data "data/team.json")}} gives a JSON file's contents, or a TOML file's, for
+// small tables that don't need their own struct. Paths are relative to the site folder and
+// can't leave it. Each file is parsed once per build.
+struct LoadData {
+    in_dir: PathBuf,
+    cache: Mutex<HashMap<PathBuf, serde_json::Value>>,
+}
+
+impl LoadData {
+    fn load(&self, path: &str) -> anyhow::Result<serde_json::Value> {
+        let path = site_file(&self.in_dir, path)?;
+        if let Some(value) = self.cache.lock().unwrap().get(&path) {
+            return Ok(value.clone());
+        }
+        let text = std::fs::read_to_string(&path)?;
+        let value = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => serde_json::from_str(&text)?,
+            Some("toml") => toml::from_str::<serde_json::Value>(&text)?,
+            _ => anyhow::bail!("only .json and .toml files can be loaded"),
+        };
+        self.cache.lock().unwrap().insert(path, value.clone());
+        Ok(value)
+    }
+}
+
+impl HelperDef for LoadData {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        helper: &Helper<'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'rc>, RenderError> {
+        let path = helper
+            .param(0)
+            .and_then(|param| param.value().as_str())
+            .ok_or(RenderErrorReason::ParamNotFoundForIndex("load_data", 0))?;
+        let value = self
+            .load(path)
+            .map_err(|err| RenderErrorReason::Other(format!("load_data {path}: {err:#}")))?;
+        Ok(ScopedJson::Derived(value))
+    }
+}
+
+// {{include_raw "static/css/critical.css"}} inlines a small file as it is, unescaped, like
+// critical CSS or a snippet of JS in the head. minify=true runs CSS and JS through the --minify
+// minifier first. A missing file fails the build instead of skipping the pages, since it's
+// usually in a partial they all use. Each file is read once per build, and changes to it
+// rebuild in watch mode.
+struct IncludeRaw {
+    in_dir: PathBuf,
+    // By path and whether it's minified.
+    cache: Mutex<HashMap<(PathBuf, bool), String>>,
+}
+
+impl IncludeRaw {
+    fn read(&self, path: &str, minify: bool) -> anyhow::Result<String> {
+        let key = (site_file(&self.in_dir, path)?, minify);
+        if let Some(text) = self.cache.lock().unwrap().get(&key) {
+            return Ok(text.clone());
+        }
+        let path = &key.0;
+        let mut data = std::fs::read(path).map_err(|source| SiteError::Io {
+            path: path.clone(),
+            source,
+        })?;
+        if minify {
+            if let Some(minified) = crate::minify::minify_asset(path, &data, true)? {
+                data = minified;
+            }
+        }
+        let text = String::from_utf8(data).context("not UTF-8")?;
+        self.cache.lock().unwrap().insert(key, text.clone());
+        Ok(text)
+    }
+}
+
+impl HelperDef for IncludeRaw {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        helper: &Helper<'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        let path = helper
+            .param(0)
+            .and_then(|param| param.value().as_str())
+            .ok_or(RenderErrorReason::ParamNotFoundForIndex("include_raw", 0))?;
+        let minify = helper
+            .hash_get("minify")
+            .is_some_and(|minify| minify.value().as_bool() == Some(true));
+        let text = self
+            .read(path, minify)
+            .map_err(|err| match err.downcast() {
+                Ok(err @ SiteError::Io { .. }) => RenderErrorReason::NestedError(Box::new(err)),
+                Ok(err) => RenderErrorReason::Other(format!("include_raw {path}: {err}")),
+                Err(err) => RenderErrorReason::Other(format!("include_raw {path}: {err:#}")),
+            })?;
+        out.write(&text)?;
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+struct SiteUrls {
+    url_base: String,
+    path_prefix: String,
+    url_style: UrlStyle,
+    // The fingerprint manifest, empty without --fingerprint.
+    assets: BTreeMap<String, String>,
+}
+
+impl SiteUrls {
+    // "css/main.css" is in /static/, "/static/css/main.css" works too. Gets the path prefix,
+    // so it works anywhere in a page, not just in links. prefix_root_links leaves it alone.
+    fn asset_url(&self, path: &str) -> String {
+        if path.contains("://") || path.starts_with("//") {
+            return path.to_owned();
+        }
+        let path = if path.starts_with('/') {
+            path.to_owned()
+        } else {
+            format!("/static/{path}")
+        };
+        let path = self.assets.get(&path).unwrap_or(&path);
+        format!("{}{path}", self.path_prefix)
+    }
+
+    // For site paths like meta.url, in feeds and og: tags, and what asset_url gives. Pages
+    // get the URL style.
+    fn abs_url(&self, url: &str) -> String {
+        if url.contains("://") {
+            return url.to_owned();
+        }
+        if crate::config::is_prefixed_asset(&self.path_prefix, url) {
+            return format!("{}{url}", self.url_base);
+        }
+        let url = if url.starts_with('/') {
+            url.to_owned()
+        } else {
+            format!("/{url}")
+        };
+        let url = crate::config::page_url(self.url_style, &url);
+        format!("{}{}{url}", self.url_base, self.path_prefix)
+    }
+}
+
+// {{asset_url "css/main.css"}} and {{abs_url meta.url}}. They return the URL rather than
+// writing it, so they work as subexpressions too.
+struct UrlHelper {
+    urls: SiteUrls,
+    name: &'static str,
+}
+
+impl HelperDef for UrlHelper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        helper: &Helper<'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'rc>, RenderError> {
+        let url = helper
+            .param(0)
+            .and_then(|param| param.value().as_str())
+            .ok_or(RenderErrorReason::ParamNotFoundForIndex(self.name, 0))?;
+        let url = if self.name == "abs_url" {
+            self.urls.abs_url(url)
+        } else {
+            self.urls.asset_url(url)
+        };
+        Ok(ScopedJson::Derived(serde_json::Value::String(url)))
+    }
+}
+
+// {{truncate text 120}} shortens text to at most that many characters, ending in an ellipsis.
+// With strip_html, {{truncate (strip_html contents) 160}} gives a plain text excerpt. Both
+// return text, so {{ }} escapes it as usual.
+handlebars::handlebars_helper!(truncate: |text: Json, max: u64| {
+    truncate_text(text.as_str().unwrap_or_default(), max as usize)
+});
+handlebars::handlebars_helper!(strip_html: |html: Json| {
+    strip_tags(html.as_str().unwrap_or_default())
+});
+// {{slugify tag}}, the same as the Rust side, for building links to tag pages and anchors.
+handlebars::handlebars_helper!(slugify: |text: Json| {
+    crate::util::slugify(text.as_str().unwrap_or_default())
+});
+
+const ELLIPSIS: char = '\u{2026}';
+
+static ENTITY: std::sync::LazyLock<regex::Regex> = std::sync::LazyLock::new(|| {
+    regex::Regex::new(r"&(#[0-9]+|#[xX][0-9a-fA-F]+|[a-zA-Z]+);").unwrap()
+});
+
+// Cuts at a word boundary when there's one in the second half, never inside a character or an
+// entity like &amp;.
+pub fn truncate_text(text: &str, max: usize) -> String {
+    if text.chars().count() <= max {
+        return text.to_owned();
+    }
+    let Some(keep) = max.checked_sub(1) else {
+        return String::new();
+    };
+    let mut end = text.char_indices().nth(keep).map_or(text.len(), |(i, _)| i);
+    if let Some(entity) = ENTITY
+        .find_iter(text)
+        .find(|entity| entity.start() < end && end < entity.end())
+    {
+        end = entity.start();
+    }
+    let at_word_end = text[end..].starts_with(char::is_whitespace);
+    if !at_word_end {
+        if let Some(space) = text[..end].rfind(char::is_whitespace) {
+            if text[..space].chars().count() >= keep / 2 {
+                end = space;
+            }
+        }
+    }
+    let cut = text[..end].trim_end_matches(|c: char| c.is_whitespace() || ",:".contains(c));
+    format!("{cut}{ELLIPSIS}")
+}
+
+static TAG: std::sync::LazyLock<regex::Regex> = std::sync::LazyLock::new(|| {
+    regex::Regex::new(r"(?is)<(script|style)\b.*?</(script|style)\s*>|<!--.*?-->|<[^>]*>").unwrap()
+});
+
+// The text of some HTML, with entities decoded and whitespace collapsed.
+pub fn strip_tags(html: &str) -> String {
+    let text = TAG.replace_all(html, " ");
+    let text = ENTITY.replace_all(&text, |captures: &regex::Captures<'_>| {
+        decode_entity(&captures[1]).map_or_else(|| captures[0].to_owned(), String::from)
+    });
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn decode_entity(entity: &str) -> Option<char> {
+    if let Some(number) = entity.strip_prefix('#') {
+        let code = match number.strip_prefix(['x', 'X']) {
+            Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+            None => number.parse().ok()?,
+        };
+        return char::from_u32(code);
+    }
+    Some(match entity {
+        "amp" => '&',
+        "lt" => '<',
+        "gt" => '>',
+        "quot" => '"',
+        "apos" => '\'',
+        "nbsp" => ' ',
+        "hellip" => ELLIPSIS,
+        "mdash" => '\u{2014}',
+        "ndash" => '\u{2013}',
+        "copy" => '\u{a9}',
+        _ => return None,
+    })
+}
+
+// {{{markdown meta.description}}} renders a string from data or front matter as markdown,
+// with the site's options but without raw HTML or dangerous link protocols. With inline=true,
+// a lone paragraph is unwrapped, for text that goes inside another element. Missing and empty
+// values render nothing.
+fn markdown_helper(options: &markdown::Options) -> Box<dyn handlebars::HelperDef + Send + Sync> {
+    // Options holds the MDX parser callbacks, which aren't Clone or Sync, and we don't use.
+    let constructs = options.parse.constructs.clone();
+    let gfm_strikethrough_single_tilde = options.parse.gfm_strikethrough_single_tilde;
+    let math_text_single_dollar = options.parse.math_text_single_dollar;
+    let compile = markdown::CompileOptions {
+        allow_dangerous_html: false,
+        allow_dangerous_protocol: false,
+        ..options.compile.clone()
+    };
+    Box::new(
+        move |helper: &Helper<'_>,
+              _: &Handlebars<'_>,
+              _: &Context,
+              _: &mut RenderContext<'_, '_>,
+              out: &mut dyn Output|
+              -> HelperResult {
+            let text = helper
+                .param(0)
+                .and_then(|param| param.value().as_str())
+                .unwrap_or_default();
+            if text.trim().is_empty() {
+                return Ok(());
+            }
+            let options = markdown::Options {
+                parse: markdown::ParseOptions {
+                    constructs: constructs.clone(),
+                    gfm_strikethrough_single_tilde,
+                    math_text_single_dollar,
+                    ..markdown::ParseOptions::default()
+                },
+                compile: compile.clone(),
+            };
+            let html = markdown::to_html_with_options(text, &options)
+                .map_err(|err| RenderErrorReason::Other(format!("markdown: {err}")))?;
+            let inline = helper
+                .hash_get("inline")
+                .is_some_and(|value| value.value().as_bool() == Some(true));
+            let html = html.trim_end();
+            let paragraph = html
+                .strip_prefix("<p>")
+                .and_then(|html| html.strip_suffix("</p>"))
+                .filter(|inner| !inner.contains("<p>"));
+            match paragraph {
+                Some(inner) if inline => out.write(inner)?,
+                _ => out.write(html)?,
+            }
+            Ok(())
+        },
+    )
+}
+
+const DEFAULT_DATE_FORMAT: &str = "%B %-d, %Y";
+
+// {{format_date meta.date "%B %-d, %Y"}} formats an ISO date, or date and time, with chrono's
+// strftime, where %-d is the day without padding. The Windows spelling %#d works too. With
+// time=true, it's wrapped in <time datetime="...">. Dates that don't parse are written as is,
+// with a warning, so a typo in front matter doesn't take the page down.
+fn format_date(
+    helper: &Helper<'_>,
+    _: &Handlebars<'_>,
+    _: &Context,
+    _: &mut RenderContext<'_, '_>,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let input = helper
+        .param(0)
+        .ok_or(RenderErrorReason::ParamNotFoundForIndex("format_date", 0))?
+        .value()
+        .as_str()
+        .unwrap_or_default();
+    let format = match helper.param(1) {
+        Some(param) => param.value().as_str().ok_or_else(|| {
+            RenderErrorReason::ParamTypeMismatchForName(
+                "format_date",
+                "1".to_owned(),
+                "string".to_owned(),
+            )
+        })?,
+        None => DEFAULT_DATE_FORMAT,
+    }
+    .replace("%#", "%-");
+    let time = helper
+        .hash_get("time")
+        .is_some_and(|value| value.value().as_bool() == Some(true));
+
+    let mut formatted = String::new();
+    let written = if let Ok(date) = NaiveDate::parse_from_str(input, "%Y-%m-%d") {
+        write!(formatted, "{}", date.format(&format))
+    } else if let Ok(datetime) = DateTime::parse_from_rfc3339(input) {
+        write!(formatted, "{}", datetime.format(&format))
+    } else {
+        println!("Warning: format_date: {input:?} isn't an ISO date, writing it as is");
+        out.write(&handlebars::html_escape(input))?;
+        return Ok(());
+    };
+    // chrono reports bad specifiers through fmt::Error.
+    written.map_err(|_| {
+        RenderErrorReason::Other(format!("format_date: bad date format {format:?}"))
+    })?;
+
+    let formatted = handlebars::html_escape(&formatted);
+    if time {
+        out.write(&format!(
+            r#"<time datetime="{}">{formatted}</time>"#,
+            handlebars::html_escape(input)
+        ))?;
+    } else {
+        out.write(&formatted)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(template: &str, date: &str) -> String {
+        let mut handlebars = Handlebars::new();
+        register(&mut handlebars);
+        handlebars
+            .render_template(template, &serde_json::json!({ "date": date }))
+            .unwrap()
+    }
+
+    #[test]
+    fn format_date_formats() {
+        assert_eq!(
+            render("{{format_date date}}", "2024-03-01"),
+            "March 1, 2024"
+        );
+        assert_eq!(
+            render(r#"{{format_date date "%B %-d, %Y"}}"#, "2024-03-01"),
+            "March 1, 2024"
+        );
+        assert_eq!(
+            render(r#"{{format_date date "%#d %b %Y"}}"#, "2024-03-01"),
+            "1 Mar 2024"
+        );
+        assert_eq!(
+            render(r#"{{format_date date "%d/%m/%Y"}}"#, "2024-03-01"),
+            "01/03/2024"
+        );
+        assert_eq!(
+            render(
+                r#"{{format_date date "%Y-%m-%d %H:%M"}}"#,
+                "2024-03-01T18:30:00Z"
+            ),
+            "2024-03-01 18:30"
+        );
+        assert_eq!(
+            render(r#"{{format_date date "%b %-d" time=true}}"#, "2024-03-01"),
+            r#"<time datetime="2024-03-01">Mar 1</time>"#
+        );
+        // Written as is.
+        assert_eq!(render(r#"{{format_date date "%Y"}}"#, "soon<"), "soon&lt;");
+    }
+
+    #[test]
+    fn markdown_renders_fields() {
+        let mut options = markdown::Options::gfm();
+        options.compile.allow_dangerous_html = true;
+        let mut handlebars = Handlebars::new();
+        handlebars.register_helper("markdown", markdown_helper(&options));
+        let render = |template: &str, data: serde_json::Value| {
+            handlebars.render_template(template, &data).unwrap()
+        };
+        let data = serde_json::json!({
+            "whats_this": "Get it on [the Play Store](https://play.google.com/), **free**. <b>x</b>",
+            "empty": "",
+        });
+        assert_eq!(
+            render("{{{markdown whats_this}}}", data.clone()),
+            "<p>Get it on <a href=\"https://play.google.com/\">the Play Store</a>, \
+             <strong>free</strong>. &lt;b&gt;x&lt;/b&gt;</p>"
+        );
+        assert_eq!(
+            render(
+                "<span>{{{markdown whats_this inline=true}}}</span>",
+                data.clone()
+            ),
+            "<span>Get it on <a href=\"https://play.google.com/\">the Play Store</a>, \
+             <strong>free</strong>. &lt;b&gt;x&lt;/b&gt;</span>"
+        );
+        assert_eq!(render("[{{{markdown empty}}}]", data.clone()), "[]");
+        assert_eq!(render("[{{{markdown missing}}}]", data), "[]");
+    }
+
+    #[test]
+    fn truncate_and_strip_html() {
+        let mut handlebars = Handlebars::new();
+        register(&mut handlebars);
+        let data = serde_json::json!({
+            "contents": "<p>PPSSPP <b>1.17</b> is out &amp; it&#39;s faster.</p>\n<script>x()</script>",
+        });
+        let render = |template: &str| handlebars.render_template(template, &data).unwrap();
+        assert_eq!(
+            render("{{strip_html contents}}"),
+            "PPSSPP 1.17 is out &amp; it&#x27;s faster."
+        );
+        assert_eq!(
+            render("{{truncate (strip_html contents) 19}}"),
+            "PPSSPP 1.17 is out\u{2026}"
+        );
+        assert_eq!(render("{{truncate missing 10}}"), "");
+        assert_eq!(truncate_text("Fast &amp; small", 8), "Fast\u{2026}");
+        assert_eq!(truncate_text("Fast&amp;small", 7), "Fast\u{2026}");
+        assert_eq!(truncate_text("Supercalifragilistic", 6), "Super\u{2026}");
+        assert_eq!(truncate_text("short", 5), "short");
+    }
+
+    #[test]
+    fn truncate_never_splits() {
+        let texts = [
+            "Emoji 🎮🕹️ everywhere 👾 in this 🎉 sentence",
+            "日本語のテキストは単語の間に空白がありません。とても長い文です。",
+            "Mixed 中文 and English with &amp; entities &#x1F3AE; and &nbsp; more",
+            "🎮🎮🎮🎮🎮🎮🎮🎮🎮🎮",
+            "a&amp;b&lt;c&gt;d&#39;e",
+        ];
+        for text in texts {
+            let length = text.chars().count();
+            for max in 0..length + 2 {
+                let truncated = truncate_text(text, max);
+                if max >= length {
+                    assert_eq!(truncated, text);
+                    continue;
+                }
+                assert!(truncated.chars().count() <= max);
+                let kept = truncated.trim_end_matches(ELLIPSIS);
+                assert!(text.starts_with(kept), "{text:?} {max}: {truncated:?}");
+                // No entity cut in half.
+                if let Some(amp) = kept.rfind('&') {
+                    let split = ENTITY.find(&text[amp..]).is_some_and(|entity| {
+                        entity.start() == 0 && entity.end() > kept.len() - amp
+                    });
+                    assert!(!split, "{text:?} {max}: {truncated:?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn url_helpers() {
+        let render = |url_base: &str, path_prefix: &str, url_style, template: &str| {
+            let urls = SiteUrls {
+                url_base: url_base.to_owned(),
+                path_prefix: path_prefix.to_owned(),
+                url_style,
+                assets: BTreeMap::from([(
+                    "/static/css/main.css".to_owned(),
+                    "/static/css/main.0123456789.css".to_owned(),
+                )]),
+            };
+            let mut handlebars = Handlebars::new();
+            for name in ["asset", "asset_url", "abs_url"] {
+                handlebars.register_helper(
+                    name,
+                    Box::new(UrlHelper {
+                        urls: urls.clone(),
+                        name,
+                    }),
+                );
+            }
+            let data = serde_json::json!({ "meta": { "url": "/blog/new-release" } });
+            handlebars.render_template(template, &data).unwrap()
+        };
+        let template = r#"{{asset_url "css/main.css"}} {{asset "/static/img/logo.png"}} {{abs_url meta.url}} {{abs_url "/"}} {{abs_url (asset_url "img/logo.png")}}"#;
+        assert_eq!(
+            render("https://www.ppsspp.org", "", UrlStyle::Folders, template),
+            "/static/css/main.0123456789.css /static/img/logo.png \
+             https://www.ppsspp.org/blog/new-release https://www.ppsspp.org/ \
+             https://www.ppsspp.org/static/img/logo.png"
+        );
+        assert_eq!(
+            render(
+                "https://example.github.io",
+                "/ppsspp",
+                UrlStyle::Folders,
+                template
+            ),
+            "/ppsspp/static/css/main.0123456789.css /ppsspp/static/img/logo.png \
+             https://example.github.io/ppsspp/blog/new-release https://example.github.io/ppsspp/ \
+             https://example.github.io/ppsspp/static/img/logo.png"
+        );
+        assert_eq!(
+            render(
+                "http://localhost:3000",
+                "/preview/pr-12",
+                UrlStyle::HtmlFiles,
+                r#"{{abs_url meta.url}} {{abs_url "blog"}} {{abs_url "/"}} {{abs_url (asset_url "css/main.css")}} {{abs_url "https://github.com/"}}"#
+            ),
+            "http://localhost:3000/preview/pr-12/blog/new-release.html \
+             http://localhost:3000/preview/pr-12/blog.html http://localhost:3000/preview/pr-12/ \
+             http://localhost:3000/preview/pr-12/static/css/main.0123456789.css https://github.com/"
+        );
+    }
+
+    #[test]
+    fn default_fills_in_missing_fields() {
+        let mut handlebars = Handlebars::new();
+        handlebars.set_strict_mode(true);
+        register(&mut handlebars);
+        let data = serde_json::json!({ "title": "PPSSPP", "empty": "", "none": null, "count": 0 });
+        let render = |template: &str| handlebars.render_template(template, &data);
+        assert!(render("{{subtitle}}").is_err());
+        assert_eq!(render("[{{default subtitle}}]").unwrap(), "[]");
+        assert_eq!(
+            render(r#"{{default subtitle "Emulator"}}"#).unwrap(),
+            "Emulator"
+        );
+        assert_eq!(
+            render(r#"{{default none "-"}}{{default empty "-"}}"#).unwrap(),
+            "--"
+        );
+        assert_eq!(
+            render(r#"{{default title "-"}} {{default count 1}}"#).unwrap(),
+            "PPSSPP 0"
+        );
+    }
+
+    #[test]
+    fn is_under_stops_at_segments() {
+        assert!(is_under("/blog", "/blog"));
+        assert!(is_under("/blog/new-release", "/blog"));
+        assert!(is_under("/blog/", "/blog/"));
+        assert!(is_under("/blog#comments", "/blog"));
+        assert!(!is_under("/blogging", "/blog"));
+        assert!(!is_under("/docs/blog", "/blog"));
+        assert!(is_under("/", "/"));
+        assert!(!is_under("/download", "/"));
+        assert!(!is_under("/forums", "https://forums.ppsspp.org/"));
+    }
+
+    #[test]
+    fn load_data_reads_files() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        std::fs::create_dir_all(dir.join("data")).unwrap();
+        std::fs::write(
+            dir.join("data/team.json"),
+            r#"[{"name": "Henrik"}, {"name": "Unknown W. Brackets"}]"#,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("data/sponsors.toml"),
+            "[gold]\nname = \"Big Co\"\nurl = 'https://example.com'\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("data/broken.json"), "[{").unwrap();
+        let mut handlebars = Handlebars::new();
+        handlebars.register_helper(
+            "load_data",
+            Box::new(LoadData {
+                in_dir: dir.to_path_buf(),
+                cache: Default::default(),
+            }),
+        );
+        let render = |template: &str| handlebars.render_template(template, &());
+        assert_eq!(
+            render(r#"{{#each (load_data "data/team.json")}}{{name}};{{/each}}"#).unwrap(),
+            "Henrik;Unknown W. Brackets;"
+        );
+        assert_eq!(
+            render(
+                r#"{{#with (load_data "data/sponsors.toml")}}{{gold.name}} {{gold.url}}{{/with}}"#
+            )
+            .unwrap(),
+            "Big Co https://example.com"
+        );
+        let err = render(r#"{{#with (load_data "data/broken.json")}}{{/with}}"#).unwrap_err();
+        assert!(err.to_string().contains("data/broken.json"), "{err}");
+        assert!(render(r#"{{load_data "../secrets.json"}}"#).is_err());
+        #[cfg(unix)]
+        {
+            let outside = tempfile::tempdir().unwrap();
+            std::fs::write(outside.path().join("secrets.json"), "{}").unwrap();
+            std::os::unix::fs::symlink(outside.path(), dir.join("data/linked")).unwrap();
+            assert!(render(r#"{{load_data "data/linked/secrets.json"}}"#).is_err());
+        }
+    }
+
+    #[test]
+    fn include_raw_inlines_files() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        std::fs::create_dir_all(dir.join("static/css")).unwrap();
+        let css = "/* Above the fold */\nbody {\n    color: #eee;\n}\n";
+        std::fs::write(dir.join("static/css/critical.css"), css).unwrap();
+        let mut handlebars = Handlebars::new();
+        handlebars.register_helper(
+            "include_raw",
+            Box::new(IncludeRaw {
+                in_dir: dir.to_path_buf(),
+                cache: Default::default(),
+            }),
+        );
+        let render = |template: &str| handlebars.render_template(template, &());
+        assert_eq!(
+            render(r#"<style>{{include_raw "static/css/critical.css"}}</style>"#).unwrap(),
+            format!("<style>{css}</style>")
+        );
+        assert_eq!(
+            render(r#"{{include_raw "static/css/critical.css" minify=true}}"#).unwrap(),
+            "body{color:#eee}"
+        );
+        // Read once per build.
+        std::fs::write(dir.join("static/css/critical.css"), "changed").unwrap();
+        assert_eq!(
+            render(r#"{{include_raw "static/css/critical.css"}}"#).unwrap(),
+            css
+        );
+        let err = render(r#"{{include_raw "static/css/gone.css"}}"#).unwrap_err();
+        assert!(matches!(
+            err.reason(),
+            RenderErrorReason::NestedError(cause) if cause.downcast_ref::<SiteError>().is_some()
+        ));
+        assert!(render(r#"{{include_raw "../secrets.txt"}}"#).is_err());
+    }
+
+    #[test]
+    fn build_conditionals() {
+        for prod in [true, false] {
+            let mut handlebars = Handlebars::new();
+            handlebars.register_helper("if_prod", Box::new(BuildIf(prod)));
+            handlebars.register_helper("if_dev", Box::new(BuildIf(!prod)));
+            let html = handlebars
+                .render_template(
+                    "{{#if_prod}}analytics{{else}}test store{{/if_prod}} {{#if_dev}}{{title}}{{/if_dev}}",
+                    &serde_json::json!({ "title": "DEVELOPMENT MODE" }),
+                )
+                .unwrap();
+            let expected = if prod {
+                "analytics "
+            } else {
+                "test store DEVELOPMENT MODE"
+            };
+            assert_eq!(html, expected);
+        }
+    }
+
+    #[test]
+    fn format_date_rejects_bad_formats() {
+        let mut handlebars = Handlebars::new();
+        register(&mut handlebars);
+        let result = handlebars.render_template(
+            r#"{{format_date date "%Q"}}"#,
+            &serde_json::json!({ "date": "2024-03-01" }),
+        );
+        assert!(result.is_err());
+    }
+}
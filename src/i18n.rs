@@ -0,0 +1,194 @@
+use crate::config::Config;
+use anyhow::Context;
+use handlebars::{
+    Handlebars, Helper, HelperDef, RenderContext, RenderError, RenderErrorReason, ScopedJson,
+};
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+pub const DIR: &str = "i18n";
+// Every string should be here, other languages fall back to it.
+pub const DEFAULT_LANG: &str = "en";
+
+// The UI strings of the templates, from i18n/<lang>.toml, for the {{t}} helper.
+pub struct Translations {
+    lang: String,
+    // Language -> key -> string. Keys are dotted, like "downloads.button" for button in the
+    // [downloads] table.
+    strings: HashMap<String, HashMap<String, String>>,
+    // Keys that fell back to the key itself, reported once at the end of the build.
+    missing: Mutex<BTreeSet<String>>,
+}
+
+impl Translations {
+    // Reads every locale file in dir. There don't have to be any, but the active language
+    // needs one.
+    pub fn load(dir: &Path, lang: &str) -> anyhow::Result<Translations> {
+        let mut strings = HashMap::new();
+        if dir.is_dir() {
+            for entry in std::fs::read_dir(dir)? {
+                let path = entry?.path();
+                if path.extension().is_some_and(|ext| ext == "toml") {
+                    let text = std::fs::read_to_string(&path)?;
+                    let table = parse_strings(&text).with_context(|| path.display().to_string())?;
+                    let name = path.file_stem().unwrap_or_default().to_string_lossy();
+                    strings.insert(name.into_owned(), table);
+                }
+            }
+        }
+        anyhow::ensure!(
+            lang == DEFAULT_LANG || strings.contains_key(lang),
+            "No {}/{lang}.toml for the language",
+            dir.display()
+        );
+        Ok(Translations {
+            lang: lang.to_owned(),
+            strings,
+            missing: Default::default(),
+        })
+    }
+
+    // The string for key in the active language, or English, with {name} placeholders filled
+    // in from args. Unknown placeholders are left as they are.
+    pub fn translate(&self, key: &str, args: &[(&str, String)]) -> String {
+        let found = [self.lang.as_str(), DEFAULT_LANG]
+            .iter()
+            .find_map(|lang| self.strings.get(*lang)?.get(key));
+        let Some(text) = found else {
+            self.missing.lock().unwrap().insert(key.to_owned());
+            return key.to_owned();
+        };
+        let mut text = text.clone();
+        for (name, value) in args {
+            text = text.replace(&format!("{{{name}}}"), value);
+        }
+        text
+    }
+
+    // One warning for all the keys that weren't found.
+    pub fn report(&self, config: &Config) {
+        let missing = std::mem::take(&mut *self.missing.lock().unwrap());
+        if missing.is_empty() {
+            return;
+        }
+        let mut files = format!("{DIR}/{DEFAULT_LANG}.toml");
+        if self.lang != DEFAULT_LANG {
+            files = format!("{DIR}/{}.toml and {files}", self.lang);
+        }
+        config.warn(format!(
+            "{} UI strings missing from {files}: {}",
+            missing.len(),
+            missing.into_iter().collect::<Vec<_>>().join(", ")
+        ));
+    }
+}
+
+// {{t "downloads.button"}}, or {{t "blog.posted_on" date=meta.date}} for a string with a
+// {date} placeholder. Returns the string, so {{ }} escapes it.
+pub struct TranslateHelper(pub Arc<Translations>);
+
+impl HelperDef for TranslateHelper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        helper: &Helper<'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc handlebars::Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'rc>, RenderError> {
+        let key = helper
+            .param(0)
+            .and_then(|param| param.value().as_str())
+            .ok_or(RenderErrorReason::ParamNotFoundForIndex("t", 0))?;
+        let args = helper
+            .hash()
+            .iter()
+            .map(|(name, value)| {
+                let value = match value.value() {
+                    serde_json::Value::String(text) => text.clone(),
+                    serde_json::Value::Null => String::new(),
+                    other => other.to_string(),
+                };
+                (*name, value)
+            })
+            .collect::<Vec<_>>();
+        Ok(ScopedJson::Derived(serde_json::Value::String(
+            self.0.translate(key, &args),
+        )))
+    }
+}
+
+// Locale files are tables of strings. Nested tables become dotted keys, so "button" in
+// [downloads] is "downloads.button".
+pub fn parse_strings(text: &str) -> anyhow::Result<HashMap<String, String>> {
+    let table: HashMap<String, toml::Value> = toml::from_str(text)?;
+    let mut strings = HashMap::new();
+    flatten("", table, &mut strings)?;
+    Ok(strings)
+}
+
+fn flatten(
+    prefix: &str,
+    table: impl IntoIterator<Item = (String, toml::Value)>,
+    strings: &mut HashMap<String, String>,
+) -> anyhow::Result<()> {
+    for (key, value) in table {
+        let key = format!("{prefix}{key}");
+        match value {
+            toml::Value::String(text) => {
+                strings.insert(key, text);
+            }
+            toml::Value::Table(table) => flatten(&format!("{key}."), table, strings)?,
+            other => anyhow::bail!(
+                "{key} is a {}, only strings are supported",
+                other.type_str()
+            ),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn translations_fall_back() {
+        let mut strings = HashMap::new();
+        strings.insert(
+            "en".to_owned(),
+            parse_strings(
+                r#"
+# UI strings
+title = "PPSSPP"
+[downloads]
+button = "Download"   # the big one
+previous = 'Previous releases'
+[blog]
+posted_on = "Posted on {date} by \"{author}\""
+"#,
+            )
+            .unwrap(),
+        );
+        strings.insert(
+            "sv".to_owned(),
+            parse_strings("[downloads]\nbutton = \"Ladda ner\"\n").unwrap(),
+        );
+        let translations = Translations {
+            lang: "sv".to_owned(),
+            strings,
+            missing: Default::default(),
+        };
+        let mut handlebars = Handlebars::new();
+        handlebars.register_helper("t", Box::new(TranslateHelper(Arc::new(translations))));
+        let data = serde_json::json!({ "meta": { "date": "2024-03-01" } });
+        let render = |template: &str| handlebars.render_template(template, &data).unwrap();
+        assert_eq!(render(r#"{{t "downloads.button"}}"#), "Ladda ner");
+        assert_eq!(render(r#"{{t "downloads.previous"}}"#), "Previous releases");
+        assert_eq!(
+            render(r#"{{t "blog.posted_on" date=meta.date author="<Henrik>"}}"#),
+            "Posted on 2024-03-01 by &quot;&lt;Henrik&gt;&quot;"
+        );
+        assert_eq!(render(r#"{{t "blog.read_more"}}"#), "blog.read_more");
+    }
+}
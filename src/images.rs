@@ -0,0 +1,428 @@
+use crate::config::Config;
+use crate::fingerprint;
+use crate::stats::MinifyStats;
+use crate::writer::SiteWriter;
+use anyhow::Context;
+use image::ImageFormat;
+use rayon::prelude::*;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+// Resized and converted images by content hash and width, so rebuilds only encode new images.
+// An empty file means the WebP version came out bigger.
+const CACHE_DIR: &str = ".cache/images";
+
+// The galleries' scripts show these, rather than pages, so they're processed up front. Other
+// images are when a page shows them, see rewrite_img_tags.
+const SCREENSHOTS_DIR: &str = "static/img/screenshots";
+
+// GIFs may be animated, and SVGs scale by themselves, so they're left alone.
+const EXTENSIONS: &[&str] = &["png", "jpg", "jpeg"];
+
+// Smaller copies and WebP versions of an image in static/, for srcset and <picture>.
+#[derive(Debug, Clone)]
+pub struct ImageVariants {
+    // Of the original.
+    pub width: u32,
+    // Width and site path of each copy, smallest first.
+    pub variants: Vec<(u32, String)>,
+    // Width and site path of the WebP version of the original and of each copy, for the ones
+    // that came out smaller.
+    pub webp: Vec<(u32, String)>,
+}
+
+#[derive(Default, Clone, Copy)]
+pub struct ImageStats {
+    pub encoded: usize,
+    pub cached: usize,
+    // WebP versions against the images they stand in for.
+    pub webp: MinifyStats,
+}
+
+struct Encoded {
+    width: u32,
+    webp: bool,
+    data: Vec<u8>,
+    cached: bool,
+    // Of the same image in the original format.
+    original_size: usize,
+}
+
+// Processes the screenshots, see process. Goes through the output rather than static/, so
+// excluded and fingerprinted files are taken care of.
+pub fn process_images(config: &Config, writer: &mut dyn SiteWriter) -> anyhow::Result<()> {
+    let screenshots = config.out_dir.join(SCREENSHOTS_DIR);
+    if !screenshots.is_dir() {
+        // Built in memory, or no screenshots.
+        return Ok(());
+    }
+    let mut images = vec![];
+    collect_images(&screenshots, &mut images)?;
+    process(config, writer, &images)
+}
+
+// Writes foo-480w.png and so on next to each image in the static output that's wider than
+// the configured widths, and foo.webp, foo-480w.webp with --webp, and records them in
+// config.images for rewrite_img_tags.
+fn process(config: &Config, writer: &mut dyn SiteWriter, images: &[PathBuf]) -> anyhow::Result<()> {
+    let cache_dir = config.in_dir.join(CACHE_DIR);
+    std::fs::create_dir_all(&cache_dir).context("image cache")?;
+
+    let (widths, webp) = (&config.thumbnail_widths, config.webp);
+    let encoded = images
+        .par_iter()
+        .map(|path| encode(path, widths, webp, &cache_dir))
+        .collect::<Vec<_>>();
+
+    let mut stats = config.image_stats.get();
+    let mut processed = config.images.borrow_mut();
+    for (path, encoded) in images.iter().zip(encoded) {
+        let mut variants = ImageVariants {
+            width: 0,
+            variants: vec![],
+            webp: vec![],
+        };
+        match encoded {
+            Ok(Some((width, encoded))) => {
+                variants.width = width;
+                for encoded in encoded {
+                    let out_path = variant_path(path, width, &encoded);
+                    writer.write(&out_path, &encoded.data)?;
+                    if encoded.cached {
+                        stats.cached += 1;
+                    } else {
+                        stats.encoded += 1;
+                    }
+                    let site_path = site_path(config, &out_path)?;
+                    if encoded.webp {
+                        stats.webp.add(encoded.original_size, encoded.data.len());
+                        variants.webp.push((encoded.width, site_path));
+                    } else {
+                        variants.variants.push((encoded.width, site_path));
+                    }
+                }
+            }
+            Ok(None) => {}
+            Err(err) => config.warn(format!(
+                "Couldn't process image {}: {:#}",
+                path.display(),
+                err
+            )),
+        }
+        // Also when there was nothing to do, so the next page showing it doesn't try again.
+        processed.insert(site_path(config, path)?, variants);
+    }
+    config.image_stats.set(stats);
+    Ok(())
+}
+
+// The original width, and the copies at each of the widths that are smaller, plus the WebP
+// versions. None when there's nothing to do.
+fn encode(
+    path: &Path,
+    widths: &[u32],
+    webp: bool,
+    cache_dir: &Path,
+) -> anyhow::Result<Option<(u32, Vec<Encoded>)>> {
+    // Only reads the header.
+    let (width, _) = image::image_dimensions(path)?;
+    let mut widths = widths
+        .iter()
+        .copied()
+        .filter(|w| *w < width)
+        .collect::<Vec<_>>();
+    if widths.is_empty() && !webp {
+        return Ok(None);
+    }
+    widths.sort_unstable();
+    widths.dedup();
+    // The original is only converted.
+    if webp {
+        widths.push(width);
+    }
+
+    let data = std::fs::read(path)?;
+    let hash = fingerprint::content_hash(&data);
+    let extension = path
+        .extension()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_lowercase();
+    let format = ImageFormat::from_extension(&extension).context("unknown format")?;
+    let mut decoded = None;
+    let mut encoded = vec![];
+    for w in widths {
+        let mut formats = vec![];
+        if w < width {
+            formats.push(format);
+        }
+        if webp {
+            formats.push(ImageFormat::WebP);
+        }
+        let mut resized = None;
+        let mut original_size = data.len();
+        for out_format in formats {
+            let is_webp = out_format == ImageFormat::WebP;
+            let out_extension = if is_webp { "webp" } else { &extension };
+            let cached_path = cache_dir.join(format!("{hash}-{w}.{out_extension}"));
+            let (out, cached) = match std::fs::read(&cached_path) {
+                Ok(out) => (out, true),
+                Err(_) => {
+                    let image = match decoded.take() {
+                        Some(image) => image,
+                        None => image::load_from_memory_with_format(&data, format)?,
+                    };
+                    if w < width && resized.is_none() {
+                        resized =
+                            Some(image.resize(w, u32::MAX, image::imageops::FilterType::Lanczos3));
+                    }
+                    let mut out = std::io::Cursor::new(vec![]);
+                    resized
+                        .as_ref()
+                        .unwrap_or(&image)
+                        .write_to(&mut out, out_format)?;
+                    decoded = Some(image);
+                    let mut out = out.into_inner();
+                    if is_webp && out.len() >= original_size {
+                        out.clear();
+                    }
+                    std::fs::write(&cached_path, &out).context("image cache")?;
+                    (out, false)
+                }
+            };
+            if out.is_empty() {
+                continue;
+            }
+            if !is_webp {
+                original_size = out.len();
+            }
+            encoded.push(Encoded {
+                width: w,
+                webp: is_webp,
+                data: out,
+                cached,
+                original_size,
+            });
+        }
+    }
+    Ok(Some((width, encoded)))
+}
+
+// foo.png -> foo-480w.png, foo-480w.webp, or foo.webp for the original.
+fn variant_path(path: &Path, original_width: u32, encoded: &Encoded) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = if encoded.webp {
+        "webp".into()
+    } else {
+        path.extension().unwrap_or_default().to_string_lossy()
+    };
+    if encoded.width == original_width {
+        path.with_file_name(format!("{stem}.{extension}"))
+    } else {
+        path.with_file_name(format!("{stem}-{}w.{extension}", encoded.width))
+    }
+}
+
+fn site_path(config: &Config, path: &Path) -> anyhow::Result<String> {
+    let relative = path.strip_prefix(&config.out_dir)?;
+    Ok(format!(
+        "/{}",
+        relative.to_string_lossy().replace('\\', "/")
+    ))
+}
+
+fn collect_images(dir: &Path, images: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            collect_images(&path, images)?;
+        } else if is_image(&path) {
+            images.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn is_image(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+}
+
+// Gives <img> tags showing a processed image a srcset with its smaller copies, so browsers can
+// pick the smallest that's sharp enough, and wraps them in a <picture> offering the WebP
+// versions first. Images in static/ are processed the first time a page shows them. Tags that
+// already have a srcset or are in a <picture> are left alone. Feeds don't go through here,
+// since readers' support for WebP varies.
+pub fn rewrite_img_tags(
+    config: &Config,
+    writer: &mut dyn SiteWriter,
+    html: &str,
+) -> anyhow::Result<String> {
+    let img_regex = regex::Regex::new(r"<img\b[^>]*>").unwrap();
+    let src_regex = regex::Regex::new(r#"\ssrc="([^"]*)""#).unwrap();
+    let new = img_regex
+        .find_iter(html)
+        .filter_map(|tag| image_path(config, html, tag, &src_regex).map(|(_, path)| path))
+        .filter(|path| path.starts_with("/static/") && !config.images.borrow().contains_key(*path))
+        .map(|path| config.out_dir.join(path.trim_start_matches('/')))
+        .filter(|path| is_image(path) && path.is_file())
+        .collect::<BTreeSet<_>>();
+    if !new.is_empty() {
+        process(config, writer, &new.into_iter().collect::<Vec<_>>())?;
+    }
+
+    let images = config.images.borrow();
+    Ok(img_regex
+        .replace_all(html, |captures: &regex::Captures<'_>| {
+            let tag = captures.get(0).unwrap();
+            let image = image_path(config, html, tag, &src_regex)
+                .and_then(|(src, path)| Some((src, images.get(path)?)));
+            match image {
+                Some((src, image)) => rewrite_img_tag(config, tag.as_str(), src, image),
+                None => tag.as_str().to_owned(),
+            }
+        })
+        .into_owned())
+}
+
+// The src of an <img> tag and the site path of the image, if it's one to rewrite.
+fn image_path<'a>(
+    config: &Config,
+    html: &str,
+    tag: regex::Match<'a>,
+    src_regex: &regex::Regex,
+) -> Option<(&'a str, &'a str)> {
+    let before = &html[..tag.start()];
+    let in_picture = before
+        .rfind('<')
+        .is_some_and(|i| before[i..].starts_with("<source") || before[i..].starts_with("<picture"));
+    if in_picture || tag.as_str().contains("srcset=") {
+        return None;
+    }
+    let src = src_regex.captures(tag.as_str())?.get(1)?.as_str();
+    Some((src, src.strip_prefix(config.path_prefix.as_str())?))
+}
+
+fn rewrite_img_tag(config: &Config, tag: &str, src: &str, image: &ImageVariants) -> String {
+    let srcset = |variants: &[(u32, String)]| {
+        variants
+            .iter()
+            .map(|(width, path)| format!("{}{path} {width}w", config.path_prefix))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    let sizes = format!("(max-width: {0}px) 100vw, {0}px", image.width);
+    let mut img = tag.to_owned();
+    if !image.variants.is_empty() {
+        let candidates = format!("{}, {src} {}w", srcset(&image.variants), image.width);
+        img = format!(
+            "<img srcset=\"{candidates}\" sizes=\"{sizes}\"{}",
+            &tag["<img".len()..]
+        );
+    }
+    // Browsers only pick from the <source>, so it needs every size.
+    if image.webp.len() < image.variants.len() + 1 {
+        return img;
+    }
+    let source = format!(
+        "<source type=\"image/webp\" srcset=\"{}\" sizes=\"{sizes}\">",
+        srcset(&image.webp)
+    );
+    format!("<picture>{source}{img}</picture>")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::writer::MemoryWriter;
+
+    fn write_png(path: &Path) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        image::RgbImage::from_fn(64, 16, |x, y| image::Rgb([x as u8 * 4, y as u8 * 16, 0]))
+            .save(path)
+            .unwrap();
+    }
+
+    #[test]
+    fn shown_images_are_processed() {
+        let temp = tempfile::tempdir().unwrap();
+        let (mut config, _) = crate::test_site(temp.path(), &["--thumbnail-widths", "32"]);
+        config.out_dir = temp.path().join("build");
+        write_png(&config.out_dir.join("static/img/shown.png"));
+        write_png(&config.out_dir.join("static/img/unused.png"));
+        write_png(&config.out_dir.join("static/img/screenshots/game.png"));
+        let mut writer = MemoryWriter::default();
+
+        process_images(&config, &mut writer).unwrap();
+        let written = |writer: &MemoryWriter| {
+            writer
+                .files
+                .keys()
+                .map(|path| site_path(&config, path).unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(written(&writer), ["/static/img/screenshots/game-32w.png"]);
+
+        let html = r#"<p><img src="/static/img/shown.png" alt="A"></p>"#;
+        assert_eq!(
+            rewrite_img_tags(&config, &mut writer, html).unwrap(),
+            r#"<p><img srcset="/static/img/shown-32w.png 32w, /static/img/shown.png 64w" sizes="(max-width: 64px) 100vw, 64px" src="/static/img/shown.png" alt="A"></p>"#
+        );
+        assert_eq!(
+            written(&writer),
+            [
+                "/static/img/screenshots/game-32w.png",
+                "/static/img/shown-32w.png"
+            ]
+        );
+        assert!(!config
+            .images
+            .borrow()
+            .contains_key("/static/img/unused.png"));
+
+        // Already taken care of.
+        for html in [
+            r#"<picture><source srcset="/static/img/shown.avif"><img src="/static/img/shown.png"></picture>"#,
+            r#"<img srcset="/static/img/shown.png 2x" src="/static/img/shown.png">"#,
+        ] {
+            assert_eq!(rewrite_img_tags(&config, &mut writer, html).unwrap(), html);
+        }
+        // The same pixels, so the second comes from the cache.
+        let stats = config.image_stats.get();
+        assert_eq!((stats.encoded, stats.cached), (1, 1));
+    }
+
+    #[test]
+    fn encoded_images_are_cached() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("shot.png");
+        write_png(&path);
+        let cache_dir = temp.path().join("cache");
+        std::fs::create_dir_all(&cache_dir).unwrap();
+        // As if the WebP versions came out bigger.
+        let hash = fingerprint::content_hash(&std::fs::read(&path).unwrap());
+        for width in [32, 64] {
+            std::fs::write(cache_dir.join(format!("{hash}-{width}.webp")), "").unwrap();
+        }
+
+        let (width, encoded) = encode(&path, &[32, 128], true, &cache_dir)
+            .unwrap()
+            .unwrap();
+        assert_eq!(width, 64);
+        let summary = |encoded: &[Encoded]| {
+            encoded
+                .iter()
+                .map(|encoded| (encoded.width, encoded.webp, encoded.cached))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(summary(&encoded), [(32, false, false)]);
+
+        let (_, again) = encode(&path, &[32, 128], true, &cache_dir)
+            .unwrap()
+            .unwrap();
+        assert_eq!(summary(&again), [(32, false, true)]);
+        assert_eq!(again[0].data, encoded[0].data);
+    }
+}
@@ -0,0 +1,553 @@
+use crate::util;
+use anyhow::Context;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+
+// What a generated page links to, and the ids in it that links can point at with #fragment.
+// Collected as pages are written, so checking doesn't need to read the output back.
+pub struct PageLinks {
+    pub source: PathBuf,
+    pub hrefs: Vec<String>,
+    pub ids: HashSet<String>,
+    // Stand-ins for moved pages have none of the ids of the page they send readers to.
+    pub redirect: bool,
+}
+
+impl PageLinks {
+    pub fn new(source: &Path, html: &str) -> Self {
+        let href_regex = regex::Regex::new(r#"\bhref="([^"]*)""#).unwrap();
+        // Old-style <a name="..."> anchors work too.
+        let id_regex = regex::Regex::new(r#"\bid="([^"]+)"|<a\b[^>]*\bname="([^"]+)""#).unwrap();
+        PageLinks {
+            source: source.to_path_buf(),
+            hrefs: href_regex
+                .captures_iter(html)
+                .map(|captures| captures[1].replace("&amp;", "&"))
+                .collect(),
+            ids: id_regex
+                .captures_iter(html)
+                .filter_map(|captures| {
+                    Some(captures.get(1).or(captures.get(2))?.as_str().to_owned())
+                })
+                .collect(),
+            redirect: html.contains(r#"http-equiv="refresh""#),
+        }
+    }
+}
+
+// Everything a build put in the output.
+pub struct Output<'a> {
+    pub out_dir: &'a Path,
+    pub path_prefix: &'a str,
+    // Whether folder URLs end in a slash, which decides what relative links are relative to.
+    pub trailing_slash: bool,
+    pub files: &'a HashMap<PathBuf, u64>,
+    // Symlinked folders in the output, and where they point.
+    pub linked_dirs: &'a [(PathBuf, PathBuf)],
+    // By output file.
+    pub pages: &'a HashMap<PathBuf, PageLinks>,
+}
+
+impl Output<'_> {
+    // Whether there's a file at path, under out_dir.
+    fn has_file(&self, path: &Path) -> bool {
+        self.files.contains_key(path)
+            || self.linked_dirs.iter().any(|(dst, src)| {
+                path.strip_prefix(dst)
+                    .is_ok_and(|rest| src.join(rest).is_file())
+            })
+    }
+
+    // The file a link from page resolves to, like the server and static hosts would: the file
+    // itself, or the index.html of the folder.
+    fn resolve(&self, page: &Path, path: &str) -> Option<PathBuf> {
+        let path = if path.contains('%') {
+            util::percent_decode(path)?
+        } else {
+            path.to_owned()
+        };
+        let mut file = if let Some(path) = path.strip_prefix('/') {
+            let path = match path.strip_prefix(self.path_prefix.trim_start_matches('/'))? {
+                "" => "",
+                rest if self.path_prefix.is_empty() => rest,
+                rest => rest.strip_prefix('/')?,
+            };
+            self.out_dir.join(path)
+        } else {
+            // Without the slash, docs/intro/index.html is at /docs/intro, in docs/.
+            let mut base = page.parent()?;
+            if !self.trailing_slash && page.ends_with("index.html") && base != self.out_dir {
+                base = base.parent()?;
+            }
+            base.join(&path)
+        };
+        // Resolve .. by hand, there's no file to canonicalize in memory builds.
+        let mut normalized = PathBuf::new();
+        for component in file.components() {
+            match component {
+                Component::ParentDir => {
+                    if !normalized.pop() {
+                        return None;
+                    }
+                }
+                Component::CurDir => {}
+                _ => normalized.push(component),
+            }
+        }
+        if !normalized.starts_with(self.out_dir) {
+            return None;
+        }
+        file = normalized;
+        if path.is_empty() || path.ends_with('/') {
+            file.push("index.html");
+            return self.has_file(&file).then_some(file);
+        }
+        if self.has_file(&file) {
+            return Some(file);
+        }
+        file.push("index.html");
+        self.has_file(&file).then_some(file)
+    }
+
+    // Why the link from page to href is broken, if it is.
+    fn check(&self, page: &Path, href: &str) -> Option<String> {
+        // Full URLs, mailto: and such, and protocol-relative links are for the external checker.
+        let before_path = href.split(['/', '?', '#']).next().unwrap_or_default();
+        if href.starts_with("//") || before_path.contains(':') {
+            return None;
+        }
+        let (path, fragment) = href.split_once('#').unwrap_or((href, ""));
+        let path = path.split('?').next().unwrap_or_default();
+        let target = if path.is_empty() {
+            page.to_path_buf()
+        } else {
+            match self.resolve(page, path) {
+                Some(target) => target,
+                None => return Some("which isn't in the output".to_owned()),
+            }
+        };
+        // "#" and "#top" go to the top of any page.
+        if fragment.is_empty() || fragment == "top" {
+            return None;
+        }
+        let fragment = util::percent_decode(fragment).unwrap_or_else(|| fragment.to_owned());
+        match self.pages.get(&target) {
+            Some(links) if !links.redirect && !links.ids.contains(&fragment) => {
+                Some(format!("but there's no #{fragment} there"))
+            }
+            _ => None,
+        }
+    }
+}
+
+// Every site-relative link on the generated pages that doesn't lead to a file in the output,
+// or to an id on the page, as "{source} links to {href}, {why}", sorted. Goes through the
+// pages in parallel, each link is a couple of lookups.
+pub fn check_links(in_dir: &Path, output: &Output<'_>) -> Vec<String> {
+    let mut broken = output
+        .pages
+        .par_iter()
+        .flat_map_iter(|(page, links)| {
+            let source = links.source.strip_prefix(in_dir).unwrap_or(&links.source);
+            let mut seen = HashSet::new();
+            links
+                .hrefs
+                .iter()
+                .filter(move |href| seen.insert(href.as_str()))
+                .filter_map(move |href| {
+                    let why = output.check(page, href)?;
+                    Some(format!("{} links to {href}, {why}", source.display()))
+                })
+        })
+        .collect::<Vec<_>>();
+    // Listings have the same links on every page.
+    broken.sort();
+    broken.dedup();
+    broken
+}
+
+// Links to other sites are only checked with --check --external, they're slow and depend on
+// other people's servers.
+
+// Requests in flight at once, over all hosts.
+const EXTERNAL_CONCURRENCY: usize = 8;
+// Each host gets one request at a time, this far apart.
+const HOST_INTERVAL: Duration = Duration::from_millis(500);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(15);
+// Results younger than this are taken from the cache instead of asking again.
+const CACHE_MAX_AGE_SECS: i64 = 24 * 60 * 60;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum LinkStatus {
+    Ok,
+    Redirect { code: u16, location: String },
+    Broken { code: u16 },
+    Timeout,
+    Error { message: String },
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedStatus {
+    // Unix time.
+    checked: i64,
+    #[serde(flatten)]
+    status: LinkStatus,
+}
+
+// Full http(s) URLs in the <a> tags of the pages in out_dir, without fragments, each with the
+// site paths of the pages linking to it.
+pub fn external_links(out_dir: &Path) -> anyhow::Result<BTreeMap<String, BTreeSet<String>>> {
+    let link_regex = regex::Regex::new(r##"<a\b[^>]*\bhref="(https?://[^"#]+)"##).unwrap();
+    let pages = crate::prune::manifest(out_dir)?
+        .into_iter()
+        .filter(|path| path.extension().is_some_and(|ext| ext == "html"))
+        .collect::<Vec<_>>();
+    let found = pages
+        .par_iter()
+        .map(|path| {
+            let html = std::fs::read_to_string(out_dir.join(path))
+                .with_context(|| format!("read {}", path.display()))?;
+            let path = path.to_string_lossy().replace('\\', "/");
+            let page = format!("/{}", path.strip_suffix("index.html").unwrap_or(&path));
+            let urls = link_regex
+                .captures_iter(&html)
+                .map(|captures| captures[1].replace("&amp;", "&"))
+                .collect::<Vec<_>>();
+            anyhow::Ok((page, urls))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let mut links = BTreeMap::<String, BTreeSet<String>>::new();
+    for (page, urls) in found {
+        for url in urls {
+            links.entry(url).or_default().insert(page.clone());
+        }
+    }
+    Ok(links)
+}
+
+// Checks every external link on the site in out_dir, going easy on each server, prints a report
+// and returns how many links are broken or unreachable. Redirects and timeouts are reported,
+// but don't count, they're often temporary or harmless.
+pub fn check_external(out_dir: &Path, cache_path: &Path) -> anyhow::Result<usize> {
+    let links = external_links(out_dir)?;
+    let now = chrono::Utc::now().timestamp();
+    let mut cache = read_cache(cache_path, now);
+
+    // One queue per host, so hosts are spread over the workers but each sees one at a time.
+    let mut hosts = BTreeMap::<&str, Vec<&str>>::new();
+    for url in links.keys().filter(|url| !cache.contains_key(*url)) {
+        hosts.entry(url_host(url)).or_default().push(url);
+    }
+    let unchecked = hosts.values().map(Vec::len).sum::<usize>();
+    println!(
+        "Checking {unchecked} external links on {} hosts, {} more from the cache",
+        hosts.len(),
+        links.len() - unchecked
+    );
+    let agent = ureq::AgentBuilder::new()
+        .timeout(REQUEST_TIMEOUT)
+        .redirects(0)
+        .user_agent(concat!("ppsspp-site-generator/", env!("CARGO_PKG_VERSION")))
+        .build();
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(EXTERNAL_CONCURRENCY)
+        .build()?;
+    let checked = pool.install(|| {
+        hosts
+            .into_par_iter()
+            .flat_map_iter(|(_, urls)| {
+                let agent = &agent;
+                urls.into_iter().enumerate().map(move |(i, url)| {
+                    if i > 0 {
+                        std::thread::sleep(HOST_INTERVAL);
+                    }
+                    (url.to_owned(), check_url(agent, url))
+                })
+            })
+            .collect::<Vec<_>>()
+    });
+    for (url, status) in checked {
+        cache.insert(
+            url,
+            CachedStatus {
+                checked: now,
+                status,
+            },
+        );
+    }
+    if let Some(parent) = cache_path.parent() {
+        std::fs::create_dir_all(parent).context("link check cache")?;
+    }
+    // Timeouts and connection errors are asked again next time, they may have been our end.
+    let answered = cache
+        .iter()
+        .filter(|(_, cached)| {
+            !matches!(
+                cached.status,
+                LinkStatus::Timeout | LinkStatus::Error { .. }
+            )
+        })
+        .collect::<BTreeMap<_, _>>();
+    std::fs::write(cache_path, serde_json::to_string_pretty(&answered)?)
+        .context("link check cache")?;
+
+    let statuses = links
+        .iter()
+        .filter_map(|(url, pages)| Some((url.as_str(), pages, &cache.get(url)?.status)))
+        .collect::<Vec<_>>();
+    Ok(print_report(&statuses))
+}
+
+// The results in the cache that are still fresh at now. A missing or unreadable cache is
+// just empty, everything gets checked again.
+fn read_cache(cache_path: &Path, now: i64) -> HashMap<String, CachedStatus> {
+    let mut cache: HashMap<String, CachedStatus> = match std::fs::read(cache_path) {
+        Ok(data) => serde_json::from_slice(&data).unwrap_or_default(),
+        Err(_) => HashMap::new(),
+    };
+    cache.retain(|_, cached| now - cached.checked < CACHE_MAX_AGE_SECS);
+    cache
+}
+
+fn url_host(url: &str) -> &str {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    rest.split(['/', '?', '#']).next().unwrap_or_default()
+}
+
+// HEAD first, since it doesn't download anything, then GET for servers that refuse HEAD.
+fn check_url(agent: &ureq::Agent, url: &str) -> LinkStatus {
+    let mut result = agent.head(url).call();
+    if matches!(result, Err(ureq::Error::Status(..))) {
+        result = agent.get(url).call();
+    }
+    match result {
+        Ok(response) if (300..400).contains(&response.status()) => {
+            let location = response.header("location").unwrap_or_default();
+            let location = if location.starts_with('/') {
+                let scheme = url.split_once("://").map_or("https", |(scheme, _)| scheme);
+                format!("{scheme}://{}{location}", url_host(url))
+            } else {
+                location.to_owned()
+            };
+            LinkStatus::Redirect {
+                code: response.status(),
+                location,
+            }
+        }
+        Ok(_) => LinkStatus::Ok,
+        Err(ureq::Error::Status(code, _)) => LinkStatus::Broken { code },
+        Err(ureq::Error::Transport(err)) => {
+            let mut source: Option<&(dyn std::error::Error + 'static)> = Some(&err);
+            while let Some(err) = source {
+                let timed_out = err.downcast_ref::<std::io::Error>().is_some_and(|err| {
+                    matches!(
+                        err.kind(),
+                        std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock
+                    )
+                });
+                if timed_out {
+                    return LinkStatus::Timeout;
+                }
+                source = err.source();
+            }
+            // Leaves out the URL, it's in the report already.
+            let message = match err.message() {
+                Some(message) => format!("{}: {message}", err.kind()),
+                None => err.kind().to_string(),
+            };
+            LinkStatus::Error { message }
+        }
+    }
+}
+
+// Returns the number of broken and unreachable links.
+fn print_report(statuses: &[(&str, &BTreeSet<String>, &LinkStatus)]) -> usize {
+    let on = |pages: &BTreeSet<String>| pages.iter().cloned().collect::<Vec<_>>().join(", ");
+    let group = |title: &str, lines: Vec<String>| {
+        if !lines.is_empty() {
+            println!("{title} ({}):", lines.len());
+            for line in &lines {
+                println!("  {line}");
+            }
+        }
+        lines.len()
+    };
+    let broken = group(
+        "Broken",
+        statuses
+            .iter()
+            .filter_map(|(url, pages, status)| match status {
+                LinkStatus::Broken { code } => Some(format!("{code} {url} on {}", on(pages))),
+                _ => None,
+            })
+            .collect(),
+    );
+    let errors = group(
+        "Unreachable",
+        statuses
+            .iter()
+            .filter_map(|(url, pages, status)| match status {
+                LinkStatus::Error { message } => Some(format!("{url} on {}: {message}", on(pages))),
+                _ => None,
+            })
+            .collect(),
+    );
+    let timeouts = group(
+        "Timed out",
+        statuses
+            .iter()
+            .filter(|(_, _, status)| **status == LinkStatus::Timeout)
+            .map(|(url, pages, _)| format!("{url} on {}", on(pages)))
+            .collect(),
+    );
+    let redirects = group(
+        "Redirected, consider updating",
+        statuses
+            .iter()
+            .filter_map(|(url, pages, status)| match status {
+                LinkStatus::Redirect { code, location } => {
+                    Some(format!("{code} {url} -> {location} on {}", on(pages)))
+                }
+                _ => None,
+            })
+            .collect(),
+    );
+    println!(
+        "External links: {} fine, {broken} broken, {errors} unreachable, {timeouts} timed out, {redirects} redirected",
+        statuses.len() - broken - errors - timeouts - redirects
+    );
+    broken + errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_links_finds_broken_links() {
+        let out_dir = Path::new("build");
+        let pages = [
+            (
+                "index.html",
+                "pages/index.hbs",
+                r##"<a href="/docs/intro#setup">ok</a> <a href="/docs/intro#gone">no id</a>
+                <a href="/static/app.zip">ok</a> <a href="/missing">missing</a>
+                <a href="https://example.com/missing">external</a> <a href="#top">top</a>
+                <a href="/docs/moved#setup">redirect</a> <a href="/docs/">ok</a>"##,
+            ),
+            (
+                "docs/intro/index.html",
+                "docs/intro.md",
+                r##"<h2 id="setup">Setup</h2> <a name="old"></a> <a href="#old">ok</a>
+                <a href="../other">relative</a> <a href="/docs/intro?q=1">ok</a>"##,
+            ),
+            (
+                "docs/moved/index.html",
+                "docs/intro.md",
+                r#"<meta http-equiv="refresh" content="0; url=/docs/intro">"#,
+            ),
+            ("docs/index.html", "docs", ""),
+        ]
+        .into_iter()
+        .map(|(path, source, html)| (out_dir.join(path), PageLinks::new(Path::new(source), html)))
+        .collect::<HashMap<_, _>>();
+        let mut files = pages
+            .keys()
+            .map(|path| (path.clone(), 0))
+            .collect::<HashMap<_, _>>();
+        files.insert(out_dir.join("static/app.zip"), 0);
+        let output = Output {
+            out_dir,
+            path_prefix: "",
+            trailing_slash: true,
+            files: &files,
+            linked_dirs: &[],
+            pages: &pages,
+        };
+        assert_eq!(
+            check_links(Path::new(""), &output),
+            [
+                "docs/intro.md links to ../other, which isn't in the output",
+                "pages/index.hbs links to /docs/intro#gone, but there's no #gone there",
+                "pages/index.hbs links to /missing, which isn't in the output",
+            ]
+        );
+
+        // Under a prefix, links without it go nowhere. Without the slash, ../other from
+        // /sub/docs/intro is /sub/other.
+        let output = Output {
+            path_prefix: "/sub",
+            trailing_slash: false,
+            ..output
+        };
+        let broken = check_links(Path::new(""), &output);
+        assert!(broken.contains(
+            &"pages/index.hbs links to /docs/intro#setup, which isn't in the output".to_owned()
+        ));
+        let mut with_other = files.clone();
+        with_other.insert(out_dir.join("other"), 0);
+        let output = Output {
+            files: &with_other,
+            ..output
+        };
+        let broken = check_links(Path::new(""), &output);
+        assert!(
+            !broken.iter().any(|broken| broken.contains("../other")),
+            "{broken:?}"
+        );
+    }
+
+    #[test]
+    fn url_hosts() {
+        assert_eq!(
+            url_host("https://www.ppsspp.org/download"),
+            "www.ppsspp.org"
+        );
+        assert_eq!(url_host("http://example.com:8080?q=1"), "example.com:8080");
+        assert_eq!(url_host("https://example.com#top"), "example.com");
+        assert_eq!(url_host("https://example.com"), "example.com");
+        assert_eq!(url_host("example.com/path"), "example.com");
+    }
+
+    #[test]
+    fn cached_results_expire() {
+        let temp = tempfile::tempdir().unwrap();
+        let cache_path = temp.path().join("linkcheck.json");
+        assert!(read_cache(&cache_path, 0).is_empty());
+
+        let now = 1_700_000_000;
+        std::fs::write(
+            &cache_path,
+            serde_json::json!({
+                "https://fresh.example/": {"checked": now - 60, "status": "ok"},
+                "https://stale.example/": {"checked": now - CACHE_MAX_AGE_SECS, "status": "ok"},
+                "https://moved.example/": {
+                    "checked": now - CACHE_MAX_AGE_SECS + 1,
+                    "status": "redirect",
+                    "code": 301,
+                    "location": "https://example.com/",
+                },
+            })
+            .to_string(),
+        )
+        .unwrap();
+        let cache = read_cache(&cache_path, now);
+        let mut urls = cache.keys().map(String::as_str).collect::<Vec<_>>();
+        urls.sort_unstable();
+        assert_eq!(urls, ["https://fresh.example/", "https://moved.example/"]);
+        assert_eq!(
+            cache["https://moved.example/"].status,
+            LinkStatus::Redirect {
+                code: 301,
+                location: "https://example.com/".to_owned()
+            }
+        );
+
+        std::fs::write(&cache_path, "not json").unwrap();
+        assert!(read_cache(&cache_path, now).is_empty());
+    }
+}
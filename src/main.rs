@@ -105,79 +105,423 @@
 
 use chrono::{DateTime, Utc};
 use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
     path::{Path, PathBuf},
     sync::mpsc,
 };
 
 mod config;
+mod dedupe;
 mod document;
+mod error;
+mod favicon;
 mod feed;
+mod fingerprint;
+mod gen_api;
 mod gen_blog;
 mod gen_doctree;
+mod gen_opensearch;
 mod gen_pages;
+mod gen_robots;
 mod gen_sitemap;
+mod helpers;
+mod i18n;
+mod images;
 mod index;
+mod linkcheck;
+mod minify;
 mod post_process;
+mod precompress;
+mod preview;
+mod prune;
 mod server;
+mod stats;
+#[cfg(feature = "tls")]
+mod tls;
 mod util;
+mod writer;
 
 use anyhow::Context;
-use clap::Parser;
+use clap::{CommandFactory, FromArgMatches, Parser};
 pub use config::Config;
 use notify::Watcher;
 
 use crate::config::{DocLink, GlobalMeta};
+use crate::writer::SiteWriter;
 
 #[allow(dead_code)]
 #[derive(Parser, Debug)]
 struct Args {
+    /// Port to serve on. Without it, the first free one from 3000 up is used.
     #[arg(long, default_value_t = 3000)]
-    port: i32,
+    port: u16,
+    /// Open the site in the default browser once the server is up.
+    #[arg(long, conflicts_with = "skip_serve")]
+    open: bool,
+    /// Serve over HTTPS, with a self-signed certificate unless --tls-cert and --tls-key are given.
+    #[arg(long)]
+    tls: bool,
+    /// PEM certificate for --tls.
+    #[arg(long, requires_all = ["tls", "tls_key"])]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key for --tls.
+    #[arg(long, requires_all = ["tls", "tls_cert"])]
+    tls_key: Option<PathBuf>,
+    /// Address for the local server to listen on. Use 0.0.0.0 to preview from other devices.
+    #[arg(long, default_value = "127.0.0.1")]
+    host: std::net::IpAddr,
     #[arg(long)]
     prod: bool,
     #[arg(long)]
     dev: bool,
+    /// Minify the generated pages, and the CSS and JS in static/. Code blocks, scripts and
+    /// styles in pages are left alone, as are files named .min. or on a single line.
     #[arg(long)]
     minify: bool,
+    /// Leave the sourceMappingURL comments out of the CSS and JS minified by --minify.
+    #[arg(long, requires = "minify")]
+    strip_source_maps: bool,
+    /// Write static files under names with a hash of their contents, like all.<hash>.css, so
+    /// they can be cached forever. Templates get the names from
+    /// `{{asset_url "/static/..."}}`, and static/manifest.json maps the original paths to them.
+    #[arg(long)]
+    fingerprint: bool,
+    /// Also keep the original names of fingerprinted files, for links that can't use the
+    /// `asset_url` helper.
+    #[arg(long, requires = "fingerprint")]
+    keep_unhashed: bool,
+    /// Widths to make smaller copies of the PNG and JPEG images in static/ that pages show,
+    /// and of the screenshots, at, like 480,960,1600. They're written next to the originals
+    /// as name-480w.png, and images in pages get a srcset with them. Resized images are cached
+    /// in .cache/images.
+    #[arg(long, value_name = "WIDTHS", value_delimiter = ',')]
+    thumbnail_widths: Vec<u32>,
+    /// Also write WebP versions of those images, and of their smaller copies, and offer them
+    /// to browsers in pages with <picture>. Ones that come out bigger are left out. Feeds keep
+    /// the originals.
+    #[arg(long)]
+    webp: bool,
     #[arg(long)]
     skip_serve: bool,
+    /// Build in memory and print every generated URL grouped by section, instead of serving.
+    /// Lines starting with # are section headings.
+    #[arg(long, conflicts_with_all = ["skip_serve", "no_build"])]
+    list_routes: bool,
+    /// Build into a temporary directory that's deleted afterwards, and fail if the build had
+    /// any warnings, like broken documents, pages overwriting each other or links to static
+    /// files that aren't there. For CI.
+    #[arg(long, conflicts_with_all = ["no_build", "list_routes", "in_memory", "preview"])]
+    check: bool,
+    /// With --check, also request every link to other sites, and report the broken ones and
+    /// the ones that redirect. Results are cached in .cache/linkcheck.json for a day.
+    #[arg(long, requires = "check")]
+    external: bool,
+    /// Serve generated files from memory instead of the disk. Large files still come from disk.
+    #[arg(long, conflicts_with_all = ["skip_serve", "no_build"])]
+    in_memory: bool,
+    /// Render just this markdown file and serve it at /, rebuilding it when it or the templates
+    /// change. Much faster than the full site for writing a post.
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["skip_serve", "no_build", "list_routes", "in_memory"])]
+    preview: Option<PathBuf>,
+    /// Serve the existing build output as is, without building or watching.
+    #[arg(long, conflicts_with = "skip_serve")]
+    no_build: bool,
+    /// Subdirectory the site is hosted under, like /ppsspp. Empty for root hosting.
+    /// Like --url-base, `${ENV_VAR}` references are expanded.
+    #[arg(long, default_value = "")]
+    path_prefix: String,
+    /// Write pages as about/index.html and link to them as /about, or as about.html, for hosts
+    /// without extensionless URLs. Links in pages, feeds and the sitemap follow.
+    #[arg(long, value_enum, default_value_t = config::UrlStyle::Folders)]
+    url_style: config::UrlStyle,
+    /// Language of the UI strings in templates, from i18n/<LANG>.toml. Strings missing there
+    /// come from i18n/en.toml.
+    #[arg(long, default_value = i18n::DEFAULT_LANG)]
+    lang: String,
+    /// Overrides the URL base picked by --prod, like `https://preview.example.com`.
+    #[arg(long)]
+    url_base: Option<String>,
+    /// Don't compress responses from the local server.
+    #[arg(long)]
+    no_compress: bool,
+    /// Also log request and response headers, and the file each request was served from.
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+    /// Don't log requests to the local server.
+    #[arg(short, long)]
+    quiet: bool,
+    /// Whether directory URLs should end in a slash. The other form redirects.
+    #[arg(long, value_enum, default_value_t = server::TrailingSlash::Add)]
+    trailing_slash: server::TrailingSlash,
+    /// Forwards requests under PREFIX to an upstream server, like `/api/=http://centraldev.ppsspp.org`.
+    /// Can be given more than once.
+    #[arg(long, value_name = "PREFIX=URL", value_parser = server::parse_proxy_rule)]
+    proxy: Vec<server::ProxyRule>,
+    /// Send CORS headers from the local server for /api/ and the --cors-path paths, allowing
+    /// ORIGIN, or any origin if left out. Pages never get them.
+    #[arg(long, value_name = "ORIGIN", num_args = 0..=1, default_missing_value = "*", value_parser = server::parse_cors_origin)]
+    cors: Option<axum::http::HeaderValue>,
+    /// Another path to send CORS headers for with --cors, like /search-index.json. A trailing
+    /// slash covers everything below. Can be given more than once.
+    #[arg(long, value_name = "PATH", requires = "cors")]
+    cors_path: Vec<String>,
+    /// Cache lifetime in seconds the local server gives files under /static/.
+    #[arg(long, default_value_t = 3600)]
+    static_max_age: u32,
+    /// Print how long each part of the build took, the output size and the largest files.
+    #[arg(long)]
+    timings: bool,
+    /// Print the output size, per section, and the largest pages and static files, and write
+    /// them to build/_report/size.json.
+    #[arg(long)]
+    size_report: bool,
+    /// Warn about HTML pages bigger than this many KiB.
+    #[arg(long, value_name = "KIB")]
+    page_budget: Option<u64>,
+    /// Print how long each part of the build takes, as tracing spans.
+    #[arg(long)]
+    profile: bool,
+    /// Deepest heading level to list in page TOCs. Docs can override it with `toc_max_depth`.
+    #[arg(long, default_value_t = 3)]
+    toc_max_depth: u32,
+    /// Number of recent posts to include in the Atom and RSS feeds.
+    #[arg(long, default_value_t = 20)]
+    feed_item_limit: usize,
+    /// Image for feed readers to show, as a site path like /static/img/logo.png or a full URL.
+    #[arg(long)]
+    feed_image: Option<String>,
+    /// Logo in the page header, as a site path or a full URL. Empty for none.
+    #[arg(long, default_value = "/static/img/platform/ppsspp-icon.png")]
+    site_logo: String,
+    /// Cover image for social previews of posts and pages that don't set `cover`, as a site
+    /// path or a full URL. Empty for none.
+    #[arg(long, default_value = "/static/img/front/gameroll.jpg")]
+    default_cover: String,
+    /// Write .gz and .br siblings of the text files in the output, for hosts that serve
+    /// precompressed files. The local server serves them too.
+    #[arg(long)]
+    precompress: bool,
+    /// Delete the files in build/ that this build didn't write, like pages whose source was
+    /// renamed or removed, and the folders that leaves empty. Otherwise they're kept from one
+    /// build to the next. Nothing is deleted when the build had warnings.
+    #[arg(long, conflicts_with_all = ["no_build", "list_routes", "check", "preview"])]
+    prune: bool,
+    /// With --prune, only print what would be deleted.
+    #[arg(long, requires = "prune")]
+    dry_run: bool,
+    /// Paths in build/ for --prune to leave alone, in .gitignore syntax, like `/CNAME`.
+    /// /.well-known/ always is. Can be given more than once.
+    #[arg(long, value_name = "PATTERN", requires = "prune")]
+    prune_protect: Vec<String>,
+    /// Trim trailing whitespace and blank line runs from generated pages, for readable diffs
+    /// of the output. <pre> contents are left alone.
+    #[arg(long)]
+    tidy_html: bool,
+    /// Sections whose posts may leave out the date, both in the file name and the front matter.
+    /// They can be ordered with an `order` field instead. Comma separated.
+    #[arg(
+        long,
+        value_name = "SECTIONS",
+        value_delimiter = ',',
+        default_value = "news"
+    )]
+    optional_dates: Vec<String>,
+    /// Search URL for browsers to add as a search engine, with {searchTerms} where the query
+    /// goes, like `https://duckduckgo.com/?q={searchTerms}+site:www.ppsspp.org`. A site path or
+    /// a full URL. Without it, there's no opensearch.xml.
+    #[arg(long, value_name = "URL")]
+    search_url: Option<String>,
+    /// Source files in static/ to leave out of the output, in .gitignore syntax and relative to
+    /// static/, like `**/originals/`. Adds to the patterns in static/.siteignore. Can be given
+    /// more than once.
+    #[arg(long, value_name = "PATTERN")]
+    static_exclude: Vec<String>,
+    /// What to do with symlinks in static/: copy what they point to, recreate them in the
+    /// output, or leave them out. Broken links and links back into a parent folder are
+    /// skipped with a warning when following.
+    #[arg(long, value_enum, default_value_t = util::SymlinkMode::Follow)]
+    symlinks: util::SymlinkMode,
+    /// Look for static files with the same contents: hard-link the copies in the output to one
+    /// file, or report them, grouped, with the space they waste. Copies stay copies where
+    /// hard links don't work.
+    #[arg(long, value_enum, value_name = "MODE")]
+    dedupe_static: Option<dedupe::DedupeMode>,
+    /// Also write every doc in reading order to a single page at /docs/all/, for printing and
+    /// offline reading. It's a big page.
+    #[arg(long)]
+    all_docs_page: bool,
+    /// Older versions of the docs, newest first, each in docs/<VERSION>/ and generated to
+    /// /docs/<VERSION>/. Their reading order can be given in sidebars-<VERSION>.json. Docs pages
+    /// then get links to the same page in each version. Comma separated.
+    #[arg(long, value_name = "VERSIONS", value_delimiter = ',')]
+    doc_versions: Vec<String>,
+    /// Posts per page in the blog and news listings.
+    #[arg(long, default_value_t = 10)]
+    blog_page_size: usize,
+    /// Abort the build on the first broken document, instead of skipping it with a warning,
+    /// and on broken links within the site.
+    #[arg(long)]
+    strict: bool,
+    /// Fail the build when a template uses a field its data doesn't have, instead of rendering
+    /// it empty. Optional fields then need {{#if}} or the default helper.
+    #[arg(long)]
+    strict_templates: bool,
+    /// Don't load the Mermaid script, leaving diagrams as source text.
+    #[arg(long)]
+    no_mermaid: bool,
 }
 
-fn build(opt: &Args) -> anyhow::Result<()> {
-    let mut handlebars = handlebars::Handlebars::new();
+fn path_prefix(opt: &Args) -> anyhow::Result<String> {
+    let prefix = config::expand_env_vars(&opt.path_prefix)?;
+    let prefix = prefix.trim_matches('/');
+    Ok(if prefix.is_empty() {
+        String::new()
+    } else {
+        format!("/{prefix}")
+    })
+}
 
-    let templates = &[
-        "common_header",
-        "common_footer",
-        "doc",
-        "cat_contents",
-        "blog_post",
-        "blog_page",
-        "blog_sidebar",
-        "unit",
-        "product_card",
-        "page",
-        "feed_rss",
-        "feed_atom",
-        "sitemap_xml",
-    ];
-    for tmpl in templates {
-        handlebars.register_template_file(tmpl, &format!("template/{tmpl}.hbs"))?;
-    }
-    handlebars.register_template_file("link_icon", "template/icons/link_icon.hbs")?;
+// Every file in here is a template, named by its path without the extension, like
+// "icons/link_icon".
+const TEMPLATE_DIR: &str = "template";
+const TEMPLATE_EXTENSIONS: &[&str] = &["hbs", "handlebars"];
+// Short names templates in subfolders had before, which still work.
+const TEMPLATE_ALIASES: &[(&str, &str)] = &[("link_icon", "icons/link_icon")];
 
-    println!("PPSSPP website generator");
+// The compiled templates of the last build, kept between rebuilds in watch mode. When an
+// edited template doesn't compile, the site is rebuilt with the version that last did and the
+// error is reported, instead of every rebuild failing until it's fixed. One-shot builds start
+// without any, so a broken template fails them.
+#[derive(Default)]
+struct LastGoodTemplates {
+    templates: std::sync::Mutex<HashMap<String, handlebars::Template>>,
+    // Why templates of the last build fell back to their last good version.
+    broken: std::sync::Mutex<Vec<anyhow::Error>>,
+}
+
+impl LastGoodTemplates {
+    // The first template that fell back in the last build, for the console and the error
+    // overlay, once the site is rebuilt with the others.
+    fn take_broken(&self) -> Option<anyhow::Error> {
+        let mut broken = std::mem::take(&mut *self.broken.lock().unwrap());
+        let message = match broken.len() {
+            0 => return None,
+            1 => "A template didn't compile, the site was rebuilt with its last good version"
+                .to_owned(),
+            count => format!(
+                "{count} templates didn't compile, the site was rebuilt with their last good versions"
+            ),
+        };
+        Some(broken.swap_remove(0).context(message))
+    }
 
+    // For the build's warnings, which the summary and --check count.
+    fn warnings(&self) -> Vec<String> {
+        let broken = self.broken.lock().unwrap();
+        broken
+            .iter()
+            .map(|err| format!("{err}, using its last good version. {}", err.root_cause()))
+            .collect()
+    }
+}
+
+fn register_templates(
+    handlebars: &mut handlebars::Handlebars<'_>,
+    last_good: &LastGoodTemplates,
+) -> anyhow::Result<()> {
+    register_templates_from(Path::new(TEMPLATE_DIR), handlebars, last_good)
+}
+
+fn register_templates_from(
+    root: &Path,
+    handlebars: &mut handlebars::Handlebars<'_>,
+    last_good: &LastGoodTemplates,
+) -> anyhow::Result<()> {
+    let mut templates = BTreeMap::new();
+    collect_templates(root, root, &mut templates)?;
+    for (alias, name) in TEMPLATE_ALIASES {
+        if let Some(path) = templates.get(*name).cloned() {
+            add_template(&mut templates, alias.to_string(), path)?;
+        }
+    }
+    let mut good = last_good.templates.lock().unwrap();
+    let mut broken = vec![];
+    for (name, path) in &templates {
+        if let Err(err) = handlebars.register_template_file(name, path) {
+            let Some(template) = good.get(name) else {
+                return Err(err.into());
+            };
+            handlebars.register_template(name, template.clone());
+            broken.push(
+                anyhow::Error::new(err).context(format!("{} didn't compile", path.display())),
+            );
+        }
+    }
+    *good = handlebars.get_templates().clone();
+    *last_good.broken.lock().unwrap() = broken;
+    helpers::register(handlebars);
+    Ok(())
+}
+
+fn collect_templates(
+    root: &Path,
+    dir: &Path,
+    templates: &mut BTreeMap<String, PathBuf>,
+) -> anyhow::Result<()> {
+    for entry in
+        std::fs::read_dir(dir).with_context(|| format!("templates in {}", dir.display()))?
+    {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_templates(root, &path, templates)?;
+            continue;
+        }
+        let is_template = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| TEMPLATE_EXTENSIONS.contains(&ext));
+        if is_template {
+            let name = path
+                .strip_prefix(root)?
+                .with_extension("")
+                .to_string_lossy()
+                .replace('\\', "/");
+            add_template(templates, name, path)?;
+        }
+    }
+    Ok(())
+}
+
+fn add_template(
+    templates: &mut BTreeMap<String, PathBuf>,
+    name: String,
+    path: PathBuf,
+) -> anyhow::Result<()> {
+    if let Some(existing) = templates.get(&name) {
+        anyhow::bail!(
+            "Template {name} is both {} and {}",
+            existing.display(),
+            path.display()
+        );
+    }
+    templates.insert(name, path);
+    Ok(())
+}
+
+fn site_config(opt: &Args, out_dir: &Path) -> anyhow::Result<Config> {
     let mut markdown_options = markdown::Options::gfm();
     markdown_options.compile.allow_dangerous_html = true;
     // println!("md: {:#?}", markdown_options);
 
-    let url_base = if opt.prod {
-        "https://www.ppsspp.org"
+    let url_base = if let Some(url_base) = &opt.url_base {
+        config::expand_env_vars(url_base)?
+            .trim_end_matches('/')
+            .to_string()
+    } else if opt.prod {
+        "https://www.ppsspp.org".to_string()
     } else {
-        "https://dev.ppsspp.org"
-    }
-    .to_string();
+        "https://dev.ppsspp.org".to_string()
+    };
 
     let top_nav: Vec<DocLink> =
         serde_json::from_str(&std::fs::read_to_string("data/top_nav.json")?)?;
@@ -187,35 +531,127 @@ fn build(opt: &Args) -> anyhow::Result<()> {
     // Format the time in the desired format
     let formatted_time = current_time.format("%a, %d %b %Y %H:%M:%S GMT").to_string();
 
-    println!("Build time: {formatted_time}");
-
     let mut config = Config {
         url_base: url_base.clone(),
+        path_prefix: path_prefix(opt)?,
+        url_style: opt.url_style,
         in_dir: PathBuf::from("."),
-        out_dir: PathBuf::from("build"),
+        out_dir: out_dir.to_path_buf(),
         markdown_options,
         global_meta: GlobalMeta::new(opt.prod, &url_base, top_nav)?,
         build_date: formatted_time,
         github_url: "https://github.com/hrydgard/ppsspp/issues/",
+        toc_max_depth: opt.toc_max_depth,
+        feed_item_limit: opt.feed_item_limit,
+        feed_image: opt.feed_image.clone(),
+        default_cover: (!opt.default_cover.is_empty()).then(|| opt.default_cover.clone()),
+        tidy_html: opt.tidy_html,
+        optional_date_sections: opt.optional_dates.clone(),
+        search_url: opt.search_url.clone(),
+        static_exclude: opt.static_exclude.clone(),
+        all_docs_page: opt.all_docs_page,
+        doc_versions: opt.doc_versions.clone(),
+        symlinks: opt.symlinks,
+        blog_page_size: opt.blog_page_size,
+        strict: opt.strict,
+        strict_templates: opt.strict_templates,
+        minify_html: opt.minify,
+        fingerprint: opt.fingerprint,
+        keep_unhashed: opt.keep_unhashed,
+        assets: Default::default(),
+        check_assets: opt.fingerprint || opt.check,
+        missing_assets: Default::default(),
+        thumbnail_widths: opt.thumbnail_widths.clone(),
+        webp: opt.webp,
+        images: Default::default(),
+        image_stats: Default::default(),
+        warnings: Default::default(),
+        skipped: Default::default(),
+        minified_pages: Default::default(),
+        outputs: Default::default(),
+        pages: Default::default(),
+        translations: std::sync::Arc::new(i18n::Translations::load(
+            Path::new(i18n::DIR),
+            &opt.lang,
+        )?),
     };
 
-    if !config.out_dir.exists() {
-        std::fs::create_dir(&config.out_dir).context("out dir")?;
-    }
+    config.global_meta.mermaid = !opt.no_mermaid;
+    config.global_meta.site_logo = (!opt.site_logo.is_empty()).then(|| opt.site_logo.clone());
+    config.global_meta.opensearch = config.search_url.is_some();
+    config.global_meta.favicons = favicon::favicon_links(&config.in_dir);
+    config.global_meta.og_image = config
+        .default_cover
+        .as_ref()
+        .map(|cover| config.absolute_url(cover));
+    // Only when we're going to serve the output ourselves, deployed builds never get the script.
+    config.global_meta.livereload = !opt.prod && !opt.skip_serve && !opt.list_routes && !opt.check;
+    Ok(config)
+}
+
+// For tests, the config of a --skip-serve build with these arguments, with the site read from
+// in_dir, and the crate's templates with every helper registered.
+#[cfg(test)]
+fn test_site(in_dir: &Path, args: &[&str]) -> (Config, handlebars::Handlebars<'static>) {
+    let opt = Args::parse_from(["test", "--skip-serve"].iter().chain(args));
+    let mut config = site_config(&opt, Path::new(OUT_DIR)).unwrap();
+    config.in_dir = in_dir.to_path_buf();
+    let mut handlebars = handlebars::Handlebars::new();
+    register_templates(&mut handlebars, &Default::default()).unwrap();
+    helpers::register_site_helpers(&mut handlebars, &config);
+    (config, handlebars)
+}
 
-    util::copy_recursive(
+// Static files, the favicon and the combined CSS. With fingerprinting, config.assets gets the
+// hashed names. Returns what minifying saved, by file extension.
+fn copy_assets(
+    opt: &Args,
+    config: &Config,
+    writer: &mut dyn writer::SiteWriter,
+) -> anyhow::Result<BTreeMap<String, stats::MinifyStats>> {
+    writer.create_dir(&config.out_dir).context("out dir")?;
+    // The icons are made from it, it's not part of the site.
+    let mut exclude = config.static_exclude.clone();
+    exclude.push(format!(
+        "/{}",
+        favicon::SOURCE.trim_start_matches("static/")
+    ));
+    // Deleting only matters when the output is reused, like the preview's.
+    let options = util::CopyOptions {
+        minify: opt.minify,
+        strip_source_maps: opt.strip_source_maps,
+        fingerprint: config.fingerprint,
+        keep_unhashed: config.keep_unhashed,
+        exclude_dirs: &["css"], // We mash the css files together, so don't copy them.
+        ignore: util::static_ignore(&config.in_dir.join("static"), &exclude)?,
+        delete_removed: true,
+        symlinks: config.symlinks,
+    };
+    let mut copied = util::copy_recursive(
+        writer,
         config.in_dir.join("static"),
         config.out_dir.join("static"),
-        opt.minify,
-        &["css"], // We mash the css files together, so don't copy them.
-    )?;
-    // Move the favicon into place.
-    std::fs::copy(
-        config.in_dir.join("static/img/favicon.ico"),
-        config.out_dir.join("favicon.ico"),
+        &options,
     )?;
+    println!(
+        "Static files: {} copied, {} unchanged, {} deleted",
+        copied.copied, copied.skipped, copied.deleted
+    );
+    if opt.verbose > 0 {
+        println!("Static files: {} excluded", copied.excluded);
+    }
+    // Make the icons from one image when there is one, otherwise just move the favicon into
+    // place.
+    if favicon::generate_favicons(config, writer)? {
+        println!("Generated icons from {}", favicon::SOURCE);
+    } else {
+        writer.copy(
+            &config.in_dir.join("static/img/favicon.ico"),
+            &config.out_dir.join("favicon.ico"),
+        )?;
+    }
     // Concat the CSS files.
-    util::concat_files(
+    let (css, css_stats) = util::concat_files(
         &config.in_dir.join("static/css"),
         &[
             "vars.css",
@@ -229,73 +665,380 @@ fn build(opt: &Args) -> anyhow::Result<()> {
             "hero.css",
             "highlight-dark.min.css",
         ],
-        &config.out_dir.join("static/css/all.css"),
-        !opt.minify,
+        opt.minify,
     )?;
+    let css_path = config.out_dir.join("static/css/all.css");
+    if config.fingerprint {
+        let hashed = fingerprint::hashed_path(&css_path, &fingerprint::content_hash(&css));
+        writer.write(&hashed, &css)?;
+        copied.assets.insert(
+            "css/all.css".to_owned(),
+            format!("css/{}", hashed.file_name().unwrap().to_string_lossy()),
+        );
+    }
+    if !config.fingerprint || config.keep_unhashed {
+        writer.write(&css_path, &css)?;
+    }
+    if css_stats.files > 0 {
+        *copied.minified.entry("css".to_owned()).or_default() += css_stats;
+    }
+    if config.fingerprint {
+        let assets: BTreeMap<String, String> = copied
+            .assets
+            .iter()
+            .map(|(path, hashed)| (format!("/static/{path}"), format!("/static/{hashed}")))
+            .collect();
+        writer.write(
+            &config.out_dir.join("static/manifest.json"),
+            serde_json::to_string_pretty(&assets)?.as_bytes(),
+        )?;
+        println!("Fingerprinted {} static files", assets.len());
+        *config.assets.borrow_mut() = assets;
+    }
+    for (extension, minified) in &copied.minified {
+        println!("Minified {extension}: {}", minified.summary());
+    }
+    Ok(copied.minified)
+}
+
+#[tracing::instrument(skip_all)]
+fn build(
+    opt: &Args,
+    out_dir: &Path,
+    writer: &mut dyn writer::SiteWriter,
+    templates: &LastGoodTemplates,
+) -> anyhow::Result<stats::BuildStats> {
+    // Links are checked against everything the build wrote.
+    let writer = &mut writer::RecordingWriter::new(writer);
+    let mut stats = stats::BuildStats::default();
+    let mut handlebars = handlebars::Handlebars::new();
+    stats.time("templates", || {
+        register_templates(&mut handlebars, templates)
+    })?;
+    handlebars.set_strict_mode(opt.strict_templates);
+
+    println!("PPSSPP website generator");
+
+    let mut config = site_config(opt, out_dir)?;
+    for warning in templates.warnings() {
+        config.warn(warning);
+    }
+    println!("Build time: {}", config.build_date);
+    // Before anything is rendered, pages need the fingerprinted names.
+    stats.minified = stats.time("assets", || copy_assets(opt, &config, writer))?;
+    helpers::register_site_helpers(&mut handlebars, &config);
+    if !config.thumbnail_widths.is_empty() || config.webp {
+        stats.time("images", || images::process_images(&config, writer))?;
+    }
+    if let Some(mode) = opt.dedupe_static {
+        let deduped = stats.time("dedupe", || {
+            dedupe::dedupe(&config.out_dir, &config.out_dir.join("static"), mode)
+        })?;
+        dedupe::print(&deduped, mode);
+    }
 
-    let docs = gen_doctree::generate_doctree(&config, "docs", &mut handlebars)?;
+    let (docs, docs_nav, older_docs) = stats.time("docs", || {
+        let (docs, docs_nav) =
+            gen_doctree::generate_doctree(&config, "docs", None, &mut handlebars, writer)?;
+        let mut older_docs = vec![];
+        for version in &config.doc_versions {
+            let (docs, _) = gen_doctree::generate_doctree(
+                &config,
+                "docs",
+                Some(version),
+                &mut handlebars,
+                writer,
+            )?;
+            older_docs.extend(docs);
+        }
+        anyhow::Ok((docs, docs_nav, older_docs))
+    })?;
 
-    let blog = gen_blog::generate_blog(&config, "blog", "Development blog", &mut handlebars)?;
-    let news = gen_blog::generate_blog(&config, "news", "Release News", &mut handlebars)?;
+    let blog = stats.time("blog", || {
+        gen_blog::generate_blog(
+            &config,
+            "blog",
+            "Development blog",
+            None,
+            &mut handlebars,
+            writer,
+        )
+    })?;
+    let news = stats.time("news", || {
+        gen_blog::generate_blog(
+            &config,
+            "news",
+            "Release News",
+            Some("news"),
+            &mut handlebars,
+            writer,
+        )
+    })?;
 
     config.global_meta.latest_news = news
         .iter()
         .take(3)
         .map(|doc| doc.to_doclink(""))
         .collect::<Vec<_>>();
+    let documents = document::DocumentIndex::new(&[&docs, &older_docs, &blog, &news]);
+    config.global_meta.add_version_highlights(&documents);
+    stats.time("redirects", || {
+        gen_doctree::write_moved_stubs(&config, &docs, &documents, writer)?;
+        gen_doctree::write_moved_stubs(&config, &older_docs, &documents, writer)
+    })?;
 
-    let pages = gen_pages::generate_pages(&config, "pages", &mut handlebars)?;
+    // Pages go last, so they can list what the rest of the site has.
+    let site = document::SiteData {
+        recent_posts: blog.iter().take(5).map(|doc| doc.to_doclink("")).collect(),
+        recent_news: news.iter().take(5).map(|doc| doc.to_doclink("")).collect(),
+        docs: docs_nav,
+    };
+    let pages = stats.time("pages", || {
+        gen_pages::generate_pages(&config, "pages", &site, &mut handlebars, writer)
+    })?;
 
-    let mut sitemap = gen_sitemap::SitemapGenerator::new();
-    sitemap.add(&docs, 0.8);
-    sitemap.add(&blog, 0.9);
-    sitemap.add(&news, 0.9);
-    sitemap.add(&pages, 1.0);
-    sitemap.generate(&config, &mut handlebars)?;
+    stats.time("api", || gen_api::generate_api(&config, writer))?;
+    stats.time("opensearch", || {
+        gen_opensearch::generate_opensearch(&config, &mut handlebars, writer)
+    })?;
 
-    Ok(())
+    stats.time("sitemap", || {
+        let mut sitemap = gen_sitemap::SitemapGenerator::new();
+        let hints = |changefreq, priority| gen_sitemap::SitemapHints {
+            changefreq,
+            priority,
+        };
+        sitemap.add(&config, &docs, hints("monthly", 0.8));
+        sitemap.add(&config, &older_docs, hints("yearly", 0.3));
+        sitemap.add(&config, &blog, hints("weekly", 0.6));
+        sitemap.add(&config, &news, hints("weekly", 0.6));
+        sitemap.add(&config, &pages, hints("monthly", 0.7));
+        sitemap.generate(&config, &mut handlebars, writer)
+    })?;
+    stats.time("robots", || gen_robots::generate_robots(&config, writer))?;
+    let broken_links = stats.time("links", || {
+        let pages = config.pages.borrow();
+        let output = linkcheck::Output {
+            out_dir: &config.out_dir,
+            path_prefix: &config.path_prefix,
+            trailing_slash: opt.trailing_slash == server::TrailingSlash::Add,
+            files: &writer.files,
+            linked_dirs: &writer.linked_dirs,
+            pages: &pages,
+        };
+        linkcheck::check_links(&config.in_dir, &output)
+    });
+    let broken_count = broken_links.len();
+    for broken in broken_links {
+        config.warn(broken);
+    }
+    anyhow::ensure!(
+        !config.strict || broken_count == 0,
+        "{broken_count} broken links, see the warnings above"
+    );
+
+    for (section, documents) in [
+        ("docs", &docs),
+        ("older docs", &older_docs),
+        ("blog", &blog),
+        ("news", &news),
+        ("pages", &pages),
+    ] {
+        stats.documents.insert(section.to_owned(), documents.len());
+    }
+
+    if !config.thumbnail_widths.is_empty() || config.webp {
+        let images = config.image_stats.get();
+        println!(
+            "Images: {} encoded, {} from the cache",
+            images.encoded, images.cached
+        );
+        if images.webp.files > 0 {
+            println!("WebP: {}", images.webp.summary());
+        }
+    }
+
+    let minified_pages = config.minified_pages.get();
+    if minified_pages.files > 0 {
+        println!("Minified pages: {}", minified_pages.summary());
+        stats.minified.insert("pages".to_owned(), minified_pages);
+    }
+
+    stats.manifest = writer.manifest(&config.out_dir);
+    let output = writer.files.iter().filter_map(|(path, size)| {
+        let relative = path.strip_prefix(&config.out_dir).ok()?;
+        Some((relative.to_string_lossy().replace('\\', "/"), *size))
+    });
+    for warning in stats.add_output(output, opt.page_budget.map(|kib| kib * 1024)) {
+        config.warn(warning);
+    }
+
+    stats.warnings = take_warnings(&config);
+    stats.skipped_documents = config.skipped.get();
+
+    Ok(stats)
 }
 
-async fn run() -> anyhow::Result<()> {
-    let (notify_tx, notify_rx) = mpsc::channel();
+const OUT_DIR: &str = "build";
+// Written with --size-report, relative to the output.
+const SIZE_REPORT: &str = "_report/size.json";
+// Results of --check --external.
+const LINKCHECK_CACHE: &str = ".cache/linkcheck.json";
 
-    let opt = Args::parse();
+// Files larger than this are served from disk even with --in-memory.
+const IN_MEMORY_MAX_FILE_SIZE: u64 = 1024 * 1024;
 
-    build(&opt).unwrap();
+// Builds into a staging directory and swaps it in on success, so a failed build
+// leaves the last good output in place, and the server never sees a half-written site.
+// With --in-memory, the served map is swapped too, once the new build is in place.
+fn rebuild(
+    opt: &Args,
+    memory: Option<&server::MemoryFilesSender>,
+    stats_tx: &server::LastBuildStatsSender,
+    templates: &LastGoodTemplates,
+) -> Result<(), error::SiteError> {
+    let staging = &util::staging_dir(Path::new(OUT_DIR))?;
+    let incremental = writer::IncrementalWriter::new(staging, Path::new(OUT_DIR));
+    let result = if memory.is_some() {
+        let mut writer = writer::CachingWriter {
+            inner: incremental,
+            max_size: IN_MEMORY_MAX_FILE_SIZE,
+            files: Default::default(),
+        };
+        build(opt, staging, &mut writer, templates)
+            .map(|stats| (stats, writer.files, writer.inner.unchanged))
+    } else {
+        let mut writer = incremental;
+        build(opt, staging, &mut writer, templates)
+            .map(|stats| (stats, Default::default(), writer.unchanged))
+    };
+    let (mut stats, files) = match result {
+        Ok((stats, files, unchanged)) => {
+            println!("{unchanged} files unchanged since the last build");
+            (stats, files)
+        }
+        Err(err) => {
+            let _ = std::fs::remove_dir_all(staging);
+            return Err(err.into());
+        }
+    };
+    let mut manifest = std::mem::take(&mut stats.manifest);
+    if opt.precompress {
+        let precompressed = stats.time("precompress", || {
+            precompress::precompress(staging, Path::new(OUT_DIR), &mut manifest)
+        })?;
+        println!(
+            "Precompressed {} files, reused {} from the last build, saving {} in total",
+            precompressed.written,
+            precompressed.reused,
+            stats::format_size(precompressed.bytes_saved)
+        );
+    }
+    let warnings = stats.warnings.len();
+    stats.time("prune", || prune_output(opt, staging, &manifest, warnings))?;
+    if opt.timings {
+        stats.print();
+    } else if opt.size_report {
+        stats.print_sizes();
+    }
+    if opt.size_report {
+        let path = staging.join(SIZE_REPORT);
+        // Maybe carried over from the last build, and linked to it.
+        let _ = std::fs::remove_file(&path);
+        writer::FsWriter.write(&path, stats.size_report_json().as_bytes())?;
+    }
+    util::swap_output(staging, Path::new(OUT_DIR))?;
+    stats_tx.send_replace(std::sync::Arc::new(stats));
 
-    if opt.skip_serve {
-        println!("not serving.");
-        return Ok(());
+    if let Some(memory) = memory {
+        let files = files
+            .into_iter()
+            .filter_map(|(path, contents)| {
+                let key = path.strip_prefix(staging).ok()?.to_string_lossy();
+                Some((key.replace('\\', "/"), contents.into()))
+            })
+            .collect();
+        memory.send_replace(std::sync::Arc::new(files));
     }
+    match templates.take_broken() {
+        Some(err) => Err(err.into()),
+        None => Ok(()),
+    }
+}
+
+const PREVIEW_DIR: &str = "build.preview";
 
+// Documents are read with paths like ./blog/post.md, relative to the checkout, which their
+// URLs are derived from.
+fn preview_source(source: &Path) -> anyhow::Result<PathBuf> {
+    let checkout = std::env::current_dir()?;
+    let source = source.strip_prefix(&checkout).unwrap_or(source);
+    anyhow::ensure!(
+        source.is_relative(),
+        "{} is outside of {}",
+        source.display(),
+        checkout.display()
+    );
+    let relative = source
+        .components()
+        .filter(|component| *component != std::path::Component::CurDir)
+        .collect::<PathBuf>();
+    Ok(Path::new(".").join(relative))
+}
+
+// For --preview. Only the one document gets rendered, the assets are copied once up front.
+fn rebuild_preview(
+    opt: &Args,
+    source: &Path,
+    templates: &LastGoodTemplates,
+) -> Result<(), error::SiteError> {
+    let mut handlebars = handlebars::Handlebars::new();
+    register_templates(&mut handlebars, templates)?;
+    handlebars.set_strict_mode(opt.strict_templates);
+    let config = site_config(opt, Path::new(PREVIEW_DIR))?;
+    for warning in templates.warnings() {
+        config.warn(warning);
+    }
+    helpers::register_site_helpers(&mut handlebars, &config);
+    preview::render_preview(&config, source, &mut handlebars, &mut writer::FsWriter)?;
+    match templates.take_broken() {
+        Some(err) => Err(err.into()),
+        None => Ok(()),
+    }
+}
+
+// Sends on the returned channel whenever something under the paths is modified.
+fn watch_paths(paths: &[PathBuf]) -> anyhow::Result<(impl Watcher, mpsc::Receiver<()>)> {
+    let (notify_tx, notify_rx) = mpsc::channel();
     let mut watcher =
         notify::recommended_watcher(move |res: Result<notify::Event, notify::Error>| match res {
             Ok(event) => {
                 if matches!(event.kind, notify::EventKind::Modify(_)) {
-                    notify_tx.send(()).unwrap();
+                    // Fails once the watch loop has quit, nothing left to tell.
+                    let _ = notify_tx.send(());
                 }
             }
             Err(e) => println!("watch error: {:?}", e),
         })?;
-
-    // Add a path to be watched. All files and directories at that path and
-    // below will be monitored for changes.
-    let watch_dirs = &[
-        "blog", "data", "docs", "news", "pages", "static", "template",
-    ];
-    for dir in watch_dirs {
-        watcher.watch(Path::new(dir), notify::RecursiveMode::Recursive)?;
+    for path in paths {
+        watcher.watch(path, notify::RecursiveMode::Recursive)?;
     }
+    Ok((watcher, notify_rx))
+}
 
-    // OK, we're done - just serve the results.
-    println!("Serving on localhost:{}", opt.port);
-
-    server::spawn_server(opt.port as u16).await;
-
+fn watch_loop(
+    notify_rx: mpsc::Receiver<()>,
+    reload_tx: &server::ReloadSender,
+    shutdown: &server::ShutdownReceiver,
+    rebuild: &dyn Fn() -> Result<(), error::SiteError>,
+) {
     let mut quit = false;
     while !quit {
         // Only look for changes every second, to kinda batch them up.
         std::thread::sleep(std::time::Duration::from_millis(1000));
+        if *shutdown.borrow() {
+            break;
+        }
 
         let mut changed = false;
         loop {
@@ -307,6 +1050,7 @@ async fn run() -> anyhow::Result<()> {
                 }
                 Err(mpsc::TryRecvError::Disconnected) => {
                     quit = true;
+                    break;
                 }
                 Err(mpsc::TryRecvError::Empty) => {
                     break;
@@ -318,13 +1062,672 @@ async fn run() -> anyhow::Result<()> {
             // TODO: Could make it more fine grained, but for now we just rebuild everything,
             // it's fast enough.
             println!("Detected changes, rebuilding!");
-            build(&opt).unwrap();
+            // Sending fails if no browser is listening, which is fine.
+            match rebuild() {
+                Ok(()) => {
+                    let _ = reload_tx.send(server::ReloadEvent::Reload);
+                }
+                Err(err) => {
+                    let message = err.message();
+                    println!("Build failed, still serving the last good build: {message}");
+                    let _ = reload_tx.send(server::ReloadEvent::BuildError(server::BuildFailure {
+                        message,
+                        file: err.file(),
+                    }));
+                }
+            }
+        }
+    }
+}
+
+// Keeps the files of the last build that this one didn't write, unless pruning. Runs on the
+// staging directory before it's swapped in.
+fn prune_output(
+    opt: &Args,
+    staging: &Path,
+    manifest: &BTreeSet<PathBuf>,
+    warnings: usize,
+) -> anyhow::Result<()> {
+    let carried = prune::carry_over(Path::new(OUT_DIR), staging, manifest)?;
+    if !opt.prune {
+        if carried > 0 {
+            println!("Kept {carried} files left over from earlier builds, --prune deletes them");
+        }
+        return Ok(());
+    }
+    // Like documents that failed to build, or images that couldn't be written, so the last
+    // build's files may be all there is of them.
+    if warnings > 0 {
+        println!("Not pruning, the build had {warnings} warnings");
+        return Ok(());
+    }
+    let protected = prune::protected_paths(staging, &opt.prune_protect)?;
+    let removed = prune::prune(staging, manifest, &protected, opt.dry_run)?;
+    let verb = if opt.dry_run { "Would prune" } else { "Pruned" };
+    for path in &removed {
+        println!("{verb} {}", path.display());
+    }
+    println!("{verb} {} files and folders", removed.len());
+    Ok(())
+}
+
+// The build's warnings, once the ones only known at the end are added, like links to static
+// files that aren't in the output. Printed as the build summary.
+fn take_warnings(config: &Config) -> Vec<String> {
+    for path in config.missing_assets.take() {
+        let hint = if config.fingerprint {
+            format!(", use {{{{asset_url \"{path}\"}}}}")
+        } else {
+            String::new()
+        };
+        config.warn(format!(
+            "{path} is linked from pages, but isn't in the output{hint}"
+        ));
+    }
+
+    config.translations.report(config);
+
+    let warnings = config.warnings.take();
+    if !warnings.is_empty() {
+        println!("Build finished with {} warnings:", warnings.len());
+        for warning in &warnings {
+            println!("  {}", warning);
         }
     }
+    warnings
+}
+
+// For --check. Builds to disk, since some steps read back what was written, but somewhere
+// that's cleaned up even when the build fails.
+fn check(opt: &Args) -> anyhow::Result<()> {
+    let out_dir = std::env::temp_dir().join(format!("ppsspp-site-check-{}", std::process::id()));
+    let result =
+        build(opt, &out_dir, &mut writer::FsWriter, &Default::default()).and_then(|stats| {
+            let external = if opt.external {
+                linkcheck::check_external(&out_dir, Path::new(LINKCHECK_CACHE))?
+            } else {
+                0
+            };
+            Ok((stats, external))
+        });
+    let _ = std::fs::remove_dir_all(&out_dir);
+    let (stats, external) = result?;
+    check_result(&stats.warnings, external)
+}
+
+// Fails on any warning, or broken link to another site.
+fn check_result(warnings: &[String], external: usize) -> anyhow::Result<()> {
+    let problems = warnings.len() + external;
+    anyhow::ensure!(problems == 0, "Check failed with {problems} problems");
+    println!("Check passed");
+    Ok(())
+}
+
+// Runs a build in memory and prints the URL of every file it would write, grouped by
+// top-level section.
+fn list_routes(opt: &Args) -> anyhow::Result<()> {
+    let mut writer = writer::MemoryWriter::default();
+    let out_dir = Path::new(OUT_DIR);
+    let _ = build(opt, out_dir, &mut writer, &Default::default())?;
+
+    let prefix = path_prefix(opt)?;
+    let mut routes = writer
+        .files
+        .keys()
+        .map(|path| {
+            let path = path
+                .strip_prefix(out_dir)?
+                .to_string_lossy()
+                .replace('\\', "/");
+            // Files directly in the root make up their own section.
+            let section = path
+                .split_once('/')
+                .map_or("/", |(first, _)| first)
+                .to_owned();
+            let url = match path.strip_suffix("index.html") {
+                Some(dir) => match opt.trailing_slash {
+                    server::TrailingSlash::Add => dir.to_owned(),
+                    server::TrailingSlash::Strip => dir.trim_end_matches('/').to_owned(),
+                },
+                None => path,
+            };
+            Ok((section, format!("{prefix}/{url}")))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    routes.sort();
+
+    // Separates the list from the build output.
+    println!();
+    let mut current_section = "";
+    for (section, url) in &routes {
+        if section != current_section {
+            println!("# {section}");
+            current_section = section;
+        }
+        println!("{url}");
+    }
+    Ok(())
+}
+
+// How far up from the default port to look for a free one.
+const PORT_SCAN_RANGE: u16 = 20;
+
+// An explicit --port has to be free. The default one can move up a bit instead, so
+// several checkouts can be served at once.
+fn pick_port(opt: &Args, explicit: bool) -> anyhow::Result<u16> {
+    let bind = |port| std::net::TcpListener::bind((opt.host, port));
+    let err = match bind(opt.port) {
+        Ok(_) => return Ok(opt.port),
+        Err(err) => err,
+    };
+    let holder = server::port_holder(opt.port)
+        .map_or(String::new(), |holder| format!(" (held by {holder})"));
+    if explicit || err.kind() != std::io::ErrorKind::AddrInUse {
+        return Err(err)
+            .with_context(|| format!("can't listen on {}:{}{}", opt.host, opt.port, holder));
+    }
+    let last = opt.port.saturating_add(PORT_SCAN_RANGE);
+    // There's nowhere to move up to from the last port.
+    let first = opt.port.checked_add(1);
+    for port in first.into_iter().flat_map(|first| first..=last) {
+        if bind(port).is_ok() {
+            println!(
+                "Port {} is busy{}, using {} instead",
+                opt.port, holder, port
+            );
+            return Ok(port);
+        }
+    }
+    anyhow::bail!(
+        "Ports {}-{} are all busy, pick another with --port",
+        opt.port,
+        last
+    )
+}
+
+// Certificate and key for --tls, loaded or generated before the build so problems show up early.
+fn tls_files(opt: &Args) -> anyhow::Result<Option<server::TlsFiles>> {
+    if !opt.tls {
+        return Ok(None);
+    }
+    if let (Some(cert), Some(key)) = (&opt.tls_cert, &opt.tls_key) {
+        anyhow::ensure!(cert.is_file(), "Certificate {} not found", cert.display());
+        anyhow::ensure!(key.is_file(), "Key {} not found", key.display());
+        return Ok(Some(server::TlsFiles {
+            cert: cert.clone(),
+            key: key.clone(),
+        }));
+    }
+    #[cfg(feature = "tls")]
+    {
+        let mut hosts = vec!["localhost".to_owned(), opt.host.to_string()];
+        hosts.extend(server::lan_ip().map(|ip| ip.to_string()));
+        hosts.dedup();
+        Ok(Some(tls::self_signed(&hosts)?))
+    }
+    #[cfg(not(feature = "tls"))]
+    anyhow::bail!("--tls needs a build with the tls feature")
+}
+
+async fn start_server(
+    opt: &Args,
+    tls: Option<server::TlsFiles>,
+    reload_tx: server::ReloadSender,
+    shutdown: server::ShutdownReceiver,
+    memory: Option<server::MemoryFiles>,
+    stats: Option<server::LastBuildStats>,
+) -> anyhow::Result<tokio::task::JoinHandle<()>> {
+    let prefix = path_prefix(opt)?;
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    let build_dir = if opt.preview.is_some() {
+        PREVIEW_DIR
+    } else {
+        OUT_DIR
+    };
+    let urls = server::reachable_urls(scheme, opt.host, opt.port, &prefix);
+
+    let handle = server::spawn_server(
+        server::ServerOptions {
+            host: opt.host,
+            port: opt.port,
+            build_dir: PathBuf::from(build_dir),
+            path_prefix: prefix,
+            compress: !opt.no_compress,
+            static_max_age: opt.static_max_age,
+            trailing_slash: opt.trailing_slash,
+            verbosity: if opt.quiet { 0 } else { 1 + opt.verbose },
+            tls,
+            proxy_rules: opt.proxy.clone(),
+            memory,
+            stats,
+            cors: opt.cors.clone().map(|origin| server::CorsOptions {
+                origin,
+                paths: opt.cors_path.clone(),
+            }),
+        },
+        reload_tx,
+        shutdown,
+    )
+    .await?;
+
+    // Tells apart several checkouts being served at once.
+    let checkout = std::env::current_dir()?;
+    println!("Serving {} on port {}", checkout.display(), opt.port);
+    for url in &urls {
+        println!("Serving on {}", url);
+    }
+    // Only called once, rebuilds in watch mode keep using the same tab.
+    if opt.open {
+        open_browser(&urls[0]);
+    }
+    Ok(handle)
+}
+
+// The first Ctrl+C asks everything to wind down, a second one doesn't wait.
+#[allow(clippy::exit)]
+fn handle_ctrl_c() -> server::ShutdownReceiver {
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_err() {
+            return;
+        }
+        println!("Shutting down, press Ctrl+C again to quit right away.");
+        let _ = shutdown_tx.send(true);
+        if tokio::signal::ctrl_c().await.is_ok() {
+            std::process::exit(130);
+        }
+    });
+    shutdown_rx
+}
+
+// Best effort, failing to open a browser shouldn't stop the server.
+fn open_browser(url: &str) {
+    let has_display = !cfg!(target_os = "linux")
+        || std::env::var_os("DISPLAY").is_some()
+        || std::env::var_os("WAYLAND_DISPLAY").is_some();
+    if !has_display {
+        println!("Warning: No display found, not opening a browser");
+        return;
+    }
+    let result = if cfg!(target_os = "windows") {
+        std::process::Command::new("cmd")
+            .args(["/C", "start", "", url])
+            .spawn()
+    } else if cfg!(target_os = "macos") {
+        std::process::Command::new("open").arg(url).spawn()
+    } else {
+        std::process::Command::new("xdg-open").arg(url).spawn()
+    };
+    if let Err(err) = result {
+        println!("Warning: Couldn't open a browser: {}", err);
+    }
+}
+
+async fn run() -> anyhow::Result<()> {
+    let matches = Args::command().get_matches();
+    let mut opt = Args::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    let explicit_port =
+        matches.value_source("port") == Some(clap::parser::ValueSource::CommandLine);
+
+    if opt.profile {
+        // Span timings are printed as each span closes. Without this, the spans are close to free.
+        tracing_subscriber::fmt()
+            .with_env_filter("ppsspp_site_generator=info")
+            .with_span_events(tracing_subscriber::fmt::format::FmtSpan::CLOSE)
+            .init();
+    }
+
+    if opt.list_routes {
+        return list_routes(&opt);
+    }
+    if opt.check {
+        return check(&opt);
+    }
+
+    // Catch addresses we can't listen on before the build, not after.
+    if !opt.skip_serve {
+        opt.port = pick_port(&opt, explicit_port)?;
+    }
+    let tls = tls_files(&opt)?;
+
+    if opt.no_build {
+        // Serve whatever the last build left behind. The sources don't even need to exist.
+        anyhow::ensure!(
+            Path::new(OUT_DIR).join("index.html").is_file(),
+            "Nothing to serve, {}/index.html doesn't exist",
+            OUT_DIR
+        );
+        let (reload_tx, _) = tokio::sync::broadcast::channel(16);
+        let shutdown = handle_ctrl_c();
+        start_server(&opt, tls, reload_tx, shutdown, None, None)
+            .await?
+            .await?;
+        return Ok(());
+    }
+
+    let memory_tx = opt
+        .in_memory
+        .then(|| tokio::sync::watch::channel(Default::default()).0);
+    let (stats_tx, _) = tokio::sync::watch::channel(Default::default());
+    if let Some(source) = &opt.preview {
+        anyhow::ensure!(
+            source.is_file(),
+            "Nothing to preview, {} not found",
+            source.display()
+        );
+        opt.preview = Some(preview_source(source)?);
+        // The preview renders with the plain names, so it has to copy under them too.
+        opt.fingerprint = false;
+    }
+    let templates = LastGoodTemplates::default();
+    let watched = if let Some(source) = &opt.preview {
+        let config = site_config(&opt, Path::new(PREVIEW_DIR))?;
+        copy_assets(&opt, &config, &mut writer::FsWriter)?;
+        rebuild_preview(&opt, source, &templates)?;
+        vec![source.clone(), PathBuf::from("template")]
+    } else {
+        rebuild(&opt, memory_tx.as_ref(), &stats_tx, &templates)?;
+        if opt.skip_serve {
+            println!("not serving.");
+            return Ok(());
+        }
+        [
+            "blog", "data", "docs", "i18n", "news", "pages", "static", "template",
+        ]
+        .map(PathBuf::from)
+        .to_vec()
+    };
+    let (watcher, notify_rx) = watch_paths(&watched)?;
+
+    // OK, we're done - just serve the results.
+    let (reload_tx, _) = tokio::sync::broadcast::channel(16);
+    let shutdown = handle_ctrl_c();
+    let memory_rx = memory_tx.as_ref().map(|memory_tx| memory_tx.subscribe());
+    // Previews don't collect stats, and production mode has no dev endpoints.
+    let stats_rx = (!opt.prod && opt.preview.is_none()).then(|| stats_tx.subscribe());
+    let server = start_server(
+        &opt,
+        tls,
+        reload_tx.clone(),
+        shutdown.clone(),
+        memory_rx,
+        stats_rx,
+    )
+    .await?;
+
+    // The watch loop sleeps, so keep it off the async worker threads.
+    tokio::task::spawn_blocking(move || {
+        let rebuild_site = || match &opt.preview {
+            Some(source) => rebuild_preview(&opt, source, &templates),
+            None => rebuild(&opt, memory_tx.as_ref(), &stats_tx, &templates),
+        };
+        watch_loop(notify_rx, &reload_tx, &shutdown, &rebuild_site);
+    })
+    .await?;
+    drop(watcher);
+    server.await?;
     Ok(())
 }
 
 #[tokio::main]
 async fn main() {
-    run().await.unwrap();
+    if let Err(err) = run().await {
+        eprintln!("Error: {:?}", err);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Builds a small site in memory, a docs section and a front page. Returns the output
+    // files, relative to the output directory, and the root-relative links in the pages.
+    fn build_site(args: &[&str]) -> (BTreeSet<PathBuf>, BTreeSet<String>) {
+        let temp = tempfile::tempdir().unwrap();
+        for (path, text) in [
+            (
+                "docs/intro.md",
+                "# Intro\n\nSee [setting up](/docs/guide/setup).\n",
+            ),
+            ("docs/guide/_category_.md", "---\ntitle: Guide\n---\n"),
+            (
+                "docs/guide/setup.md",
+                "# Setup\n\nBack to the [intro](/docs/intro#top), or [home](/).\n",
+            ),
+            ("pages/index.hbs", r#"<a href="/docs/intro">Docs</a>"#),
+        ] {
+            let path = temp.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, text).unwrap();
+        }
+        let (config, mut handlebars) = test_site(temp.path(), args);
+        let mut writer = writer::MemoryWriter::default();
+        let (_, docs) =
+            gen_doctree::generate_doctree(&config, "docs", None, &mut handlebars, &mut writer)
+                .unwrap();
+        let site = document::SiteData {
+            docs,
+            ..Default::default()
+        };
+        gen_pages::generate_pages(&config, "pages", &site, &mut handlebars, &mut writer).unwrap();
+
+        let link_regex = regex::Regex::new(r##"\bhref="(/[^"#?]*)"##).unwrap();
+        let mut links = BTreeSet::new();
+        for (path, contents) in &writer.files {
+            if path.extension().is_some_and(|ext| ext == "html") {
+                let html = String::from_utf8_lossy(contents);
+                links.extend(link_regex.captures_iter(&html).map(|c| c[1].to_owned()));
+            }
+        }
+        let files = writer
+            .files
+            .into_keys()
+            .map(|path| path.strip_prefix(OUT_DIR).unwrap().to_path_buf())
+            .collect();
+        (files, links)
+    }
+
+    #[test]
+    fn check_fails_on_a_missing_asset() {
+        let check_pages = |pages: &[(&str, &str)]| {
+            let temp = tempfile::tempdir().unwrap();
+            std::fs::create_dir_all(temp.path().join("pages")).unwrap();
+            for (name, html) in pages {
+                std::fs::write(temp.path().join("pages").join(name), html).unwrap();
+            }
+            let (config, mut handlebars) = test_site(temp.path(), &["--check"]);
+            let site = document::SiteData::default();
+            let mut writer = writer::MemoryWriter::default();
+            gen_pages::generate_pages(&config, "pages", &site, &mut handlebars, &mut writer)
+                .unwrap();
+            let warnings = take_warnings(&config);
+            check_result(&warnings, 0).map(|_| warnings)
+        };
+        let page = ("index.hbs", r#"<a href="/about">About</a>"#);
+        assert_eq!(check_pages(&[page]).unwrap(), Vec::<String>::new());
+
+        let broken = (
+            "about.hbs",
+            r#"<img src="/static/img/no-such-screenshot.png">"#,
+        );
+        let err = check_pages(&[page, broken]).unwrap_err();
+        assert_eq!(err.to_string(), "Check failed with 1 problems");
+    }
+
+    #[test]
+    fn asset_urls_get_the_path_prefix_once() {
+        let temp = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(temp.path().join("pages")).unwrap();
+        std::fs::write(
+            temp.path().join("pages/index.hbs"),
+            r#"<link href="{{asset_url "css/main.css"}}"><img src="{{asset "/static/img/logo.png"}}"><div style="background: url({{asset_url "img/bg.png"}})"></div><a href="/docs">Docs</a>"#,
+        )
+        .unwrap();
+        let (config, mut handlebars) = test_site(temp.path(), &["--path-prefix", "/ppsspp"]);
+        let site = document::SiteData::default();
+        let mut writer = writer::MemoryWriter::default();
+        gen_pages::generate_pages(&config, "pages", &site, &mut handlebars, &mut writer).unwrap();
+        let html = String::from_utf8_lossy(&writer.files[&Path::new(OUT_DIR).join("index.html")]);
+        assert_eq!(
+            html.trim(),
+            r#"<link href="/ppsspp/static/css/main.css"><img src="/ppsspp/static/img/logo.png"><div style="background: url(/ppsspp/static/img/bg.png)"></div><a href="/ppsspp/docs">Docs</a>"#
+        );
+    }
+
+    // Links to something that isn't in the output. Only folders get their index.html.
+    fn broken_links(files: &BTreeSet<PathBuf>, links: &BTreeSet<String>) -> BTreeSet<String> {
+        links
+            .iter()
+            .filter(|link| {
+                let path = Path::new(link.trim_start_matches('/'));
+                !files.contains(path) && !files.contains(&path.join("index.html"))
+            })
+            .cloned()
+            .collect()
+    }
+
+    #[test]
+    fn broken_templates_fall_back() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("greeting.hbs");
+        std::fs::write(&path, "Hello {{name}}").unwrap();
+        let last_good = LastGoodTemplates::default();
+        let register = |last_good: &LastGoodTemplates| {
+            let mut handlebars = handlebars::Handlebars::new();
+            register_templates_from(temp.path(), &mut handlebars, last_good).map(|_| handlebars)
+        };
+        register(&last_good).unwrap();
+        assert!(last_good.take_broken().is_none());
+
+        std::fs::write(&path, "Hello {{#if name}}").unwrap();
+        let handlebars = register(&last_good).unwrap();
+        let html = handlebars
+            .render("greeting", &serde_json::json!({"name": "world"}))
+            .unwrap();
+        assert_eq!(html, "Hello world");
+        let warnings = last_good.warnings();
+        assert_eq!(warnings.len(), 1);
+        assert!(
+            warnings[0].contains("greeting.hbs didn't compile"),
+            "{warnings:?}"
+        );
+        let err = last_good.take_broken().unwrap();
+        assert!(
+            format!("{err:#}").contains("greeting.hbs didn't compile"),
+            "{err:#}"
+        );
+        assert!(last_good.take_broken().is_none());
+
+        // Without a last good version, like in a one-shot build, it's an error.
+        assert!(register(&LastGoodTemplates::default()).is_err());
+    }
+
+    #[test]
+    fn header_highlights_the_section() {
+        let (config, mut handlebars) = test_site(Path::new("."), &[]);
+        let mut active = |url: &str| {
+            let mut context = document::PageContext::new(None, None, &config.global_meta);
+            context.url = url.to_owned();
+            let html = context.render("common_header", &mut handlebars).unwrap();
+            let menu = html.split(r#"<ul class="menu">"#).nth(1).unwrap();
+            let menu = menu.split("</ul>").next().unwrap();
+            let link_regex = regex::Regex::new(r#"href="([^"]*)"\s+class="selected"#).unwrap();
+            link_regex
+                .captures_iter(menu)
+                .map(|captures| captures[1].to_owned())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(active("/docs/getting-started/installing-games"), ["/docs"]);
+        assert_eq!(active("/blog/2024-01-26-1.17-release"), ["/blog"]);
+        assert_eq!(active("/blog"), ["/blog"]);
+        assert!(active("/blogging").is_empty());
+    }
+
+    #[test]
+    fn header_escapes_top_nav_titles() {
+        let (mut config, mut handlebars) = test_site(Path::new("."), &[]);
+        let mut link = config.global_meta.top_nav[0].clone();
+        link.title = r#"Save & load "states" <fast>"#.to_owned();
+        config.global_meta.top_nav = vec![link];
+        let context = document::PageContext::new(None, None, &config.global_meta);
+        let html = context.render("common_header", &mut handlebars).unwrap();
+        // Once in the menu and once in the burger menu.
+        assert_eq!(
+            html.matches("Save &amp; load &quot;states&quot; &lt;fast&gt;")
+                .count(),
+            2,
+            "{html}"
+        );
+        assert!(!html.contains("<fast>"));
+    }
+
+    #[test]
+    fn front_matter_picks_the_layout() {
+        let (config, mut handlebars) = test_site(Path::new("."), &[]);
+        // A new layout is only a template.
+        handlebars
+            .register_template_string(
+                "doc_wide",
+                r#"{{#*inline "content"}}<main class="wide">{{{contents}}}</main>{{/inline}}{{> base}}"#,
+            )
+            .unwrap();
+        let (meta, _) = document::Document::read_dash_meta(&mut std::io::Cursor::new(
+            "---\ntitle: Wide\nlayout: doc_wide\n---\n",
+        ))
+        .unwrap();
+        let mut document = document::Document::for_test("/docs/wide", &meta.title);
+        document.html = "<p>Wide table</p>".to_owned();
+        document.meta = meta;
+        let mut render = |document: &document::Document| {
+            document::PageContext::from_document(document, &config.global_meta)
+                .render_layout("doc", &mut handlebars)
+                .unwrap()
+        };
+        let html = render(&document);
+        assert!(html.contains(r#"<main class="wide"><p>Wide table</p></main>"#));
+        assert!(html.contains("<ul class=\"menu\">") && html.trim_end().ends_with("</html>"));
+        assert!(!html.contains("doc-sidebar"));
+
+        let mut plain = document.clone();
+        plain.meta.layout = None;
+        let html = render(&plain);
+        assert!(html.contains(r#"<div class="doc-sidebar""#) && html.contains("Wide table"));
+    }
+
+    #[test]
+    fn busy_last_port() {
+        // Fails if something else has it, which is just as busy.
+        let _busy = std::net::TcpListener::bind(("127.0.0.1", u16::MAX));
+        let opt = Args::parse_from(["test", "--port", "65535"]);
+        let err = pick_port(&opt, false).unwrap_err();
+        assert!(err.to_string().contains("65535"), "{err}");
+    }
+
+    #[test]
+    fn url_styles_link_to_output_files() {
+        let (files, links) = build_site(&[]);
+        let broken = broken_links(&files, &links);
+
+        let html_files = ["--url-style", "html-files"];
+        let (files, links) = build_site(&html_files);
+        assert_eq!(
+            files
+                .iter()
+                .filter(|path| path.ends_with("index.html"))
+                .collect::<Vec<_>>(),
+            [Path::new("index.html")]
+        );
+        assert!(files.contains(Path::new("docs/guide/setup.html")));
+        assert!(links.contains("/docs/intro.html"));
+        assert!(links.contains("/docs/guide/setup.html"));
+        assert!(!links.contains("/docs/intro"));
+        // Links broken in the content itself stay broken, none break because of the style.
+        let (config, _) = test_site(Path::new("."), &html_files);
+        let expected = broken
+            .iter()
+            .map(|link| config.page_url(link))
+            .collect::<BTreeSet<_>>();
+        assert_eq!(broken_links(&files, &links), expected);
+    }
 }
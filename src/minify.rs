@@ -0,0 +1,180 @@
+use anyhow::Context;
+use std::path::Path;
+
+// Minifies a CSS or JS file from static/. None for other files, and for ones that look
+// minified already: .min. in the name, or all on one line. The source map reference is put
+// back at the end unless strip_source_map.
+pub fn minify_asset(
+    path: &Path,
+    data: &[u8],
+    strip_source_map: bool,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    if !is_asset(path) || name.contains(".min.") || !data.trim_ascii_end().contains(&b'\n') {
+        return Ok(None);
+    }
+    let text = std::str::from_utf8(data).context("not UTF-8")?;
+    let (text, source_map) = split_source_map(text);
+    let mut minified = if path.extension().is_some_and(|ext| ext == "css") {
+        minify_css(text)?
+    } else {
+        let session = minify_js::Session::new();
+        let mut output = vec![];
+        minify_js::minify(
+            &session,
+            minify_js::TopLevelMode::Global,
+            text.as_bytes(),
+            &mut output,
+        )
+        .map_err(|err| anyhow::anyhow!("{err}"))?;
+        String::from_utf8(output)?
+    };
+    if let Some(source_map) = source_map.filter(|_| !strip_source_map) {
+        minified.push('\n');
+        minified += source_map;
+    }
+    Ok(Some(minified.into_bytes()))
+}
+
+// Whether minify_asset handles the kind of file.
+pub fn is_asset(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext == "css" || ext == "js")
+}
+
+// Takes the sourceMappingURL comment off the end, in either the JS or the CSS form.
+fn split_source_map(text: &str) -> (&str, Option<&str>) {
+    let trimmed = text.trim_end();
+    let line_start = trimmed.rfind('\n').map_or(0, |i| i + 1);
+    let last_line = &trimmed[line_start..];
+    if last_line.starts_with("//# sourceMappingURL=")
+        || last_line.starts_with("/*# sourceMappingURL=")
+    {
+        (&trimmed[..line_start], Some(last_line))
+    } else {
+        (text, None)
+    }
+}
+
+// Drops comments, except /*! ones which are usually licenses, and all whitespace that doesn't
+// separate words. Strings are left alone.
+fn minify_css(css: &str) -> anyhow::Result<String> {
+    // Whitespace around these never matters.
+    const PUNCTUATION: &[char] = &['{', '}', ';', ':', ',', '>', '~'];
+    // Whether a space is needed between what's there and the next word.
+    let separate = |minified: &str| {
+        !minified.is_empty() && !minified.ends_with(PUNCTUATION) && !minified.ends_with("*/")
+    };
+    let mut minified = String::with_capacity(css.len());
+    let mut space = false;
+    let mut chars = css.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '/' if css[i..].starts_with("/*") => {
+                let end = css[i + 2..].find("*/").context("unterminated comment")? + i + 4;
+                if css[i..].starts_with("/*!") {
+                    minified += &css[i..end];
+                } else {
+                    space = true;
+                }
+                while chars.peek().is_some_and(|&(j, _)| j < end) {
+                    chars.next();
+                }
+            }
+            '"' | '\'' => {
+                if space && separate(&minified) {
+                    minified.push(' ');
+                }
+                space = false;
+                minified.push(c);
+                loop {
+                    let (_, next) = chars.next().context("unterminated string")?;
+                    minified.push(next);
+                    if next == '\\' {
+                        minified.push(chars.next().context("unterminated string")?.1);
+                    } else if next == c {
+                        break;
+                    }
+                }
+            }
+            c if c.is_whitespace() => space = true,
+            c => {
+                if c == '}' && minified.ends_with(';') {
+                    minified.pop();
+                }
+                if space && separate(&minified) && !PUNCTUATION.contains(&c) {
+                    minified.push(' ');
+                }
+                // "a :hover" isn't "a:hover".
+                if space && c == ':' && separate(&minified) {
+                    minified.push(' ');
+                }
+                space = false;
+                minified.push(c);
+            }
+        }
+    }
+    Ok(minified)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn minify_css_keeps_meaning() {
+        let css = r#"/*! License */
+/* Layout */
+.nav a :hover ,
+.nav > li {
+    margin: 0 auto;
+    width: calc(100% - 2em);
+    font-family: "Open  Sans", sans-serif;
+}
+
+@media screen and (max-width: 600px) {
+    .nav { display: none; }
+}
+"#;
+        assert_eq!(
+            minify_css(css).unwrap(),
+            concat!(
+                "/*! License */.nav a :hover,.nav>li{margin:0 auto;width:calc(100% - 2em);",
+                "font-family:\"Open  Sans\",sans-serif}@media screen and (max-width:600px)",
+                "{.nav{display:none}}"
+            )
+        );
+        assert!(minify_css("a { color: red; /* oops").is_err());
+    }
+
+    #[test]
+    fn minify_asset_source_maps() {
+        let js = "function add(a, b) {\n    return a + b;\n}\n//# sourceMappingURL=add.js.map\n";
+        let kept = minify_asset(Path::new("add.js"), js.as_bytes(), false)
+            .unwrap()
+            .unwrap();
+        let kept = String::from_utf8(kept).unwrap();
+        assert!(kept.len() < js.len());
+        assert!(kept.ends_with("\n//# sourceMappingURL=add.js.map"));
+        assert_eq!(kept.lines().count(), 2);
+        let stripped = minify_asset(Path::new("add.js"), js.as_bytes(), true)
+            .unwrap()
+            .unwrap();
+        assert!(!String::from_utf8(stripped)
+            .unwrap()
+            .contains("sourceMappingURL"));
+
+        // Already minified.
+        assert!(minify_asset(Path::new("add.min.js"), js.as_bytes(), false)
+            .unwrap()
+            .is_none());
+        assert!(minify_asset(
+            Path::new("add.js"),
+            b"function add(a,b){return a+b}\n",
+            false
+        )
+        .unwrap()
+        .is_none());
+        assert!(minify_asset(Path::new("add.js"), b"function (\n{", false).is_err());
+    }
+}
@@ -23,7 +23,61 @@ fn truncate_string(str: String, max_length: usize) -> String {
         str
     } else {
         // TODO: cut at whole words.
-        format!("{}...", &str[0..max_length])
+        let mut end = max_length;
+        while !str.is_char_boundary(end) {
+            end -= 1;
+        }
+        format!("{}...", &str[0..end])
+    }
+}
+
+const HIGHLIGHTS_START: &str = "<!-- highlights -->";
+const HIGHLIGHTS_END: &str = "<!-- /highlights -->";
+const MAX_HIGHLIGHT_CHARS: usize = 120;
+
+fn markdown_to_plain_text(markdown: &str, config: &Config) -> anyhow::Result<String> {
+    let tree =
+        markdown::to_mdast(markdown, &config.markdown_options.parse).map_err(anyhow::Error::msg)?;
+    let mut text = String::new();
+    recurse_text_join(&[tree], &mut text);
+    Ok(truncate_string(
+        text.trim().to_string(),
+        MAX_HIGHLIGHT_CHARS,
+    ))
+}
+
+// Each list item inside a <!-- highlights --> ... <!-- /highlights --> block becomes one highlight.
+fn extract_highlights(markdown: &str, config: &Config) -> anyhow::Result<Vec<String>> {
+    let Some((_, rest)) = markdown.split_once(HIGHLIGHTS_START) else {
+        return Ok(vec![]);
+    };
+    let (block, _) = rest
+        .split_once(HIGHLIGHTS_END)
+        .context("unterminated highlights block")?;
+    let tree =
+        markdown::to_mdast(block, &config.markdown_options.parse).map_err(anyhow::Error::msg)?;
+    let mut highlights = vec![];
+    collect_list_items(&[tree], &mut highlights);
+    Ok(highlights)
+}
+
+fn collect_list_items(nodes: &[Node], items: &mut Vec<String>) {
+    for node in nodes {
+        match node {
+            Node::ListItem(item) => {
+                let mut text = String::new();
+                recurse_text_join(&item.children, &mut text);
+                items.push(truncate_string(
+                    text.trim().to_string(),
+                    MAX_HIGHLIGHT_CHARS,
+                ));
+            }
+            _ => {
+                if let Some(children) = node.children() {
+                    collect_list_items(children, items);
+                }
+            }
+        }
     }
 }
 
@@ -73,23 +127,616 @@ pub fn add_meta_from_markdown(
     // If no dash-meta, grab the title string.
     let tree = markdown::to_mdast(markdown, &config.markdown_options.parse).unwrap();
     markdown_summary_recurse(&[tree], meta)?;
+
+    // Highlights from the front matter take precedence over the comment block.
+    if meta.highlights.is_empty() {
+        meta.highlights = extract_highlights(markdown, config)?;
+    } else {
+        meta.highlights = meta
+            .highlights
+            .iter()
+            .map(|highlight| markdown_to_plain_text(highlight, config))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+    }
     Ok(())
 }
 
-// Markdown post-processing. This is for linking github issues.
-pub fn preprocess_markdown(md: &str, doc_name: &str, config: &Config) -> anyhow::Result<String> {
-    anyhow::ensure!(
-        !md.contains(".md)"),
-        "Markdown {} contains bad link",
-        doc_name
-    );
+// Resolves a link to another markdown file (minus the .md) to the URL of the generated page.
+// Relative links are resolved against the directory of the linking document.
+fn resolve_md_link(doc_url: &str, target: &str) -> String {
+    let base = if target.starts_with('/') {
+        ""
+    } else {
+        doc_url.rsplit_once('/').map_or("", |(dir, _)| dir)
+    };
+    let mut segments: Vec<&str> = vec![];
+    for segment in base.split('/').chain(target.split('/')) {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            _ => segments.push(segment),
+        }
+    }
+    let url = format!("/{}", segments.join("/"));
+    // Category pages are generated at the folder's URL.
+    match url.strip_suffix("/_category_") {
+        Some(folder_url) => folder_url.to_string(),
+        None => url,
+    }
+}
 
+// Markdown post-processing. This is for linking github issues, and rewriting links to
+// other markdown files (handy in editors) to the generated pages.
+pub fn preprocess_markdown(md: &str, doc_url: &str, config: &Config) -> anyhow::Result<String> {
+    let md = attach_line_highlights(md, doc_url, config);
     let issue_regex = regex::Regex::new(r"\[#(\d+)\]").unwrap();
-    Ok(issue_regex
-        .replace_all(md, |captures: &regex::Captures<'_>| {
-            let issue_number = captures.get(1).unwrap().as_str();
-            format!("[#{}]({}{})", issue_number, config.github_url, issue_number)
-            // Construct the replacement with the GitHub URL
+    let md = issue_regex.replace_all(&md, |captures: &regex::Captures<'_>| {
+        let issue_number = captures.get(1).unwrap().as_str();
+        format!("[#{}]({}{})", issue_number, config.github_url, issue_number)
+        // Construct the replacement with the GitHub URL
+    });
+
+    // No colon allowed in the target, so external links with a scheme are left alone.
+    let md_link_regex = regex::Regex::new(r"\]\(([^)\s#:]+)\.md(#[^)\s]*)?\)").unwrap();
+    Ok(md_link_regex
+        .replace_all(&md, |captures: &regex::Captures<'_>| {
+            let anchor = captures.get(2).map_or("", |anchor| anchor.as_str());
+            format!("]({}{})", resolve_md_link(doc_url, &captures[1]), anchor)
         })
         .to_string())
 }
+
+// Parses a line spec like "2,4-6" into inclusive ranges. None if any part is malformed.
+fn parse_line_spec(spec: &str) -> Option<Vec<(u32, u32)>> {
+    spec.split(',')
+        .map(|part| {
+            let part = part.trim();
+            let (first, last) = part.split_once('-').unwrap_or((part, part));
+            let (first, last) = (first.trim().parse().ok()?, last.trim().parse().ok()?);
+            (first >= 1 && first <= last).then_some((first, last))
+        })
+        .collect()
+}
+
+// Fence info strings can end in a line spec, like ```rust {2,4-6}. markdown only keeps the
+// first word of the info as the language, so valid specs are glued onto it, like
+// rust{2,4-6}, for highlight_lines to pick up from the class. Malformed ones are dropped.
+fn attach_line_highlights(md: &str, doc_url: &str, config: &Config) -> String {
+    let fence_regex = regex::Regex::new(r"^ {0,3}(`{3,}|~{3,})(.*?)\s*$").unwrap();
+    let spec_regex = regex::Regex::new(r"^([^`\s{]+)\s+\{([^}]*)\}$").unwrap();
+    // The fence we're inside of, if any. Fence-like lines in there are just code.
+    let mut open_fence: Option<&str> = None;
+    let mut output = String::with_capacity(md.len());
+    for line in md.split_inclusive('\n') {
+        let Some(captures) = fence_regex.captures(line) else {
+            output += line;
+            continue;
+        };
+        let (fence, info) = (captures.get(1).unwrap().as_str(), &captures[2]);
+        match open_fence {
+            Some(open) => {
+                if info.is_empty() && fence.starts_with(open) {
+                    open_fence = None;
+                }
+                output += line;
+            }
+            None => {
+                open_fence = Some(fence);
+                let Some(spec) = spec_regex.captures(info) else {
+                    output += line;
+                    continue;
+                };
+                let indent = &line[..captures.get(1).unwrap().start()];
+                let lang = &spec[1];
+                if parse_line_spec(&spec[2]).is_some() {
+                    let spec = spec[2].replace([' ', '\t'], "");
+                    output += &format!("{indent}{fence}{lang}{{{spec}}}\n");
+                } else {
+                    config.warn(format!(
+                        "{doc_url}: ignoring malformed line highlight spec {{{}}}",
+                        &spec[2]
+                    ));
+                    output += &format!("{indent}{fence}{lang}\n");
+                }
+            }
+        }
+    }
+    output
+}
+
+// Wraps the lines picked out by a spec from attach_line_highlights in highlighted-line
+// spans. The code is already escaped, so lines can't contain tags.
+pub fn highlight_lines(code: &str, spec: &str) -> String {
+    let ranges = parse_line_spec(spec).unwrap_or_default();
+    let mut lines = code.split('\n').collect::<Vec<_>>();
+    // The block ends with a newline, which isn't a line of its own.
+    let trailing_newline = lines.last() == Some(&"");
+    if trailing_newline {
+        lines.pop();
+    }
+    let mut html = lines
+        .iter()
+        .zip(1..)
+        .map(|(line, number)| {
+            if ranges
+                .iter()
+                .any(|&(first, last)| (first..=last).contains(&number))
+            {
+                format!("<span class=\"highlighted-line\">{line}</span>")
+            } else {
+                line.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    if trailing_newline {
+        html.push('\n');
+    }
+    html
+}
+
+// Gives the h2-h6 headings ids to link to, and returns them for the page TOC. The title
+// (h1) is left out. Headings written as raw HTML with attributes are left alone.
+pub fn add_heading_ids(html: &str) -> (String, Vec<TocEntry>) {
+    let heading_regex = regex::Regex::new(r"(?s)<h([2-6])>(.*?)</h[2-6]>").unwrap();
+    let tag_regex = regex::Regex::new(r"<[^>]*>").unwrap();
+    let mut toc = vec![];
+    let mut used = std::collections::HashSet::new();
+    let html = heading_regex.replace_all(html, |captures: &regex::Captures<'_>| {
+        let depth = captures[1].parse::<u32>().unwrap();
+        let text = tag_regex
+            .replace_all(&captures[2], "")
+            .replace("&quot;", "\"")
+            .replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&amp;", "&");
+        let mut base = crate::util::slugify(&text);
+        // Headings with no letters or digits still need an id.
+        if base.is_empty() {
+            base = "section".to_owned();
+        }
+        let mut slug = base.clone();
+        let mut count = 0;
+        while !used.insert(slug.clone()) {
+            count += 1;
+            slug = format!("{base}-{count}");
+        }
+        let heading = format!("<h{depth} id=\"{slug}\">{}</h{depth}>", &captures[2]);
+        toc.push(TocEntry {
+            slug,
+            text: text.trim().to_owned(),
+            depth,
+        });
+        heading
+    });
+    (html.to_string(), toc)
+}
+
+// Puts prefix- in front of the ids in a page and the #links to them, so the page can be
+// combined with others without clashes. Covers heading ids and footnotes.
+pub fn prefix_ids(html: &str, prefix: &str) -> String {
+    let id_regex =
+        regex::Regex::new(r#"(\s(?:id|aria-describedby|aria-labelledby)=")([^"]*)""#).unwrap();
+    let link_regex = regex::Regex::new(r##"href="#([^"]+)""##).unwrap();
+    let html = id_regex.replace_all(html, format!("${{1}}{prefix}-${{2}}\""));
+    link_regex
+        .replace_all(&html, format!("href=\"#{prefix}-${{1}}\""))
+        .to_string()
+}
+
+// Evens out the whitespace templates leave behind, for readable diffs of the output: no
+// trailing whitespace, no runs of blank lines, and a single newline at the end. Whitespace
+// inside <pre> and <textarea> is content, so it's kept as is.
+pub fn tidy_html(html: &str) -> String {
+    let tag_regex = regex::Regex::new(r"(?i)<(/?)(pre|textarea)\b").unwrap();
+    let mut tidy = String::with_capacity(html.len());
+    let mut depth = 0usize;
+    let mut blank_line = false;
+    for line in html.split('\n') {
+        let inside_at_start = depth > 0;
+        for captures in tag_regex.captures_iter(line) {
+            if captures[1].is_empty() {
+                depth += 1;
+            } else {
+                depth = depth.saturating_sub(1);
+            }
+        }
+        if inside_at_start {
+            tidy += line;
+            blank_line = false;
+        } else {
+            // When a <pre> opens on this line, the end of the line is already its content.
+            let line = if depth > 0 { line } else { line.trim_end() };
+            if line.is_empty() && (blank_line || tidy.is_empty()) {
+                continue;
+            }
+            blank_line = line.is_empty();
+            tidy += line;
+        }
+        tidy.push('\n');
+    }
+    if depth == 0 {
+        tidy.truncate(tidy.trim_end().len());
+        tidy.push('\n');
+    }
+    tidy
+}
+
+// Elements whose contents are copied as they are by minify_html.
+const RAW_ELEMENTS: &[&str] = &["pre", "code", "textarea", "script", "style"];
+
+// Elements that whitespace next to doesn't show, so minify_html can drop it.
+const BLOCK_ELEMENTS: &[&str] = &[
+    "html",
+    "head",
+    "body",
+    "title",
+    "meta",
+    "link",
+    "script",
+    "style",
+    "noscript",
+    "div",
+    "p",
+    "ul",
+    "ol",
+    "li",
+    "dl",
+    "dt",
+    "dd",
+    "nav",
+    "main",
+    "section",
+    "article",
+    "aside",
+    "header",
+    "footer",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "table",
+    "thead",
+    "tbody",
+    "tfoot",
+    "tr",
+    "td",
+    "th",
+    "br",
+    "hr",
+    "pre",
+    "blockquote",
+    "figure",
+    "figcaption",
+    "form",
+    "details",
+    "summary",
+    "select",
+    "option",
+    "iframe",
+    "video",
+    "source",
+];
+
+// Makes generated pages smaller: whitespace runs collapse to a space and disappear next to
+// block elements, comments go except conditional ones, and attribute quotes are dropped where
+// that's safe. The contents of RAW_ELEMENTS are left byte for byte.
+pub fn minify_html(html: &str) -> String {
+    let mut minified = String::with_capacity(html.len());
+    // Text is held back until the next tag, which decides what happens to its whitespace.
+    let mut text = String::new();
+    let mut after_block = true;
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        text += &rest[..start];
+        rest = &rest[start..];
+
+        if rest.starts_with("<!--") {
+            let end = rest.find("-->").map_or(rest.len(), |end| end + 3);
+            if rest.starts_with("<!--[if") || rest.starts_with("<!--<![endif]") {
+                flush_text(&mut minified, &mut text, after_block, true);
+                minified += &rest[..end];
+            }
+            rest = &rest[end..];
+            continue;
+        }
+        if rest.starts_with("<!") || rest.starts_with("<?") {
+            flush_text(&mut minified, &mut text, after_block, true);
+            let end = rest.find('>').map_or(rest.len(), |end| end + 1);
+            minified += &rest[..end];
+            rest = &rest[end..];
+            after_block = true;
+            continue;
+        }
+        let Some((tag, name, closing, len)) = minify_tag(rest) else {
+            // A < that doesn't start a tag.
+            text.push('<');
+            rest = &rest[1..];
+            continue;
+        };
+        let block = BLOCK_ELEMENTS.contains(&name.as_str());
+        flush_text(&mut minified, &mut text, after_block, block);
+        minified += &tag;
+        rest = &rest[len..];
+        after_block = block;
+
+        if !closing && RAW_ELEMENTS.contains(&name.as_str()) {
+            let end = find_closing_tag(rest, &name).unwrap_or(rest.len());
+            minified += &rest[..end];
+            rest = &rest[end..];
+        }
+    }
+    text += rest;
+    flush_text(&mut minified, &mut text, after_block, true);
+    minified
+}
+
+fn flush_text(minified: &mut String, text: &mut String, after_block: bool, before_block: bool) {
+    let mut collapsed = String::with_capacity(text.len());
+    let mut space = false;
+    for c in text.chars() {
+        if c.is_ascii_whitespace() {
+            space = true;
+        } else {
+            if space && !(collapsed.is_empty() && after_block) {
+                collapsed.push(' ');
+            }
+            space = false;
+            collapsed.push(c);
+        }
+    }
+    if space && !before_block && !(collapsed.is_empty() && after_block) {
+        collapsed.push(' ');
+    }
+    *minified += &collapsed;
+    text.clear();
+}
+
+// Byte offset of </name> in html, ignoring case.
+fn find_closing_tag(html: &str, name: &str) -> Option<usize> {
+    html.match_indices("</").map(|(i, _)| i).find(|&i| {
+        html.get(i + 2..i + 2 + name.len())
+            .is_some_and(|tag| tag.eq_ignore_ascii_case(name))
+    })
+}
+
+// Parses the tag at the start of html, returning it minified, its lowercased name, whether
+// it's a closing tag, and how long the original was. None when it isn't a tag.
+fn minify_tag(html: &str) -> Option<(String, String, bool, usize)> {
+    let bytes = html.as_bytes();
+    let mut pos = 1;
+    let closing = bytes.get(pos) == Some(&b'/');
+    if closing {
+        pos += 1;
+    }
+    let name_start = pos;
+    while pos < bytes.len() && (bytes[pos].is_ascii_alphanumeric() || bytes[pos] == b'-') {
+        pos += 1;
+    }
+    if pos == name_start || !bytes[name_start].is_ascii_alphabetic() {
+        return None;
+    }
+    let name = &html[name_start..pos];
+    let mut tag = format!("<{}{}", if closing { "/" } else { "" }, name);
+    loop {
+        while pos < bytes.len() && bytes[pos].is_ascii_whitespace() {
+            pos += 1;
+        }
+        match bytes.get(pos) {
+            None => return None,
+            Some(b'>') => {
+                tag.push('>');
+                pos += 1;
+                break;
+            }
+            Some(b'/') if bytes.get(pos + 1) == Some(&b'>') => {
+                tag += "/>";
+                pos += 2;
+                break;
+            }
+            _ => {}
+        }
+        let attr_start = pos;
+        while pos < bytes.len() && !b" \t\r\n=>".contains(&bytes[pos]) {
+            if bytes[pos] == b'/' && bytes.get(pos + 1) == Some(&b'>') {
+                break;
+            }
+            pos += 1;
+        }
+        if pos == attr_start {
+            // A stray slash.
+            pos += 1;
+            continue;
+        }
+        tag.push(' ');
+        tag += &html[attr_start..pos];
+        if bytes.get(pos) != Some(&b'=') {
+            continue;
+        }
+        pos += 1;
+        let value = match bytes.get(pos) {
+            Some(&quote @ (b'"' | b'\'')) => {
+                let len = html[pos + 1..].find(quote as char)?;
+                let value = &html[pos + 1..pos + 1 + len];
+                pos += len + 2;
+                if is_safe_unquoted(value) {
+                    value.to_owned()
+                } else {
+                    format!("{}{}{}", quote as char, value, quote as char)
+                }
+            }
+            _ => {
+                let value_start = pos;
+                while pos < bytes.len() && !b" \t\r\n>".contains(&bytes[pos]) {
+                    pos += 1;
+                }
+                html[value_start..pos].to_owned()
+            }
+        };
+        tag.push('=');
+        tag += &value;
+    }
+    Some((tag, name.to_ascii_lowercase(), closing, pos))
+}
+
+// Whether an attribute value can go without quotes. Stays well within what HTML allows.
+fn is_safe_unquoted(value: &str) -> bool {
+    !value.is_empty()
+        && !value.ends_with('/')
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-_.:#?%+,;~".contains(&b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{add_heading_ids, highlight_lines, minify_html, parse_line_spec};
+
+    #[test]
+    fn heading_ids_match_slugify() {
+        let (html, toc) = add_heading_ids(
+            "<h2>What's new in 1.17</h2><h3>Save &amp; load</h3><h2>What's new in 1.17</h2><h3>?!</h3>",
+        );
+        assert_eq!(
+            html,
+            r#"<h2 id="what-s-new-in-1-17">What's new in 1.17</h2><h3 id="save-load">Save &amp; load</h3><h2 id="what-s-new-in-1-17-1">What's new in 1.17</h2><h3 id="section">?!</h3>"#
+        );
+        // Links built with {{slugify}} in templates land on the heading.
+        assert_eq!(crate::util::slugify("Save & load"), toc[1].slug);
+        assert_eq!(toc[1].text, "Save & load");
+    }
+
+    #[test]
+    fn line_specs() {
+        assert_eq!(parse_line_spec("2"), Some(vec![(2, 2)]));
+        assert_eq!(parse_line_spec("1,3-5"), Some(vec![(1, 1), (3, 5)]));
+        assert_eq!(parse_line_spec(" 2 , 4 - 6 "), Some(vec![(2, 2), (4, 6)]));
+        for bad in ["", "0", "5-3", "a", "1,", "1-", "-2", "1-2-3", "1;2"] {
+            assert_eq!(parse_line_spec(bad), None, "{bad}");
+        }
+    }
+
+    #[test]
+    fn highlighted_lines() {
+        let code = "one\ntwo\nthree\nfour\n";
+        let marked = |line: &str| format!("<span class=\"highlighted-line\">{line}</span>");
+        assert_eq!(
+            highlight_lines(code, "1,3-4"),
+            format!(
+                "{}\ntwo\n{}\n{}\n",
+                marked("one"),
+                marked("three"),
+                marked("four")
+            )
+        );
+        // Ranges past the end only pick out the lines there are.
+        assert_eq!(
+            highlight_lines(code, "4-9"),
+            format!("one\ntwo\nthree\n{}\n", marked("four"))
+        );
+        assert_eq!(highlight_lines(code, "5-9"), code);
+        assert_eq!(highlight_lines(code, "bad"), code);
+        assert_eq!(
+            highlight_lines("one\ntwo", "2"),
+            format!("one\n{}", marked("two"))
+        );
+    }
+
+    #[test]
+    fn minify_html_snapshot() {
+        let html = r#"<!DOCTYPE html>
+<html>
+  <head>
+    <!-- The header -->
+    <meta charset="utf-8">
+    <!--[if lt IE 9]><script src="html5shiv.js"></script><![endif]-->
+    <link rel="stylesheet" href="/static/css/all.css">
+    <style>
+      body  { margin: 0; }
+    </style>
+  </head>
+  <body class="doc page">
+    <div id="main" data-empty="">
+      <p>
+        Some   <em>emphasized</em>
+        text with <a href="https://www.ppsspp.org/download/">a link</a> and
+        <code>inline  code</code>.
+      </p>
+      <img src="/static/img/logo.png" alt="The logo" />
+      <textarea name="t">
+  keep   this
+</textarea>
+    </div>
+    <script>
+      if (a < b && c > d) { console.log("  spaces  "); }
+    </script>
+  </body>
+</html>
+"#;
+        let expected = concat!(
+            "<!DOCTYPE html><html><head><meta charset=utf-8>",
+            "<!--[if lt IE 9]><script src=\"html5shiv.js\"></script><![endif]-->",
+            "<link rel=stylesheet href=\"/static/css/all.css\"><style>\n",
+            "      body  { margin: 0; }\n",
+            "    </style></head><body class=\"doc page\"><div id=main data-empty=\"\">",
+            "<p>Some <em>emphasized</em> text with ",
+            "<a href=\"https://www.ppsspp.org/download/\">a link</a> and ",
+            "<code>inline  code</code>.</p><img src=\"/static/img/logo.png\" alt=\"The logo\"/> ",
+            "<textarea name=t>\n  keep   this\n</textarea></div><script>\n",
+            "      if (a < b && c > d) { console.log(\"  spaces  \"); }\n",
+            "    </script></body></html>",
+        );
+        assert_eq!(minify_html(html), expected);
+    }
+
+    #[test]
+    fn minify_html_keeps_code_blocks() {
+        let markdown = r#"# Building
+
+Run this:
+
+```sh
+git clone --recursive https://github.com/hrydgard/ppsspp.git
+cd ppsspp
+./b.sh   --release
+
+```
+
+Then:
+
+```cpp
+template <typename T>
+static  void  Log(const T &value) {
+	if (value < 0 && value > -10)   {
+		printf("<!-- %d -->\n", value);
+	}
+}
+```
+
+	indented   code
+	    block
+
+Done.
+"#;
+        let html = markdown::to_html_with_options(markdown, &markdown::Options::gfm()).unwrap();
+        let minified = minify_html(&html);
+        let blocks = |html: &str| {
+            let mut blocks = vec![];
+            let mut rest = html;
+            while let Some(start) = rest.find("<pre>") {
+                let end = start + rest[start..].find("</pre>").unwrap() + "</pre>".len();
+                blocks.push(rest[start..end].to_owned());
+                rest = &rest[end..];
+            }
+            blocks
+        };
+        assert_eq!(blocks(&html).len(), 3);
+        assert_eq!(blocks(&minified), blocks(&html));
+        assert!(minified.len() < html.len());
+        assert!(minified.starts_with("<h1>Building</h1><p>Run this:</p><pre>"));
+    }
+}
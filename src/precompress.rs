@@ -0,0 +1,115 @@
+use anyhow::Context;
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+// Smaller files aren't worth it, the headers alone are about this big.
+const MIN_SIZE: u64 = 1024;
+
+// Files that compress well. Images, video and fonts are already compressed.
+const TEXT_EXTENSIONS: &[&str] = &["html", "css", "js", "json", "xml", "txt", "svg", "map"];
+
+#[derive(Default)]
+pub struct PrecompressStats {
+    pub written: usize,
+    // Siblings carried over from the previous build because the file didn't change.
+    pub reused: usize,
+    // Total of what the siblings save over the originals.
+    pub bytes_saved: u64,
+}
+
+struct Encoding {
+    extension: &'static str,
+    compress: fn(&[u8]) -> anyhow::Result<Vec<u8>>,
+}
+
+const ENCODINGS: &[Encoding] = &[
+    Encoding {
+        extension: "gz",
+        compress: gzip,
+    },
+    #[cfg(feature = "brotli")]
+    Encoding {
+        extension: "br",
+        compress: brotli,
+    },
+];
+
+fn gzip(contents: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::best());
+    encoder.write_all(contents)?;
+    Ok(encoder.finish()?)
+}
+
+#[cfg(feature = "brotli")]
+fn brotli(contents: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut compressed = vec![];
+    let params = brotli::enc::BrotliEncoderParams {
+        quality: 11,
+        ..Default::default()
+    };
+    brotli::BrotliCompress(&mut &contents[..], &mut compressed, &params)?;
+    Ok(compressed)
+}
+
+// Writes .gz siblings, and .br ones with the brotli feature, next to the text files in
+// the manifest, for hosts that serve them to clients accepting the encoding, and adds them
+// to it. Siblings that don't save anything are left out. Unchanged files get theirs from
+// previous_dir when it has them, since compressing at the highest levels is slow.
+pub fn precompress(
+    out_dir: &Path,
+    previous_dir: &Path,
+    manifest: &mut BTreeSet<PathBuf>,
+) -> anyhow::Result<PrecompressStats> {
+    let files = manifest
+        .iter()
+        .filter(|relative| {
+            relative
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .is_some_and(|ext| TEXT_EXTENSIONS.contains(&ext))
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+    let mut stats = PrecompressStats::default();
+    for relative in files {
+        let file = out_dir.join(&relative);
+        // Symlinks from --symlinks preserve are left alone.
+        let metadata = std::fs::symlink_metadata(&file)?;
+        if !metadata.is_file() || metadata.len() < MIN_SIZE {
+            continue;
+        }
+        let contents = std::fs::read(&file).with_context(|| format!("read {}", file.display()))?;
+        let previous = previous_dir.join(&relative);
+        let unchanged = std::fs::read(&previous).is_ok_and(|old| old == contents);
+        for encoding in ENCODINGS {
+            let sibling = with_extension(&file, encoding.extension);
+            let previous_sibling = with_extension(&previous, encoding.extension);
+            if unchanged && std::fs::hard_link(&previous_sibling, &sibling).is_ok() {
+                let size = std::fs::metadata(&sibling)?.len();
+                stats.bytes_saved += (contents.len() as u64).saturating_sub(size);
+                stats.reused += 1;
+                manifest.insert(with_extension(&relative, encoding.extension));
+                continue;
+            }
+            let compressed = (encoding.compress)(&contents)?;
+            if compressed.len() >= contents.len() {
+                continue;
+            }
+            std::fs::write(&sibling, &compressed)
+                .with_context(|| format!("write {}", sibling.display()))?;
+            stats.bytes_saved += (contents.len() - compressed.len()) as u64;
+            stats.written += 1;
+            manifest.insert(with_extension(&relative, encoding.extension));
+        }
+    }
+    Ok(stats)
+}
+
+// foo.html -> foo.html.gz
+fn with_extension(path: &Path, extension: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".");
+    path.push(extension);
+    PathBuf::from(path)
+}
@@ -0,0 +1,84 @@
+use crate::document::{Document, PageContext};
+use crate::writer::SiteWriter;
+use crate::{config::Config, gen_blog, util};
+use std::path::Path;
+
+// Renders a single markdown file to index.html at the root of the output, with the same
+// templates the full build would use for it, minus the sidebar. Problems with the document
+// end up on the page instead of stopping the preview.
+#[tracing::instrument(skip_all, fields(source = ?source))]
+pub fn render_preview(
+    config: &Config,
+    source: &Path,
+    handlebars: &mut handlebars::Handlebars<'_>,
+    writer: &mut dyn SiteWriter,
+) -> anyhow::Result<()> {
+    let html = match render_document(config, source, handlebars) {
+        Ok(html) => html,
+        Err(err) => {
+            println!("Preview of {} failed: {:#}", source.display(), err);
+            error_page(source, &err)
+        }
+    };
+    util::write_file_as_folder_with_index(config, writer, &config.out_dir, html, false, source)?;
+    println!("Wrote preview of {}", source.display());
+    Ok(())
+}
+
+fn render_document(
+    config: &Config,
+    source: &Path,
+    handlebars: &mut handlebars::Handlebars<'_>,
+) -> anyhow::Result<String> {
+    let mut doc = Document::from_md(source, config)?;
+    let section = source
+        .parent()
+        .and_then(Path::file_name)
+        .map(util::filename_to_string)
+        .unwrap_or_default();
+
+    // Posts go through their section's templates like in gen_blog, anything else is a doc.
+    let html = match section.as_str() {
+        "blog" | "news" => {
+            let name = util::filename_to_string(source.file_name().unwrap_or_default());
+            doc.meta.section = section.clone();
+            gen_blog::meta_from_file_name(&mut doc.meta, &name);
+            doc.validate(&section, source, config)?;
+
+            let template_prefix = (section == "news").then_some("news");
+            let post_template = gen_blog::section_template(handlebars, template_prefix, "post");
+            let page_template = gen_blog::section_template(handlebars, template_prefix, "page");
+            let post_html = PageContext::from_document(&doc, &config.global_meta)
+                .render(&post_template, handlebars)?;
+            let mut context = PageContext::from_document(&doc, &config.global_meta);
+            context.contents = Some(post_html);
+            context.render_layout(&page_template, handlebars)?
+        }
+        _ => PageContext::from_document(&doc, &config.global_meta)
+            .render_layout("doc", handlebars)?,
+    };
+    Ok(config.prefix_root_links(html))
+}
+
+// Doesn't go through the templates, they might be what's broken. Still reloads on changes.
+fn error_page(source: &Path, err: &anyhow::Error) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>Preview failed</title>
+</head>
+<body>
+<h1>Couldn't render {}</h1>
+<pre>{}</pre>
+<script>
+    new EventSource("/__livereload").onmessage = function () {{ location.reload(); }};
+</script>
+</body>
+</html>
+"#,
+        handlebars::html_escape(&source.display().to_string()),
+        handlebars::html_escape(&format!("{err:#}"))
+    )
+}
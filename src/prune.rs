@@ -0,0 +1,240 @@
+use crate::util;
+use anyhow::Context;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+// Never pruned, hosts and certificate renewal put files there.
+const ALWAYS_PROTECTED: &[&str] = &["/.well-known/"];
+
+// Every file a build wrote, relative to its output directory. Symlinked folders, from
+// --symlinks preserve, count as one file. Leaves out the work folders of swap_output.
+pub fn manifest(out_dir: &Path) -> anyhow::Result<BTreeSet<PathBuf>> {
+    let mut files = BTreeSet::new();
+    collect_files(out_dir, out_dir, &mut files)?;
+    Ok(files)
+}
+
+fn collect_files(root: &Path, dir: &Path, files: &mut BTreeSet<PathBuf>) -> anyhow::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if dir == root && util::is_swap_dir(&entry.file_name()) {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            collect_files(root, &path, files)?;
+        } else {
+            files.insert(path.strip_prefix(root)?.to_path_buf());
+        }
+    }
+    Ok(())
+}
+
+// Links the files in previous_dir that the build didn't write into out_dir, so they survive
+// the swap like they would building in place: pages whose source is gone, and whatever the
+// host or deploy scripts put in the output. Returns how many there were.
+pub fn carry_over(
+    previous_dir: &Path,
+    out_dir: &Path,
+    manifest: &BTreeSet<PathBuf>,
+) -> anyhow::Result<usize> {
+    if !previous_dir.is_dir() {
+        return Ok(0);
+    }
+    let previous = self::manifest(previous_dir)?;
+    let mut carried = 0;
+    for relative in previous.difference(manifest) {
+        let path = out_dir.join(relative);
+        // The new build has something else there, or a folder on the way is a symlink that
+        // would put the file outside the output. Compressed copies of pages it wrote are stale.
+        let stale_sibling = relative
+            .extension()
+            .is_some_and(|ext| ext == "gz" || ext == "br")
+            && manifest.contains(&relative.with_extension(""));
+        let blocked = stale_sibling
+            || relative
+                .ancestors()
+                .filter(|ancestor| !ancestor.as_os_str().is_empty())
+                .any(|ancestor| out_dir.join(ancestor).is_symlink())
+            || path.symlink_metadata().is_ok();
+        if blocked {
+            continue;
+        }
+        let linked = match path.parent() {
+            Some(parent) => std::fs::create_dir_all(parent),
+            None => Ok(()),
+        }
+        .and_then(|()| std::fs::hard_link(previous_dir.join(relative), &path));
+        if linked.is_ok() {
+            carried += 1;
+        }
+    }
+    Ok(carried)
+}
+
+// Paths in out_dir that --prune leaves alone, in .gitignore syntax and relative to out_dir.
+pub fn protected_paths(out_dir: &Path, patterns: &[String]) -> anyhow::Result<Gitignore> {
+    let mut builder = GitignoreBuilder::new(out_dir);
+    let patterns = ALWAYS_PROTECTED
+        .iter()
+        .copied()
+        .chain(patterns.iter().map(String::as_str));
+    for pattern in patterns {
+        builder
+            .add_line(None, pattern)
+            .with_context(|| format!("protect pattern {pattern}"))?;
+    }
+    Ok(builder.build()?)
+}
+
+// Deletes the files in out_dir that aren't in the manifest or protected, and the folders
+// that leaves empty. Returns what was deleted, relative to out_dir, or with dry_run, what
+// would be, without deleting anything.
+pub fn prune(
+    out_dir: &Path,
+    manifest: &BTreeSet<PathBuf>,
+    protected: &Gitignore,
+    dry_run: bool,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let mut removed = vec![];
+    prune_dir(out_dir, out_dir, manifest, protected, dry_run, &mut removed)?;
+    Ok(removed)
+}
+
+// Whether dir ends up empty.
+fn prune_dir(
+    root: &Path,
+    dir: &Path,
+    manifest: &BTreeSet<PathBuf>,
+    protected: &Gitignore,
+    dry_run: bool,
+    removed: &mut Vec<PathBuf>,
+) -> anyhow::Result<bool> {
+    let mut empty = true;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let relative = path.strip_prefix(root)?.to_path_buf();
+        let is_dir = entry.file_type()?.is_dir();
+        let swap_dir = dir == root && util::is_swap_dir(&entry.file_name());
+        if swap_dir || protected.matched(&path, is_dir).is_ignore() {
+            empty = false;
+        } else if is_dir {
+            // Folders that were empty to begin with are left alone.
+            let before = removed.len();
+            if prune_dir(root, &path, manifest, protected, dry_run, removed)?
+                && removed.len() > before
+            {
+                if !dry_run {
+                    std::fs::remove_dir(&path)
+                        .with_context(|| format!("prune {}", path.display()))?;
+                }
+                removed.push(relative);
+            } else {
+                empty = false;
+            }
+        } else if manifest.contains(&relative) {
+            empty = false;
+        } else {
+            if !dry_run {
+                std::fs::remove_file(&path).with_context(|| format!("prune {}", path.display()))?;
+            }
+            removed.push(relative);
+        }
+    }
+    Ok(empty)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An output folder with what a build wrote, and leftovers from earlier ones.
+    fn test_output() -> (tempfile::TempDir, PathBuf, BTreeSet<PathBuf>) {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path().join("build");
+        let written = ["index.html", "docs/new-slug/index.html", "static/all.css"];
+        let leftovers = [
+            "docs/old-slug/index.html",
+            "docs/old-slug/img/shot.png",
+            "stale.html",
+            ".well-known/acme-challenge/token",
+            "CNAME",
+        ];
+        for file in written.iter().chain(&leftovers) {
+            let path = dir.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, file).unwrap();
+        }
+        std::fs::create_dir_all(dir.join("empty")).unwrap();
+        let manifest = written.iter().map(PathBuf::from).collect();
+        (temp, dir, manifest)
+    }
+
+    fn sorted(paths: &[&str]) -> Vec<PathBuf> {
+        let mut paths = paths.iter().map(PathBuf::from).collect::<Vec<_>>();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn prune_removes_leftovers() {
+        let (_temp, dir, manifest) = test_output();
+        let protected = protected_paths(&dir, &["/CNAME".to_owned()]).unwrap();
+        let mut removed = prune(&dir, &manifest, &protected, false).unwrap();
+        removed.sort();
+        assert_eq!(
+            removed,
+            sorted(&[
+                "docs/old-slug",
+                "docs/old-slug/img",
+                "docs/old-slug/img/shot.png",
+                "docs/old-slug/index.html",
+                "stale.html",
+            ])
+        );
+        assert!(!dir.join("docs/old-slug").exists());
+        assert!(!dir.join("stale.html").exists());
+        for kept in [
+            "index.html",
+            "docs/new-slug/index.html",
+            "static/all.css",
+            ".well-known/acme-challenge/token",
+            "CNAME",
+        ] {
+            assert!(dir.join(kept).is_file(), "{kept}");
+        }
+        assert!(dir.join("empty").is_dir());
+    }
+
+    #[test]
+    fn prune_dry_run_deletes_nothing() {
+        let (_temp, dir, manifest) = test_output();
+        let protected = protected_paths(&dir, &[]).unwrap();
+        let before = self::manifest(&dir).unwrap();
+        let removed = prune(&dir, &manifest, &protected, true).unwrap();
+        assert!(removed.contains(&PathBuf::from("docs/old-slug")));
+        assert!(removed.contains(&PathBuf::from("CNAME")));
+        assert_eq!(self::manifest(&dir).unwrap(), before);
+    }
+
+    #[test]
+    fn carry_over_links_leftovers() {
+        let (_temp, previous, _) = test_output();
+        let out_dir = previous.with_extension("new");
+        std::fs::create_dir_all(out_dir.join("docs/new-slug")).unwrap();
+        std::fs::write(out_dir.join("index.html"), "new").unwrap();
+        std::fs::write(out_dir.join("docs/new-slug/index.html"), "new").unwrap();
+        std::fs::write(previous.join("index.html.gz"), "old").unwrap();
+        let manifest = self::manifest(&out_dir).unwrap();
+
+        assert_eq!(carry_over(&previous, &out_dir, &manifest).unwrap(), 6);
+        assert_eq!(
+            std::fs::read_to_string(out_dir.join("index.html")).unwrap(),
+            "new"
+        );
+        assert!(out_dir.join("docs/old-slug/img/shot.png").is_file());
+        assert!(out_dir.join(".well-known/acme-challenge/token").is_file());
+    }
+}
@@ -1,78 +1,1740 @@
+use anyhow::Context;
 use axum::{
-    body::Body,
+    body::{Body, Bytes, HttpBody},
     extract::{Request, State},
-    http::{uri::Uri, HeaderValue},
-    response::{IntoResponse, Response},
-    routing::{on, MethodFilter},
+    http::{header, uri::Uri, HeaderValue, Method},
+    middleware::{self, Next},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    routing::get,
     Router,
 };
+use futures_util::Stream;
 use hyper::StatusCode;
 use hyper_util::{client::legacy::connect::HttpConnector, rt::TokioExecutor};
-use std::net::SocketAddr;
-use tower_http::{services::ServeDir, trace::TraceLayer};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    future::{Future, IntoFuture},
+    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+use tokio::sync::{broadcast, watch};
+use tower_http::{
+    compression::{
+        predicate::{NotForContentType, Predicate, SizeAbove},
+        CompressionLayer,
+    },
+    services::ServeDir,
+    trace::TraceLayer,
+};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 type Client = hyper_util::client::legacy::Client<HttpConnector, Body>;
 
-pub async fn spawn_server(port: u16) {
+// Pushed to the browsers over the livereload channel.
+#[derive(Clone, Debug)]
+pub enum ReloadEvent {
+    Reload,
+    BuildError(BuildFailure),
+}
+
+pub type ReloadSender = broadcast::Sender<ReloadEvent>;
+
+// Why the last rebuild failed, for the browser.
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct BuildFailure {
+    // The whole error chain.
+    pub message: String,
+    // The file to go fix, if known.
+    pub file: Option<String>,
+}
+
+// Set while the last rebuild failed, and served at /__build_error.
+type LastBuildError = watch::Receiver<Option<BuildFailure>>;
+
+// The generated site for --in-memory, keyed by path relative to the build directory, like
+// docs/index.html. Each rebuild swaps in a whole new map.
+pub type MemoryFiles = watch::Receiver<Arc<HashMap<String, Bytes>>>;
+pub type MemoryFilesSender = watch::Sender<Arc<HashMap<String, Bytes>>>;
+
+// The last build's stats, for /__stats.
+pub type LastBuildStats = watch::Receiver<Arc<crate::stats::BuildStats>>;
+pub type LastBuildStatsSender = watch::Sender<Arc<crate::stats::BuildStats>>;
+
+// Flips to true when the server should stop accepting connections.
+pub type ShutdownReceiver = watch::Receiver<bool>;
+
+// Which form of a directory URL is canonical, the other one redirects to it. Should match
+// the production server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum TrailingSlash {
+    // /blog redirects to /blog/.
+    Add,
+    // /blog/ redirects to /blog, which serves blog/index.html.
+    Strip,
+}
+
+// PEM files for serving HTTPS.
+#[cfg_attr(not(feature = "tls"), allow(dead_code))]
+pub struct TlsFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+pub struct ServerOptions {
+    pub host: IpAddr,
+    pub port: u16,
+    // Where the site to serve is.
+    pub build_dir: PathBuf,
+    // See Config::path_prefix.
+    pub path_prefix: String,
+    // Compress responses when the client accepts it.
+    pub compress: bool,
+    // Seconds files under /static/ may be cached for.
+    pub static_max_age: u32,
+    pub trailing_slash: TrailingSlash,
+    // 0 logs nothing, 1 a line per request, 2 adds headers and the file served.
+    pub verbosity: u8,
+    pub tls: Option<TlsFiles>,
+    pub proxy_rules: Vec<ProxyRule>,
+    // Serve from here when possible, instead of from the disk.
+    pub memory: Option<MemoryFiles>,
+    // Serves /__stats when set. Left out in production mode.
+    pub stats: Option<LastBuildStats>,
+    pub cors: Option<CorsOptions>,
+}
+
+// Returns once the server is listening, so callers can rely on it being up. The handle
+// finishes after a shutdown request, once in-flight responses are done.
+pub async fn spawn_server(
+    options: ServerOptions,
+    reload_tx: ReloadSender,
+    shutdown: ShutdownReceiver,
+) -> anyhow::Result<tokio::task::JoinHandle<()>> {
     tracing_subscriber::registry()
         .with(
             tracing_subscriber::EnvFilter::try_from_default_env()
                 .unwrap_or_else(|_| "example_static_file_server=debug,tower_http=debug".into()),
         )
         .with(tracing_subscriber::fmt::layer())
-        .init();
+        // Fails if --profile already set up tracing, in which case we keep that.
+        .try_init()
+        .ok();
 
-    tokio::spawn(server(port));
+    let addr = SocketAddr::new(options.host, options.port);
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("can't listen on {}", addr))?;
+    tracing::debug!("listening on {}", listener.local_addr()?);
+    Ok(tokio::spawn(server(options, listener, reload_tx, shutdown)))
 }
 
-async fn server(port: u16) {
-    let client: Client =
-        hyper_util::client::legacy::Client::<(), ()>::builder(TokioExecutor::new())
-            .build(HttpConnector::new());
-
-    let app = Router::new().nest_service("/", ServeDir::new("build"));
+async fn server(
+    options: ServerOptions,
+    listener: tokio::net::TcpListener,
+    reload_tx: ReloadSender,
+    shutdown: ShutdownReceiver,
+) {
+    // Mirror subdirectory hosting locally so the prefixed links resolve.
+    let root = if options.path_prefix.is_empty() {
+        "/"
+    } else {
+        &options.path_prefix
+    };
+    let header_options = HeaderOptions {
+        build_dir: options.build_dir.clone(),
+        path_prefix: options.path_prefix.clone(),
+        static_max_age: options.static_max_age,
+        trailing_slash: options.trailing_slash,
+        memory: options.memory.clone(),
+    };
+    let app = static_router(root, header_options);
+    let app = if options.compress { compress(app) } else { app };
     let app = app
-        .route(
-            "/api/*path",
-            on(
-                MethodFilter::GET.or(MethodFilter::POST),
-                reverse_proxy_handler,
-            ),
-        )
-        .with_state(client);
+        .merge(build_error_router(track_build_errors(&reload_tx)))
+        .merge(
+            Router::new()
+                .route("/__livereload", get(livereload_handler))
+                .with_state(reload_tx),
+        );
+    let app = match options.stats.clone() {
+        Some(stats) => app.merge(stats_router(stats)),
+        None => app,
+    };
+    // Outside the proxy, so proxied endpoints get the headers too.
+    let app = match options.cors.clone() {
+        Some(cors_options) => {
+            let state = CorsState {
+                options: cors_options,
+                path_prefix: options.path_prefix.clone(),
+            };
+            app.layer(middleware::from_fn_with_state(state, cors))
+        }
+        None => app,
+    };
+    // Off unless rules are configured.
+    let app = if options.proxy_rules.is_empty() {
+        app
+    } else {
+        let state = ProxyState {
+            client: hyper_util::client::legacy::Client::<(), ()>::builder(TokioExecutor::new())
+                .build(HttpConnector::new()),
+            rules: options.proxy_rules.clone(),
+        };
+        app.layer(middleware::from_fn_with_state(state, reverse_proxy))
+    };
 
-    let addr = SocketAddr::from(([127, 0, 0, 1], port));
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    tracing::debug!("listening on {}", listener.local_addr().unwrap());
-    axum::serve(listener, app.layer(TraceLayer::new_for_http()))
-        .await
-        .unwrap();
+    let app = if options.verbosity > 0 {
+        let log_options = LogOptions {
+            build_dir: options.build_dir.clone(),
+            path_prefix: options.path_prefix.clone(),
+            detailed: options.verbosity > 1,
+        };
+        app.layer(middleware::from_fn_with_state(log_options, access_log))
+    } else {
+        app
+    };
+
+    let app = app.layer(TraceLayer::new_for_http());
+    match options.tls {
+        #[cfg(feature = "tls")]
+        Some(tls) => {
+            let config = axum_server::tls_rustls::RustlsConfig::from_pem_file(&tls.cert, &tls.key)
+                .await
+                .unwrap();
+            let handle = axum_server::Handle::new();
+            let shutdown_handle = handle.clone();
+            let mut shutdown = shutdown;
+            tokio::spawn(async move {
+                let _ = shutdown.wait_for(|quit| *quit).await;
+                shutdown_handle.graceful_shutdown(Some(SHUTDOWN_TIMEOUT));
+            });
+            axum_server::from_tcp_rustls(listener.into_std().unwrap(), config)
+                .handle(handle)
+                .serve(app.into_make_service())
+                .await
+                .unwrap();
+        }
+        _ => {
+            let serve = axum::serve(listener, app)
+                .with_graceful_shutdown(wait_for_shutdown(shutdown.clone()))
+                .into_future();
+            drain_on_shutdown(async { serve.await.unwrap() }, shutdown).await;
+        }
+    }
+}
+
+// How long in-flight responses get to finish once shutdown is requested. Livereload
+// streams never finish on their own, so waiting for everything isn't an option.
+const SHUTDOWN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(3);
+
+async fn wait_for_shutdown(mut shutdown: ShutdownReceiver) {
+    // An error means the sender is gone, treat that as a shutdown request too.
+    let _ = shutdown.wait_for(|quit| *quit).await;
+}
+
+async fn drain_on_shutdown(serve: impl Future<Output = ()>, shutdown: ShutdownReceiver) {
+    tokio::pin!(serve);
+    tokio::select! {
+        _ = &mut serve => return,
+        _ = wait_for_shutdown(shutdown) => {}
+    }
+    if tokio::time::timeout(SHUTDOWN_TIMEOUT, serve).await.is_err() {
+        println!("Gave up waiting for open connections");
+    }
+}
+
+// The address other machines on the network would reach us at. Connecting a UDP socket
+// doesn't send anything, it just picks the outgoing interface.
+pub fn lan_ip() -> Option<IpAddr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect((Ipv4Addr::new(192, 0, 2, 1), 80)).ok()?;
+    Some(socket.local_addr().ok()?.ip())
+}
+
+// The process listening on a TCP port, like "pid 1234 (node)". Best effort, only works on
+// Linux and for processes we're allowed to look into.
+pub fn port_holder(port: u16) -> Option<String> {
+    if !cfg!(target_os = "linux") {
+        return None;
+    }
+    // Listening sockets in /proc/net/tcp look like "0: 00000000:0BB8 00000000:0000 0A ...",
+    // with the port in hex, 0A meaning LISTEN, and the socket inode in the tenth column.
+    let port_hex = format!(":{port:04X}");
+    let inode = ["/proc/net/tcp", "/proc/net/tcp6"]
+        .iter()
+        .filter_map(|table| std::fs::read_to_string(table).ok())
+        .flat_map(|table| {
+            table
+                .lines()
+                .skip(1)
+                .filter_map(|line| {
+                    let fields = line.split_whitespace().collect::<Vec<_>>();
+                    (fields.get(1)?.ends_with(&port_hex) && *fields.get(3)? == "0A")
+                        .then(|| fields.get(9).map(|inode| inode.to_string()))?
+                })
+                .collect::<Vec<_>>()
+        })
+        .next()?;
+
+    let socket = format!("socket:[{inode}]");
+    for process in std::fs::read_dir("/proc").ok()?.flatten() {
+        let Ok(fds) = std::fs::read_dir(process.path().join("fd")) else {
+            continue;
+        };
+        let holds_socket = fds.flatten().any(|fd| {
+            std::fs::read_link(fd.path()).is_ok_and(|target| target.as_os_str() == socket.as_str())
+        });
+        if holds_socket {
+            let name = std::fs::read_to_string(process.path().join("comm")).unwrap_or_default();
+            return Some(format!(
+                "pid {} ({})",
+                process.file_name().to_string_lossy(),
+                name.trim()
+            ));
+        }
+    }
+    None
+}
+
+// URLs to print at startup. Binding to all interfaces means both localhost and the LAN.
+pub fn reachable_urls(scheme: &str, host: IpAddr, port: u16, path_prefix: &str) -> Vec<String> {
+    let hosts = if host.is_unspecified() {
+        let mut hosts = vec![IpAddr::V4(Ipv4Addr::LOCALHOST)];
+        hosts.extend(lan_ip());
+        hosts
+    } else {
+        vec![host]
+    };
+    hosts
+        .into_iter()
+        .map(|host| format!("{scheme}://{}{}/", SocketAddr::new(host, port), path_prefix))
+        .collect()
+}
+
+// By the file that's served, so directories get the type of their index.html, whatever the
+// URL looked like.
+fn content_type_for(file: &Path) -> &'static str {
+    let extension = file.extension().unwrap_or_default().to_string_lossy();
+    match extension.to_ascii_lowercase().as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" | "map" => "application/json; charset=utf-8",
+        "xml" => "application/xml; charset=utf-8",
+        "txt" => "text/plain; charset=utf-8",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "ico" => "image/x-icon",
+        "woff2" => "font/woff2",
+        "wasm" => "application/wasm",
+        "mp4" => "video/mp4",
+        _ => "application/octet-stream",
+    }
+}
+
+// A year, the most browsers and CDNs go by.
+const IMMUTABLE_MAX_AGE: u32 = 365 * 24 * 60 * 60;
+
+// Whether a file name has a content hash in it, like style.3f2a9c1b.css. At least 8 hex digits
+// between the name and the extension.
+pub fn is_fingerprinted(path: &str) -> bool {
+    let name = path.rsplit('/').next().unwrap_or_default();
+    let mut parts = name.rsplit('.');
+    let (Some(extension), Some(hash), Some(stem)) = (parts.next(), parts.next(), parts.next())
+    else {
+        return false;
+    };
+    !extension.is_empty()
+        && !stem.is_empty()
+        && hash.len() >= 8
+        && hash.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+// How long browsers may keep a file, given its path relative to the site root. Kept apart
+// from the middleware so the production header config can be derived from it too.
+pub fn cache_control_for(path: &str, static_max_age: u32) -> String {
+    if is_fingerprinted(path) {
+        // A new version gets a new name, so this one never changes.
+        format!("public, max-age={IMMUTABLE_MAX_AGE}, immutable")
+    } else if path.starts_with("/static/") && !path.ends_with('/') {
+        format!("public, max-age={static_max_age}")
+    } else {
+        // HTML, feeds and the API files change with every build.
+        "no-cache".to_owned()
+    }
+}
+
+#[derive(Clone)]
+struct HeaderOptions {
+    build_dir: PathBuf,
+    path_prefix: String,
+    static_max_age: u32,
+    trailing_slash: TrailingSlash,
+    memory: Option<MemoryFiles>,
+}
+
+// Serves the build output. GET and HEAD resolve the same way, anything else gets a 405
+// with an Allow header from ServeDir.
+fn static_router<S>(root: &str, header_options: HeaderOptions) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    // Serves the siblings from --precompress, like production, when the client accepts them.
+    let serve_dir = ServeDir::new(&header_options.build_dir)
+        .precompressed_br()
+        .precompressed_gzip();
+    Router::new()
+        .nest_service(root, serve_dir)
+        .layer(middleware::from_fn_with_state(
+            header_options,
+            static_headers,
+        ))
+}
+
+// Compresses responses that weren't precompressed, unless they're small or already
+// compressed formats. Images other than SVG are excluded by IMAGES.
+fn compress<S>(app: Router<S>) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    let predicate = SizeAbove::new(1024)
+        .and(NotForContentType::IMAGES)
+        .and(NotForContentType::SSE)
+        .and(NotForContentType::const_new("font/woff2"))
+        .and(NotForContentType::const_new("application/zip"));
+    app.layer(CompressionLayer::new().compress_when(predicate))
+}
+
+// The site's own 404 page, once generated. Always the same file, so the requested path
+// has no say in what gets read here.
+async fn not_found_response(build_dir: &Path, head: bool) -> Response {
+    let body = match resolve_path(build_dir, "/404.html") {
+        Some(file) => tokio::fs::read(file).await.ok(),
+        None => None,
+    };
+    let body = body.unwrap_or_else(|| {
+        b"<!doctype html><html><body><h1>404 Not Found</h1></body></html>".to_vec()
+    });
+    let content_length = body.len().to_string();
+    // HEAD gets the same headers, just without the body.
+    let body = if head { vec![] } else { body };
+    (
+        StatusCode::NOT_FOUND,
+        [
+            (header::CONTENT_TYPE, "text/html; charset=utf-8".to_owned()),
+            (header::CONTENT_LENGTH, content_length),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+// Redirects directory URLs to their canonical form, or for TrailingSlash::Strip, rewrites
+// the request so ServeDir finds the index.html. Other paths pass through untouched.
+fn canonicalize_slash(options: &HeaderOptions, req: &mut Request) -> Option<Response> {
+    let path = req.uri().path();
+    let site_path = path.strip_prefix(&options.path_prefix).unwrap_or(path);
+    let relative = site_path.trim_matches('/');
+    if relative.is_empty()
+        || !resolve_path(&options.build_dir, &format!("/{relative}/")).is_some_and(|f| f.is_file())
+    {
+        return None;
+    }
+    let query = req.uri().query().map_or(String::new(), |q| format!("?{q}"));
+    let location = match (options.trailing_slash, path.ends_with('/')) {
+        (TrailingSlash::Add, false) => format!("{path}/{query}"),
+        (TrailingSlash::Strip, true) => format!("{}{query}", path.trim_end_matches('/')),
+        (TrailingSlash::Strip, false) => {
+            let uri = format!("{path}/{query}");
+            *req.uri_mut() = Uri::try_from(uri).ok()?;
+            return None;
+        }
+        (TrailingSlash::Add, true) => return None,
+    };
+    Some(
+        (
+            StatusCode::MOVED_PERMANENTLY,
+            [(header::LOCATION, location)],
+        )
+            .into_response(),
+    )
 }
 
-async fn reverse_proxy_handler(
-    State(client): State<Client>,
+// Applied to everything served from the build directory.
+async fn static_headers(
+    State(options): State<HeaderOptions>,
     mut req: Request,
-) -> Result<Response, StatusCode> {
+    next: Next,
+) -> Response {
+    // ServeDir handles single byte ranges, but rejects multiple ones. A full response is a
+    // valid answer to those too, so just drop the header.
+    if req
+        .headers()
+        .get(header::RANGE)
+        .is_some_and(|range| range.as_bytes().contains(&b','))
+    {
+        req.headers_mut().remove(header::RANGE);
+    }
+    let head = req.method() == Method::HEAD;
+    let path = req.uri().path().to_string();
+    let site_path = path.strip_prefix(&options.path_prefix).unwrap_or(&path);
+    // ServeDir has its own checks, but everything else here touches the disk too.
+    let Some(file) = resolve_path(&options.build_dir, site_path) else {
+        return not_found_response(&options.build_dir, head).await;
+    };
+    if let Some(redirect) = canonicalize_slash(&options, &mut req) {
+        return redirect;
+    }
+    let etag = etag_for(&file).await;
+
+    // ServeDir answers If-Modified-Since on its own, If-None-Match is up to us.
+    let if_none_match = req.headers().get(header::IF_NONE_MATCH);
+    let mut response = match (&etag, if_none_match) {
+        (Some(etag), Some(if_none_match)) if etag_matches(etag, if_none_match) => {
+            StatusCode::NOT_MODIFIED.into_response()
+        }
+        _ => match memory_response(&options, &req, &file) {
+            Some(response) => response,
+            None => next.run(req).await,
+        },
+    };
+    if response.status() == StatusCode::NOT_FOUND {
+        return not_found_response(&options.build_dir, head).await;
+    }
+    if response.status().is_success() {
+        response.headers_mut().insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static(content_type_for(&file)),
+        );
+    }
+    if response.status().is_success() || response.status() == StatusCode::NOT_MODIFIED {
+        if let Ok(value) =
+            HeaderValue::from_str(&cache_control_for(site_path, options.static_max_age))
+        {
+            response.headers_mut().insert(header::CACHE_CONTROL, value);
+        }
+        if let Some(etag) = etag {
+            response.headers_mut().insert(header::ETAG, etag);
+        }
+    }
+    response
+}
+
+// Plain GETs and HEADs of files in the --in-memory map. Everything else, like range
+// requests, large files and other methods, is left to ServeDir.
+fn memory_response(options: &HeaderOptions, req: &Request, file: &Path) -> Option<Response> {
+    let memory = options.memory.as_ref()?;
+    let head = req.method() == Method::HEAD;
+    if !(req.method() == Method::GET || head) || req.headers().contains_key(header::RANGE) {
+        return None;
+    }
+    let key = file
+        .strip_prefix(&options.build_dir)
+        .ok()?
+        .to_string_lossy()
+        .replace('\\', "/");
+    let contents = memory.borrow().get(&key)?.clone();
+    // The copy on disk is written along with the one in memory, like ServeDir this goes by it.
+    let modified = std::fs::metadata(file)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .map(chrono::DateTime::<chrono::Utc>::from);
+    let last_modified = modified.and_then(|modified| {
+        HeaderValue::from_str(&modified.format("%a, %d %b %Y %H:%M:%S GMT").to_string()).ok()
+    });
+    // If-None-Match wins when both are sent, and was already answered if it matched.
+    let if_modified_since = req
+        .headers()
+        .get(header::IF_MODIFIED_SINCE)
+        .filter(|_| !req.headers().contains_key(header::IF_NONE_MATCH))
+        .and_then(|since| chrono::DateTime::parse_from_rfc2822(since.to_str().ok()?).ok());
+    if let (Some(modified), Some(since), Some(last_modified)) =
+        (modified, if_modified_since, &last_modified)
+    {
+        if modified.timestamp() <= since.timestamp() {
+            let headers = [(header::LAST_MODIFIED, last_modified.clone())];
+            return Some((StatusCode::NOT_MODIFIED, headers).into_response());
+        }
+    }
+    let content_length = contents.len().to_string();
+    // HEAD gets the same headers, just without the body.
+    let body = if head { Bytes::new() } else { contents };
+    let mut response = ([(header::CONTENT_LENGTH, content_length)], body).into_response();
+    if let Some(last_modified) = last_modified {
+        response
+            .headers_mut()
+            .insert(header::LAST_MODIFIED, last_modified);
+    }
+    Some(response)
+}
+
+// A weak validator from the file's size and modification time, no need to read it.
+async fn etag_for(file: &Path) -> Option<HeaderValue> {
+    let metadata = tokio::fs::metadata(file).await.ok()?;
+    let modified = metadata
+        .modified()
+        .ok()?
+        .duration_since(std::time::UNIX_EPOCH)
+        .ok()?;
+    HeaderValue::from_str(&format!(
+        "W/\"{:x}-{:x}\"",
+        metadata.len(),
+        modified.as_nanos()
+    ))
+    .ok()
+}
+
+// If-None-Match holds a list of tags, or *. Weak comparison, so W/ prefixes don't matter.
+fn etag_matches(etag: &HeaderValue, if_none_match: &HeaderValue) -> bool {
+    let (Ok(etag), Ok(if_none_match)) = (etag.to_str(), if_none_match.to_str()) else {
+        return false;
+    };
+    let etag = etag.trim_start_matches("W/");
+    if_none_match
+        .split(',')
+        .map(str::trim)
+        .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == etag)
+}
+
+#[derive(Clone)]
+struct LogOptions {
+    build_dir: PathBuf,
+    path_prefix: String,
+    detailed: bool,
+}
+
+// One access log line, with the status colored by class.
+fn format_log_line(
+    method: &Method,
+    path: &str,
+    status: StatusCode,
+    size: Option<u64>,
+    duration: std::time::Duration,
+) -> String {
+    let color = match status.as_u16() {
+        200..=299 => "32",
+        300..=399 => "36",
+        400..=499 => "33",
+        _ => "31",
+    };
+    let size = size.map_or("-".to_owned(), |size| format!("{size}B"));
+    format!(
+        "\x1b[{color}m{}\x1b[0m {method} {path} {size} {:.1}ms",
+        status.as_u16(),
+        duration.as_secs_f64() * 1000.0
+    )
+}
+
+// Longer request paths are turned away outright, nothing on the site comes close.
+const MAX_PATH_LEN: usize = 1024;
+
+// The one place request paths become file paths. Returns where ServeDir would look for a
+// path, or None if it has no business being served: malformed escapes, NUL bytes,
+// backslashes, too long, leaving the build directory, whether through .. or a symlink, or
+// the work folders of a build being swapped in.
+// Callers answer None with a plain 404, so probing can't tell it apart from a missing file.
+fn resolve_path(build_dir: &Path, site_path: &str) -> Option<PathBuf> {
+    if site_path.len() > MAX_PATH_LEN {
+        return None;
+    }
+    let decoded = crate::util::percent_decode(site_path)?;
+    if decoded.contains(['\0', '\\']) {
+        return None;
+    }
+    let mut file = build_dir.to_path_buf();
+    for part in decoded.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => return None,
+            _ => {
+                // Catches drive letters and such, which would replace the whole path on Windows.
+                let mut components = Path::new(part).components();
+                if !matches!(
+                    (components.next(), components.next()),
+                    (Some(Component::Normal(_)), None)
+                ) {
+                    return None;
+                }
+                if file == build_dir && crate::util::is_swap_dir(part.as_ref()) {
+                    return None;
+                }
+                file.push(part);
+            }
+        }
+    }
+    if decoded.ends_with('/') || file.is_dir() {
+        file.push("index.html");
+    }
+    if let (Ok(real), Ok(root)) = (file.canonicalize(), build_dir.canonicalize()) {
+        if !real.starts_with(root) {
+            return None;
+        }
+    }
+    Some(file)
+}
+
+async fn access_log(State(options): State<LogOptions>, req: Request, next: Next) -> Response {
+    let start = std::time::Instant::now();
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let request_headers = options.detailed.then(|| req.headers().clone());
+    let response = next.run(req).await;
+
+    let size = response
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok()?.parse().ok())
+        .or_else(|| HttpBody::size_hint(response.body()).exact());
+    println!(
+        "{}",
+        format_log_line(&method, &path, response.status(), size, start.elapsed())
+    );
+    if let Some(request_headers) = request_headers {
+        let site_path = path.strip_prefix(&options.path_prefix).unwrap_or(&path);
+        match resolve_path(&options.build_dir, site_path) {
+            Some(file) => println!("  file: {}", file.display()),
+            None => println!("  file: (rejected)"),
+        }
+        for (name, value) in &request_headers {
+            println!("  > {}: {}", name, value.to_str().unwrap_or("<binary>"));
+        }
+        for (name, value) in response.headers() {
+            println!("  < {}: {}", name, value.to_str().unwrap_or("<binary>"));
+        }
+    }
+    response
+}
+
+// Server-sent events telling the pages injected with the livereload script to reload.
+async fn livereload_handler(
+    State(reload_tx): State<ReloadSender>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let stream = futures_util::stream::unfold(reload_tx.subscribe(), |mut rx| async move {
+        let event = match rx.recv().await {
+            Ok(ReloadEvent::Reload) | Err(broadcast::error::RecvError::Lagged(_)) => {
+                Event::default().data("reload")
+            }
+            Ok(ReloadEvent::BuildError(failure)) => Event::default()
+                .event("build-error")
+                .data(serde_json::to_string(&failure).unwrap()),
+            Err(broadcast::error::RecvError::Closed) => return None,
+        };
+        Some((Ok(event), rx))
+    });
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+// Follows the rebuild results, so pages loaded after a failed rebuild can still show the
+// error. The next successful rebuild clears it.
+fn track_build_errors(reload_tx: &ReloadSender) -> LastBuildError {
+    let (error_tx, error_rx) = watch::channel(None);
+    let mut events = reload_tx.subscribe();
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(ReloadEvent::Reload) => {
+                    error_tx.send_replace(None);
+                }
+                Ok(ReloadEvent::BuildError(failure)) => {
+                    error_tx.send_replace(Some(failure));
+                }
+                Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+    error_rx
+}
+
+// The livereload script checks /__build_error.json when a page loads, and shows an overlay
+// linking to the full page at /__build_error.
+fn build_error_router(errors: LastBuildError) -> Router {
+    Router::new()
+        .route("/__build_error", get(build_error_page_handler))
+        .route("/__build_error.json", get(build_error_handler))
+        .with_state(errors)
+}
+
+async fn build_error_handler(State(errors): State<LastBuildError>) -> Response {
+    let json = serde_json::to_string(&*errors.borrow()).unwrap();
+    ([(header::CONTENT_TYPE, "application/json")], json).into_response()
+}
+
+async fn build_error_page_handler(State(errors): State<LastBuildError>) -> Response {
+    let Some(failure) = errors.borrow().clone() else {
+        return (StatusCode::NOT_FOUND, "The last build succeeded.").into_response();
+    };
+    let file = match &failure.file {
+        Some(file) => format!("<p>In <mark>{}</mark></p>\n", handlebars::html_escape(file)),
+        None => String::new(),
+    };
+    let html = format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>Build failed</title>
+</head>
+<body>
+<h1>Build failed</h1>
+{file}<pre>{}</pre>
+<p>Still serving the last good build. This page reloads when the next build is done.</p>
+<script>
+    new EventSource("/__livereload").onmessage = function () {{ location.reload(); }};
+</script>
+</body>
+</html>
+"#,
+        handlebars::html_escape(&failure.message)
+    );
+    axum::response::Html(html).into_response()
+}
+
+// The last build's stats as JSON, and as a table at /__stats.html.
+fn stats_router(stats: LastBuildStats) -> Router {
+    Router::new()
+        .route("/__stats", get(stats_handler))
+        .route("/__stats.html", get(stats_page_handler))
+        .with_state(stats)
+}
+
+async fn stats_handler(State(stats): State<LastBuildStats>) -> Response {
+    let json = serde_json::to_string_pretty(stats.borrow().as_ref()).unwrap();
+    ([(header::CONTENT_TYPE, "application/json")], json).into_response()
+}
+
+async fn stats_page_handler(State(stats): State<LastBuildStats>) -> Response {
+    let html = stats.borrow().to_html();
+    axum::response::Html(html).into_response()
+}
+
+// Lets pages on other origins, like the app or forum widgets under test, fetch the JSON
+// endpoints from the local server.
+#[derive(Clone, Debug)]
+pub struct CorsOptions {
+    // Sent as Access-Control-Allow-Origin, like * or http://localhost:8080.
+    pub origin: HeaderValue,
+    // Site paths that get the headers, besides everything under /api/. A trailing slash
+    // matches everything below.
+    pub paths: Vec<String>,
+}
+
+impl CorsOptions {
+    fn applies_to(&self, site_path: &str) -> bool {
+        site_path.starts_with("/api/")
+            || self.paths.iter().any(|path| {
+                if path.ends_with('/') {
+                    site_path.starts_with(path.as_str())
+                } else {
+                    site_path == path
+                }
+            })
+    }
+}
+
+// Checks an origin given to --cors.
+pub fn parse_cors_origin(origin: &str) -> Result<HeaderValue, String> {
+    HeaderValue::from_str(origin).map_err(|_| format!("bad origin {origin}"))
+}
+
+#[derive(Clone)]
+struct CorsState {
+    options: CorsOptions,
+    path_prefix: String,
+}
+
+async fn cors(State(state): State<CorsState>, req: Request, next: Next) -> Response {
+    let path = req.uri().path();
+    let site_path = path.strip_prefix(&state.path_prefix).unwrap_or(path);
+    if !state.options.applies_to(site_path) {
+        return next.run(req).await;
+    }
+    let origin = state.options.origin.clone();
+
+    // Answer preflights here, the static files don't know about OPTIONS.
+    if req.method() == Method::OPTIONS
+        && req
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+    {
+        let mut response = StatusCode::NO_CONTENT.into_response();
+        let headers = response.headers_mut();
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        headers.insert(
+            header::ACCESS_CONTROL_ALLOW_METHODS,
+            HeaderValue::from_static("GET, HEAD, OPTIONS"),
+        );
+        if let Some(requested) = req.headers().get(header::ACCESS_CONTROL_REQUEST_HEADERS) {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, requested.clone());
+        }
+        headers.insert(
+            header::ACCESS_CONTROL_MAX_AGE,
+            HeaderValue::from_static("86400"),
+        );
+        return response;
+    }
+
+    let mut response = next.run(req).await;
+    // Pages are never meant to be read cross-origin, even when listed.
+    let is_html = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("text/html"));
+    if !is_html {
+        response
+            .headers_mut()
+            .insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+    }
+    response
+}
+
+// Forwards requests under a path prefix to another server, for endpoints like /api/ that
+// only exist in production.
+#[derive(Clone, Debug)]
+pub struct ProxyRule {
+    pub prefix: String,
+    // Scheme and authority, plus an optional base path without a trailing slash.
+    pub upstream: String,
+}
+
+// Parses PREFIX=URL, as given to --proxy.
+pub fn parse_proxy_rule(rule: &str) -> Result<ProxyRule, String> {
+    let (prefix, upstream) = rule
+        .split_once('=')
+        .ok_or_else(|| format!("expected PREFIX=URL, got {rule}"))?;
+    if !prefix.starts_with('/') {
+        return Err(format!("proxy prefix {prefix} should start with /"));
+    }
+    let uri = Uri::try_from(upstream).map_err(|err| format!("bad upstream {upstream}: {err}"))?;
+    // The client only speaks plain HTTP.
+    if uri.scheme_str() != Some("http") || uri.authority().is_none() {
+        return Err(format!(
+            "upstream {upstream} should look like http://host[:port]"
+        ));
+    }
+    Ok(ProxyRule {
+        prefix: prefix.to_owned(),
+        upstream: upstream.trim_end_matches('/').to_owned(),
+    })
+}
+
+#[derive(Clone)]
+struct ProxyState {
+    client: Client,
+    rules: Vec<ProxyRule>,
+}
+
+// Connection-level headers, which apply to a single hop and mustn't be passed along.
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+fn strip_hop_by_hop(headers: &mut header::HeaderMap) {
+    for name in HOP_BY_HOP_HEADERS {
+        headers.remove(*name);
+    }
+}
+
+async fn reverse_proxy(State(state): State<ProxyState>, mut req: Request, next: Next) -> Response {
     let path = req.uri().path();
+    // The longest matching prefix wins.
+    let Some(rule) = state
+        .rules
+        .iter()
+        .filter(|rule| path.starts_with(&rule.prefix))
+        .max_by_key(|rule| rule.prefix.len())
+    else {
+        return next.run(req).await;
+    };
     let path_query = req.uri().path_and_query().map_or(path, |v| v.as_str());
+    let uri = format!("{}{}", rule.upstream, path_query);
+    let bad_gateway = |message: String| {
+        println!("{}", message);
+        (StatusCode::BAD_GATEWAY, message).into_response()
+    };
+
+    *req.uri_mut() = match Uri::try_from(&uri) {
+        Ok(uri) => uri,
+        Err(err) => return bad_gateway(format!("Proxy error for {uri}: {err}")),
+    };
+    let authority = req.uri().authority().unwrap().as_str().to_owned();
+    strip_hop_by_hop(req.headers_mut());
+    req.headers_mut()
+        .insert(header::HOST, HeaderValue::from_str(&authority).unwrap());
+
+    match state.client.request(req).await {
+        Ok(response) => {
+            // The body streams through as it arrives.
+            let mut response = response.map(Body::new);
+            strip_hop_by_hop(response.headers_mut());
+            response
+        }
+        Err(err) => bad_gateway(format!("Proxy error for {uri}: {err:?}")),
+    }
+}
 
-    // let proxy_url = "http://127.0.0.1:3000";
-    // let proxy_url = "http://46.246.45.220:9101";
-    let proxy_url = "http://centraldev.ppsspp.org";
+#[cfg(test)]
+mod tests {
+    use super::{
+        build_error_router, cache_control_for, compress, cors, format_log_line, is_fingerprinted,
+        parse_proxy_rule, resolve_path, reverse_proxy, static_router, stats_router,
+        track_build_errors, BuildFailure, CorsOptions, CorsState, HeaderOptions, ProxyState,
+        TrailingSlash,
+    };
+    use axum::{
+        body::{to_bytes, Body},
+        http::{header, Method, Request, StatusCode},
+        response::Response,
+    };
+    use hyper_util::{client::legacy::connect::HttpConnector, rt::TokioExecutor};
+    use std::path::Path;
+    use tower::ServiceExt;
 
-    let uri = format!("{}{}", proxy_url, path_query);
+    // A throwaway build directory with an index, a page and a 404 page.
+    fn test_build_dir() -> tempfile::TempDir {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        std::fs::create_dir_all(dir.join("docs")).unwrap();
+        std::fs::write(dir.join("index.html"), "<h1>Home</h1>").unwrap();
+        std::fs::write(dir.join("docs/index.html"), "<h1>Docs</h1>").unwrap();
+        std::fs::write(dir.join("404.html"), "<h1>Lost</h1>").unwrap();
+        let video = (0..1000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        std::fs::write(dir.join("video.mp4"), video).unwrap();
+        temp
+    }
 
-    *req.uri_mut() = Uri::try_from(uri).unwrap();
-    if let Some(host) = req.headers_mut().get_mut("host") {
-        *host = HeaderValue::from_static("centraldev.ppsspp.org:80");
+    fn test_options(dir: &Path) -> HeaderOptions {
+        HeaderOptions {
+            build_dir: dir.to_path_buf(),
+            path_prefix: String::new(),
+            static_max_age: 3600,
+            trailing_slash: TrailingSlash::Add,
+            memory: None,
+        }
     }
 
-    println!("{:#?}", *req.headers());
+    async fn send(options: HeaderOptions, request: Request<Body>) -> Response {
+        static_router::<()>("/", options)
+            .oneshot(request)
+            .await
+            .unwrap()
+    }
 
-    Ok(client
-        .request(req)
-        .await
-        .map_err(|_| StatusCode::BAD_REQUEST)?
-        .into_response())
+    async fn request(dir: &Path, method: Method, path: &str) -> Response {
+        request_with_range(dir, method, path, None).await
+    }
+
+    async fn request_with_range(
+        dir: &Path,
+        method: Method,
+        path: &str,
+        range: Option<&str>,
+    ) -> Response {
+        let mut request = Request::builder().method(method).uri(path);
+        if let Some(range) = range {
+            request = request.header(header::RANGE, range);
+        }
+        let request = request.body(Body::empty()).unwrap();
+        send(test_options(dir), request).await
+    }
+
+    fn content_length(response: &Response) -> &str {
+        response.headers()[header::CONTENT_LENGTH].to_str().unwrap()
+    }
+
+    async fn body_len(response: Response) -> usize {
+        to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap()
+            .len()
+    }
+
+    #[tokio::test]
+    async fn head_matches_get_without_body() {
+        let temp = test_build_dir();
+        let dir = temp.path();
+        for path in ["/index.html", "/docs/"] {
+            let get = request(dir, Method::GET, path).await;
+            let head = request(dir, Method::HEAD, path).await;
+            assert_eq!(head.status(), StatusCode::OK, "{path}");
+            assert_eq!(content_length(&head), content_length(&get), "{path}");
+            assert_eq!(
+                head.headers()[header::CONTENT_TYPE],
+                get.headers()[header::CONTENT_TYPE]
+            );
+            assert_eq!(body_len(head).await, 0, "{path}");
+            assert!(body_len(get).await > 0, "{path}");
+        }
+    }
+
+    #[tokio::test]
+    async fn head_on_missing_path() {
+        let temp = test_build_dir();
+        let dir = temp.path();
+        let head = request(dir, Method::HEAD, "/nope").await;
+        assert_eq!(head.status(), StatusCode::NOT_FOUND);
+        assert_eq!(content_length(&head), "13");
+        assert_eq!(body_len(head).await, 0);
+        let get = request(dir, Method::GET, "/nope").await;
+        assert_eq!(body_len(get).await, 13);
+    }
+
+    #[tokio::test]
+    async fn other_methods_are_rejected() {
+        let temp = test_build_dir();
+        let dir = temp.path();
+        let response = request(dir, Method::DELETE, "/index.html").await;
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert!(response.headers().contains_key(header::ALLOW));
+    }
+
+    #[test]
+    fn log_lines() {
+        let duration = std::time::Duration::from_micros(1500);
+        assert_eq!(
+            format_log_line(
+                &Method::GET,
+                "/docs/",
+                StatusCode::OK,
+                Some(10688),
+                duration
+            ),
+            "\x1b[32m200\x1b[0m GET /docs/ 10688B 1.5ms"
+        );
+        assert_eq!(
+            format_log_line(
+                &Method::HEAD,
+                "/nope",
+                StatusCode::NOT_FOUND,
+                None,
+                duration
+            ),
+            "\x1b[33m404\x1b[0m HEAD /nope - 1.5ms"
+        );
+    }
+
+    #[test]
+    fn html_and_feeds_are_revalidated() {
+        for path in [
+            "/",
+            "/index.html",
+            "/docs/intro/",
+            "/blog/atom.xml",
+            "/rss.xml",
+        ] {
+            assert_eq!(cache_control_for(path, 3600), "no-cache", "{path}");
+        }
+    }
+
+    #[test]
+    fn static_assets_are_cached() {
+        assert_eq!(
+            cache_control_for("/static/css/style.css", 3600),
+            "public, max-age=3600"
+        );
+        assert_eq!(
+            cache_control_for("/static/img/logo.png", 60),
+            "public, max-age=60"
+        );
+    }
+
+    #[test]
+    fn fingerprinted_files_are_immutable() {
+        for path in [
+            "/static/css/style.3f2a9c1b.css",
+            "/static/js/app.0123456789abcdef.js",
+            "/favicon.deadbeef.ico",
+        ] {
+            assert_eq!(
+                cache_control_for(path, 3600),
+                "public, max-age=31536000, immutable",
+                "{path}"
+            );
+        }
+        for path in [
+            "/static/css/style.css",
+            "/static/css/style.3f2a9c.css",
+            "/static/img/shot.2024-01-01.png",
+            "/static/js/.3f2a9c1b.js",
+            "/docs/3f2a9c1b.deadbeef/",
+        ] {
+            assert!(!is_fingerprinted(path), "{path}");
+        }
+    }
+
+    #[test]
+    fn static_lookalikes_are_not_cached() {
+        assert_eq!(cache_control_for("/docs/static/", 3600), "no-cache");
+        assert_eq!(cache_control_for("/staticfoo/a.css", 3600), "no-cache");
+    }
+
+    async fn range_request(dir: &Path, range: &str) -> Response {
+        request_with_range(dir, Method::GET, "/video.mp4", Some(range)).await
+    }
+
+    fn header_str(response: &Response, name: header::HeaderName) -> &str {
+        response.headers()[name].to_str().unwrap()
+    }
+
+    #[tokio::test]
+    async fn ranges_are_served() {
+        let temp = test_build_dir();
+        let dir = temp.path();
+
+        let full = request(dir, Method::GET, "/video.mp4").await;
+        assert_eq!(header_str(&full, header::ACCEPT_RANGES), "bytes");
+
+        let open_ended = range_request(dir, "bytes=100-").await;
+        assert_eq!(open_ended.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            header_str(&open_ended, header::CONTENT_RANGE),
+            "bytes 100-999/1000"
+        );
+        assert_eq!(body_len(open_ended).await, 900);
+
+        let bounded = range_request(dir, "bytes=10-19").await;
+        assert_eq!(bounded.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            header_str(&bounded, header::CONTENT_RANGE),
+            "bytes 10-19/1000"
+        );
+        let body = to_bytes(bounded.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], &(10..20).collect::<Vec<u8>>()[..]);
+    }
+
+    #[tokio::test]
+    async fn unsatisfiable_and_multi_ranges() {
+        let temp = test_build_dir();
+        let dir = temp.path();
+
+        let out_of_bounds = range_request(dir, "bytes=5000-").await;
+        assert_eq!(out_of_bounds.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(
+            header_str(&out_of_bounds, header::CONTENT_RANGE),
+            "bytes */1000"
+        );
+
+        let multi = range_request(dir, "bytes=0-9,20-29").await;
+        assert_eq!(multi.status(), StatusCode::OK);
+        assert_eq!(body_len(multi).await, 1000);
+    }
+
+    async fn slash_request(dir: &Path, trailing_slash: TrailingSlash, path: &str) -> Response {
+        let options = HeaderOptions {
+            trailing_slash,
+            ..test_options(dir)
+        };
+        send(options, Request::get(path).body(Body::empty()).unwrap()).await
+    }
+
+    fn location(response: &Response) -> &str {
+        assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+        response.headers()[header::LOCATION].to_str().unwrap()
+    }
+
+    #[tokio::test]
+    async fn trailing_slash_added() {
+        let temp = test_build_dir();
+        let dir = temp.path();
+        let add = TrailingSlash::Add;
+        assert_eq!(location(&slash_request(dir, add, "/docs").await), "/docs/");
+        assert_eq!(
+            location(&slash_request(dir, add, "/docs?q=1&x=2").await),
+            "/docs/?q=1&x=2"
+        );
+        let response = slash_request(dir, add, "/docs/?q=1").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = slash_request(dir, add, "/index.html").await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn trailing_slash_stripped() {
+        let temp = test_build_dir();
+        let dir = temp.path();
+        let strip = TrailingSlash::Strip;
+        assert_eq!(
+            location(&slash_request(dir, strip, "/docs/").await),
+            "/docs"
+        );
+        assert_eq!(
+            location(&slash_request(dir, strip, "/docs/?q=1").await),
+            "/docs?q=1"
+        );
+        for path in ["/docs", "/docs?q=1"] {
+            let response = slash_request(dir, strip, path).await;
+            assert_eq!(response.status(), StatusCode::OK, "{path}");
+            assert_eq!(
+                response.headers()[header::CONTENT_TYPE],
+                "text/html; charset=utf-8",
+                "{path}"
+            );
+            assert_eq!(body_len(response).await, "<h1>Docs</h1>".len(), "{path}");
+        }
+        // The root and plain files are left alone.
+        let response = slash_request(dir, strip, "/").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = slash_request(dir, strip, "/video.mp4").await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn content_types() {
+        let temp = test_build_dir();
+        let dir = temp.path();
+        for file in ["logo.svg", "atom.xml", "font.woff2", "app.wasm", "data.xyz"] {
+            std::fs::write(dir.join(file), "x").unwrap();
+        }
+        for (path, content_type) in [
+            ("/logo.svg", "image/svg+xml"),
+            ("/atom.xml", "application/xml; charset=utf-8"),
+            ("/font.woff2", "font/woff2"),
+            ("/app.wasm", "application/wasm"),
+            ("/data.xyz", "application/octet-stream"),
+            ("/docs/", "text/html; charset=utf-8"),
+        ] {
+            let response = request(dir, Method::GET, path).await;
+            assert_eq!(response.status(), StatusCode::OK, "{path}");
+            assert_eq!(
+                response.headers()[header::CONTENT_TYPE],
+                content_type,
+                "{path}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn etag_revalidation() {
+        let temp = test_build_dir();
+        let dir = temp.path();
+        for path in ["/index.html", "/docs/"] {
+            let first = request(dir, Method::GET, path).await;
+            assert_eq!(first.status(), StatusCode::OK, "{path}");
+            assert!(
+                first.headers().contains_key(header::LAST_MODIFIED),
+                "{path}"
+            );
+            let etag = first.headers()[header::ETAG].clone();
+
+            let request = Request::get(path)
+                .header(header::IF_NONE_MATCH, etag.clone())
+                .body(Body::empty())
+                .unwrap();
+            let second = send(test_options(dir), request).await;
+            assert_eq!(second.status(), StatusCode::NOT_MODIFIED, "{path}");
+            assert_eq!(second.headers()[header::ETAG], etag, "{path}");
+            assert_eq!(body_len(second).await, 0, "{path}");
+        }
+
+        let request = Request::get("/index.html")
+            .header(header::IF_NONE_MATCH, "W/\"stale\"")
+            .body(Body::empty())
+            .unwrap();
+        let response = send(test_options(dir), request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    fn proxy_router(dir: &Path, upstream: &str) -> axum::Router {
+        let state = ProxyState {
+            client: hyper_util::client::legacy::Client::<(), ()>::builder(TokioExecutor::new())
+                .build(HttpConnector::new()),
+            rules: vec![parse_proxy_rule(&format!("/api/={upstream}")).unwrap()],
+        };
+        static_router::<()>("/", test_options(dir))
+            .layer(axum::middleware::from_fn_with_state(state, reverse_proxy))
+    }
+
+    fn cors_router(dir: &Path) -> axum::Router {
+        let state = CorsState {
+            options: CorsOptions {
+                origin: axum::http::HeaderValue::from_static("*"),
+                paths: vec!["/search-index.json".to_owned(), "/docs/".to_owned()],
+            },
+            path_prefix: String::new(),
+        };
+        static_router::<()>("/", test_options(dir))
+            .layer(axum::middleware::from_fn_with_state(state, cors))
+    }
+
+    #[tokio::test]
+    async fn cors_headers() {
+        let temp = test_build_dir();
+        let dir = temp.path();
+        std::fs::create_dir_all(dir.join("api/badge")).unwrap();
+        std::fs::write(dir.join("api/badge/version.json"), "{}").unwrap();
+        std::fs::write(dir.join("search-index.json"), "[]").unwrap();
+        let router = cors_router(dir);
+
+        let request = Request::builder()
+            .method(Method::OPTIONS)
+            .uri("/api/badge/version.json")
+            .header(header::ORIGIN, "http://localhost:8080")
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "GET")
+            .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "x-requested-with")
+            .body(Body::empty())
+            .unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            header_str(&response, header::ACCESS_CONTROL_ALLOW_ORIGIN),
+            "*"
+        );
+        assert_eq!(
+            header_str(&response, header::ACCESS_CONTROL_ALLOW_METHODS),
+            "GET, HEAD, OPTIONS"
+        );
+        assert_eq!(
+            header_str(&response, header::ACCESS_CONTROL_ALLOW_HEADERS),
+            "x-requested-with"
+        );
+
+        for path in ["/api/badge/version.json", "/search-index.json"] {
+            let request = Request::get(path).body(Body::empty()).unwrap();
+            let response = router.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(
+                header_str(&response, header::ACCESS_CONTROL_ALLOW_ORIGIN),
+                "*"
+            );
+        }
+
+        // Pages don't get the header, not even under a listed path.
+        for path in ["/", "/docs/"] {
+            let request = Request::get(path).body(Body::empty()).unwrap();
+            let response = router.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert!(!response
+                .headers()
+                .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+        }
+    }
+
+    #[test]
+    fn proxy_rules_are_parsed() {
+        let rule = parse_proxy_rule("/api/=http://localhost:3000/").unwrap();
+        assert_eq!(rule.prefix, "/api/");
+        assert_eq!(rule.upstream, "http://localhost:3000");
+        assert!(parse_proxy_rule("/api/").is_err());
+        assert!(parse_proxy_rule("api/=http://localhost").is_err());
+        assert!(parse_proxy_rule("/api/=https://localhost").is_err());
+    }
+
+    #[tokio::test]
+    async fn proxied_requests() {
+        let upstream = axum::Router::new().route(
+            "/api/*path",
+            axum::routing::get(|uri: axum::http::Uri| async move {
+                (
+                    StatusCode::CREATED,
+                    [("x-upstream", "yes")],
+                    uri.to_string(),
+                )
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, upstream).await.unwrap() });
+
+        let temp = test_build_dir();
+        let dir = temp.path();
+        let router = proxy_router(dir, &format!("http://{addr}"));
+        let request = Request::get("/api/goldfiles/x?a=1")
+            .body(Body::empty())
+            .unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(response.headers()["x-upstream"], "yes");
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"/api/goldfiles/x?a=1");
+
+        // Everything else is still served from the build.
+        let request = Request::get("/index.html").body(Body::empty()).unwrap();
+        let response = router.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn unreachable_upstream() {
+        // Grab a free port and let it go, so nothing is listening there.
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let temp = test_build_dir();
+        let dir = temp.path();
+        let request = Request::get("/api/x").body(Body::empty()).unwrap();
+        let response = proxy_router(dir, &format!("http://{addr}"))
+            .oneshot(request)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("Proxy error"));
+    }
+
+    #[tokio::test]
+    async fn traversal_is_rejected() {
+        let temp = test_build_dir();
+        let dir = temp.path();
+        let long_path = format!("/{}", "a/".repeat(600));
+        let malicious = [
+            "/../../etc/passwd",
+            "/docs/../../etc/passwd",
+            "/%2e%2e/%2e%2e/etc/passwd",
+            "/%2E%2E%2F%2E%2E%2Fetc%2Fpasswd",
+            "/..%2f..%2fetc/passwd",
+            "/docs/..\\..\\etc\\passwd",
+            "/docs%5c..%5c..%5cetc%5cpasswd",
+            "/index.html%00.png",
+            "/%00",
+            "/%zz/index.html",
+            "/%2",
+            "/%c0%ae%c0%ae/etc/passwd",
+            long_path.as_str(),
+        ];
+        for path in malicious {
+            assert_eq!(resolve_path(dir, path), None, "{path}");
+            let response = request(dir, Method::GET, path).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{path}");
+        }
+
+        // Plain requests still resolve, escapes included.
+        assert_eq!(resolve_path(dir, "/"), Some(dir.join("index.html")));
+        assert_eq!(
+            resolve_path(dir, "/docs"),
+            Some(dir.join("docs/index.html"))
+        );
+        assert_eq!(
+            resolve_path(dir, "/vid%65o.mp4"),
+            Some(dir.join("video.mp4"))
+        );
+
+        // The work folders of a build being swapped in don't.
+        std::fs::create_dir(dir.join(".staging-1")).unwrap();
+        std::fs::write(dir.join(".staging-1/index.html"), "").unwrap();
+        assert_eq!(resolve_path(dir, "/.staging-1/"), None);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn symlinks_out_of_the_build_are_rejected() {
+        let temp = test_build_dir();
+        let dir = temp.path();
+        let secret = dir.with_extension("secret");
+        std::fs::write(&secret, "secret").unwrap();
+        std::os::unix::fs::symlink(&secret, dir.join("leak.txt")).unwrap();
+        assert_eq!(resolve_path(dir, "/leak.txt"), None);
+        let response = request(dir, Method::GET, "/leak.txt").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        std::fs::remove_file(secret).unwrap();
+    }
+
+    #[tokio::test]
+    async fn in_memory_files() {
+        let temp = test_build_dir();
+        let dir = temp.path();
+        let files = [("index.html".to_owned(), "<h1>From memory</h1>".into())];
+        let (_memory_tx, memory) =
+            tokio::sync::watch::channel(std::sync::Arc::new(files.into_iter().collect()));
+        let options = HeaderOptions {
+            memory: Some(memory),
+            ..test_options(dir)
+        };
+
+        let response = send(
+            options.clone(),
+            Request::get("/").body(Body::empty()).unwrap(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            header_str(&response, header::CONTENT_TYPE),
+            "text/html; charset=utf-8"
+        );
+        let last_modified = response.headers()[header::LAST_MODIFIED].clone();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"<h1>From memory</h1>");
+
+        // Revalidates by the copy on disk.
+        let request = Request::get("/")
+            .header(header::IF_MODIFIED_SINCE, last_modified.clone())
+            .body(Body::empty())
+            .unwrap();
+        let response = send(options.clone(), request).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[header::LAST_MODIFIED], last_modified);
+        let request = Request::get("/")
+            .header(header::IF_MODIFIED_SINCE, "Thu, 01 Jan 1970 00:00:00 GMT")
+            .body(Body::empty())
+            .unwrap();
+        let response = send(options.clone(), request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Files missing from the map, and range requests, come from the disk.
+        let response = send(
+            options.clone(),
+            Request::get("/docs/").body(Body::empty()).unwrap(),
+        )
+        .await;
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"<h1>Docs</h1>");
+        let request = Request::get("/")
+            .header(header::RANGE, "bytes=0-3")
+            .body(Body::empty())
+            .unwrap();
+        let response = send(options, request).await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"<h1>");
+    }
+
+    #[tokio::test]
+    async fn build_errors() {
+        let (reload_tx, _) = tokio::sync::broadcast::channel(16);
+        let errors = track_build_errors(&reload_tx);
+        let mut updates = errors.clone();
+        let get = |path: &str| {
+            build_error_router(errors.clone())
+                .oneshot(Request::get(path).body(Body::empty()).unwrap())
+        };
+
+        let response = get("/__build_error").await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let failure = BuildFailure {
+            message: "blog/x.md: front matter is missing its closing ---".to_owned(),
+            file: Some("blog/x.md".to_owned()),
+        };
+        reload_tx
+            .send(super::ReloadEvent::BuildError(failure))
+            .unwrap();
+        updates.changed().await.unwrap();
+        let response = get("/__build_error").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let html = String::from_utf8(body.to_vec()).unwrap();
+        assert!(html.contains("<mark>blog/x.md</mark>"));
+        assert!(html.contains("missing its closing ---"));
+        let response = get("/__build_error.json").await.unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["file"], "blog/x.md");
+
+        // Cleared by the next good build.
+        reload_tx.send(super::ReloadEvent::Reload).unwrap();
+        updates.changed().await.unwrap();
+        let response = get("/__build_error").await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = get("/__build_error.json").await.unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"null");
+    }
+
+    async fn encoded_request(dir: &Path, path: &str, accept_encoding: &str) -> Response {
+        let request = Request::get(path)
+            .header(header::ACCEPT_ENCODING, accept_encoding)
+            .body(Body::empty())
+            .unwrap();
+        compress(static_router::<()>("/", test_options(dir)))
+            .oneshot(request)
+            .await
+            .unwrap()
+    }
+
+    fn content_encoding(response: &Response) -> Option<&str> {
+        response
+            .headers()
+            .get(header::CONTENT_ENCODING)
+            .map(|value| value.to_str().unwrap())
+    }
+
+    #[tokio::test]
+    async fn precompressed_files_are_served() {
+        let temp = test_build_dir();
+        let dir = temp.path();
+        let script = "console.log('hello');\n".repeat(100);
+        std::fs::write(dir.join("app.js"), &script).unwrap();
+        std::fs::write(dir.join("app.js.gz"), "gzipped").unwrap();
+        std::fs::write(dir.join("app.js.br"), "brotlied").unwrap();
+
+        for (accept, encoding, body) in [
+            ("gzip, br", Some("br"), "brotlied"),
+            ("gzip", Some("gzip"), "gzipped"),
+            ("identity", None, script.as_str()),
+        ] {
+            let response = encoded_request(dir, "/app.js", accept).await;
+            assert_eq!(response.status(), StatusCode::OK, "{accept}");
+            assert_eq!(content_encoding(&response), encoding, "{accept}");
+            assert_eq!(
+                header_str(&response, header::CONTENT_TYPE),
+                "text/javascript; charset=utf-8"
+            );
+            let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            assert_eq!(&bytes[..], body.as_bytes(), "{accept}");
+        }
+
+        // Without a sibling, it's compressed on the fly, unless it's tiny or an image.
+        std::fs::write(dir.join("page.html"), "<p>Hello</p>\n".repeat(100)).unwrap();
+        std::fs::write(dir.join("shot.png"), vec![7u8; 4096]).unwrap();
+        let response = encoded_request(dir, "/page.html", "gzip").await;
+        assert_eq!(content_encoding(&response), Some("gzip"));
+        let gzipped = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let mut html = String::new();
+        std::io::Read::read_to_string(&mut flate2::read::GzDecoder::new(&gzipped[..]), &mut html)
+            .unwrap();
+        assert_eq!(html, "<p>Hello</p>\n".repeat(100));
+        let response = encoded_request(dir, "/index.html", "gzip").await;
+        assert_eq!(content_encoding(&response), None);
+        let response = encoded_request(dir, "/shot.png", "gzip").await;
+        assert_eq!(content_encoding(&response), None);
+    }
+
+    #[tokio::test]
+    async fn build_stats() {
+        let mut stats = crate::stats::BuildStats::default();
+        stats.time("docs", || ());
+        stats.documents.insert("blog".to_owned(), 6);
+        let (stats_tx, stats_rx) = tokio::sync::watch::channel(std::sync::Arc::new(stats));
+
+        let response = stats_router(stats_rx.clone())
+            .oneshot(Request::get("/__stats").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            header_str(&response, header::CONTENT_TYPE),
+            "application/json"
+        );
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["phases"][0]["name"], "docs");
+        assert_eq!(json["documents"]["blog"], 6);
+
+        // Always the latest build.
+        stats_tx.send_replace(Default::default());
+        let response = stats_router(stats_rx)
+            .oneshot(Request::get("/__stats.html").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let html = String::from_utf8(body.to_vec()).unwrap();
+        assert!(html.contains("<title>Build stats</title>"));
+        assert!(!html.contains("docs"));
+    }
 }
@@ -0,0 +1,356 @@
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+use std::time::Instant;
+
+// How many of the biggest pages and static files to keep.
+const LARGEST_FILES: usize = 20;
+
+// Top-level output folders that get their own line in the size report. Everything else is
+// a page, or a file next to them like sitemap.xml.
+const SECTIONS: &[&str] = &["api", "blog", "docs", "news", "static"];
+
+// What the last build did and produced. Printed by --timings, and served at /__stats by the
+// dev server.
+#[derive(Debug, Default, Serialize)]
+pub struct BuildStats {
+    pub phases: Vec<Phase>,
+    pub total_millis: f64,
+    // Number of documents generated per section, like docs or blog.
+    pub documents: BTreeMap<String, usize>,
+    pub output_files: usize,
+    pub output_size: u64,
+    // Files and bytes per section, see SECTIONS.
+    pub sections: BTreeMap<String, SectionSize>,
+    // HTML files and files in static/, biggest first.
+    pub largest_pages: Vec<OutputFile>,
+    pub largest_assets: Vec<OutputFile>,
+    // What minifying saved, for pages and by asset file extension.
+    pub minified: BTreeMap<String, MinifyStats>,
+    pub warnings: Vec<String>,
+    // Documents left out because they failed to build.
+    pub skipped_documents: usize,
+    // Every file the build wrote, relative to the output, see RecordingWriter::manifest.
+    #[serde(skip)]
+    pub manifest: BTreeSet<PathBuf>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct MinifyStats {
+    pub files: usize,
+    pub size_before: u64,
+    pub size_after: u64,
+}
+
+impl std::ops::AddAssign for MinifyStats {
+    fn add_assign(&mut self, other: Self) {
+        self.files += other.files;
+        self.size_before += other.size_before;
+        self.size_after += other.size_after;
+    }
+}
+
+impl MinifyStats {
+    pub fn add(&mut self, size_before: usize, size_after: usize) {
+        self.files += 1;
+        self.size_before += size_before as u64;
+        self.size_after += size_after as u64;
+    }
+
+    pub fn summary(&self) -> String {
+        format!(
+            "{} files, {} to {}, saving {:.1}%",
+            self.files,
+            format_size(self.size_before),
+            format_size(self.size_after),
+            100.0 * self.size_before.saturating_sub(self.size_after) as f64
+                / self.size_before.max(1) as f64
+        )
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Phase {
+    pub name: String,
+    pub millis: f64,
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct SectionSize {
+    pub files: usize,
+    pub size: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OutputFile {
+    // Relative to the output directory, with forward slashes.
+    pub path: String,
+    pub size: u64,
+}
+
+impl BuildStats {
+    // Runs one step of the build, recording how long it took.
+    pub fn time<T>(&mut self, phase: &str, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        let millis = start.elapsed().as_secs_f64() * 1000.0;
+        self.total_millis += millis;
+        self.phases.push(Phase {
+            name: phase.to_owned(),
+            millis,
+        });
+        result
+    }
+
+    // Sizes up what the build wrote, by path relative to the output, with forward slashes.
+    // Returns a warning for each page over page_budget bytes.
+    pub fn add_output(
+        &mut self,
+        files: impl IntoIterator<Item = (String, u64)>,
+        page_budget: Option<u64>,
+    ) -> Vec<String> {
+        let mut files = files
+            .into_iter()
+            .map(|(path, size)| OutputFile { path, size })
+            .collect::<Vec<_>>();
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        let mut warnings = vec![];
+        let mut pages = vec![];
+        let mut assets = vec![];
+        for file in files {
+            self.output_files += 1;
+            self.output_size += file.size;
+            let section = file
+                .path
+                .split_once('/')
+                .map(|(first, _)| first)
+                .filter(|first| SECTIONS.contains(first))
+                .unwrap_or("pages");
+            let section = self.sections.entry(section.to_owned()).or_default();
+            section.files += 1;
+            section.size += file.size;
+            if file.path.ends_with(".html") {
+                if let Some(budget) = page_budget.filter(|budget| file.size > *budget) {
+                    warnings.push(format!(
+                        "{} is {}, over the page budget of {}",
+                        file.path,
+                        format_size(file.size),
+                        format_size(budget)
+                    ));
+                }
+                pages.push(file);
+            } else if file.path.starts_with("static/") {
+                assets.push(file);
+            }
+        }
+        self.largest_pages = largest(pages);
+        self.largest_assets = largest(assets);
+        warnings
+    }
+
+    pub fn print(&self) {
+        println!("Build timings:");
+        for phase in &self.phases {
+            println!("  {:<12} {:>8.1} ms", phase.name, phase.millis);
+        }
+        println!("  {:<12} {:>8.1} ms", "total", self.total_millis);
+        let documents = self
+            .documents
+            .iter()
+            .map(|(section, count)| format!("{section} {count}"))
+            .collect::<Vec<_>>();
+        println!("Documents: {}", documents.join(", "));
+        if !self.minified.is_empty() {
+            println!("Minified:");
+            for (kind, minified) in &self.minified {
+                println!("  {:<12} {}", kind, minified.summary());
+            }
+        }
+        self.print_sizes();
+    }
+
+    pub fn print_sizes(&self) {
+        println!(
+            "Output: {} files, {}",
+            self.output_files,
+            format_size(self.output_size)
+        );
+        for (section, size) in &self.sections {
+            println!(
+                "  {:<12} {:>10}  {} files",
+                section,
+                format_size(size.size),
+                size.files
+            );
+        }
+        for (title, files) in [
+            ("Largest pages:", &self.largest_pages),
+            ("Largest static files:", &self.largest_assets),
+        ] {
+            println!("{title}");
+            for file in files {
+                println!("  {:>10}  {}", format_size(file.size), file.path);
+            }
+        }
+    }
+
+    // The output sizes, for build/_report/size.json.
+    pub fn size_report_json(&self) -> String {
+        #[derive(Serialize)]
+        struct SizeReport<'a> {
+            output_files: usize,
+            output_size: u64,
+            sections: &'a BTreeMap<String, SectionSize>,
+            largest_pages: &'a [OutputFile],
+            largest_assets: &'a [OutputFile],
+        }
+        serde_json::to_string_pretty(&SizeReport {
+            output_files: self.output_files,
+            output_size: self.output_size,
+            sections: &self.sections,
+            largest_pages: &self.largest_pages,
+            largest_assets: &self.largest_assets,
+        })
+        .unwrap()
+    }
+
+    // A bare page with the same numbers, for looking at in the browser.
+    pub fn to_html(&self) -> String {
+        let row = |name: &str, value: String| {
+            format!(
+                "<tr><td>{}</td><td>{}</td></tr>\n",
+                handlebars::html_escape(name),
+                value
+            )
+        };
+        let mut phases = String::new();
+        for phase in &self.phases {
+            phases += &row(&phase.name, format!("{:.1} ms", phase.millis));
+        }
+        phases += &row("total", format!("{:.1} ms", self.total_millis));
+        let mut documents = String::new();
+        for (section, count) in &self.documents {
+            documents += &row(section, count.to_string());
+        }
+        let mut minified = String::new();
+        for (kind, stats) in &self.minified {
+            minified += &row(kind, stats.summary());
+        }
+        let mut sections = String::new();
+        for (section, size) in &self.sections {
+            let value = format!("{}, {} files", format_size(size.size), size.files);
+            sections += &row(section, value);
+        }
+        let mut largest_pages = String::new();
+        for file in &self.largest_pages {
+            largest_pages += &row(&file.path, format_size(file.size));
+        }
+        let mut largest_assets = String::new();
+        for file in &self.largest_assets {
+            largest_assets += &row(&file.path, format_size(file.size));
+        }
+        format!(
+            r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>Build stats</title>
+</head>
+<body>
+<h1>Last build</h1>
+<h2>Timings</h2>
+<table>
+{phases}</table>
+<h2>Documents</h2>
+<table>
+{documents}</table>
+<h2>Minified</h2>
+<table>
+{minified}</table>
+<h2>Output</h2>
+<p>{} files, {}</p>
+<table>
+{sections}</table>
+<h2>Largest pages</h2>
+<table>
+{largest_pages}</table>
+<h2>Largest static files</h2>
+<table>
+{largest_assets}</table>
+</body>
+</html>
+"#,
+            self.output_files,
+            format_size(self.output_size)
+        )
+    }
+}
+
+// Biggest first, then by path.
+fn largest(mut files: Vec<OutputFile>) -> Vec<OutputFile> {
+    files.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.path.cmp(&b.path)));
+    files.truncate(LARGEST_FILES);
+    files
+}
+
+pub fn format_size(size: u64) -> String {
+    if size >= 1024 * 1024 {
+        format!("{:.1} MiB", size as f64 / (1024.0 * 1024.0))
+    } else if size >= 1024 {
+        format!("{:.1} KiB", size as f64 / 1024.0)
+    } else {
+        format!("{size} B")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn output_sizes() {
+        let mut stats = BuildStats::default();
+        let files = [
+            ("index.html", 3000),
+            ("docs/intro/index.html", 900),
+            ("docs/intro/big/index.html", 5000),
+            ("static/img/logo.png", 20_000),
+            ("sitemap.xml", 100),
+        ];
+        let warnings = stats.add_output(
+            files.map(|(path, size)| (path.to_owned(), size)),
+            Some(2048),
+        );
+        assert_eq!(
+            warnings,
+            [
+                "docs/intro/big/index.html is 4.9 KiB, over the page budget of 2.0 KiB",
+                "index.html is 2.9 KiB, over the page budget of 2.0 KiB",
+            ]
+        );
+        assert_eq!((stats.output_files, stats.output_size), (5, 29_000));
+        let sections = stats
+            .sections
+            .iter()
+            .map(|(name, size)| (name.as_str(), size.files, size.size))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            sections,
+            [("docs", 2, 5900), ("pages", 2, 3100), ("static", 1, 20_000)]
+        );
+        let largest = stats
+            .largest_pages
+            .iter()
+            .map(|file| file.path.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            largest,
+            [
+                "docs/intro/big/index.html",
+                "index.html",
+                "docs/intro/index.html"
+            ]
+        );
+        assert_eq!(stats.largest_assets[0].path, "static/img/logo.png");
+    }
+}
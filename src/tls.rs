@@ -0,0 +1,27 @@
+use crate::server::TlsFiles;
+use anyhow::Context;
+use std::path::Path;
+
+const CACHE_DIR: &str = ".cache/tls";
+
+// A self-signed certificate for the given host names and addresses. Generated once and then
+// reused, so the browser exception only needs accepting once. Delete .cache/tls to regenerate,
+// for example after the LAN address changes.
+pub fn self_signed(hosts: &[String]) -> anyhow::Result<TlsFiles> {
+    let dir = Path::new(CACHE_DIR);
+    let files = TlsFiles {
+        cert: dir.join("cert.pem"),
+        key: dir.join("key.pem"),
+    };
+    if files.cert.is_file() && files.key.is_file() {
+        return Ok(files);
+    }
+
+    let rcgen::CertifiedKey { cert, key_pair } =
+        rcgen::generate_simple_self_signed(hosts.to_vec()).context("generating certificate")?;
+    std::fs::create_dir_all(dir)?;
+    std::fs::write(&files.cert, cert.pem())?;
+    std::fs::write(&files.key, key_pair.serialize_pem())?;
+    println!("Generated a self-signed certificate in {}", dir.display());
+    Ok(files)
+}
@@ -1,7 +1,12 @@
+use crate::config::{Config, UrlStyle};
+use crate::stats::MinifyStats;
+use crate::writer::SiteWriter;
+use crate::{fingerprint, images, minify, post_process};
 use anyhow::Context;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::collections::{BTreeMap, HashSet};
 use std::ffi::{OsStr, OsString};
 use std::fs;
-use std::io::Write;
 use std::path::{Path, PathBuf};
 
 pub fn strip_extension(str: OsString) -> String {
@@ -15,110 +20,946 @@ pub fn filename_to_string(name: &OsStr) -> String {
     name.to_string_lossy().to_string()
 }
 
-#[allow(clippy::single_match)]
+// What copy_recursive did, for logging.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct CopyStats {
+    pub copied: usize,
+    // Already in the output, unchanged.
+    pub skipped: usize,
+    pub deleted: usize,
+    // Files and folders left out by the ignore patterns, and symlinks left out by
+    // SymlinkMode::Skip. Folders count once.
+    pub excluded: usize,
+    // By extension.
+    pub minified: BTreeMap<String, MinifyStats>,
+    // Path -> fingerprinted path, relative to the destination, with forward slashes.
+    pub assets: BTreeMap<String, String>,
+}
+
+// Patterns for source files in static/ that shouldn't ship, from static/.siteignore and the
+// extra ones given, in .gitignore syntax and relative to static/.
+pub fn static_ignore(static_dir: &Path, extra: &[String]) -> anyhow::Result<Gitignore> {
+    let mut builder = GitignoreBuilder::new(static_dir);
+    let ignore_file = static_dir.join(".siteignore");
+    if ignore_file.exists() {
+        if let Some(err) = builder.add(&ignore_file) {
+            return Err(err).context("siteignore");
+        }
+    }
+    builder.add_line(None, "/.siteignore")?;
+    for pattern in extra {
+        builder
+            .add_line(None, pattern)
+            .with_context(|| format!("exclude pattern {pattern}"))?;
+    }
+    Ok(builder.build()?)
+}
+
+// Whether dst already has the contents of src. Copies keep the modification time, so
+// usually the size and time settle it. Not everything that copies files keeps the time,
+// so when it differs, the contents are compared instead.
+pub fn is_copy_of(src: &Path, dst: &Path) -> bool {
+    let (Ok(src_meta), Ok(dst_meta)) = (fs::metadata(src), fs::metadata(dst)) else {
+        return false;
+    };
+    if !dst_meta.is_file() || src_meta.len() != dst_meta.len() {
+        return false;
+    }
+    if src_meta.modified().ok().is_some() && src_meta.modified().ok() == dst_meta.modified().ok() {
+        return true;
+    }
+    match (fs::read(src), fs::read(dst)) {
+        (Ok(src), Ok(dst)) => src == dst,
+        _ => false,
+    }
+}
+
+// What to do with symlinks in the source folder.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum SymlinkMode {
+    // Copy what they point to. Folder links back into a folder being copied are skipped.
+    #[default]
+    Follow,
+    // Recreate the links in the output.
+    Preserve,
+    // Leave them out.
+    Skip,
+}
+
+pub struct CopyOptions<'a> {
+    // CSS and JS files, see minify::minify_asset.
+    pub minify: bool,
+    pub strip_source_maps: bool,
+    // Write files under fingerprinted names, like style.<hash>.css, see CopyStats::assets.
+    pub fingerprint: bool,
+    // Also write them under the original names when fingerprinting.
+    pub keep_unhashed: bool,
+    // Folders that are created, but left empty.
+    pub exclude_dirs: &'a [&'a str],
+    // Not copied at all, and ignored folders aren't even looked into.
+    pub ignore: Gitignore,
+    // Delete what's in the destination but not in the source, or ignored there.
+    pub delete_removed: bool,
+    pub symlinks: SymlinkMode,
+}
+
+impl Default for CopyOptions<'_> {
+    fn default() -> Self {
+        Self {
+            minify: false,
+            strip_source_maps: false,
+            fingerprint: false,
+            keep_unhashed: false,
+            exclude_dirs: &[],
+            ignore: Gitignore::empty(),
+            delete_removed: false,
+            symlinks: SymlinkMode::Follow,
+        }
+    }
+}
+
+// Copies the files that changed since the last copy.
 pub fn copy_recursive(
+    writer: &mut dyn SiteWriter,
     src: impl AsRef<Path>,
     dst: impl AsRef<Path>,
-    minify: bool,
-    exclude_dirs: &[&str],
+    options: &CopyOptions<'_>,
+) -> anyhow::Result<CopyStats> {
+    let mut stats = CopyStats::default();
+    copy_dir(
+        writer,
+        src.as_ref(),
+        dst.as_ref(),
+        dst.as_ref(),
+        options,
+        &mut vec![],
+        &mut stats,
+    )?;
+    Ok(stats)
+}
+
+// ancestors are the canonical paths of the folders being copied, to catch symlink loops.
+fn copy_dir(
+    writer: &mut dyn SiteWriter,
+    src: &Path,
+    dst: &Path,
+    dst_root: &Path,
+    options: &CopyOptions<'_>,
+    ancestors: &mut Vec<PathBuf>,
+    stats: &mut CopyStats,
 ) -> anyhow::Result<()> {
-    let minify_session = minify_js::Session::new();
-    fs::create_dir_all(&dst)?;
+    writer.create_dir(dst)?;
+    ancestors.push(fs::canonicalize(src)?);
+    // Fingerprinted files written here, which delete_removed has to leave alone.
+    let mut hashed_names = HashSet::new();
     for entry in fs::read_dir(src)? {
         let entry = entry?;
-        let ty = entry.file_type()?;
+        let path = entry.path();
+        let dst = dst.join(entry.file_name());
+        let mut ty = entry.file_type()?;
+        if ty.is_symlink() {
+            match options.symlinks {
+                SymlinkMode::Skip => {
+                    stats.excluded += 1;
+                    continue;
+                }
+                SymlinkMode::Preserve => {
+                    if !options.ignore.matched(&path, path.is_dir()).is_ignore() {
+                        writer.symlink(&path, &dst)?;
+                        stats.copied += 1;
+                    } else {
+                        stats.excluded += 1;
+                    }
+                    continue;
+                }
+                SymlinkMode::Follow => match fs::metadata(&path) {
+                    Ok(metadata) => ty = metadata.file_type(),
+                    Err(err) => {
+                        println!(
+                            "Warning: Skipping broken symlink {}: {}",
+                            path.display(),
+                            err
+                        );
+                        continue;
+                    }
+                },
+            }
+        }
+        if options.ignore.matched(&path, ty.is_dir()).is_ignore() {
+            stats.excluded += 1;
+            continue;
+        }
         if ty.is_dir() {
             let name = filename_to_string(&entry.file_name());
-            if !exclude_dirs.contains(&name.as_str()) {
-                copy_recursive(
-                    entry.path(),
-                    dst.as_ref().join(entry.file_name()),
-                    minify,
-                    exclude_dirs,
-                )
-                .context("copy-recurse")?;
-            } else {
+            if options.exclude_dirs.contains(&name.as_str()) {
                 // Just create the empty dir.
-                fs::create_dir_all(&dst.as_ref().join(entry.file_name()))?;
+                writer.create_dir(&dst)?;
+            } else if ancestors.contains(&fs::canonicalize(&path)?) {
+                println!(
+                    "Warning: Skipping {}, a symlink to a folder containing it",
+                    path.display()
+                );
+            } else {
+                copy_dir(writer, &path, &dst, dst_root, options, ancestors, stats)
+                    .context("copy-recurse")?;
             }
         } else {
-            let dst = dst.as_ref().join(entry.file_name());
-            if minify {
-                // TODO: This complains about utf-8. Oh well, let's fix this later.
-
-                // TODO: This read can be done more efficiently.
-                let mut data = std::fs::read_to_string(entry.path())?.as_bytes().to_owned();
-
-                // Here we can minify.
-                if let Some(os_str) = entry.path().extension() {
-                    // Check file extension to figure out what to do.
-                    match os_str.to_str().unwrap() {
-                        "js" => {
-                            let mut buffer = vec![];
-                            let _ = minify_js::minify(
-                                &minify_session,
-                                minify_js::TopLevelMode::Global,
-                                &data,
-                                &mut buffer,
-                            );
-                            data = buffer;
-                        }
-                        _ => {}
+            let minified = if options.minify && minify::is_asset(&path) {
+                minify_file(&path, options, stats)?
+            } else {
+                None
+            };
+            // Source maps are found by the name in the sourceMappingURL comment, and only
+            // downloaded by devtools anyway.
+            let fingerprint =
+                options.fingerprint && path.extension().is_none_or(|ext| ext != "map");
+            let mut targets = vec![];
+            if fingerprint {
+                let hash = match &minified {
+                    Some(data) => fingerprint::content_hash(data),
+                    None => fingerprint::content_hash(&fs::read(&path)?),
+                };
+                let hashed = fingerprint::hashed_path(&dst, &hash);
+                let relative = |path: &Path| -> anyhow::Result<String> {
+                    Ok(path
+                        .strip_prefix(dst_root)?
+                        .to_string_lossy()
+                        .replace('\\', "/"))
+                };
+                stats.assets.insert(relative(&dst)?, relative(&hashed)?);
+                hashed_names.insert(hashed.file_name().unwrap_or_default().to_owned());
+                targets.push(hashed);
+            }
+            if !fingerprint || options.keep_unhashed {
+                targets.push(dst);
+            }
+            for (i, target) in targets.iter().enumerate() {
+                let copied = match &minified {
+                    Some(data) => {
+                        writer.write(target, data).context("minify")?;
+                        true
+                    }
+                    None => writer.copy(&path, target)?,
+                };
+                // Only count the file once.
+                if i == 0 {
+                    if copied {
+                        stats.copied += 1;
+                    } else {
+                        stats.skipped += 1;
                     }
                 }
-
-                let mut out_file = std::fs::File::create(&dst).context("minify")?;
-                out_file.write_all(&data)?;
             }
-
-            fs::copy(entry.path(), dst).context("copy-file")?;
         }
     }
+    ancestors.pop();
+    if options.delete_removed {
+        stats.deleted += delete_removed_files(src, dst, options, &hashed_names)?;
+    }
     Ok(())
 }
 
+// Minifies a CSS or JS file. None if it should be copied as it is instead, because it looks
+// minified already, or failed to parse.
+fn minify_file(
+    path: &Path,
+    options: &CopyOptions<'_>,
+    stats: &mut CopyStats,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    let data = fs::read(path)?;
+    match minify::minify_asset(path, &data, options.strip_source_maps) {
+        Ok(Some(minified)) => {
+            let extension = path.extension().unwrap_or_default().to_string_lossy();
+            stats
+                .minified
+                .entry(extension.into_owned())
+                .or_default()
+                .add(data.len(), minified.len());
+            Ok(Some(minified))
+        }
+        Ok(None) => Ok(None),
+        Err(err) => {
+            println!(
+                "Warning: Couldn't minify {}, copying it as is: {:#}",
+                path.display(),
+                err
+            );
+            Ok(None)
+        }
+    }
+}
+
+// Deletes what's in dst but not in src, or left out of the copy, counting the files. Only the
+// output on disk is looked at, there's nothing to delete when the writer doesn't write there.
+fn delete_removed_files(
+    src: &Path,
+    dst: &Path,
+    options: &CopyOptions<'_>,
+    hashed_names: &HashSet<OsString>,
+) -> anyhow::Result<usize> {
+    let Ok(listing) = fs::read_dir(dst) else {
+        return Ok(0);
+    };
+    let mut deleted = 0;
+    for entry in listing {
+        let entry = entry?;
+        if hashed_names.contains(&entry.file_name()) {
+            continue;
+        }
+        let source = src.join(entry.file_name());
+        let is_link = source.is_symlink();
+        let exists = if is_link && options.symlinks == SymlinkMode::Preserve {
+            true
+        } else {
+            source.exists()
+        };
+        if exists
+            && !(is_link && options.symlinks == SymlinkMode::Skip)
+            && !options.ignore.matched(&source, source.is_dir()).is_ignore()
+        {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            deleted += count_files(&entry.path())?;
+            fs::remove_dir_all(entry.path())?;
+        } else {
+            fs::remove_file(entry.path())?;
+            deleted += 1;
+        }
+    }
+    Ok(deleted)
+}
+
+fn count_files(dir: &Path) -> anyhow::Result<usize> {
+    let mut count = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            count += count_files(&entry.path())?;
+        } else {
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+// Writes path/index.html, or path.html with UrlStyle::HtmlFiles, for source. Fails if anything
+// else wrote there this build, see Config::claim_output.
 pub fn write_file_as_folder_with_index(
+    config: &Config,
+    writer: &mut dyn SiteWriter,
     path: &Path,
     contents: String,
     strip_extension: bool,
+    source: &Path,
 ) -> anyhow::Result<()> {
     let mut extensionless = path.to_path_buf();
     if strip_extension {
         extensionless.set_extension("");
     }
-
-    let _ = std::fs::create_dir_all(&extensionless);
-    let file_path = extensionless.join("index.html");
-    let mut file = std::fs::File::create(file_path).context("create_file_as_dir")?;
-    file.write_all(contents.as_bytes())?;
-    Ok(())
+    // Version folders like 1.16 have dots, so no set_extension.
+    let file_path = if config.url_style == UrlStyle::Folders || extensionless == config.out_dir {
+        extensionless.join("index.html")
+    } else {
+        let mut file_path = extensionless.into_os_string();
+        file_path.push(".html");
+        PathBuf::from(file_path)
+    };
+    write_page(config, writer, &file_path, contents, source)
 }
 
-pub fn create_folder_if_missing(path: &Path) -> anyhow::Result<()> {
-    // Create folder if missing.
-    let parent = path.parent().unwrap();
-    if !parent.exists() {
-        println!("creating {}", parent.display());
-        std::fs::create_dir_all(parent).context("create_dir_if_missing")
+// Writes a generated page to file_path as it is, after the same checks and post-processing
+// as every other page.
+pub fn write_page(
+    config: &Config,
+    writer: &mut dyn SiteWriter,
+    file_path: &Path,
+    contents: String,
+    source: &Path,
+) -> anyhow::Result<()> {
+    config.claim_output(file_path, source)?;
+
+    if config.check_assets {
+        fingerprint::check_asset_references(config, &contents);
+    }
+    let contents = if config.thumbnail_widths.is_empty() && !config.webp {
+        contents
+    } else {
+        images::rewrite_img_tags(config, writer, &contents)?
+    };
+    config.pages.borrow_mut().insert(
+        file_path.to_path_buf(),
+        crate::linkcheck::PageLinks::new(source, &contents),
+    );
+    let contents = if config.minify_html {
+        let minified = post_process::minify_html(&contents);
+        let mut stats = config.minified_pages.get();
+        stats.add(contents.len(), minified.len());
+        config.minified_pages.set(stats);
+        minified
+    } else if config.tidy_html {
+        post_process::tidy_html(&contents)
     } else {
-        Ok(())
+        contents
+    };
+    writer
+        .write(file_path, contents.as_bytes())
+        .context("create_file_as_dir")
+}
+
+// Undoes %XX escapes. None for broken escapes, or if the result isn't UTF-8.
+pub fn percent_decode(path: &str) -> Option<String> {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes.get(i + 1..i + 3)?;
+            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+            decoded.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+// The one slug scheme, for heading ids, tag pages and anchors, and the slugify template
+// helper, so links built in templates match. Lowercase, Latin accents dropped, runs of
+// anything that isn't a letter or digit turned into one dash, and no dashes at the ends.
+// Letters from other scripts are kept. Changing what this gives for existing input breaks
+// links, see the slugify_is_stable test.
+pub fn slugify(text: &str) -> String {
+    let mut slug = String::with_capacity(text.len());
+    let mut dash = false;
+    for c in text.chars().flat_map(char::to_lowercase) {
+        let folded = fold_diacritic(c);
+        let mut push = |c: char| {
+            if c.is_alphanumeric() {
+                if dash && !slug.is_empty() {
+                    slug.push('-');
+                }
+                dash = false;
+                slug.push(c);
+            } else {
+                dash = true;
+            }
+        };
+        match folded {
+            Some(folded) => folded.chars().for_each(&mut push),
+            None => push(c),
+        }
+    }
+    slug
+}
+
+// The ASCII spelling of lowercase Latin letters with diacritics, or ligatures.
+fn fold_diacritic(c: char) -> Option<&'static str> {
+    Some(match c {
+        'à' | 'á' | 'â' | 'ã' | 'ä' | 'å' | 'ā' | 'ă' | 'ą' => "a",
+        'ç' | 'ć' | 'ĉ' | 'ċ' | 'č' => "c",
+        'ď' | 'đ' | 'ð' => "d",
+        'è' | 'é' | 'ê' | 'ë' | 'ē' | 'ĕ' | 'ė' | 'ę' | 'ě' => "e",
+        'ĝ' | 'ğ' | 'ġ' | 'ģ' => "g",
+        'ĥ' | 'ħ' => "h",
+        'ì' | 'í' | 'î' | 'ï' | 'ĩ' | 'ī' | 'ĭ' | 'į' | 'ı' => "i",
+        'ĵ' => "j",
+        'ķ' => "k",
+        'ĺ' | 'ļ' | 'ľ' | 'ŀ' | 'ł' => "l",
+        'ñ' | 'ń' | 'ņ' | 'ň' => "n",
+        'ò' | 'ó' | 'ô' | 'õ' | 'ö' | 'ø' | 'ō' | 'ŏ' | 'ő' => "o",
+        'ŕ' | 'ŗ' | 'ř' => "r",
+        'ś' | 'ŝ' | 'ş' | 'š' => "s",
+        'ţ' | 'ť' | 'ŧ' => "t",
+        'ù' | 'ú' | 'û' | 'ü' | 'ũ' | 'ū' | 'ŭ' | 'ů' | 'ű' | 'ų' => "u",
+        'ŵ' => "w",
+        'ý' | 'ÿ' | 'ŷ' => "y",
+        'ź' | 'ż' | 'ž' => "z",
+        'ß' => "ss",
+        'æ' => "ae",
+        'œ' => "oe",
+        'þ' => "th",
+        _ => return None,
+    })
+}
+
+// A stand-in page for a URL that has moved. Static hosting can't send real redirects, so
+// this refreshes to the new location, and tells search engines which URL to keep.
+pub fn redirect_html(target_url: &str, canonical_url: &str) -> String {
+    let target_url = handlebars::html_escape(target_url);
+    let canonical_url = handlebars::html_escape(canonical_url);
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>Moved</title>
+<link rel="canonical" href="{canonical_url}">
+<meta name="robots" content="noindex">
+<meta http-equiv="refresh" content="0; url={target_url}">
+</head>
+<body>
+<p>This page has moved to <a href="{target_url}">{target_url}</a>.</p>
+</body>
+</html>
+"#
+    )
+}
+
+// Work folders inside the output directory while a build is swapped in, named after the
+// process. They're not part of the site, so they're not served, pruned or carried over.
+const STAGING_PREFIX: &str = ".staging-";
+const OLD_OUTPUT_PREFIX: &str = ".old-";
+
+pub fn is_swap_dir(name: &OsStr) -> bool {
+    name.to_str()
+        .is_some_and(|name| name.starts_with(STAGING_PREFIX) || name.starts_with(OLD_OUTPUT_PREFIX))
+}
+
+// Where swap_output moves out_dir aside to, next to it, like build.old-1234.
+fn old_output_dir(out_dir: &Path, pid: u32) -> anyhow::Result<PathBuf> {
+    let mut old_name = out_dir.file_name().context("swap_output")?.to_owned();
+    old_name.push(format!("{OLD_OUTPUT_PREFIX}{pid}"));
+    Ok(out_dir.with_file_name(old_name))
+}
+
+// Where to build before swap_output, inside out_dir so it's on the same filesystem. Creates
+// out_dir if needed, and removes what interrupted builds left behind, in it and next to it.
+pub fn staging_dir(out_dir: &Path) -> anyhow::Result<PathBuf> {
+    fs::create_dir_all(out_dir).context("staging_dir")?;
+    for entry in fs::read_dir(out_dir).context("staging_dir")? {
+        let entry = entry?;
+        if is_swap_dir(&entry.file_name()) && entry.file_type()?.is_dir() {
+            fs::remove_dir_all(entry.path()).context("staging_dir")?;
+        }
+    }
+    let name = out_dir
+        .file_name()
+        .context("staging_dir")?
+        .to_string_lossy();
+    let old_prefix = format!("{name}{OLD_OUTPUT_PREFIX}");
+    let parent = match out_dir.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    for entry in fs::read_dir(parent).context("staging_dir")? {
+        let entry = entry?;
+        let is_old = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.strip_prefix(&old_prefix))
+            .is_some_and(|pid| pid.parse::<u32>().is_ok());
+        if is_old && entry.file_type()?.is_dir() {
+            fs::remove_dir_all(entry.path()).context("staging_dir")?;
+        }
+    }
+    Ok(out_dir.join(format!("{STAGING_PREFIX}{}", std::process::id())))
+}
+
+// Replaces the contents of out_dir with those of staging, from staging_dir. Renames out_dir
+// aside and staging into its place, so the whole site switches over at once. Where out_dir
+// can't be renamed, like when it's a mount point, or on Windows while a file in it is open,
+// the top-level entries are swapped one at a time instead.
+pub fn swap_output(staging: &Path, out_dir: &Path) -> anyhow::Result<()> {
+    let old = old_output_dir(out_dir, std::process::id())?;
+    if fs::rename(out_dir, &old).is_ok() {
+        let moved_staging = old.join(staging.strip_prefix(out_dir)?);
+        if let Err(err) = fs::rename(moved_staging, out_dir) {
+            // Put the last good output back.
+            let _ = fs::rename(&old, out_dir);
+            return Err(err).context("swap_output");
+        }
+        return fs::remove_dir_all(&old).context("swap_output");
+    }
+    swap_entries(staging, out_dir)
+}
+
+fn swap_entries(staging: &Path, out_dir: &Path) -> anyhow::Result<()> {
+    let old = out_dir.join(format!("{OLD_OUTPUT_PREFIX}{}", std::process::id()));
+    fs::create_dir(&old).context("swap_output")?;
+    let mut names = HashSet::new();
+    for entry in fs::read_dir(staging).context("swap_output")? {
+        let name = entry?.file_name();
+        let target = out_dir.join(&name);
+        if target.symlink_metadata().is_ok() {
+            fs::rename(&target, old.join(&name)).context("swap_output")?;
+        }
+        fs::rename(staging.join(&name), &target).context("swap_output")?;
+        names.insert(name);
+    }
+    // What the new build doesn't have.
+    for entry in fs::read_dir(out_dir).context("swap_output")? {
+        let name = entry?.file_name();
+        if !is_swap_dir(&name) && !names.contains(&name) {
+            fs::rename(out_dir.join(&name), old.join(&name)).context("swap_output")?;
+        }
     }
+    fs::remove_dir(staging).context("swap_output")?;
+    fs::remove_dir_all(&old).context("swap_output")
 }
 
+#[tracing::instrument(skip_all)]
+// Without minify, each file gets a heading comment.
 pub fn concat_files(
     in_parent: &Path,
     inputs: &[&str],
-    out_path: &Path,
-    include_headings: bool,
-) -> anyhow::Result<()> {
-    let mut file = std::fs::File::create(out_path)?;
+    minify: bool,
+) -> anyhow::Result<(Vec<u8>, MinifyStats)> {
+    let mut data = vec![];
+    let mut stats = MinifyStats::default();
     for input in inputs {
-        if include_headings {
+        if !minify {
             let heading = format!("\n\n/* ============== {} ============== */\n\n", input);
-            file.write_all(heading.as_bytes())?;
+            data.extend_from_slice(heading.as_bytes());
         }
-        let data = std::fs::read(in_parent.join(input))?;
-        file.write_all(&data)?;
+        let path = in_parent.join(input);
+        let contents = std::fs::read(&path)?;
+        if minify {
+            match minify::minify_asset(&path, &contents, false) {
+                Ok(Some(minified)) => {
+                    stats.add(contents.len(), minified.len());
+                    data.extend(minified);
+                    data.push(b'\n');
+                    continue;
+                }
+                Ok(None) => {}
+                Err(err) => println!(
+                    "Warning: Couldn't minify {}, using it as is: {:#}",
+                    path.display(),
+                    err
+                ),
+            }
+        }
+        data.extend(contents);
+    }
+    Ok((data, stats))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::writer::FsWriter;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn slugify_is_stable() {
+        // Existing URLs and anchors depend on these.
+        let fixed = [
+            ("Releases", "releases"),
+            ("rendering-tricks", "rendering-tricks"),
+            ("PPSSPP 1.17 is out!", "ppsspp-1-17-is-out"),
+            ("  Getting started -- Android ", "getting-started-android"),
+            ("What's new?", "what-s-new"),
+            ("Crème brûlée, Straße & Ærø", "creme-brulee-strasse-aero"),
+            ("Łódź", "lodz"),
+            ("日本語 ガイド", "日本語-ガイド"),
+            ("snake_case_name", "snake-case-name"),
+            ("---", ""),
+            ("", ""),
+        ];
+        for (text, slug) in fixed {
+            assert_eq!(slugify(text), slug, "{text:?}");
+        }
+    }
+
+    #[test]
+    fn slugify_properties() {
+        let texts = [
+            "Hello, World!",
+            "-leading and trailing-",
+            "Multiple   spaces\tand\nnewlines",
+            "ARM64 / Windows (on Snapdragon)",
+            "Ünïcödé everywhere — em dash",
+            "🎮 Controller setup 🎮",
+            "a--b__c  d",
+            "1.17.1",
+            "Ελληνικά και Кириллица",
+        ];
+        for text in texts {
+            let slug = slugify(text);
+            assert_eq!(slugify(&slug), slug, "not idempotent for {text:?}");
+            assert!(!slug.starts_with('-') && !slug.ends_with('-'), "{slug:?}");
+            assert!(!slug.contains("--"), "{slug:?}");
+            assert!(
+                slug.chars().all(|c| c == '-' || c.is_alphanumeric()),
+                "{slug:?}"
+            );
+            assert_eq!(slug, slug.to_lowercase(), "{slug:?}");
+        }
+    }
+
+    // A source folder with a few files and a subfolder, and an empty output folder next to it.
+    fn test_dirs() -> (tempfile::TempDir, PathBuf, PathBuf) {
+        let temp = tempfile::tempdir().unwrap();
+        let src = temp.path().join("src");
+        fs::create_dir_all(src.join("img")).unwrap();
+        fs::write(src.join("a.txt"), "a").unwrap();
+        fs::write(src.join("b.txt"), "b").unwrap();
+        fs::write(src.join("img/c.png"), "c").unwrap();
+        let out = temp.path().join("out");
+        (temp, src, out)
+    }
+
+    fn copy(src: &Path, dst: &Path) -> CopyStats {
+        let options = CopyOptions {
+            delete_removed: true,
+            ..Default::default()
+        };
+        copy_recursive(&mut FsWriter, src, dst, &options).unwrap()
+    }
+
+    fn set_modified(path: &Path, time: SystemTime) {
+        let file = fs::File::options().write(true).open(path).unwrap();
+        file.set_modified(time).unwrap();
+    }
+
+    #[test]
+    fn copy_recursive_skips_unchanged() {
+        let (_temp, src, out) = test_dirs();
+
+        // New files.
+        let stats = copy(&src, &out);
+        assert_eq!(
+            stats,
+            CopyStats {
+                copied: 3,
+                ..Default::default()
+            }
+        );
+        assert_eq!(fs::read_to_string(out.join("img/c.png")).unwrap(), "c");
+
+        // Unchanged files.
+        let stats = copy(&src, &out);
+        assert_eq!(stats.copied, 0);
+        assert_eq!(stats.skipped, 3);
+
+        // Changed file, same size but a different time.
+        fs::write(src.join("a.txt"), "A").unwrap();
+        set_modified(
+            &src.join("a.txt"),
+            SystemTime::now() + Duration::from_secs(10),
+        );
+        // Same contents, only the time differs.
+        set_modified(
+            &src.join("b.txt"),
+            SystemTime::now() + Duration::from_secs(10),
+        );
+        let stats = copy(&src, &out);
+        assert_eq!(stats.copied, 1);
+        assert_eq!(stats.skipped, 2);
+        assert_eq!(fs::read_to_string(out.join("a.txt")).unwrap(), "A");
+
+        // Removed files and folders.
+        fs::remove_file(src.join("b.txt")).unwrap();
+        fs::remove_dir_all(src.join("img")).unwrap();
+        let stats = copy(&src, &out);
+        assert_eq!(
+            stats,
+            CopyStats {
+                skipped: 1,
+                deleted: 2,
+                ..Default::default()
+            }
+        );
+        assert!(!out.join("b.txt").exists());
+        assert!(!out.join("img").exists());
+
+        // Without the flag, extra files stay.
+        fs::write(out.join("extra.txt"), "extra").unwrap();
+        let stats = copy_recursive(&mut FsWriter, &src, &out, &CopyOptions::default()).unwrap();
+        assert_eq!(stats.deleted, 0);
+        assert!(out.join("extra.txt").exists());
+    }
+
+    #[test]
+    fn copy_recursive_fingerprints() {
+        let (_temp, src, out) = test_dirs();
+        let mut options = CopyOptions {
+            fingerprint: true,
+            delete_removed: true,
+            ..Default::default()
+        };
+        let stats = copy_recursive(&mut FsWriter, &src, &out, &options).unwrap();
+        assert_eq!(stats.copied, 3);
+        let hashed = &stats.assets["img/c.png"];
+        assert_eq!(
+            hashed,
+            &format!("img/c.{}.png", fingerprint::content_hash(b"c"))
+        );
+        assert_eq!(fs::read_to_string(out.join(hashed)).unwrap(), "c");
+        assert!(!out.join("img/c.png").exists());
+
+        // Unchanged, the hashed files stay, and the originals come back with keep_unhashed.
+        options.keep_unhashed = true;
+        let stats = copy_recursive(&mut FsWriter, &src, &out, &options).unwrap();
+        assert_eq!((stats.copied, stats.skipped, stats.deleted), (0, 3, 0));
+        assert!(out.join(hashed).exists());
+        assert!(out.join("img/c.png").exists());
+
+        // A changed file gets a new name, and the old one is deleted.
+        fs::write(src.join("img/c.png"), "C").unwrap();
+        let stats = copy_recursive(&mut FsWriter, &src, &out, &options).unwrap();
+        assert_eq!(stats.deleted, 1);
+        assert!(!out.join(hashed).exists());
+        assert!(out.join(&stats.assets["img/c.png"]).exists());
+    }
+
+    #[test]
+    fn copy_recursive_ignores() {
+        let (_temp, src, out) = test_dirs();
+        fs::create_dir_all(src.join("img/front/originals/big")).unwrap();
+        fs::create_dir_all(src.join("originals")).unwrap();
+        fs::create_dir_all(src.join("models")).unwrap();
+        fs::write(src.join("img/front/originals/big/shot.png"), "big").unwrap();
+        fs::write(src.join("originals/logo.png"), "logo").unwrap();
+        fs::write(src.join("img/front/cover.psd"), "psd").unwrap();
+        fs::write(src.join("models/psp.blend"), "blend").unwrap();
+        fs::write(src.join("models/psp.png"), "render").unwrap();
+        // Only a folder matches the trailing slash.
+        fs::write(src.join("img/originals"), "file").unwrap();
+        fs::write(src.join(".siteignore"), "# Sources\n**/originals/\n*.psd\n").unwrap();
+
+        let options = CopyOptions {
+            ignore: static_ignore(&src, &["/models/*.blend".to_string()]).unwrap(),
+            delete_removed: true,
+            ..Default::default()
+        };
+        let stats = copy_recursive(&mut FsWriter, &src, &out, &options).unwrap();
+        // The two originals folders, the psd, the blend and .siteignore.
+        assert_eq!(stats.excluded, 5);
+        assert_eq!(stats.copied, 5);
+        assert!(out.join("img/front").is_dir());
+        assert!(!out.join("img/front/originals").exists());
+        assert!(!out.join("originals").exists());
+        assert!(!out.join("img/front/cover.psd").exists());
+        assert!(!out.join("models/psp.blend").exists());
+        assert!(!out.join(".siteignore").exists());
+        assert!(out.join("models/psp.png").exists());
+        assert!(out.join("img/originals").is_file());
+
+        // Files that were copied before being ignored get deleted.
+        let stats = copy(&src, &out);
+        assert!(out.join("img/front/cover.psd").exists());
+        assert_eq!(stats.excluded, 0);
+        let stats = copy_recursive(&mut FsWriter, &src, &out, &options).unwrap();
+        assert_eq!(stats.deleted, 5);
+        assert!(!out.join("img/front/originals").exists());
+        assert!(!out.join("img/front/cover.psd").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn copy_recursive_symlinks() {
+        use std::os::unix::fs::symlink;
+
+        let (_temp, src, out) = test_dirs();
+        let shared = src.parent().unwrap().join("shared");
+        fs::create_dir_all(&shared).unwrap();
+        fs::write(shared.join("shot.png"), "shot").unwrap();
+        symlink(&shared, src.join("screenshots")).unwrap();
+        symlink("a.txt", src.join("link.txt")).unwrap();
+        symlink("missing.txt", src.join("broken.txt")).unwrap();
+        // Would recurse forever if followed.
+        symlink("..", src.join("img/up")).unwrap();
+        symlink(&src, src.join("self")).unwrap();
+
+        // Following copies the targets, skipping the loops and the broken link.
+        let stats = copy(&src, &out);
+        assert_eq!(stats.copied, 5);
+        assert_eq!(
+            fs::read_to_string(out.join("screenshots/shot.png")).unwrap(),
+            "shot"
+        );
+        assert_eq!(fs::read_to_string(out.join("link.txt")).unwrap(), "a");
+        assert!(!out.join("link.txt").is_symlink());
+        assert!(!out.join("broken.txt").exists());
+        assert!(!out.join("img/up").exists());
+        assert!(!out.join("self").exists());
+
+        // Preserving recreates the links, replacing the copies.
+        let preserve = CopyOptions {
+            delete_removed: true,
+            symlinks: SymlinkMode::Preserve,
+            ..Default::default()
+        };
+        let stats = copy_recursive(&mut FsWriter, &src, &out, &preserve).unwrap();
+        assert_eq!(stats.copied, 5);
+        assert_eq!(stats.skipped, 3);
+        assert_eq!(
+            fs::read_link(out.join("link.txt")).unwrap(),
+            Path::new("a.txt")
+        );
+        assert_eq!(fs::read_link(out.join("screenshots")).unwrap(), shared);
+        assert_eq!(fs::read_link(out.join("img/up")).unwrap(), Path::new(".."));
+        assert!(out.join("broken.txt").is_symlink());
+
+        // Skipping leaves them out, and deletes what was there for them.
+        let skip = CopyOptions {
+            delete_removed: true,
+            symlinks: SymlinkMode::Skip,
+            ..Default::default()
+        };
+        let stats = copy_recursive(&mut FsWriter, &src, &out, &skip).unwrap();
+        assert_eq!(stats.excluded, 5);
+        assert_eq!(stats.deleted, 5);
+        for link in ["screenshots", "link.txt", "broken.txt", "img/up", "self"] {
+            assert!(!out.join(link).is_symlink(), "{link}");
+            assert!(!out.join(link).exists(), "{link}");
+        }
+        // The shared folder was never written through.
+        assert_eq!(fs::read_dir(&shared).unwrap().count(), 1);
+        assert_eq!(fs::read_to_string(out.join("a.txt")).unwrap(), "a");
+    }
+
+    #[test]
+    fn swap_output_replaces_the_site() {
+        let (_temp, _, out) = test_dirs();
+        fs::create_dir_all(out.join("docs")).unwrap();
+        fs::write(out.join("index.html"), "old").unwrap();
+        fs::write(out.join("docs/index.html"), "old").unwrap();
+        // Left by interrupted builds.
+        fs::create_dir_all(out.join(".staging-1")).unwrap();
+        let stale = old_output_dir(&out, 1).unwrap();
+        fs::create_dir_all(stale.join("docs")).unwrap();
+        // Not from swap_output.
+        let kept = out.with_file_name("out.old-copy");
+        fs::create_dir_all(&kept).unwrap();
+
+        let staging = staging_dir(&out).unwrap();
+        assert!(!out.join(".staging-1").exists());
+        assert!(!stale.exists());
+        assert!(kept.is_dir());
+        fs::create_dir_all(staging.join("blog")).unwrap();
+        fs::write(staging.join("index.html"), "new").unwrap();
+        fs::write(staging.join("blog/index.html"), "new").unwrap();
+        swap_output(&staging, &out).unwrap();
+
+        assert_eq!(fs::read_to_string(out.join("index.html")).unwrap(), "new");
+        assert!(out.join("blog/index.html").is_file());
+        assert!(!out.join("docs").exists());
+        assert!(!staging.exists());
+        assert!(!old_output_dir(&out, std::process::id()).unwrap().exists());
+    }
+
+    #[test]
+    fn swap_entries_replaces_the_site() {
+        let (_temp, _, out) = test_dirs();
+        fs::create_dir_all(out.join("docs")).unwrap();
+        fs::write(out.join("index.html"), "old").unwrap();
+        fs::write(out.join("docs/index.html"), "old").unwrap();
+        let staging = staging_dir(&out).unwrap();
+        fs::create_dir_all(staging.join("blog")).unwrap();
+        fs::write(staging.join("index.html"), "new").unwrap();
+        fs::write(staging.join("blog/index.html"), "new").unwrap();
+        swap_entries(&staging, &out).unwrap();
+
+        assert_eq!(fs::read_to_string(out.join("index.html")).unwrap(), "new");
+        assert!(out.join("blog/index.html").is_file());
+        assert!(!out.join("docs").exists());
+        assert_eq!(fs::read_dir(&out).unwrap().count(), 2);
     }
-    Ok(())
 }
@@ -0,0 +1,348 @@
+use crate::util;
+use anyhow::Context;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+
+// All generated output goes through this, so generation can run without touching the disk.
+pub trait SiteWriter {
+    // Creates parent directories as needed.
+    fn write(&mut self, path: &Path, contents: &[u8]) -> anyhow::Result<()>;
+    // Copies a source file into the output. False if it was already there, unchanged.
+    fn copy(&mut self, src: &Path, dst: &Path) -> anyhow::Result<bool>;
+    // Recreates the symlink src in the output, pointing to the same place.
+    fn symlink(&mut self, src: &Path, dst: &Path) -> anyhow::Result<()>;
+    fn create_dir(&mut self, path: &Path) -> anyhow::Result<()>;
+}
+
+pub struct FsWriter;
+
+impl SiteWriter for FsWriter {
+    fn write(&mut self, path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).context("create_dir")?;
+        }
+        std::fs::write(path, contents).with_context(|| format!("write {}", path.display()))
+    }
+
+    fn copy(&mut self, src: &Path, dst: &Path) -> anyhow::Result<bool> {
+        // Don't write through a link left by an earlier copy.
+        remove_symlink(dst)?;
+        if util::is_copy_of(src, dst) {
+            return Ok(false);
+        }
+        std::fs::copy(src, dst).context("copy-file")?;
+        // Keep the time, so the next build can tell the copy is up to date.
+        let modified = std::fs::metadata(src).and_then(|m| m.modified());
+        if let Ok(modified) = modified {
+            let _ = std::fs::File::options()
+                .write(true)
+                .open(dst)
+                .and_then(|file| file.set_modified(modified));
+        }
+        Ok(true)
+    }
+
+    fn symlink(&mut self, src: &Path, dst: &Path) -> anyhow::Result<()> {
+        let target = std::fs::read_link(src).context("read_link")?;
+        if std::fs::read_link(dst).is_ok_and(|existing| existing == target) {
+            return Ok(());
+        }
+        match std::fs::symlink_metadata(dst) {
+            Ok(metadata) if metadata.is_dir() => {
+                std::fs::remove_dir_all(dst).context("remove_dir")?;
+            }
+            Ok(_) => std::fs::remove_file(dst).context("remove_file")?,
+            Err(_) => {}
+        }
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(&target, dst).context("symlink")?;
+        #[cfg(windows)]
+        if src.is_dir() {
+            std::os::windows::fs::symlink_dir(&target, dst).context("symlink")?;
+        } else {
+            std::os::windows::fs::symlink_file(&target, dst).context("symlink")?;
+        }
+        Ok(())
+    }
+
+    fn create_dir(&mut self, path: &Path) -> anyhow::Result<()> {
+        remove_symlink(path)?;
+        std::fs::create_dir_all(path).context("create_dir")
+    }
+}
+
+fn remove_symlink(path: &Path) -> anyhow::Result<()> {
+    if path.is_symlink() {
+        std::fs::remove_file(path).context("remove_file")?;
+    }
+    Ok(())
+}
+
+// Writes to disk, but files whose contents are the same as in the previous build are
+// hard-linked from there instead, so they keep their modification times. That way rsync
+// and CDNs only see the pages that really changed.
+pub struct IncrementalWriter {
+    pub out_dir: PathBuf,
+    pub previous_dir: PathBuf,
+    // Files reused from the previous build.
+    pub unchanged: usize,
+}
+
+impl IncrementalWriter {
+    pub fn new(out_dir: &Path, previous_dir: &Path) -> Self {
+        Self {
+            out_dir: out_dir.to_path_buf(),
+            previous_dir: previous_dir.to_path_buf(),
+            unchanged: 0,
+        }
+    }
+
+    fn reuse_previous(&self, path: &Path, contents: &[u8]) -> bool {
+        let Ok(relative) = path.strip_prefix(&self.out_dir) else {
+            return false;
+        };
+        let previous = self.previous_dir.join(relative);
+        // Only read files that could match.
+        let same_size =
+            std::fs::metadata(&previous).is_ok_and(|m| m.len() == contents.len() as u64);
+        if !same_size || std::fs::read(&previous).ok().as_deref() != Some(contents) {
+            return false;
+        }
+        if let Some(parent) = path.parent() {
+            if std::fs::create_dir_all(parent).is_err() {
+                return false;
+            }
+        }
+        std::fs::hard_link(&previous, path).is_ok()
+    }
+}
+
+impl SiteWriter for IncrementalWriter {
+    fn write(&mut self, path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+        // Written before, maybe as a link to the previous build, which must be left alone.
+        let _ = std::fs::remove_file(path);
+        if self.reuse_previous(path, contents) {
+            self.unchanged += 1;
+            return Ok(());
+        }
+        FsWriter.write(path, contents)
+    }
+
+    fn copy(&mut self, src: &Path, dst: &Path) -> anyhow::Result<bool> {
+        if let Ok(relative) = dst.strip_prefix(&self.out_dir) {
+            let previous = self.previous_dir.join(relative);
+            if util::is_copy_of(src, &previous) && std::fs::hard_link(&previous, dst).is_ok() {
+                return Ok(false);
+            }
+        }
+        FsWriter.copy(src, dst)
+    }
+
+    fn symlink(&mut self, src: &Path, dst: &Path) -> anyhow::Result<()> {
+        FsWriter.symlink(src, dst)
+    }
+
+    fn create_dir(&mut self, path: &Path) -> anyhow::Result<()> {
+        FsWriter.create_dir(path)
+    }
+}
+
+// Writes through to another writer, and keeps a copy of every file up to max_size bytes,
+// for serving from memory.
+pub struct CachingWriter<W> {
+    pub inner: W,
+    pub max_size: u64,
+    pub files: HashMap<PathBuf, Vec<u8>>,
+}
+
+impl<W: SiteWriter> SiteWriter for CachingWriter<W> {
+    fn write(&mut self, path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+        self.inner.write(path, contents)?;
+        if contents.len() as u64 <= self.max_size {
+            self.files.insert(path.to_path_buf(), contents.to_vec());
+        }
+        Ok(())
+    }
+
+    fn copy(&mut self, src: &Path, dst: &Path) -> anyhow::Result<bool> {
+        let copied = self.inner.copy(src, dst)?;
+        if std::fs::metadata(src).context("copy-file")?.len() <= self.max_size {
+            let contents = std::fs::read(src).context("copy-file")?;
+            self.files.insert(dst.to_path_buf(), contents);
+        }
+        Ok(copied)
+    }
+
+    // Not kept in memory, requests through it go to the disk.
+    fn symlink(&mut self, src: &Path, dst: &Path) -> anyhow::Result<()> {
+        self.inner.symlink(src, dst)
+    }
+
+    fn create_dir(&mut self, path: &Path) -> anyhow::Result<()> {
+        self.inner.create_dir(path)
+    }
+}
+
+// Writes through to another writer, and remembers what's in the output, for checking links.
+pub struct RecordingWriter<'a> {
+    pub inner: &'a mut dyn SiteWriter,
+    // Every file written, with its size.
+    pub files: HashMap<PathBuf, u64>,
+    // Symlinked folders in the output, and where they point.
+    pub linked_dirs: Vec<(PathBuf, PathBuf)>,
+}
+
+impl<'a> RecordingWriter<'a> {
+    pub fn new(inner: &'a mut dyn SiteWriter) -> Self {
+        Self {
+            inner,
+            files: HashMap::new(),
+            linked_dirs: vec![],
+        }
+    }
+
+    // Everything written, relative to out_dir, to prune the rest. Symlinked folders count as
+    // one file.
+    pub fn manifest(&self, out_dir: &Path) -> BTreeSet<PathBuf> {
+        self.files
+            .keys()
+            .chain(self.linked_dirs.iter().map(|(dst, _)| dst))
+            .filter_map(|path| Some(path.strip_prefix(out_dir).ok()?.to_path_buf()))
+            .collect()
+    }
+}
+
+impl SiteWriter for RecordingWriter<'_> {
+    fn write(&mut self, path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+        self.inner.write(path, contents)?;
+        self.files.insert(path.to_path_buf(), contents.len() as u64);
+        Ok(())
+    }
+
+    fn copy(&mut self, src: &Path, dst: &Path) -> anyhow::Result<bool> {
+        let copied = self.inner.copy(src, dst)?;
+        self.files.insert(dst.to_path_buf(), file_size(src));
+        Ok(copied)
+    }
+
+    fn symlink(&mut self, src: &Path, dst: &Path) -> anyhow::Result<()> {
+        self.inner.symlink(src, dst)?;
+        if src.is_dir() {
+            self.linked_dirs
+                .push((dst.to_path_buf(), src.to_path_buf()));
+        } else {
+            self.files.insert(dst.to_path_buf(), file_size(src));
+        }
+        Ok(())
+    }
+
+    fn create_dir(&mut self, path: &Path) -> anyhow::Result<()> {
+        self.inner.create_dir(path)
+    }
+}
+
+fn file_size(path: &Path) -> u64 {
+    std::fs::metadata(path).map_or(0, |metadata| metadata.len())
+}
+
+// Keeps the output in a map keyed by path, for tests and --list-routes.
+#[derive(Default)]
+pub struct MemoryWriter {
+    pub files: BTreeMap<PathBuf, Vec<u8>>,
+}
+
+impl SiteWriter for MemoryWriter {
+    fn write(&mut self, path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+        self.files.insert(path.to_path_buf(), contents.to_vec());
+        Ok(())
+    }
+
+    fn copy(&mut self, src: &Path, dst: &Path) -> anyhow::Result<bool> {
+        let contents = std::fs::read(src).context("copy-file")?;
+        self.files.insert(dst.to_path_buf(), contents);
+        Ok(true)
+    }
+
+    // Keeps what the link points to, like a copy would. Folder links are left out.
+    fn symlink(&mut self, src: &Path, dst: &Path) -> anyhow::Result<()> {
+        if src.is_file() {
+            self.copy(src, dst)?;
+        }
+        Ok(())
+    }
+
+    fn create_dir(&mut self, _path: &Path) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn incremental_links_unchanged_files() {
+        let temp = tempfile::tempdir().unwrap();
+        let (out, previous) = (temp.path().join("out"), temp.path().join("previous"));
+        std::fs::create_dir_all(previous.join("docs")).unwrap();
+        std::fs::write(previous.join("docs/index.html"), "same").unwrap();
+        std::fs::write(previous.join("index.html"), "old").unwrap();
+
+        let mut writer = IncrementalWriter::new(&out, &previous);
+        writer.write(&out.join("docs/index.html"), b"same").unwrap();
+        writer.write(&out.join("index.html"), b"new").unwrap();
+        assert_eq!(writer.unchanged, 1);
+        assert_eq!(std::fs::read(out.join("index.html")).unwrap(), b"new");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let inode = |path: &Path| std::fs::metadata(path).unwrap().ino();
+            assert_eq!(
+                inode(&out.join("docs/index.html")),
+                inode(&previous.join("docs/index.html"))
+            );
+        }
+
+        // Writing the linked file again replaces the link, the last build keeps its copy.
+        writer
+            .write(&out.join("docs/index.html"), b"changed")
+            .unwrap();
+        assert_eq!(
+            std::fs::read(out.join("docs/index.html")).unwrap(),
+            b"changed"
+        );
+        assert_eq!(
+            std::fs::read(previous.join("docs/index.html")).unwrap(),
+            b"same"
+        );
+        assert_eq!(std::fs::read(previous.join("index.html")).unwrap(), b"old");
+    }
+
+    #[test]
+    fn recording_writer_lists_what_it_wrote() {
+        let temp = tempfile::tempdir().unwrap();
+        let src = temp.path().join("src");
+        std::fs::create_dir_all(src.join("models")).unwrap();
+        std::fs::write(src.join("app.js"), "let x;").unwrap();
+        let out = temp.path().join("out");
+        let mut memory = MemoryWriter::default();
+        let mut writer = RecordingWriter::new(&mut memory);
+        writer.write(&out.join("index.html"), b"<p>Hi</p>").unwrap();
+        writer
+            .copy(&src.join("app.js"), &out.join("static/app.js"))
+            .unwrap();
+        writer
+            .symlink(&src.join("models"), &out.join("static/models"))
+            .unwrap();
+        assert_eq!(writer.files[&out.join("index.html")], 9);
+        assert_eq!(writer.files[&out.join("static/app.js")], 6);
+        assert_eq!(
+            writer.manifest(&out),
+            BTreeSet::from([
+                PathBuf::from("index.html"),
+                PathBuf::from("static/app.js"),
+                PathBuf::from("static/models"),
+            ])
+        );
+    }
+}
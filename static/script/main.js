@@ -796,10 +796,60 @@ function setupCollapsibles() {
     }
 }
 
+function setupCopyButtons() {
+    // The generator wraps each code block in a div.code-block with a button in front of the pre.
+    var buttons = document.getElementsByClassName("copy-code-button");
+    for (var i = 0; i < buttons.length; i++) {
+        buttons[i].addEventListener("click", function () {
+            var button = this;
+            var code = button.nextElementSibling.textContent;
+            navigator.clipboard.writeText(code).then(function () {
+                button.textContent = "Copied!";
+                window.setTimeout(function () { button.textContent = "Copy"; }, 1500);
+            });
+        });
+    }
+}
+
+// highlight.js rebuilds code blocks from their text, dropping the generator's highlighted
+// lines. Redo them from the spec it leaves on the code element, closing and reopening the
+// highlight.js spans around each line so every line stays well-formed on its own.
+function restoreHighlightedLines(el) {
+    var spec = el.dataset.highlightLines;
+    if (!spec) {
+        return;
+    }
+    var ranges = spec.split(",").map(function (part) {
+        var bounds = part.split("-");
+        return [Number(bounds[0]), Number(bounds[1] || bounds[0])];
+    });
+    var open = [];
+    var lines = el.innerHTML.split("\n");
+    el.innerHTML = lines.map(function (line, i) {
+        var number = i + 1;
+        line = open.join("") + line;
+        var tags = line.match(/<span[^>]*>|<\/span>/g) || [];
+        open = [];
+        tags.forEach(function (tag) {
+            if (tag === "</span>") {
+                open.pop();
+            } else {
+                open.push(tag);
+            }
+        });
+        line += "</span>".repeat(open.length);
+        var highlighted = ranges.some(function (range) {
+            return number >= range[0] && number <= range[1];
+        });
+        return highlighted ? '<span class="highlighted-line">' + line + "</span>" : line;
+    }).join("\n");
+}
+
 function onLoadPage() {
     loadCredentials();
     applyDOMVisibility();
     setupCollapsibles();
+    setupCopyButtons();
     if (g_thankYouPage) {
         window.setTimeout(pollPurchase, g_pollInterval);
     }
@@ -809,6 +859,11 @@ function onLoadPage() {
 
     if (typeof hljs !== 'undefined') {
         console.log("highlighting");
+        hljs.addPlugin({
+            "after:highlightElement": function (data) {
+                restoreHighlightedLines(data.el);
+            }
+        });
         hljs.highlightAll();
     }
 